@@ -0,0 +1,7 @@
+import Config
+
+# The test suite drives the NIF's error paths through deterministic fault
+# injection, which is compiled in only with this cargo feature.
+if config_env() == :test do
+  config :px, Px, features: ["fault_injection"]
+end
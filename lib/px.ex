@@ -21,8 +21,13 @@ defmodule Px do
 
   - `nil` - /dev/null (default)
   - `:pipe` - pipe for reading/writing from Elixir
+  - `:socket` - Unix socket pair, read and written like a pipe
+  - `:pty` - pseudo-terminal, set for all three streams with `pty: true`
   - `:inherit` - share BEAM's stdio (for interactive programs)
   - `{:file, path}` - redirect to/from file
+  - `:zero` - stdin only: `/dev/zero`, an endless stream of zero bytes
+  - `{:repeat, pattern}` - stdin only: `pattern` written over and over, see
+    [Synthetic Input](#module-synthetic-input)
 
   ### Fire and Forget
 
@@ -40,6 +45,13 @@ defmodule Px do
         stdout: {:file, "/var/log/out.log"},
         stderr: {:file, "/var/log/err.log"})
 
+  Output files are truncated by default. Pass options to append instead, or
+  to control how the file is created:
+
+      p = Px.spawn!("my-server", [],
+        stdout: {:file, "/var/log/out.log", flags: [:append]},
+        stderr: {:file, "/run/secrets.log", flags: [:exclusive], mode: 0o600})
+
   ### Pipes
 
   For reading output or writing input. Non-blocking by default.
@@ -60,6 +72,18 @@ defmodule Px do
   than the buffer can hold and you don't read, the child blocks forever.
   Drain pipes continuously for long-running processes.
 
+  ### Synthetic Input
+
+  For load-testing a consumer, stdin can be fed without the BEAM's help. A
+  detached thread writes `pattern` into the pipe until the child closes it or
+  exits; `{:repeat, pattern, bytes}` stops after `bytes` and closes stdin, so
+  a child reading to EOF sees a bounded stream:
+
+      p = Px.spawn!("wc", ["-c"], stdin: {:repeat, <<0>>, 1_000_000}, stdout: :pipe)
+      {:ok, _, "1000000\\n", ""} = Px.communicate(p)
+
+  The pipe belongs to the thread, so `write/2` returns `{:error, :not_piped}`.
+
   ### Inherit
 
   Child uses BEAM's terminal directly. For interactive programs:
@@ -88,7 +112,45 @@ defmodule Px do
         cd: "/path/to/project",
         env: %{"CC" => "clang", "CFLAGS" => "-O2"})
 
-  Environment variables are merged with the inherited environment.
+  Environment variables are merged with the inherited environment. To start
+  from a clean environment and carry over only a few variables:
+
+      Px.spawn!("make", ["build"],
+        clear_env: true,
+        inherit_env: ["PATH", "HOME", "LANG"],
+        env: %{"CC" => "clang"})
+
+  Large environments can be read from a dotenv-style file with `:env_file`
+  instead of being passed as a map. Each line is `KEY=VALUE`, optionally
+  prefixed with `export`; blank lines and `#` comments are skipped. Values
+  may be single-quoted (literal) or double-quoted (`\\n`, `\\t`, `\\"` and
+  `\\\\` escapes). A malformed line fails the spawn with
+  `{:error, {:invalid_env_file, line_number}}`.
+
+  Each `:env` entry given as a list may also say how it relates to what the
+  child would otherwise get. `{key, value, :force}`, like `{key, value}`,
+  always sets the variable; `{key, value, :default}` sets it only if the key
+  is in neither the inherited environment, `:env_file` nor another `:env`
+  entry, so a fallback never overrides the caller's own setting:
+
+      Px.spawn!("make", ["build"],
+        env: [{"CC", "cc", :default}, {"CFLAGS", "-O2", :force}])
+
+  ## Cleanup on VM Exit
+
+  By default each child gets `PR_SET_PDEATHSIG` (Linux), so it is SIGKILLed
+  as soon as the BEAM dies, with no chance to flush state. macOS has no
+  PDEATHSIG; there a small detached watchdog process waits for the BEAM to
+  exit and SIGKILLs the child. On Linux the child checks its parent again
+  after `prctl`, so if the BEAM dies before the death signal is armed, the
+  child exits instead of running on uncleanable. For a graceful
+  alternative, turn that off and ask for a signal on normal VM shutdown:
+
+      Px.spawn!("my-server", [], pdeathsig: false, cleanup_signal: :sigterm)
+
+  The cleanup signal is sent from an `atexit` hook, so it only fires when the
+  VM exits normally (`System.stop/0`, `System.halt/0`). If the VM is killed
+  outright, only PDEATHSIG can clean up.
 
   ## Signals
 
@@ -116,7 +178,8 @@ defmodule Px do
       #=> :ok              - all written
       #=> {:partial, n}    - buffer full, n bytes written
       #=> :would_block     - buffer completely full
-      #=> {:error, :broken_pipe}  - child closed stdin
+      #=> {:error, :reader_gone}  - child closed stdin
+      #=> {:error, :already_exited}  - child's exit already collected
 
   ## Process Lifecycle
 
@@ -125,8 +188,24 @@ defmodule Px do
   3. `signal/2` - sends signal
   4. `wait/1,2` - blocks until exit, updates `status` to `{:exited, code}`
 
+  `spawn/3` returns only once the child has exec'd `cmd`. A failed exec, or
+  a failed setup step before it, is reported back to the BEAM through a
+  close-on-exec pipe that a successful exec closes unwritten, so an error
+  says precisely what went wrong and `{:ok, p}` means `p.pid` is already
+  running the target program, on either spawn path.
+
   Exit codes: normal exit returns the code (0-255). Signal termination
-  returns 128 + signal number (e.g., SIGKILL=9 → 137).
+  returns 128 + signal number (e.g., SIGKILL=9 → 137), as a shell reports
+  it, so `exit 137` and a SIGKILL look the same in `status`. Use
+  `exit_info/1` to get the exit code and signal as separate fields.
+
+  If something else reaps the child first (e.g. a SIGCHLD handler that
+  collects every child), its exit status is lost and `status` becomes
+  `{:exited, :reaped_externally}` rather than raising. A `wait/1` blocked
+  at the time still returns: on Linux it watches a pidfd opened when the
+  child was spawned, which reports the exit whoever reaps the child and
+  cannot be confused with a later process that reuses the PID; elsewhere
+  it re-checks the PID every few milliseconds.
   """
   import Kernel, except: [spawn: 1, spawn: 3]
 
@@ -134,13 +213,30 @@ defmodule Px do
 
   defstruct [:cmd, :args, :pid, :status, :resource, :stdin, :stdout, :stderr]
 
-  @type stdio_config :: nil | :pipe | :inherit | {:file, Path.t()}
+  @type stdio_config ::
+          nil
+          | :pipe
+          | :socket
+          | :pty
+          | :inherit
+          | {:file, Path.t()}
+          | {:file, Path.t(), keyword()}
+          | {:tail, pos_integer()}
+          | :fanout
+          | :active
+          | {:log, Path.t()}
+          | :tmpfile
+          | :zero
+          | {:repeat, binary()}
+          | {:repeat, binary(), non_neg_integer()}
+
+  @piped [:pipe, :socket, :pty]
 
   @type t :: %__MODULE__{
-          cmd: String.t(),
+          cmd: String.t() | nil,
           args: [String.t()],
           pid: pos_integer(),
-          status: :running | {:exited, integer()},
+          status: :running | {:exited, integer() | :reaped_externally},
           resource: reference(),
           stdin: stdio_config(),
           stdout: stdio_config(),
@@ -155,19 +251,386 @@ defmodule Px do
   - `:stdin` - stdin configuration (default: `nil` for /dev/null)
   - `:stdout` - stdout configuration (default: `nil` for /dev/null)
   - `:stderr` - stderr configuration (default: `nil` for /dev/null)
-  - `:env` - environment variables as a map (merged with inherited environment)
-  - `:cd` - working directory for the child process
+  - `:env` - environment variables as a map or a list of `{key, value}` and
+    `{key, value, :force | :default}` entries (merged with inherited
+    environment). See "Environment and Working Directory" above
+  - `:clear_env` - start the child with an empty environment instead of the
+    BEAM's (default: `false`); `:env` is still applied
+  - `:inherit_env` - with `:clear_env`, names of variables to copy from the
+    BEAM's environment before `:env` is applied; missing ones are skipped
+  - `:env_file` - path of a dotenv-style file applied after `:inherit_env`
+    and before `:env`. See "Environment and Working Directory" above
+  - `:cd` - working directory for the child process; it must exist
+  - `:pdeathsig` - SIGKILL the child if the BEAM dies (default: `true`, Linux
+    and macOS)
+  - `:cleanup_signal` - signal sent to the child if it is still running when
+    the VM shuts down (default: `nil`, no signal)
+  - `:lines` - make `read/2` on piped stdout/stderr return one complete line
+    per call (default: `false`). Partial lines are buffered until their
+    newline arrives; a final line without one is returned at EOF
+  - `:pty` - run the child on a pseudo-terminal (default: `false`). See
+    "Pseudo-terminals" below
+  - `:inherit_or_pty` - inherit the BEAM's stdin, stdout and stderr when
+    they are all terminals, and run on a pseudo-terminal otherwise
+    (default: `false`). See "Pseudo-terminals" below
+  - `:start_stopped` - leave the child stopped right after exec until
+    `resume/1` (default: `false`, Linux only). See "Starting stopped" below
+  - `:oom_score_adj` - OOM killer score adjustment for the child, from
+    `-1000` to `1000` (default: `nil`, inherited; Linux only). A positive
+    value makes the child a likelier victim than the BEAM under memory
+    pressure. It is written from the parent right after spawning; if that
+    fails the child is killed and `{:error, :oom_score_adj_failed, errno}`
+    is returned, where errno 13 (EACCES) means lowering the score below the
+    BEAM's needs `CAP_SYS_RESOURCE`
+  - `:notify_exit` - a pid to send `{:px_exit, resource, exit}` when the
+    child exits, where `resource` is the process's `:resource` and `exit`
+    is `{:exited, code}`, `{:signaled, signal, core_dumped}` or
+    `:reaped_externally` (default: `nil`). See "Exit notification" below
+  - `:nonblocking` - put piped streams in non-blocking mode (default:
+    `true`). With `false` they are left blocking, for handing the
+    descriptors to code that expects that; `read/2` and `write/2` then block
+    the calling scheduler until the child acts, and timeouts can no longer
+    interrupt a read or write in progress, so prefer the dirty-scheduled
+    `communicate/3`, `request/3`, `wait_stdout_eof/2` and `read_frame/2`
+  - `:tolerate_nonblock_errors` - when putting a piped stream in
+    non-blocking mode fails, keep the already started child with that
+    stream left blocking instead of failing the spawn (default: `false`).
+    `nonblocking_streams/1` tells which streams made it
+  - `:file_actions` - a list of descriptor operations applied in order in
+    the child after the stdio options (default: `[]`). See "File actions"
+    below
+  - `:namespaces` - new namespaces to run the child in, any of `:user`,
+    `:mount`, `:uts`, `:ipc` and `:net` (default: `[]`). Linux only. See
+    "Namespaces" below
+  - `:search_path` - a list of directories to look `cmd` up in, in order,
+    instead of the `PATH` of the BEAM or of `:env` (default: `nil`). A
+    bare `cmd` is resolved to an absolute path before spawning, and the
+    child's `PATH` is left alone; relative directories are taken relative
+    to `:cd`. Spawning returns `{:error, :not_found}` if no directory has
+    an executable `cmd`. Names containing a slash are not searched
+  - `:pgid` - process group for the child to join, or `0` to make it the
+    leader of a new group (default: `nil`, the BEAM's group). See "Process
+    groups" below
+  - `:kill_group_on_drop` - SIGKILL the child's whole process group when
+    the process is garbage collected, e.g. because its owner died, before
+    the child was reaped (default: `false`). Requires `pgid: 0` or
+    `pty: true`. See "Process groups" below
+  - `:interleaved_log` - with `{:tail, bytes}` stdout or stderr, also record
+    each chunk the tail threads read, with its stream and a timestamp, in
+    one combined log of at most this many bytes (default: `nil`). Read it
+    with `interleaved_log/1`
+  - `:cgroup` - path of a cgroup v2 directory for the child to join before
+    exec (default: `nil`). Linux only. The cgroup must already exist and be
+    writable; spawning fails with `{:error, :cgroup_failed, errno}` if the
+    child cannot join it. See "Process groups" below
+  - `:timeout` - milliseconds after which the child is killed with SIGKILL
+    if it is still running (default: `:infinity`). See "Timeouts" below
+  - `:owner` - the process sent the output of `:active` streams and the exit
+    that follows it, and the outcome of `:ready` (default: `self()`). See
+    "Active output" and "Readiness" below
+  - `:ready` - a probe telling `:owner` when the child is ready for work:
+    `{:output, stream, pattern}`, `{:unix, path}` or `{:tcp, host, port}`
+    (default: `nil`). See "Readiness" below
+  - `:ready_timeout` - milliseconds the `:ready` probe may take before it
+    gives up (default: `:infinity`)
+  - `:drain_buf_bytes` - size of each read the internal drain threads of
+    `{:tail, bytes}`, `{:log, path}`, `:fanout`, `:active` and a `:ready`
+    output probe make from the pipe, and of the reads `communicate/3` makes
+    (default: `4096`).
+    Larger reads help children that write a lot; with `:fanout` and
+    `:active` it is also the largest chunk a message carries
 
   Each stdio option accepts:
   - `nil` - redirect to /dev/null (safe default)
   - `:pipe` - create a pipe (enables `read/2` for stdout/stderr, `write/2` for stdin)
+  - `:socket` - create a Unix socket pair; behaves like `:pipe`, but `read/2`
+    only reports `:eof` once the child has actually hung up its end
   - `:inherit` - inherit from parent (child uses BEAM's stdio directly)
   - `{:file, path}` - redirect to/from a file
+  - `{:file, path, opts}` - for `:stdout` and `:stderr`, redirect to a file
+    opened with `opts`:
+    - `:flags` - any of `:append` (keep existing contents), `:exclusive`
+      (fail with `{:error, {:file_exists, path}}` if the file exists) and
+      `:sync` (`O_SYNC`)
+    - `:mode` - permission bits for a new file, before the umask
+      (default: `0o666`)
+  - `{:tail, bytes}` - for `:stdout` and `:stderr`, pipe the output into a
+    ring that keeps only its last `bytes` bytes, drained in the background
+    so the child never blocks on a full pipe. Read it with `tail_output/2`
+  - `{:log, path}` - for `:stdout` and `:stderr`, pipe the output to a
+    background thread that appends it to `path`. Unlike `{:file, path}`, the
+    BEAM holds the file, so `reopen_output/3` can rotate it
+  - `:fanout` - for `:stdout`, deliver the output to any number of
+    subscribed processes as messages. See "Fan-out" below
+  - `:tmpfile` - for `:stdout` and `:stderr`, write to an anonymous
+    temporary file that is deleted with the process. Read it back in ranges
+    with `read_output_file/4`
+  - `:active` - for `:stdout` and `:stderr`, send the output to the
+    `:owner` process as messages, followed by the exit. See "Active output"
+    below
+
+  ## Pseudo-terminals
+
+  With `pty: true` the `:stdin`, `:stdout` and `:stderr` options are ignored
+  and all three are set to `:pty`. The child sees a terminal on all of them,
+  so it line-buffers output and enables interactive behaviour, and it runs
+  as the foreground process group of its own session. `write/2` types into
+  the terminal and `read(p, :stdout)` returns everything it prints, stderr
+  included; input is echoed back while the terminal is in its default mode.
+
+  `write/2` hands its bytes to the terminal verbatim, so escape sequences for
+  arrow and function keys arrive as if typed, e.g. `Px.write(p, "\\e[A")` for
+  Up. Any translation (Enter's `\\r` to `\\n`, echo, Ctrl-C to SIGINT) is the
+  terminal's line discipline acting on its current mode; with
+  `set_term_mode(p, :raw)` the program receives exactly what was written.
+
+  Closing stdin does not signal end of input on a terminal; use
+  `send_control(p, :eof)` instead.
+
+  An interactive tool started with `:inherit` behaves differently depending
+  on how the BEAM was started: from a shell it finds a terminal, while in a
+  release running as a daemon, with stdio on `/dev/null` or a log, it takes
+  its non-interactive path. `inherit_or_pty: true` keeps the terminal in
+  both cases. Like `:pty` it overrides the `:stdin`, `:stdout` and `:stderr`
+  options; the child inherits the BEAM's stdio if all three are terminals,
+  and otherwise gets a pty of its own. The process's `:stdin`, `:stdout` and
+  `:stderr` fields tell which: `:inherit` or `:pty`.
+
+  ## Fan-out
+
+  With `stdout: :fanout`, a background thread reads stdout and sends every
+  chunk to each process registered with `subscribe/2`, so several
+  processes can observe one child's output without stealing bytes from each
+  other. Subscribers receive `{:px_stdout, os_pid, data}` messages, then
+  `{:px_stdout, os_pid, :eof}` when stdout closes.
+
+  Each subscriber has a window of chunks it may be sent without calling
+  `ack/2`. Reading stops while any subscriber has used up its window, or
+  while there are no subscribers, and the child then blocks on the full
+  pipe: the slowest subscriber sets the pace. Subscribers that exit are
+  removed automatically. `read/2` on a fan-out stdout returns
+  `{:error, :not_piped}`.
+
+  ## Exit notification
+
+  With `notify_exit: pid`, a background thread waits for the child and
+  reaps it as soon as it exits, then messages `pid`, so the owner need not
+  poll `alive?/1` or block in `wait/1`. It coordinates with `wait/1` and
+  the other reaping functions through the cached exit, so they keep working
+  and report the same exit. The process resource is kept alive until the
+  child exits.
+
+      {:ok, p} = Px.spawn("sleep", ["1"], notify_exit: self())
+      resource = p.resource
+
+      receive do
+        {:px_exit, ^resource, {:exited, code}} -> code
+      end
+
+  ## Active output
+
+  With `stdout: :active` and/or `stderr: :active`, Px manages the child
+  entirely: a background thread reads the active streams as data arrives
+  and sends each chunk to the `:owner` process (default: the caller of
+  `spawn/3`) as `{:px_output, resource, stream, data}`, where `stream` is
+  `:stdout` or `:stderr`. Once every active stream is closed it reaps the
+  child and sends `{:px_exit, resource, exit}`, with `exit` as in
+  `:notify_exit`.
+
+  All of it comes from one thread, so the exit message always follows the
+  last output and no byte the child wrote is lost. The child never blocks on
+  a full pipe: output is not held back for a slow owner, whose mailbox
+  grows instead. A grandchild that inherited the pipes delays the exit
+  message until it closes them too. The process resource is kept alive
+  until the exit message is sent, and `wait/1` and the other reaping
+  functions keep working alongside. `read/2` on an active stream returns
+  `{:error, :not_piped}`.
+
+      {:ok, p} = Px.spawn("sh", ["-c", "echo out; echo err >&2; exit 3"],
+                          stdout: :active, stderr: :active)
+      resource = p.resource
+
+      receive do
+        {:px_output, ^resource, :stdout, data} -> data
+      end
+      #=> "out\n"
+
+      receive do
+        {:px_exit, ^resource, exit} -> exit
+      end
+      #=> {:exited, 3}
+
+  ## Readiness
+
+  A spawned service is not necessarily accepting work yet. With `:ready`, a
+  background thread watches for it and sends the `:owner` process (default:
+  the caller of `spawn/3`) `{:px_ready, resource}` once it is ready, or
+  `{:px_ready_timeout, resource}` if the probe gives up first: when
+  `:ready_timeout` passes, or earlier if the child closes the probed stream
+  or exits. Exactly one of the two is sent. The probe is one of:
+
+  - `{:output, stream, pattern}` - `pattern` appears on `:stdout` or
+    `:stderr`, which must be `:pipe`. The output is scanned natively as it
+    passes through a second pipe, so `read/2` still returns all of it, and
+    by the time the message arrives the matching output can be read
+  - `{:unix, path}` - a connection to the Unix socket at `path` succeeds
+  - `{:tcp, host, port}` - a TCP connection to `host` and `port` succeeds
+
+  Connects are retried every 20 milliseconds. The process resource is kept
+  alive until the message is sent.
+
+      {:ok, p} = Px.spawn("my_server", [], stdout: :pipe,
+                          ready: {:output, :stdout, "Listening on"},
+                          ready_timeout: 5000)
+      resource = p.resource
+
+      receive do
+        {:px_ready, ^resource} -> :ok
+        {:px_ready_timeout, ^resource} -> Px.signal(p, :sigkill)
+      end
+
+  ## File actions
+
+  `:file_actions` sets up the child's descriptors with explicit operations,
+  like `posix_spawn` file actions. They run in the child in list order,
+  after the `:stdin`, `:stdout` and `:stderr` options have been applied, so
+  each one sees the descriptors the earlier ones left:
+
+  - `{:dup2, src, dst}` - make `dst` a copy of `src`; with `src == dst`,
+    keep a descriptor of the BEAM open in the child instead
+  - `{:open, fd, path, flags, mode}` - open `path` as `fd`, where `flags`
+    are any of `:read`, `:write`, `:append`, `:create`, `:truncate` and
+    `:exclusive`, and `mode` is the permission bits for a new file
+  - `{:close, fd}` - close `fd`
+
+  If an action fails, spawning returns `{:error, :file_action_failed, errno}`.
+
+      # stdout to a file, then stderr to wherever stdout now points
+      Px.spawn("make", [],
+        file_actions: [
+          {:open, 1, "build.log", [:write, :create, :truncate], 0o644},
+          {:dup2, 1, 2}
+        ]
+      )
+
+  ## Namespaces
+
+  With `namespaces: [:user, ...]` the child unshares the given namespaces
+  just before exec. In a new user namespace it is root, with uid and gid 0
+  mapped to the BEAM's own user and group, but it has no privileges
+  outside it; the other namespaces are owned by the user namespace, so no
+  privileges are needed to create them. That allows rootless isolation:
+  mounts the child makes in a new mount namespace are invisible outside
+  it, and a new network namespace has only a loopback device, down.
+
+      Px.spawn("sh", ["-c", "id -u"], namespaces: [:user, :mount, :net], stdout: :pipe)
+
+  Without `:user`, the other namespaces need `CAP_SYS_ADMIN`. Spawning
+  returns `{:error, :user_namespaces_disabled}` if the kernel is set to
+  refuse unprivileged user namespaces, `{:error, :unshare_failed, errno}`
+  if `unshare(2)` fails anyway (e.g. under a seccomp profile or a
+  container runtime that blocks it) and `{:error, :id_map_failed, errno}`
+  if the id maps cannot be written. `:pid` is not supported: `unshare`
+  only moves the caller's later children into a new pid namespace, not
+  the child itself.
+
+  ## Process groups
+
+  With `pgid:` the child calls `setpgid(2)` before exec. Spawn a leader with
+  `pgid: 0`, then further children with `pgid: leader.pid`, and
+  `signal_group/2` reaches the whole cohort at once:
+
+      leader = Px.spawn!("worker", [], pgid: 0)
+      helper = Px.spawn!("worker", ["--helper"], pgid: leader.pid)
+      :ok = Px.signal_group(leader.pid, :sigterm)
+
+  The group must be in the BEAM's session; otherwise spawning returns
+  `{:error, :setpgid_failed, errno}`, with `errno` 1 (EPERM). That includes
+  a group whose members have all exited. `:pgid` cannot be combined with
+  `pty: true`, which puts the child in a session of its own.
+
+  A leader spawned with `kill_group_on_drop: true` takes its group down
+  with it when the `Px` struct is garbage collected before the child has
+  been reaped, typically because the owning process crashed. Shells, `make`
+  and package managers leave grandchildren behind when only the direct
+  child is killed; they share its group, so they are killed too. The leader
+  is then reaped in the background. Once the leader has been reaped, its
+  pid and so the group id may be reused, so nothing is sent:
+
+      {:ok, p} = Px.spawn("make", ["-j8"], pgid: 0, kill_group_on_drop: true)
+
+  A process group is only a convention: a grandchild can leave it with
+  `setsid`, and one forked while the group is being signalled can miss the
+  signal. On cgroup v2, with `cgroup: path` the child is moved into the
+  existing cgroup directory at `path`, which neither it nor anything it
+  starts can leave without privileges, and `kill_tree/1` freezes that
+  cgroup, SIGKILLs everything in it and thaws it, so nothing forks in
+  between:
+
+      File.mkdir!("/sys/fs/cgroup/px/job")
+      {:ok, p} = Px.spawn("make", ["-j8"], cgroup: "/sys/fs/cgroup/px/job")
+      {:ok, p} = Px.kill_tree(p)
+
+  ## Timeouts
+
+  With `timeout: ms` a background thread SIGKILLs the child if it is still
+  running `ms` milliseconds after spawning. The deadline is not fixed:
+  `extend_timeout/2` moves it and `cancel_timeout/1` removes it, for jobs
+  whose allowed running time changes while they run. Either takes effect
+  immediately, and once the deadline has passed and the kill is decided
+  both return `{:error, :no_timeout}`. A child killed this way has status
+  `{:exited, 137}`, and `termination_signal/1` reports `{:signaled, 9, true}`.
+
+      p = Px.spawn!("make", ["release"], timeout: 60_000)
+      # the user asked for more time
+      :ok = Px.extend_timeout(p, 300_000)
+
+  The process resource is kept alive until the child is reaped or the
+  deadline passes.
+
+  ## Starting stopped
+
+  With `start_stopped: true` the child is stopped once its exec has
+  succeeded, with the new program loaded but before any of its code has
+  run, so a debugger or tracer can attach first. `resume/1` lets it run.
+
+  This works through ptrace: the child asks to be traced, the kernel stops
+  it after the exec, and Px detaches again before `spawn/3` returns, leaving
+  it in an ordinary SIGSTOP stop with no tracer. A tracer can then attach
+  with `PTRACE_SEIZE` or `PTRACE_ATTACH` (subject to the usual Yama
+  `ptrace_scope` rules), and must detach or let the child go before
+  `resume/1` has any effect. Spawning fails with
+  `{:error, :ptrace_failed, errno}` where ptrace is not permitted, as in
+  some containers.
 
   ## Returns
 
   - `{:ok, process}` - process spawned successfully
-  - `{:error, reason}` - failed to spawn (command not found, file error, etc.)
+  - `{:error, kind, message}` - the command could not be executed, where
+    `kind` is `:not_found` (ENOENT), `:permission_denied` (EACCES),
+    `:not_executable` (ENOEXEC) or `:too_many_files` (EMFILE/ENFILE) and
+    `message` is the OS description
+  - `{:error, :bad_interpreter, interpreter}` - the command is an executable
+    script whose `#!` interpreter does not exist
+  - `{:error, :is_a_directory}` - the command is a directory
+  - `{:error, :invalid_arg, index}` - the argument at `index` in `args`
+    contains a NUL byte, which cannot be passed to a program
+  - `{:error, :invalid_env, name}` - the `:env` variable `name` has a NUL
+    byte in its name or value
+  - `{:error, :invalid_command}` - `cmd` contains a NUL byte
+  - `{:error, :arg_list_too_long}` - the arguments and environment add up
+    to more than the system's `ARG_MAX`, or on Linux one of them is 128 KiB
+    or longer. These are checked before anything is created
+  - `{:error, :not_executable, mode}` - the command is a file without any
+    execute bits; `mode` is its permission bits, e.g. `0o644`
+  - `{:error, :bad_cwd, path}` - the `:cd` directory does not exist or is not
+    a directory, checked before spawning so it is not mistaken for a missing
+    command
+  - `{:error, :too_many_children}` - the limit set with `set_max_children/1`
+    has been reached
+  - `{:error, reason}` - failed to spawn for another reason (file error, etc.)
+  - `{:error, step, errno}` - a setup step in the child failed before exec,
+    e.g. `{:error, :pdeathsig_failed, errno}`
 
   ## Examples
 
@@ -177,50 +640,126 @@ defmodule Px do
       {:ok, "hello\\n"}
 
       iex> Px.spawn("nonexistent_command_12345", [])
-      {:error, "Failed to spawn: No such file or directory (os error 2)"}
+      {:error, :not_found, "No such file or directory (os error 2)"}
   """
   def spawn(cmd, args, opts \\ []) when is_binary(cmd) and is_list(args) do
+    do_spawn(cmd, args, opts, fn spawn_args ->
+      apply(__MODULE__, :spawn_nif, [cmd, args | spawn_args])
+    end)
+  end
+
+  @doc """
+  Prepare a reusable template for spawning many children of `cmd` that
+  differ only in their arguments.
+
+  `opts` are the `spawn/3` options. They are checked and handed to the NIF
+  once, so `spawn_from/2` only passes the arguments of each child. An
+  `:env_file` is still read at every spawn.
+
+  ## Examples
+
+      iex> {:ok, template} = Px.template("echo", stdout: :pipe)
+      iex> p = Px.spawn_from!(template, ["one"]) |> Px.wait()
+      iex> Px.read(p, :stdout)
+      {:ok, "one\\n"}
+  """
+  def template(cmd, opts \\ []) when is_binary(cmd) do
+    with resource when is_reference(resource) <-
+           apply(__MODULE__, :build_command_nif, [cmd | encode_spawn_args(opts)]) do
+      {:ok, %Px.Template{cmd: cmd, opts: opts, resource: resource}}
+    end
+  end
+
+  @doc """
+  Spawn a child from a template made by `template/2`, with `args`.
+
+  Returns the same as `spawn/3`.
+  """
+  def spawn_from(%Px.Template{cmd: cmd, opts: opts, resource: resource}, args)
+      when is_list(args) do
     ensure_sigchild()
 
-    stdin = Keyword.get(opts, :stdin, nil)
-    stdout = Keyword.get(opts, :stdout, nil)
-    stderr = Keyword.get(opts, :stderr, nil)
-    env = Keyword.get(opts, :env, %{})
-    cd = Keyword.get(opts, :cd, nil)
-
-    {stdin_mode, stdin_path} = encode_stdio(stdin)
-    {stdout_mode, stdout_path} = encode_stdio(stdout)
-    {stderr_mode, stderr_path} = encode_stdio(stderr)
-    env_list = encode_env(env)
-    cd_str = cd || ""
-
-    with {resource, pid} when is_reference(resource) and is_integer(pid) <-
-           spawn_nif(
-             cmd,
-             args,
-             stdin_mode,
-             stdin_path,
-             stdout_mode,
-             stdout_path,
-             stderr_mode,
-             stderr_path,
-             env_list,
-             cd_str
-           ) do
-      {:ok,
-       struct(__MODULE__,
-         cmd: cmd,
-         args: args,
-         pid: pid,
-         resource: resource,
-         status: :running,
-         stdin: stdin,
-         stdout: stdout,
-         stderr: stderr
-       )}
+    resource
+    |> spawn_from_nif(args)
+    |> spawned(cmd, args, opts)
+  end
+
+  @doc """
+  Spawn a child from a template, raising on failure.
+  """
+  def spawn_from!(%Px.Template{cmd: cmd} = template, args) do
+    case spawn_from(template, args) do
+      {:ok, process} -> process
+      {:error, reason} -> raise "Failed to spawn #{cmd}: #{inspect(reason)}"
+      {:error, reason, detail} -> raise "Failed to spawn #{cmd}: #{inspect({reason, detail})}"
     end
   end
 
+  @doc """
+  Spawn `name` relative to the open directory descriptor `dir_fd`. Linux only.
+
+  The child is exec'd with `execveat(2)` against `dir_fd` (typically an
+  `O_PATH` descriptor obtained elsewhere), so the program is looked up in
+  exactly that directory even if its path is renamed or replaced between
+  resolution and exec. `name` is not searched in `PATH`; an absolute `name`
+  ignores `dir_fd`.
+
+  Accepts the same options as `spawn/3`. If the exec itself fails, the
+  result is `{:error, :execveat_failed, errno}`.
+
+  ## Examples
+
+      iex> {:ok, p} = Px.spawn_at(-100, "/bin/echo", ["hi"], stdout: :pipe)
+      iex> p = Px.wait(p)
+      iex> Px.read(p, :stdout)
+      {:ok, "hi\n"}
+  """
+  def spawn_at(dir_fd, name, args, opts \\ [])
+      when is_integer(dir_fd) and is_binary(name) and is_list(args) do
+    spawn(name, args, Keyword.put(opts, :exec_dirfd, dir_fd))
+  end
+
+  @doc """
+  Spawn the executable open as `exec_fd`, with `name` as its `argv[0]`.
+  Linux only.
+
+  The child is exec'd with `fexecve(3)`, so what runs is exactly the file
+  behind the descriptor, even if its path has since been renamed or
+  replaced. Open it with `open_exec/1`, verify it (for example by hashing
+  `"/proc/self/fd/\#{fd}"`, which reads the same file), then spawn it; the
+  bytes that were checked are the bytes that run. `name` is not searched
+  for or opened.
+
+  Descriptors from `open_exec/1` are close-on-exec, which suits binaries
+  but not scripts: the interpreter can no longer open the script, and the
+  exec fails with `ENOENT`.
+
+  Accepts the same options as `spawn/3`, except `:exec_dirfd`. If the exec
+  itself fails, the result is `{:error, :fexecve_failed, errno}`. The
+  descriptor stays open, and can be spawned again, until `close_fd/1`.
+
+  ## Examples
+
+      iex> {:ok, fd} = Px.open_exec("/bin/echo")
+      iex> {:ok, p} = Px.spawn_fd(fd, "echo", ["hi"], stdout: :pipe)
+      iex> Px.close_fd(fd)
+      :ok
+      iex> Px.wait(p) |> Px.read(:stdout)
+      {:ok, "hi\\n"}
+  """
+  def spawn_fd(exec_fd, name, args, opts \\ [])
+      when is_integer(exec_fd) and exec_fd >= 0 and is_binary(name) and is_list(args) do
+    spawn(name, args, Keyword.put(opts, :exec_fd, exec_fd))
+  end
+
+  @doc """
+  Open the executable at `path` read-only, for `spawn_fd/4`.
+
+  Returns `{:ok, fd}` or `{:error, message}`. The caller owns the
+  descriptor and closes it with `close_fd/1`.
+  """
+  def open_exec(path) when is_binary(path), do: open_exec_nif(path)
+
   @doc """
   Spawn an OS process, raising on failure.
 
@@ -236,16 +775,193 @@ defmodule Px do
     case spawn(cmd, args, opts) do
       {:ok, process} -> process
       {:error, reason} -> raise "Failed to spawn #{cmd}: #{inspect(reason)}"
+      {:error, reason, detail} -> raise "Failed to spawn #{cmd}: #{inspect({reason, detail})}"
+    end
+  end
+
+  @doc """
+  Check that `spawn/3` would accept `cmd`, `args` and `opts`, without
+  spawning anything.
+
+  Checks the stdio configs (input files must be readable; output files
+  writable, or creatable in their directory, and not present with
+  `flags: [:exclusive]`), the options, the `:env_file`, the `:cd` directory
+  and that `cmd` resolves to an executable, including a script's `#!`
+  interpreter. Nothing is created or truncated.
+
+  Returns `:ok` or the error `spawn/3` would return for the first problem
+  found. A configuration that validates can still fail to spawn if the
+  filesystem changes in between, or for resource limits.
+
+  ## Examples
+
+      iex> Px.validate("echo", ["hi"], stdout: :pipe)
+      :ok
+
+      iex> Px.validate("nonexistent_command_12345", [])
+      {:error, :not_found, "No such file or directory (os error 2)"}
+  """
+  def validate(cmd, args, opts \\ []) when is_binary(cmd) and is_list(args) do
+    apply(__MODULE__, :validate_spawn_nif, [cmd, args | encode_spawn_args(opts)])
+  end
+
+  @doc """
+  Spawn a command given as a single command line, split into words the way a
+  shell would, without running one.
+
+  Words are separated by blanks. Single quotes keep their contents as is;
+  double quotes do too, except for `\\` escaping `$`, `` ` ``, `"` and `\\`;
+  an unquoted `\\` keeps the next character as is. There is no expansion,
+  globbing, piping or redirection, so unlike `shell/2` no part of the line
+  can run anything but the command named by its first word. The first word
+  is the command, looked up in `PATH` like the `cmd` of `spawn/3`, and the
+  rest are its arguments.
+
+  Accepts every option of `spawn/3`. The returned process has `cmd` set to
+  `command_line` and `args` set to `[]`.
+
+  Returns what `spawn/3` does, or `{:error, :parse_error, offset}` for a quote
+  left open or a trailing `\\`, `offset` being the byte where it starts.
+
+  ## Examples
+
+      iex> {:ok, p} = Px.spawn_parse(~S(printf "%s|%s" 'a b' c\\ d), stdout: :pipe)
+      iex> p = Px.wait(p)
+      iex> Px.read(p, :stdout)
+      {:ok, "a b|c d"}
+
+      iex> Px.spawn_parse(~S(echo "unterminated))
+      {:error, :parse_error, 5}
+  """
+  def spawn_parse(command_line, opts \\ []) when is_binary(command_line) do
+    do_spawn(command_line, [], opts, fn spawn_args ->
+      apply(__MODULE__, :spawn_parse_nif, [command_line | spawn_args])
+    end)
+  end
+
+  @doc """
+  Spawn a command line split into words, raising on failure.
+
+  Same as `spawn_parse/2` but raises on error instead of returning an error
+  tuple.
+  """
+  def spawn_parse!(command_line, opts \\ []) do
+    case spawn_parse(command_line, opts) do
+      {:ok, process} -> process
+      {:error, reason} -> raise "Failed to spawn #{command_line}: #{inspect(reason)}"
+
+      {:error, reason, detail} ->
+        raise "Failed to spawn #{command_line}: #{inspect({reason, detail})}"
+    end
+  end
+
+  @doc """
+  Run `command` through a login shell.
+
+  The command string is passed to `shell -lc command`, so the shell reads
+  the user's profile before running it. This picks up `PATH` additions and
+  other environment setup that the BEAM's own environment may be missing,
+  and makes shell features (globbing, pipes, redirection) available.
+
+  ## Options
+
+  Accepts every option of `spawn/3`, plus:
+
+  - `:shell` - path to the shell (default: `$SHELL`, falling back to `/bin/sh`)
+
+  The returned process has `cmd` set to `command` and `args` set to `[]`.
+
+  ## Security
+
+  The command string is interpreted by the shell. Never interpolate untrusted
+  input into it: a value such as `"; rm -rf ~"` will be executed. Use
+  `spawn/3` with an explicit argument list when any part of the command comes
+  from outside your program.
+
+  ## Examples
+
+      iex> {:ok, p} = Px.shell("echo hello | tr a-z A-Z", stdout: :pipe)
+      iex> p = Px.wait(p)
+      iex> Px.read(p, :stdout)
+      {:ok, "HELLO\\n"}
+  """
+  def shell(command, opts \\ []) when is_binary(command) do
+    shell = Keyword.get(opts, :shell) || ""
+
+    do_spawn(command, [], opts, fn spawn_args ->
+      apply(__MODULE__, :spawn_shell_nif, [command, shell | spawn_args])
+    end)
+  end
+
+  @doc """
+  Run `command` through a login shell, raising on failure.
+
+  Same as `shell/2` but raises on error instead of returning `{:error, reason}`.
+  """
+  def shell!(command, opts \\ []) do
+    case shell(command, opts) do
+      {:ok, process} -> process
+      {:error, reason} -> raise "Failed to spawn shell command: #{inspect(reason)}"
+
+      {:error, reason, detail} ->
+        raise "Failed to spawn shell command: #{inspect({reason, detail})}"
+    end
+  end
+
+  @doc """
+  Take over a running process that Px did not spawn, by its OS pid.
+
+  For processes started by a supervisor script or handed over from
+  elsewhere. The process is watched and signalled through a pidfd, so
+  `wait/2`, `alive?/1`, `signal/3`, `state/1` and the like work on it
+  as on a spawned child, and never reach another process that later reuses
+  the pid. Linux 5.3+ only.
+
+  Px is not the parent, so it can neither reap the process nor learn how it
+  exited: once it exits its status is `{:exited, :reaped_externally}`, and
+  `exit_info/1` and `termination_signal/1` have nothing to report. An
+  adopted process has no pipes (all its streams read as `:null` in
+  `stdio_modes/1`), `spawn_method/1` returns `:adopted`, it does not count
+  towards `set_max_children/1` and `:cleanup_signal` does not apply to it.
+
+  ## Returns
+
+  - `{:ok, process}` - the process, with `cmd` set to `nil` and `args` to `[]`
+  - `{:error, :not_found}` - there is no process with that pid
+  - `{:error, :not_supported}` - pidfds are unavailable on this system
+
+  ## Examples
+
+      iex> {pid, 0} = System.cmd("sh", ["-c", "sleep 10 > /dev/null 2>&1 & echo $!"])
+      iex> {:ok, p} = pid |> String.trim() |> String.to_integer() |> Px.adopt()
+      iex> {:ok, p} = Px.signal(p, :sigterm)
+      iex> Px.wait(p).status
+      {:exited, :reaped_externally}
+  """
+  def adopt(os_pid) when is_integer(os_pid) and os_pid > 0 do
+    case adopt_nif(os_pid) do
+      {:ok, resource} ->
+        {:ok, %__MODULE__{cmd: nil, args: [], pid: os_pid, resource: resource, status: :running}}
+
+      error ->
+        error
     end
   end
 
   @doc """
   Send `signal` to the given process.
 
+  ## Options
+
+  - `:verify_identity` - check the child's start time before signaling
+    (default: `false`, Linux only). See "Safety" below
+
   ## Returns
 
   - `{:ok, process}` - signal was sent successfully
   - `{:error, :already_exited}` - process has already exited and been reaped
+  - `{:error, :identity_mismatch}` - with `verify_identity: true`, the PID no
+    longer refers to the spawned process
   - `{:error, reason}` - other error (e.g., permission denied)
 
   ## Safety
@@ -254,6 +970,12 @@ defmodule Px do
   been reaped (by `alive?/1` or `wait/1`) before sending the signal. This
   prevents accidentally signaling an unrelated process that reused the PID.
 
+  That check cannot see an exit reaped by someone else, such as a SIGCHLD
+  handler installed by another library. On Linux, `verify_identity: true`
+  also compares the start time in `/proc/<pid>/stat` with the one recorded at
+  spawn and refuses with `{:error, :identity_mismatch}` if the PID now belongs
+  to another process (or to none). Elsewhere the option has no effect.
+
   ## Examples
 
       iex> {:ok, p} = Px.spawn("sleep", ["10"])
@@ -270,7 +992,7 @@ defmodule Px do
       iex> Px.signal(p, :sigterm)
       {:error, :already_exited}
   """
-  def signal(%__MODULE__{resource: resource, status: status} = process, signal)
+  def signal(%__MODULE__{resource: resource, status: status} = process, signal, opts \\ [])
       when is_atom(signal) or (is_integer(signal) and signal > 0) do
     ensure_sigchild()
 
@@ -279,17 +1001,112 @@ defmodule Px do
         {:error, :already_exited}
 
       :running ->
-        case signal_nif(resource, signal_int(signal)) do
+        verify_identity = Keyword.get(opts, :verify_identity, false)
+
+        case signal_nif(resource, signal_int(signal), verify_identity) do
+          :ok -> {:ok, process}
+          {:error, _} = err -> err
+        end
+    end
+  end
+
+  @doc """
+  Send `signal` only if the process is in the `expected` state, `:running`
+  or `:stopped`.
+
+  The state is checked and the signal sent while holding the lock that
+  guards signaling and reaping, so the child cannot be reaped in between,
+  as it could with a separate check followed by `signal/3`. For job
+  control, e.g. stopping a job only if it is running.
+
+  ## Returns
+
+  - `{:ok, process}` - the process was in the expected state and the signal
+    was sent
+  - `{:error, :state_mismatch}` - the process is in the other state
+  - `{:error, :already_exited}` - the process has exited, reaped or not
+  - `{:error, reason}` - other error
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> {:ok, p} = Px.signal_if(p, :running, :sigstop)
+      iex> Process.sleep(50)
+      iex> Px.signal_if(p, :running, :sigstop)
+      {:error, :state_mismatch}
+      iex> {:ok, p} = Px.signal_if(p, :stopped, :sigkill)
+      iex> Px.wait(p).status
+      {:exited, 137}
+  """
+  def signal_if(%__MODULE__{resource: resource} = process, expected, signal)
+      when expected in [:running, :stopped] do
+    ensure_sigchild()
+
+    case signal_if_nif(resource, expected, signal_int(signal)) do
+      :ok -> {:ok, process}
+      {:error, _} = err -> err
+    end
+  end
+
+  @doc """
+  Queue `signal` for the process with an integer payload, as `sigqueue(3)`
+  does. Linux only.
+
+  A handler the child installs with `SA_SIGINFO` reads `value` from
+  `siginfo_t.si_value`; most read its `sival_int`, the low 32 bits. Unlike
+  `signal/3`, `signal` may be a realtime signal number, e.g. `SIGRTMIN + 1`
+  (`SIGRTMIN` is 34 under glibc), and queued realtime signals are never
+  merged, so each payload arrives. The same reaping check as `signal/3`
+  keeps the PID from being reused in between.
+
+  ## Returns
+
+  - `{:ok, process}` - the signal was queued
+  - `{:error, :already_exited}` - process has already exited and been reaped
+  - `{:error, :not_supported}` - not on Linux, or the process was adopted
+  - `{:error, errno}` - `sigqueue` failed, e.g. `11` (`EAGAIN`) when the
+    queue of pending signals is full
+
+  ## Examples
+
+      p = Px.spawn!("my_server", [])
+      {:ok, p} = Px.sigqueue(p, 35, 42)
+  """
+  def sigqueue(%__MODULE__{resource: resource, status: status} = process, signal, value)
+      when (is_atom(signal) or (is_integer(signal) and signal > 0)) and is_integer(value) do
+    ensure_sigchild()
+
+    case status do
+      {:exited, _} ->
+        {:error, :already_exited}
+
+      :running ->
+        case sigqueue_nif(resource, signal_int(signal), value) do
           :ok -> {:ok, process}
           {:error, _} = err -> err
         end
     end
   end
 
+  @doc """
+  Let a process spawned with `start_stopped: true` (or stopped by SIGSTOP)
+  run, by sending it SIGCONT.
+
+  ## Examples
+
+      iex> p = Px.spawn!("echo", ["hi"], start_stopped: true, stdout: :pipe)
+      iex> Px.read(p, :stdout)
+      :would_block
+      iex> {:ok, p} = Px.resume(p)
+      iex> Px.wait(p).status
+      {:exited, 0}
+  """
+  def resume(%__MODULE__{} = process), do: signal(process, :sigcont)
+
   @doc """
   Send `signal` to the given process, raising on failure.
 
-  Same as `signal/2` but raises on error instead of returning `{:error, reason}`.
+  Same as `signal/3` but raises on error instead of returning `{:error, reason}`.
 
   ## Examples
 
@@ -299,13 +1116,32 @@ defmodule Px do
       iex> p.status
       {:exited, 143}
   """
-  def signal!(%__MODULE__{} = process, signal) do
-    case signal(process, signal) do
+  def signal!(%__MODULE__{} = process, signal, opts \\ []) do
+    case signal(process, signal, opts) do
       {:ok, process} -> process
       {:error, reason} -> raise "Failed to signal process: #{inspect(reason)}"
     end
   end
 
+  @doc """
+  Send `signal` to every process in the process group `pgid`.
+
+  Meant for children spawned with `:pgid`, see "Process groups" in
+  `spawn/3`. Unlike `signal/3` this works on a raw group ID, so it cannot
+  tell a group that has emptied and been reused from the original one.
+
+  ## Returns
+
+  - `:ok` - the signal was sent
+  - `{:error, :not_found}` - no process is left in the group
+  - `{:error, reason}` - other error, e.g. not permitted
+  """
+  def signal_group(pgid, signal)
+      when is_integer(pgid) and pgid > 0 and
+             (is_atom(signal) or (is_integer(signal) and signal > 0)) do
+    signal_group_nif(pgid, signal_int(signal))
+  end
+
   @doc """
   Wait for the given process to complete.
 
@@ -315,6 +1151,9 @@ defmodule Px do
   With a timeout (in milliseconds), returns `:timeout` if the process
   doesn't exit within the specified time.
 
+  An indefinite wait returns `:cancelled` if another Elixir process calls
+  `cancel_wait/1` first; the child keeps running.
+
   ## Examples
 
       # Block forever
@@ -351,8 +1190,10 @@ defmodule Px do
         process
 
       :running ->
-        code = wait_nif(resource)
-        %{process | status: {:exited, code}}
+        case wait_nif(resource) do
+          :cancelled -> :cancelled
+          code -> %{process | status: {:exited, code}}
+        end
 
       nil ->
         raise "Invalid process state"
@@ -384,187 +1225,2414 @@ defmodule Px do
   end
 
   @doc """
-  Check if the process is still alive.
+  Make every `wait/1` blocked on the process return `:cancelled`.
+
+  Useful when a supervisor gives up on a child it was waiting for and
+  decides to kill it instead: the waiter is released right away rather than
+  holding a dirty scheduler thread until the child exits. The child itself is
+  not affected.
+
+  Returns `:ok`, or `{:error, :not_waiting}` if no wait was in progress.
 
   ## Examples
 
       iex> p = Px.spawn!("sleep", ["10"])
-      iex> Px.alive?(p)
-      true
-      iex> Px.signal!(p, :sigterm)
-      iex> Px.wait(p)
-      iex> Px.alive?(p)
-      false
+      iex> waiter = Task.async(fn -> Px.wait(p) end)
+      iex> Process.sleep(50)
+      iex> Px.cancel_wait(p)
+      :ok
+      iex> Task.await(waiter)
+      :cancelled
+      iex> Px.destroy(p)
   """
-  def alive?(%__MODULE__{resource: resource, status: :running}) do
-    alive_nif(resource)
-  end
-
-  def alive?(%__MODULE__{status: {:exited, _}}), do: false
+  def cancel_wait(%__MODULE__{resource: resource}), do: cancel_wait_nif(resource)
 
   @doc """
-  Write data to the process stdin.
-
-  Requires the process to be spawned with `stdin: :pipe`.
+  Remove the `:timeout` deadline set at spawn, so the child is not killed.
 
-  ## Returns
-
-  - `:ok` - all bytes written successfully
-  - `{:partial, bytes_written}` - only some bytes written (buffer full)
-  - `:would_block` - no bytes written, buffer completely full
-  - `{:error, :not_piped}` - stdin was not configured as `:pipe`
-  - `{:error, :broken_pipe}` - child closed stdin or exited
-  - `{:error, reason}` - other IO error
+  Returns `:ok`, or `{:error, :no_timeout}` if the process was spawned
+  without `:timeout`, the deadline was already cancelled, or it has passed.
 
   ## Examples
 
-      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
-      iex> Px.write(p, "hello")
-      :ok
-      iex> Px.close!(p, :stdin)
+      iex> p = Px.spawn!("sleep", ["10"], timeout: 50)
+      iex> Px.cancel_timeout(p)
       :ok
-      iex> Process.sleep(50)
-      iex> Px.read(p, :stdout)
-      {:ok, "hello"}
+      iex> Process.sleep(100)
+      iex> Px.alive?(p)
+      true
+      iex> Px.cancel_timeout(p)
+      {:error, :no_timeout}
+      iex> Px.destroy(p)
   """
-  def write(%__MODULE__{stdin: :pipe, resource: resource}, data) when is_binary(data) do
-    write_stdin_nif(resource, data)
-  end
-
-  def write(%__MODULE__{}, _data), do: {:error, :not_piped}
+  def cancel_timeout(%__MODULE__{resource: resource}), do: cancel_timeout_nif(resource)
 
   @doc """
-  Close a pipe to/from the child process.
+  Move the `:timeout` deadline set at spawn to `timeout` milliseconds from
+  now.
 
-  ## Streams
+  The new deadline may be earlier or later than the old one. Returns `:ok`,
+  or `{:error, :no_timeout}` like `cancel_timeout/1`; a cancelled deadline
+  is not re-armed.
+  """
+  def extend_timeout(%__MODULE__{resource: resource}, timeout)
+      when is_integer(timeout) and timeout > 0 do
+    extend_timeout_nif(resource, timeout)
+  end
+
+  @doc """
+  Cap how long the child may run, counted from spawn, whatever it is doing.
+
+  Once the child has run `lifetime` milliseconds it is sent SIGTERM, and
+  SIGKILL if it has not exited `:grace` milliseconds later; it is not
+  reaped. Meant for recycling long-running workers in a pool, so unlike
+  `:timeout` it can be set on a running child, and gives it a chance to
+  shut down cleanly. A lifetime the child has already exceeded ends it at
+  once.
+
+  Calling it again replaces the lifetime, earlier or later, and `:infinity`
+  removes it; a single background thread per child does the waiting however
+  often it is changed. It is independent of the `:timeout` deadline, and
+  whichever comes first ends the child.
+
+  ## Options
+
+  - `:grace` - milliseconds to allow after SIGTERM before SIGKILL
+    (default: `5000`)
+
+  Returns `:ok`, or `{:error, :already_exited}` once the child has been
+  reaped.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> Px.set_max_lifetime(p, 50)
+      :ok
+      iex> Px.wait(p).status
+      {:exited, 143}
+  """
+  def set_max_lifetime(%__MODULE__{resource: resource}, lifetime, opts \\ [])
+      when lifetime == :infinity or (is_integer(lifetime) and lifetime >= 0) do
+    lifetime_ms = if lifetime == :infinity, do: nil, else: lifetime
+    set_max_lifetime_nif(resource, lifetime_ms, Keyword.get(opts, :grace, 5000))
+  end
+
+  @doc """
+  Check if the process is still alive.
+
+  A child found to have exited is reaped. Once any call has reaped it, this
+  answers from a flag without taking a lock, so supervisors can poll
+  thousands of children cheaply; only a child still running costs a
+  `waitpid` check.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> Px.alive?(p)
+      true
+      iex> Px.signal!(p, :sigterm)
+      iex> Px.wait(p)
+      iex> Px.alive?(p)
+      false
+  """
+  def alive?(%__MODULE__{resource: resource, status: :running}) do
+    alive_nif(resource)
+  end
+
+  def alive?(%__MODULE__{status: {:exited, _}}), do: false
+
+  @doc """
+  Check whether the process's PID still exists, without reaping it.
+
+  `alive?/1` reaps a child that has exited. This only probes the PID with
+  signal 0, so an exited child stays unreaped for `wait/1` or another
+  component to collect its status. A child that has exited but not been
+  reaped yet still exists, so this returns `true` for it. On Linux, a PID
+  that has been reused by an unrelated process (after the child was reaped
+  outside Px) is detected by its start time and reported as `false`.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> Px.pid_alive?(p)
+      true
+      iex> Px.destroy(p)
+      iex> Px.pid_alive?(p)
+      false
+  """
+  def pid_alive?(%__MODULE__{resource: resource}), do: is_pid_alive_nif(resource)
+
+  @doc """
+  Report the child's state without reaping it.
+
+  Unlike `alive?/1`, which reaps a child it finds exited, this leaves an
+  exited child as a zombie, so it can tell the two apart: a zombie that
+  stays around points at a child nobody waits for.
+
+  ## Returns
+
+  - `:running` - the child is running
+  - `:stopped` - the child is stopped, e.g. by SIGSTOP or `start_stopped:`
+  - `:zombie` - the child has exited and not been reaped yet
+  - `:reaped` - the child has been reaped, by Px or by something else
+
+  ## Examples
+
+      iex> p = Px.spawn!("true", [])
+      iex> Process.sleep(50)
+      iex> Px.state(p)
+      :zombie
+      iex> Px.alive?(p)
+      false
+      iex> Px.state(p)
+      :reaped
+  """
+  def state(%__MODULE__{resource: resource}), do: state_nif(resource)
+
+  @doc """
+  Write data to the process stdin.
+
+  Requires the process to be spawned with `stdin: :pipe`. `data` may be any
+  iodata. Its binaries are written in place with a single `writev(2)`, so
+  a payload built as nested lists need not be flattened first; `:partial`
+  counts bytes across the whole of it.
+
+  Empty data, such as `""` or `[]`, is a no-op that returns `:ok` without
+  touching the pipe, even if the child has exited or closed stdin, and
+  leaves any bytes pending from an earlier write in place.
+
+  ## Returns
+
+  - `:ok` - all bytes written successfully
+  - `{:partial, bytes_written}` - only some bytes written (buffer full)
+  - `:would_block` - no bytes written, buffer completely full
+  - `{:error, :not_piped}` - stdin was not configured as `:pipe`
+  - `{:error, :already_exited}` - the child is known to have exited (a wait
+    or `alive?/1` has collected its status); nothing is written
+  - `{:error, :reader_gone}` - child closed stdin, or exited without its
+    exit having been collected yet; nothing is written. This is checked
+    with a non-blocking poll before writing, so the write never fails
+    with EPIPE
+  - `{:error, :broken_pipe}` - the child closed stdin while the write was
+    in progress
+  - `{:error, reason}` - other IO error
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.write(p, "hello")
+      :ok
+      iex> Px.close!(p, :stdin)
+      :ok
+      iex> Process.sleep(50)
+      iex> Px.read(p, :stdout)
+      {:ok, "hello"}
+  """
+  def write(%__MODULE__{stdin: stdin, resource: resource}, data)
+      when stdin in @piped and is_binary(data) do
+    write_stdin_nif(resource, data)
+  end
+
+  def write(%__MODULE__{stdin: stdin, resource: resource}, data)
+      when stdin in @piped and is_list(data) do
+    write_stdin_iodata_nif(resource, data)
+  end
+
+  def write(%__MODULE__{}, _data), do: {:error, :not_piped}
+
+  @doc """
+  Write `data` to the process stdin as one line.
+
+  Appends a newline unless `data` already ends with one, so a child reading
+  lines does not wait for more input. Returns the same as `write/2`; on
+  `{:partial, n}` or `:would_block` the rest of the line, newline included,
+  is kept for `write_continue/1`.
+
+  ## Examples
+
+      iex> {:ok, p} = Px.coprocess("cat", [])
+      iex> Px.write_line(p, "hello")
+      :ok
+      iex> Process.sleep(50)
+      iex> Px.read(p, :stdout)
+      {:ok, "hello\\n"}
+  """
+  def write_line(%__MODULE__{stdin: stdin, resource: resource}, data)
+      when stdin in @piped and is_binary(data) do
+    write_line_nif(resource, data)
+  end
+
+  def write_line(%__MODULE__{}, _data), do: {:error, :not_piped}
+
+  @doc """
+  Resume a write that returned `{:partial, n}` or `:would_block`.
+
+  The bytes the pipe did not accept are kept with the process, so callers
+  can retry once the child has drained its input instead of slicing the
+  binary themselves. A new `write/2` replaces whatever was pending.
+
+  Returns the same values as `write/2`; `:ok` once nothing is pending.
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.write(p, "hello")
+      :ok
+      iex> Px.write_continue(p)
+      :ok
+  """
+  def write_continue(%__MODULE__{stdin: stdin, resource: resource}) when stdin in @piped do
+    write_stdin_continue_nif(resource)
+  end
+
+  def write_continue(%__MODULE__{}), do: {:error, :not_piped}
+
+  @doc """
+  Return how many bytes are still waiting for `write_continue/1`.
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe)
+      iex> Px.pending_write(p)
+      {:ok, 0}
+  """
+  def pending_write(%__MODULE__{stdin: stdin, resource: resource}) when stdin in @piped do
+    pending_write_nif(resource)
+  end
+
+  def pending_write(%__MODULE__{}), do: {:error, :not_piped}
+
+  @doc """
+  Write out everything Px is holding for the child, blocking until done.
+
+  Pushes the bytes a `{:partial, n}` or `:would_block` write left pending
+  into stdin, waiting for the child to make room, then syncs `{:log, path}`
+  files to disk.
+
+  Output streams are otherwise not covered. `{:tail, bytes}`, `:fanout`,
+  `:active` and `{:log, path}` outputs are copied by a background thread as
+  data arrives; output still in the pipe, or a chunk that thread has read
+  but not yet stored, sent or written, is not waited for. `{:file, path}`
+  outputs are written by the child itself and are not fsynced.
+
+  ## Options
+
+  - `:timeout` - milliseconds to wait (default: `:infinity`), or a
+    `{:deadline, ns}` from `monotonic_ns/0`
+
+  ## Returns
+
+  - `:ok` - nothing is left pending
+  - `:timeout` - the child did not read everything in time; the rest is
+    still pending
+  - `{:error, {:stdin, reason}}` - writing stdin failed (e.g.
+    `:broken_pipe`); the pending bytes are dropped
+  - `{:error, {stream, reason}}` - syncing the `:stdout` or `:stderr` log
+    file failed
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe)
+      iex> Px.flush(p)
+      :ok
+  """
+  def flush(%__MODULE__{resource: resource}, opts \\ []) do
+    flush_nif(resource, timeout_arg(Keyword.get(opts, :timeout, :infinity)))
+  end
+
+  @doc """
+  Write all of `data` to stdin and then close it, signaling EOF.
+
+  The usual way to hand a child its complete input in one call. Anything a
+  previous `write/2` left pending goes first. Blocks until the child has
+  made room for everything, then closes stdin, so there is no partial
+  write to handle in between.
+
+  ## Options
+
+  - `:timeout` - milliseconds to wait (default: `:infinity`), or a
+    `{:deadline, ns}` from `monotonic_ns/0`
+
+  ## Returns
+
+  - `:ok` - everything was written and stdin is closed
+  - `{:partial, n}` - the timeout passed after `n` bytes of `data`; stdin
+    stays open and the rest is pending, for `flush/2` or `write_continue/1`
+  - `{:error, :broken_pipe}` - the child closed stdin or exited
+  - `{:error, :not_piped}` - stdin is not piped or already closed
+  - `{:error, :already_exited}` - the child is known to have exited
+  - `{:error, reason}` - other IO error
+
+  ## Examples
+
+      iex> p = Px.spawn!("wc", ["-c"], stdin: :pipe, stdout: :pipe)
+      iex> Px.write_close(p, "hello")
+      :ok
+      iex> Px.wait_stdout_eof(p, 1_000)
+      {:eof, "5\n"}
+  """
+  def write_close(process, data, opts \\ [])
+
+  def write_close(%__MODULE__{stdin: stdin, resource: resource}, data, opts)
+      when stdin in @piped and is_binary(data) do
+    write_stdin_close_nif(resource, data, timeout_arg(Keyword.get(opts, :timeout, :infinity)))
+  end
+
+  def write_close(%__MODULE__{}, _data, _opts), do: {:error, :not_piped}
+
+  @doc """
+  Close a pipe to/from the child process.
+
+  ## Streams
 
   - `:stdin` - Closes the write end of stdin, signaling EOF to the child.
     Many programs (cat, grep, sort, etc.) wait for stdin EOF before processing.
 
-  - `:stdout` - Closes the read end of stdout. The child will receive SIGPIPE
-    or get EPIPE on its next write to stdout, which typically causes it to exit.
+  - `:stdout` - Closes the read end of stdout. The child will receive SIGPIPE
+    or get EPIPE on its next write to stdout, which typically causes it to exit.
+
+  - `:stderr` - Closes the read end of stderr. Same behavior as stdout.
+
+  ## Closing output as flow control
+
+  Closing `:stdout` or `:stderr` is how to tell a producer "enough", the
+  way `head` does once it has read its lines. Px holds the only read end,
+  so closing it leaves the pipe with no reader: the child's next write to
+  the stream raises SIGPIPE, which terminates it unless it ignores or
+  handles the signal, in which case the write fails with EPIPE and a
+  well-behaved program exits on its own. Children start with SIGPIPE at its
+  default, even though the BEAM ignores it. Data already in the pipe, and
+  anything buffered by `:lines`, is discarded.
+
+  Nothing is signalled until the child writes again, so a child that is
+  idle or busy elsewhere keeps running; use `signal/2` to stop it sooner.
+  With a `:ready` output probe on the stream, the child is refused from the
+  write after the next one.
+
+  ## Returns
+
+  - `:ok` - pipe closed successfully
+  - `{:error, :not_piped}` - stream was not configured as `:pipe`, or was
+    already closed
+  - `{:error, :not_supported}` - `:stdout` or `:stderr` of a `pty: true`
+    child, whose output cannot be refused while the pty stays open
+  - `{:error, reason}` - closing the descriptor failed
+
+  ## Examples
+
+      iex> {:ok, p} = Px.spawn("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.write(p, "data")
+      :ok
+      iex> Px.close(p, :stdin)
+      :ok
+  """
+  def close(%__MODULE__{stdin: stdin, resource: resource}, :stdin) when stdin in @piped do
+    close_stdin_nif(resource)
+  end
+
+  def close(%__MODULE__{stdout: stdout, resource: resource}, :stdout) when stdout in @piped do
+    close_stdout_nif(resource)
+  end
+
+  def close(%__MODULE__{stderr: stderr, resource: resource}, :stderr) when stderr in @piped do
+    close_stderr_nif(resource)
+  end
+
+  def close(%__MODULE__{}, _stream), do: {:error, :not_piped}
+
+  @doc """
+  Detach the BEAM's end of a piped stream and return it as a raw file
+  descriptor, to hand to another component.
+
+  For `:stdin` this is the write end of the child's stdin pipe, for
+  `:stdout` and `:stderr` the read end of its output. Whoever produces the
+  child's input, or consumes its output, then no longer needs to go
+  through the process: a NIF, a port or another child (see
+  `:file_actions`) can use the descriptor directly.
+
+  ## Ownership
+
+  The caller owns the descriptor from then on. Px no longer reads, writes
+  or closes it, and `read/2`, `write/2` and `close/2` on the stream return
+  `{:error, :not_piped}`. It must be closed exactly once, by `close_fd/1`
+  or by whatever it was handed to, or the child never sees EOF on stdin.
+  It keeps its flags: close-on-exec, and non-blocking unless spawned with
+  `nonblocking: false`.
+
+  ## Returns
+
+  - `{:ok, fd}` - the descriptor, now owned by the caller
+  - `{:error, :pending_data}` - stdin has bytes pending from a partial
+    write, or the output stream has bytes buffered past the last line or
+    frame returned; flush or read them first
+  - `{:error, :not_piped}` - the stream is not piped, or was already taken
+    or closed
+
+  ## Examples
+
+      iex> {:ok, cat} = Px.coprocess("cat", [])
+      iex> {:ok, fd} = Px.take_fd(cat, :stdin)
+      iex> Px.spawn!("echo", ["hi"], file_actions: [{:dup2, fd, 1}]) |> Px.wait()
+      iex> Px.close_fd(fd)
+      :ok
+      iex> Px.wait(cat) |> Px.read(:stdout)
+      {:ok, "hi\\n"}
+  """
+  def take_fd(%__MODULE__{resource: resource}, stream)
+      when stream in [:stdin, :stdout, :stderr] do
+    take_fd_nif(resource, stream)
+  end
+
+  @doc """
+  Duplicate the BEAM's read end of piped `:stdout` or `:stderr` and return
+  the copy as a handle, leaving the stream with the process.
+
+  Unlike `take_fd/2`, `read/2` and friends keep working. The copy is a
+  second handle on the same pipe, not a second stream: each byte the child
+  writes is read once, through whichever descriptor reads it first, so the
+  two readers split the output between them. For a logger or another
+  consumer that must see everything `read/2` sees, spawn with
+  `stdout: :fanout` and `subscribe/2` instead.
+
+  ## Ownership
+
+  The copy is closed when the handle is garbage collected. Read from it
+  with `read_fd/2`, or take the raw descriptor with `into_fd/1` to pass it
+  on, after which the caller owns it and closes it with `close_fd/1`. It is
+  close-on-exec, and shares the stream's non-blocking mode, which belongs to
+  the pipe rather than to either descriptor. While it is open the pipe
+  still has a reader, so `close/2` on the stream no longer makes the
+  child's writes fail.
+
+  ## Returns
+
+  - `{:ok, handle}` - the copy
+  - `{:error, :not_piped}` - the stream is not piped, or was taken or closed
+  - `{:error, reason}` - `dup` failed, e.g. out of descriptors
+
+  ## Examples
+
+      iex> p = Px.spawn!("echo", ["hi"], stdout: :pipe)
+      iex> {:ok, dup} = Px.dup_fd(p, :stdout)
+      iex> Px.wait(p)
+      iex> Px.read_fd(dup)
+      {:ok, "hi\\n"}
+  """
+  def dup_fd(%__MODULE__{resource: resource}, stream) when stream in [:stdout, :stderr] do
+    dup_fd_nif(resource, stream)
+  end
+
+  @doc """
+  Read up to `max_bytes` (default 65536) from a `dup_fd/2` handle.
+
+  The copy shares the stream's mode, so an empty pipe gives `:would_block`,
+  or blocks for data if the process was spawned with `nonblocking: false`.
+
+  ## Returns
+
+  - `{:ok, data}` - bytes read
+  - `:would_block` - nothing available on a non-blocking pipe
+  - `:eof` - the child's end is closed
+  - `{:error, :closed}` - the descriptor was taken with `into_fd/1`
+  - `{:error, reason}` - the read failed
+  """
+  def read_fd(dup, max_bytes \\ 65_536) when is_integer(max_bytes) and max_bytes > 0 do
+    dup_fd_read_nif(dup, max_bytes)
+  end
+
+  @doc """
+  Take the raw descriptor out of a `dup_fd/2` handle, e.g. to pass it to
+  `file_actions:`. The caller then owns it and must close it exactly once,
+  by `close_fd/1` or by whatever it was handed to; the handle no longer
+  closes it. Returns `{:ok, fd}`, or `{:error, :closed}` if it was already
+  taken.
+
+  ## Examples
+
+      iex> p = Px.spawn!("echo", ["hi"], stdout: :pipe)
+      iex> {:ok, dup} = Px.dup_fd(p, :stdout)
+      iex> {:ok, fd} = Px.into_fd(dup)
+      iex> Px.wait(p)
+      iex> reader = Px.spawn!("cat", [], stdout: :pipe, file_actions: [{:dup2, fd, 0}])
+      iex> Px.close_fd(fd)
+      :ok
+      iex> Px.wait(reader) |> Px.read(:stdout)
+      {:ok, "hi\\n"}
+  """
+  def into_fd(dup), do: dup_fd_into_nif(dup)
+
+  @doc """
+  Close a descriptor obtained from `take_fd/2`, `into_fd/1` or `open_exec/1`.
+  """
+  def close_fd(fd) when is_integer(fd) and fd >= 0, do: close_fd_nif(fd)
+
+  @doc """
+  Close a pipe to/from the child process, raising on failure.
+
+  Same as `close/2` but raises on error instead of returning `{:error, reason}`.
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.write(p, "data")
+      :ok
+      iex> Px.close!(p, :stdin)
+      :ok
+  """
+  def close!(%__MODULE__{} = process, stream) do
+    case close(process, stream) do
+      :ok -> :ok
+      {:error, reason} -> raise "Failed to close #{stream}: #{inspect(reason)}"
+    end
+  end
+
+  @doc """
+  Feed `input` to the process, collect its output, and wait for it to exit.
+
+  Writes `input` to stdin from a background thread and then closes stdin,
+  while stdout and stderr are drained concurrently, so a child that fills
+  one pipe while waiting on another can never deadlock. Streams that are not
+  piped are skipped; stdin must be piped when `input` is non-empty.
+
+  ## Options
+
+  - `:timeout` - milliseconds to allow for the whole exchange, or a
+    `{:deadline, ns}` from `monotonic_ns/0` (default: `:infinity`)
+  - `:max_output` - maximum bytes to collect from each of stdout and stderr
+    (default: `:infinity`)
+  - `:kill_signal` - signal sent first when a limit is hit, e.g. `:sigterm`
+    to let the child flush its state (default: `nil`, SIGKILL right away)
+  - `:kill_timeout` - milliseconds to allow after `:kill_signal` before
+    escalating to SIGKILL (default: `5000`)
+
+  When either limit is hit, the child is terminated as above and reaped
+  before this returns.
+
+  ## Returns
+
+  - `{:ok, process, stdout, stderr}` - the child exited; `process.status` is updated
+  - `:timeout` - the deadline passed before the child finished
+  - `:output_limit_exceeded` - an output stream grew past `:max_output`
+  - `{:error, :not_piped}` - `input` was given but stdin is not piped
+
+  ## Examples
+
+      iex> p = Px.spawn!("tr", ["a-z", "A-Z"], stdin: :pipe, stdout: :pipe)
+      iex> {:ok, p, stdout, ""} = Px.communicate(p, "hello")
+      iex> {p.status, stdout}
+      {{:exited, 0}, "HELLO"}
+  """
+  def communicate(%__MODULE__{resource: resource} = process, input \\ "", opts \\ [])
+      when is_binary(input) do
+    ensure_sigchild()
+
+    timeout = timeout_arg(Keyword.get(opts, :timeout, :infinity))
+    max_output = limit_arg(Keyword.get(opts, :max_output, :infinity))
+    kill_signal = Keyword.get(opts, :kill_signal)
+    kill_timeout = Keyword.get(opts, :kill_timeout, 5000)
+
+    case communicate_nif(
+           resource,
+           input,
+           timeout,
+           max_output,
+           kill_signal && signal_int(kill_signal),
+           kill_timeout
+         ) do
+      {:ok, exit, stdout, stderr} ->
+        {:ok, %{process | status: {:exited, exit_code(exit)}}, stdout, stderr}
+
+      other ->
+        other
+    end
+  end
+
+  @doc """
+  Wait for the process to exit, returning everything it wrote.
+
+  Reads stdout and stderr to EOF on a dirty scheduler while the child runs,
+  then reaps it, so no output is lost and a child blocked on a full pipe
+  still finishes. Unlike `communicate/3` nothing is written to stdin; feed
+  and close it first if the child needs input. Streams that are not piped
+  come back as `""`. A grandchild holding a pipe open keeps this waiting.
+
+  ## Options
+
+  - `:max_output` - bytes to keep from the end of each of stdout and stderr;
+    earlier output is read and discarded (default: `:infinity`)
+
+  ## Returns
+
+  `{process, stdout, stderr}` with `process.status` updated.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "echo out; echo err >&2; exit 3"],
+      ...>   stdout: :pipe, stderr: :pipe)
+      iex> {p, stdout, stderr} = Px.wait_full(p)
+      iex> {p.status, stdout, stderr}
+      {{:exited, 3}, "out\\n", "err\\n"}
+  """
+  def wait_full(%__MODULE__{resource: resource} = process, opts \\ []) do
+    ensure_sigchild()
+
+    max_output = limit_arg(Keyword.get(opts, :max_output, :infinity))
+    {exit, stdout, stderr} = wait_full_nif(resource, max_output)
+    {%{process | status: {:exited, exit_code(exit)}}, stdout, stderr}
+  end
+
+  @doc """
+  Send a terminal control character to a process spawned with `pty: true`.
+
+  The character is taken from the terminal's current settings and written
+  to it as if typed, so the terminal acts on it: `:intr` (usually Ctrl-C)
+  sends SIGINT and `:susp` (Ctrl-Z) sends SIGTSTP to the foreground process
+  group, and `:eof` (Ctrl-D) ends the current line of input.
+
+  ## Returns
+
+  - `:ok` - the character was written
+  - `:would_block` - the terminal's input buffer is full
+  - `{:error, :not_pty}` - the process was not spawned with `pty: true`
+  - `{:error, reason}` - other IO error
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], pty: true)
+      iex> Px.send_control(p, :eof)
+      :ok
+      iex> Px.wait(p).status
+      {:exited, 0}
+  """
+  def send_control(%__MODULE__{resource: resource}, control)
+      when control in [:intr, :eof, :susp] do
+    send_control_char_nif(resource, control)
+  end
+
+  @doc """
+  Set the terminal mode of a process spawned with `pty: true`.
+
+  - `:cooked` - line editing, echo and signal characters; the default
+  - `:raw` - bytes pass through unprocessed, as full-screen programs expect
+    (no echo, no line buffering, Ctrl-C is an ordinary byte, no `\r\n`
+    translation on output)
+  - `:no_echo` - cooked, but input is not echoed, as for password prompts
+
+  Programs may change the mode themselves; this sets it from the outside.
+
+  ## Returns
+
+  - `:ok` - the mode was applied
+  - `{:error, :not_pty}` - the process was not spawned with `pty: true`
+  - `{:error, reason}` - the terminal could not be configured
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], pty: true)
+      iex> Px.set_term_mode(p, :no_echo)
+      :ok
+      iex> Px.send_control(p, :eof)
+      :ok
+  """
+  def set_term_mode(%__MODULE__{resource: resource}, mode)
+      when mode in [:raw, :cooked, :no_echo] do
+    set_term_mode_nif(resource, mode)
+  end
+
+  @doc """
+  Resize the terminal of a process spawned with `pty: true`.
+
+  Sets the terminal to `rows` by `cols`, then sends SIGWINCH to its
+  foreground process group, so full-screen programs repaint. The new size
+  is in place before the signal is sent, so the program reads it when it
+  handles the signal. The signal is sent even if the size did not change.
+
+  ## Returns
+
+  - `:ok` - the terminal was resized and the signal sent
+  - `{:error, :not_pty}` - the process was not spawned with `pty: true`
+  - `{:error, reason}` - the terminal could not be resized or had no
+    foreground process group
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], pty: true)
+      iex> Px.resize(p, 40, 120)
+      :ok
+      iex> Px.send_control(p, :eof)
+      :ok
+  """
+  def resize(%__MODULE__{resource: resource}, rows, cols)
+      when rows in 0..65_535 and cols in 0..65_535 do
+    notify_resize_nif(resource, rows, cols)
+  end
+
+  @doc """
+  Tear the process down: close all pipes, SIGKILL it if still running, and
+  reap it.
+
+  Releases the child deterministically instead of waiting for the resource
+  to be garbage collected. Idempotent: calling it again, or on a process
+  that already exited, returns the same final status.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"], stdin: :pipe, stdout: :pipe)
+      iex> {:ok, p} = Px.destroy(p)
+      iex> p.status
+      {:exited, 137}
+  """
+  def destroy(%__MODULE__{resource: resource} = process) do
+    ensure_sigchild()
+
+    {:ok, exit} = destroy_nif(resource)
+    {:ok, %{process | status: {:exited, exit_code(exit)}}}
+  end
+
+  @doc """
+  Shut the process down in the order that loses no output and cannot hang:
+  close stdin, signal the child, drain stdout and stderr, then reap it.
+
+  Doing these steps by hand is easy to get wrong: waiting before closing
+  stdin hangs a child that reads until EOF, and waiting before draining
+  hangs one blocked on a full pipe, or loses what it wrote while exiting.
+  Without `:drain`, the output pipes are closed before the wait instead, so
+  a child writing to them gets `EPIPE` rather than blocking forever.
+
+  With `:signal`, the drain and the wait share the `:grace` period; a child
+  still running after it is sent SIGKILL, and what it wrote up to then is
+  still returned. Without a signal, both wait for as long as the child
+  takes. Every pipe is closed afterwards.
+
+  ## Options
+
+  - `:close_stdin` - close stdin first (default: `true`). Bytes pending
+    from a partial write are discarded; `flush/2` them beforehand
+  - `:drain` - read stdout and stderr to EOF and return them (default:
+    `true`)
+  - `:signal` - signal sent after stdin is closed, e.g. `:sigterm`
+    (default: `nil`, let the child exit by itself)
+  - `:grace` - milliseconds to allow after `:signal` before SIGKILL
+    (default: `5000`)
+
+  ## Returns
+
+  `{:ok, process, stdout, stderr}` with `process.status` updated; `stdout`
+  and `stderr` are empty without `:drain` or for streams that are not
+  piped.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sort", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.write(p, "b\\na\\n")
+      iex> {:ok, p, stdout, ""} = Px.shutdown(p)
+      iex> {p.status, stdout}
+      {{:exited, 0}, "a\\nb\\n"}
+  """
+  def shutdown(%__MODULE__{resource: resource} = process, opts \\ []) do
+    ensure_sigchild()
+
+    signal = Keyword.get(opts, :signal)
+
+    options = %{
+      close_stdin: Keyword.get(opts, :close_stdin, true),
+      drain: Keyword.get(opts, :drain, true),
+      signal: signal && signal_int(signal),
+      grace_ms: Keyword.get(opts, :grace, 5000)
+    }
+
+    {:ok, exit, stdout, stderr} = shutdown_nif(resource, options)
+    {:ok, %{process | status: {:exited, exit_code(exit)}}, stdout, stderr}
+  end
+
+  @doc """
+  Kill the process and everything it started, through its cgroup, and reap
+  it. Linux only.
+
+  The child's cgroup, and every cgroup below it, is frozen, every process
+  in them is sent SIGKILL and the cgroup is thawed again. Frozen processes
+  cannot fork, so unlike `signal_group/2` nothing can start between the
+  signals and escape them. The cgroup is left in place, empty once the
+  kernel has torn the processes down, for the caller to remove or reuse.
+
+  ## Returns
+
+  - `{:ok, process}` - the process, reaped, as from `destroy/1`
+  - `{:error, :not_in_cgroup}` - the child was not spawned with `:cgroup`
+  - `{:error, :own_cgroup}` - the BEAM itself is in that cgroup; nothing
+    was killed
+
+  ## Examples
+
+      File.mkdir!("/sys/fs/cgroup/px/job")
+      p = Px.spawn!("sh", ["-c", "sleep 10 & sleep 10"], cgroup: "/sys/fs/cgroup/px/job")
+      {:ok, %Px{status: {:exited, 137}}} = Px.kill_tree(p)
+  """
+  def kill_tree(%__MODULE__{resource: resource} = process) do
+    ensure_sigchild()
+
+    with {:ok, exit} <- kill_tree_nif(resource) do
+      {:ok, %{process | status: {:exited, exit_code(exit)}}}
+    end
+  end
+
+  @doc """
+  Spawn `cmd` as a coprocess, with stdin and stdout piped.
+
+  Drive it with `request/3`. Accepts the same options as `spawn/3`; any
+  `:stdin` or `:stdout` setting is overridden.
+
+  ## Examples
+
+      iex> {:ok, p} = Px.coprocess("cat", [])
+      iex> Px.request(p, "2 + 2\n")
+      {:ok, "2 + 2\n"}
+  """
+  def coprocess(cmd, args, opts \\ []) do
+    spawn(cmd, args, Keyword.merge(opts, stdin: :pipe, stdout: :pipe))
+  end
+
+  @doc """
+  Run several commands at once and take the first to succeed.
+
+  Each spec is `{cmd, args}` or `{cmd, args, opts}`, spawned as by
+  `spawn/3` with stdout piped. Their output is collected as it arrives
+  until one exits with status 0; the others are then killed with SIGKILL
+  and reaped, so no loser outlives the call. Useful for redundant attempts,
+  e.g. querying two mirrors and keeping whichever answers first.
+
+  If a spawn fails, the children already spawned are destroyed and the
+  spawn error is returned.
+
+  ## Options
+
+  - `:timeout` - milliseconds to wait for a winner (default: `:infinity`),
+    or a `{:deadline, ns}` from `monotonic_ns/0`
+
+  ## Returns
+
+  - `{:ok, index, stdout}` - the spec at `index` (zero-based) won; `stdout`
+    is everything it wrote
+  - `{:error, :all_failed, exits}` - every command exited unsuccessfully;
+    `exits` lists their exits in spec order, each `{:exited, code}`,
+    `{:signaled, signal, core_dumped}` or `:reaped_externally`
+  - `{:error, :timeout}` - no command succeeded in time; all were killed
+
+  ## Examples
+
+      iex> Px.race([{"sh", ["-c", "sleep 5; echo slow"]}, {"echo", ["fast"]}])
+      {:ok, 1, "fast\n"}
+      iex> Px.race([{"false", []}, {"sh", ["-c", "exit 3"]}])
+      {:error, :all_failed, [{:exited, 1}, {:exited, 3}]}
+  """
+  def race(specs, opts \\ []) when is_list(specs) and specs != [] do
+    timeout = timeout_arg(Keyword.get(opts, :timeout, :infinity))
+
+    spawned =
+      Enum.reduce_while(specs, [], fn spec, acc ->
+        {cmd, args, spawn_opts} = race_spec(spec)
+
+        case spawn(cmd, args, Keyword.merge(spawn_opts, stdout: :pipe, nonblocking: true)) do
+          {:ok, process} ->
+            {:cont, [process | acc]}
+
+          error ->
+            Enum.each(acc, &destroy/1)
+            {:halt, error}
+        end
+      end)
+
+    case spawned do
+      processes when is_list(processes) ->
+        resources = processes |> Enum.reverse() |> Enum.map(& &1.resource)
+        race_nif(resources, timeout)
+
+      error ->
+        error
+    end
+  end
+
+  @doc """
+  Write `input` to the process and wait for one response on stdout.
+
+  Meant for coprocesses such as `bc` or `dc` that answer each request on
+  stdout. Blocks until `input` is fully written and a response arrives. The
+  write and the read happen in one call on one dirty scheduler, so a tight
+  ping-pong loop pays a single NIF round trip per exchange.
+
+  Many programs buffer stdout when it is not a terminal and will not answer
+  until their buffer fills. Ask the program to flush (e.g. `stdbuf -oL`,
+  `python -u`), or run it in PTY mode.
+
+  ## Options
+
+  - `:timeout` - milliseconds to wait for the whole round trip
+    (default: `5_000`; `:infinity` to wait forever), or a `{:deadline, ns}`
+    from `monotonic_ns/0` shared with other calls
+  - `:read` - `:line` to return exactly one complete line, keeping any
+    further output for the next call (default), or `:chunk` to return
+    whatever is ready once the first bytes arrive
+  - `:max_bytes` - with `read: :chunk`, the most bytes to return
+    (default: `65_536`); the rest stays for the next call
+
+  ## Returns
+
+  - `{:ok, response}` - the response; on EOF a final unterminated line is
+    returned as is
+  - `:eof` - stdout closed with nothing left to read
+  - `:timeout` - no response in time
+  - `{:error, :not_piped}` - stdin or stdout is not piped
+  - `{:error, :broken_pipe}` - the child closed stdin
+  - `{:error, reason}` - other IO error
+
+  ## Examples
+
+      iex> {:ok, p} = Px.coprocess("cat", [])
+      iex> Px.request(p, "a\nb\n")
+      {:ok, "a\n"}
+      iex> Px.request(p, "")
+      {:ok, "b\n"}
+  """
+  def request(%__MODULE__{resource: resource}, input, opts \\ []) when is_binary(input) do
+    timeout = timeout_arg(Keyword.get(opts, :timeout, 5_000))
+    line? = Keyword.get(opts, :read, :line) == :line
+    max_bytes = Keyword.get(opts, :max_bytes, 65_536)
+
+    request_nif(resource, input, timeout, line?, max_bytes)
+  end
+
+  @doc """
+  Return the OS monotonic clock (`CLOCK_MONOTONIC`) in nanoseconds.
+
+  Functions that take a `:timeout` also accept `{:deadline, ns}`, an
+  absolute time on this clock. A sequence of calls can then share one
+  overall budget without recomputing what is left of it before each call.
+  This is not the clock behind `System.monotonic_time/1`, so build deadlines
+  from this function only.
+
+  ## Examples
+
+      iex> {:ok, p} = Px.coprocess("cat", [])
+      iex> deadline = {:deadline, Px.monotonic_ns() + 1_000_000_000}
+      iex> Px.request(p, "a\n", timeout: deadline)
+      {:ok, "a\n"}
+      iex> Px.request(p, "b\n", timeout: deadline)
+      {:ok, "b\n"}
+  """
+  def monotonic_ns, do: monotonic_ns_nif()
+
+  @doc """
+  Read stdout until EOF and return all of it.
+
+  Stdout reaching EOF means the child has produced all of its output, which
+  can happen well before it exits. Conversely, stdout stays open after the
+  child exits if a process it forked still holds it.
+
+  `timeout` is in milliseconds, `:infinity` (the default), or a
+  `{:deadline, ns}` from `monotonic_ns/0`.
+
+  ## Returns
+
+  - `{:eof, output}` - stdout is closed; `output` is everything not read yet
+  - `{:timeout, output}` - stdout was still open at the deadline; `output`
+    is what was read until then
+  - `{:error, :not_piped}` - stdout is not piped
+  - `{:error, reason}` - IO error; output read so far is kept for `read/2`
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "echo done; exec >&-; sleep 1"], stdout: :pipe)
+      iex> Px.wait_stdout_eof(p, 500)
+      {:eof, "done\n"}
+  """
+  def wait_stdout_eof(%__MODULE__{resource: resource}, timeout \\ :infinity) do
+    wait_stdout_eof_nif(resource, timeout_arg(timeout))
+  end
+
+  @doc """
+  Read the next length-prefixed frame from stdout.
+
+  For children that speak a binary protocol of `[length][payload]` frames,
+  this reads the length prefix and then exactly that many payload bytes,
+  however the data was split across pipe reads. Bytes of an incomplete
+  frame are kept for the next call, and `read/2` returns them too, so a
+  read that times out mid-frame can be resumed without losing the
+  protocol's framing.
+
+  ## Options
+
+  - `:length_bytes` - size of the unsigned length prefix: 1, 2, 4 or 8
+    (default: `4`)
+  - `:endianness` - `:big` or `:little` (default: `:big`)
+  - `:timeout` - milliseconds to wait for a complete frame, `:infinity`, or
+    a `{:deadline, ns}` from `monotonic_ns/0` (default: `0`, do not wait)
+
+  ## Returns
+
+  - `{:ok, payload}` - a complete frame was read
+  - `:would_block` - no complete frame yet, with no `:timeout` given
+  - `{:timeout, buffered}` - no complete frame arrived before the timeout;
+    `buffered` bytes of the next frame, length prefix included, have been
+    read and are kept for the next call
+  - `:eof` - stdout is closed between frames
+  - `{:error, :truncated_frame}` - stdout closed in the middle of a frame
+  - `{:error, :not_piped}` - stdout is not piped
+  - `{:error, reason}` - IO error
+
+  ## Examples
+
+      iex> p = Px.spawn!("printf", ["\\000\\002hi\\000\\001!"], stdout: :pipe)
+      iex> Px.read_frame(p, length_bytes: 2, timeout: 1000)
+      {:ok, "hi"}
+      iex> Px.read_frame(p, length_bytes: 2, timeout: 1000)
+      {:ok, "!"}
+      iex> Px.read_frame(p, length_bytes: 2, timeout: 1000)
+      :eof
+  """
+  def read_frame(%__MODULE__{resource: resource}, opts \\ []) do
+    timeout =
+      case Keyword.get(opts, :timeout, 0) do
+        0 -> nil
+        timeout -> timeout_arg(timeout)
+      end
+
+    read_frame_nif(
+      resource,
+      Keyword.get(opts, :length_bytes, 4),
+      Keyword.get(opts, :endianness, :big),
+      timeout
+    )
+  end
+
+  @doc """
+  Wait for output on stdout, then read everything that is ready as one chunk.
+
+  For interactive programs that answer each command within a bounded time:
+  this waits up to `:timeout` for the first byte, then keeps reading
+  without waiting until the pipe is empty or `:max_bytes` have been read.
+
+  ## Options
+
+  - `:timeout` - milliseconds to wait for the first byte (default: `5_000`;
+    `:infinity` to wait forever), or a `{:deadline, ns}` from
+    `monotonic_ns/0`
+  - `:max_bytes` - most bytes to return (default: `65_536`)
+
+  ## Returns
+
+  - `{:ok, data}` - the data that was ready
+  - `:timeout` - nothing arrived in time
+  - `:eof` - stdout is closed with nothing left to read
+  - `{:error, :not_piped}` - stdout is not piped
+  - `{:error, reason}` - IO error
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "echo a; echo b; sleep 1"], stdout: :pipe)
+      iex> Process.sleep(50)
+      iex> Px.read_chunk(p, timeout: 1000)
+      {:ok, "a\\nb\\n"}
+      iex> Px.read_chunk(p, timeout: 100)
+      :timeout
+  """
+  def read_chunk(%__MODULE__{resource: resource}, opts \\ []) do
+    timeout = timeout_arg(Keyword.get(opts, :timeout, 5_000))
+    read_chunk_timeout_nif(resource, timeout, Keyword.get(opts, :max_bytes, 65_536))
+  end
+
+  @doc """
+  Read from the process stdout or stderr.
+
+  Requires the process to be spawned with `stdout: :pipe` or `stderr: :pipe`.
+
+  ## Returns
+
+  - `{:ok, binary}` - data was read successfully
+  - `:eof` - the stream has been closed
+  - `:would_block` - no data available right now (non-blocking)
+  - `{:error, :not_piped}` - stream was not configured as `:pipe`
+  - `{:error, reason}` - an error occurred
+
+  ## Options
+
+  - `:eof_reason` - report `:eof` as `{:eof, :exited}` if the child has
+    exited, or `{:eof, :closed}` if it closed the stream but is still
+    running (default: `false`). The check does not reap the child
+
+  Reaping the child does not close its pipes: after `wait/1`, keep reading
+  until `:eof` to get everything it wrote before exiting. `:eof` only comes
+  once the pipe is drained and every writer, including any background
+  process that inherited it, has closed it.
+
+  The result binary is allocated before anything is read, so if allocation
+  fails the call returns `{:error, reason}` with the data still in the pipe.
+  The allocation is reused across reads that return nothing, so polling is
+  cheap. Output a line-mode read buffered is likewise only dropped once the
+  binary returning it has been allocated.
+
+  ## Examples
+
+      iex> p = Px.spawn!("echo", ["hello"], stdout: :pipe)
+      iex> Process.sleep(50)
+      iex> Px.read(p, :stdout)
+      {:ok, "hello\\n"}
+
+      iex> p = Px.spawn!("sh", ["-c", "echo error >&2"], stderr: :pipe)
+      iex> Process.sleep(50)
+      iex> Px.read(p, :stderr)
+      {:ok, "error\\n"}
+
+      iex> {:ok, p} = Px.spawn("echo", ["hello"])  # no pipe configured
+      iex> Px.read(p, :stdout)
+      {:error, :not_piped}
+  """
+  def read(process, stream, opts \\ [])
+
+  def read(%__MODULE__{stdout: stdout, resource: resource}, :stdout, opts)
+      when stdout in @piped do
+    read_stdout_nif(resource, Keyword.get(opts, :eof_reason, false))
+  end
+
+  def read(%__MODULE__{stderr: stderr, resource: resource}, :stderr, opts)
+      when stderr in @piped do
+    read_stderr_nif(resource, Keyword.get(opts, :eof_reason, false))
+  end
+
+  def read(%__MODULE__{}, _stream, _opts), do: {:error, :not_piped}
+
+  @doc """
+  Read from stdout or stderr as UTF-8 text.
+
+  Like `read/2`, but the data is always a valid string: a multibyte
+  character split across reads is held back until the rest of it arrives,
+  and bytes that are not valid UTF-8 are replaced with U+FFFD (`"\\uFFFD"`).
+  At EOF a held-back partial character is returned, replaced. `:lines` mode
+  does not apply; each call returns whatever text is ready.
+
+  Held-back bytes stay with the stream, so mixing `read/2` and
+  `read_text/2` on one stream loses nothing.
+
+  ## Examples
+
+      iex> p = Px.spawn!("printf", ["caf\\\\303\\\\251 \\\\377"], stdout: :pipe)
+      iex> Process.sleep(50)
+      iex> Px.read_text(p, :stdout)
+      {:ok, "café \\uFFFD"}
+  """
+  def read_text(%__MODULE__{stdout: stdout, resource: resource}, :stdout)
+      when stdout in @piped do
+    read_stdout_text_nif(resource)
+  end
+
+  def read_text(%__MODULE__{stderr: stderr, resource: resource}, :stderr)
+      when stderr in @piped do
+    read_stderr_text_nif(resource)
+  end
+
+  def read_text(%__MODULE__{}, _stream), do: {:error, :not_piped}
+
+  @doc """
+  Read whatever is buffered on stdout right now, in one read.
+
+  Asks the kernel how many bytes the pipe holds (`FIONREAD`) and reads
+  exactly that many into a binary of that size: one ioctl and one read,
+  however much is waiting, where `read/2` takes at most 4096 bytes a call.
+  Meant for consumers that read once per readiness event and come back on
+  the next one rather than loop. Anything a `:lines` read left buffered is
+  returned first, as is, without a syscall.
+
+  Returns `{:ok, binary}`, `:would_block`, `:eof`, `{:error, :not_piped}` or
+  `{:error, reason}`, like `read/2`.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "head -c 10000 /dev/zero"], stdout: :pipe)
+      iex> Px.wait(p)
+      iex> {:ok, data} = Px.read_buffered(p)
+      iex> byte_size(data)
+      10000
+      iex> Px.read_buffered(p)
+      :eof
+  """
+  def read_buffered(%__MODULE__{stdout: stdout, resource: resource}) when stdout in @piped do
+    read_stdout_buffered_nif(resource)
+  end
+
+  def read_buffered(%__MODULE__{}), do: {:error, :not_piped}
+
+  @doc """
+  Read stdout into several binaries at once, one per size in `sizes`.
+
+  Allocates a binary of each size and fills them in order with a single
+  `readv(2)`, so a reader of a framed protocol can take a fixed-size header
+  and the body after it in one call, each in a binary of its own, without
+  splitting a larger binary afterwards. The scatter write to go with it is
+  `write/2` with a list, which hands the pieces to one `writev(2)`.
+
+  A read returns what the pipe has, which may be less than the sizes add
+  up to. The binaries are then filled in order up to that point: the result
+  always has one binary per size, the one where the data ran out is shorter
+  than asked for, and those after it are empty. Nothing waits for the rest;
+  read again for it.
+
+  Bytes a `:lines` read left buffered fill the first binaries before
+  anything is read. Like `read/2`, the binaries are allocated before the
+  read, so a failed allocation leaves the data in the pipe.
+
+  Returns `{:ok, binaries}`, `:would_block`, `:eof`, `{:error, :not_piped}`
+  or `{:error, reason}`. `sizes` must hold between 1 and 1024 positive
+  integers.
+
+  ## Examples
+
+      iex> p = Px.spawn!("printf", ["HDR1body"], stdout: :pipe)
+      iex> Px.wait(p)
+      iex> Px.read_vectored(p, [4, 8, 8])
+      {:ok, ["HDR1", "body", ""]}
+  """
+  def read_vectored(%__MODULE__{stdout: stdout, resource: resource}, sizes)
+      when stdout in @piped and is_list(sizes) do
+    read_stdout_vectored_nif(resource, sizes)
+  end
+
+  def read_vectored(%__MODULE__{}, _sizes), do: {:error, :not_piped}
+
+  @doc """
+  Limit how many spawned children may be live at once, node-wide.
+
+  A child counts against the limit from spawn until it is reaped (by
+  `wait/1`, `alive?/1` and the like) or its process struct is garbage
+  collected. Once the limit is reached, spawns fail with
+  `{:error, :too_many_children}` rather than blocking; retry after reaping.
+  Lowering the limit never affects children that are already running.
+
+  Pass `:infinity` to remove the limit (the default).
+
+  ## Examples
+
+      iex> Px.set_max_children(:infinity)
+      :ok
+  """
+  def set_max_children(:infinity), do: set_max_children_nif(0)
+  def set_max_children(max) when is_integer(max) and max > 0, do: set_max_children_nif(max)
+
+  @doc """
+  Return the number of children currently counted against
+  `set_max_children/1`.
+  """
+  def live_children, do: live_children_nif()
+
+  @doc """
+  Return the OS PIDs of spawned children that have not been reaped yet.
+
+  A child is listed from spawn until it is reaped or its process struct is
+  garbage collected, so a PID that stays listed long after its owner is done
+  with it points at a missing `wait/1`. The list is in spawn order.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> p.pid in Px.live_child_pids()
+      true
+      iex> Px.destroy(p)
+      iex> p.pid in Px.live_child_pids()
+      false
+  """
+  def live_child_pids, do: live_child_pids_nif()
+
+  @doc """
+  Reap the process if it has exited, along with its resource usage.
+
+  Never blocks. Resource usage is only reported by the wait that reaps the
+  child, so this collects both in one `wait4(2)` call, and the usage always
+  belongs to that exit.
+
+  ## Returns
+
+  - `:still_running` - the process has not exited
+  - `{exit, usage}` - the process has exited, where `exit` is
+    `{:exited, code}`, `{:signaled, signal, core_dumped}` or
+    `:reaped_externally`, and `usage` is a map with `:user_time_us`,
+    `:system_time_us`, `:max_rss_kb`, `:minor_faults`, `:major_faults`,
+    `:voluntary_context_switches` and `:involuntary_context_switches`.
+    `usage` is `nil` if the process had already been reaped by another
+    call such as `wait/1` or `alive?/1`, or outside Px
+
+  ## Examples
+
+      iex> p = Px.spawn!("true", [])
+      iex> Process.sleep(50)
+      iex> {{:exited, 0}, %{max_rss_kb: rss}} = Px.try_wait_usage(p)
+      iex> rss > 0
+      true
+  """
+  def try_wait_usage(%__MODULE__{resource: resource}), do: try_wait_usage_nif(resource)
+
+  @doc """
+  Get the exit code and terminating signal of a waited process as separate
+  fields.
+
+  The `status` set by `wait/1` folds signal deaths into `128 + signal`, so a
+  program that runs `exit 137` looks the same as one killed by SIGKILL. This
+  reports the raw exit status and signal apart. It never waits: call it
+  after `wait/1` or once `alive?/1` has returned false.
+
+  ## Returns
+
+  - `{:ok, %{code: code, signal: signal, core_dumped: boolean}}` - `code` is
+    `nil` for a signal death and `signal` is `nil` for a normal exit
+  - `{:error, :running}` - no exit has been recorded yet
+  - `{:error, :reaped_externally}` - the exit status was lost
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "exit 3"]) |> Px.wait()
+      iex> Px.exit_info(p)
+      {:ok, %{code: 3, signal: nil, core_dumped: false}}
+  """
+  def exit_info(%__MODULE__{resource: resource}), do: exit_info_nif(resource)
+
+  @doc """
+  Describe how a child killed by a signal died, as `waitid(2)` reported it.
+
+  Before reaping a child, Px reads the `siginfo_t` of its exit, which tells
+  a plain kill from one that dumped core and carries the child's user ID.
+  It never waits: call it after `wait/1` or once `alive?/1` has returned
+  false.
+
+  The kernel does not say who sent the signal: `pid` is the child's own
+  PID, as in every `SIGCHLD`. To tell an external kill from one Px sent,
+  see `termination_signal/1`; a SIGKILL from outside Px is often the OOM
+  killer, which the kernel log confirms.
+
+  ## Returns
+
+  - `{:ok, %{signal: signal, code: code, status: status, pid: pid, uid: uid}}` -
+    `code` is `:killed` or `:dumped` (`CLD_KILLED`, `CLD_DUMPED`), `status`
+    the signal as `si_status` holds it. `uid` is `nil` when the exit was
+    reaped before its `siginfo_t` could be read; `code` then comes from the
+    wait status
+  - `{:error, :not_signaled}` - the child exited normally
+  - `{:error, :running}` - no exit has been recorded yet
+  - `{:error, :reaped_externally}` - the exit status was lost
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> Px.signal!(p, :sigkill)
+      iex> p = Px.wait(p)
+      iex> {:ok, %{signal: 9, code: :killed, pid: pid}} = Px.signal_info(p)
+      iex> pid == p.pid
+      true
+  """
+  def signal_info(%__MODULE__{resource: resource}), do: signal_info_nif(resource)
+
+  @doc """
+  Decode a raw `wait(2)` status integer.
+
+  Applies the same `WIFEXITED`/`WEXITSTATUS`/`WIFSIGNALED`/`WTERMSIG`/
+  `WCOREDUMP` interpretation Px uses for its own children, for statuses that
+  come from elsewhere (logs, other tools). Note that the `128 + signal`
+  codes in `status` are not raw wait statuses and cannot be decoded here.
+
+  ## Returns
+
+  - `{:exited, code}` - the process exited normally
+  - `{:signaled, signal, core_dumped}` - the process was killed by a signal
+  - `{:error, :not_terminated}` - the status describes a stopped or
+    continued process
+
+  ## Examples
+
+      iex> Px.decode_exit_status(0x0300)
+      {:exited, 3}
+      iex> Px.decode_exit_status(0x0089)
+      {:signaled, 9, true}
+  """
+  def decode_exit_status(raw_status) when is_integer(raw_status) do
+    decode_exit_status_nif(raw_status)
+  end
+
+  @doc """
+  Read from stdout and stderr fairly, up to `max_bytes` in total.
+
+  Takes a chunk from each stream in turn until `max_bytes` have been read or
+  neither stream has data ready, so a chatty stream cannot starve the other
+  the way two sequential `read/2` calls can. Streams that are not piped are
+  treated as empty. Large reads are split into several NIF calls whenever
+  one uses up its scheduler timeslice or makes `:max_reads` reads, so the VM
+  stays responsive even when the child writes in many tiny pieces.
+
+  ## Options
+
+  - `:max_reads` - most `read` calls one NIF call may make (default: `64`)
+
+  Returns `{stdout, stderr}`; both are `""` when nothing was ready. Use
+  `read/2` to tell an empty stream from one at EOF. Returns
+  `{:error, reason}` on a read error, or if the binaries cannot be
+  allocated; bytes already read stay buffered for the next read.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "echo out; echo err >&2"], stdout: :pipe, stderr: :pipe)
+      iex> Px.wait(p)
+      iex> Px.read_balanced(p)
+      {"out\n", "err\n"}
+  """
+  def read_balanced(%__MODULE__{resource: resource}, max_bytes \\ 65_536, opts \\ [])
+      when is_integer(max_bytes) and max_bytes > 0 do
+    read_balanced(resource, max_bytes, max_reads(opts), [], [])
+  end
+
+  defp read_balanced(resource, max_bytes, max_reads, stdout_acc, stderr_acc) do
+    case read_balanced_nif(resource, max_bytes, max_reads) do
+      {:more, stdout, stderr} ->
+        remaining = max_bytes - byte_size(stdout) - byte_size(stderr)
+        stdout_acc = [stdout_acc, stdout]
+        read_balanced(resource, remaining, max_reads, stdout_acc, [stderr_acc, stderr])
+
+      {:error, _reason} = error ->
+        read_error(error, stdout_acc, stderr_acc)
+
+      {stdout, stderr} ->
+        {IO.iodata_to_binary([stdout_acc, stdout]), IO.iodata_to_binary([stderr_acc, stderr])}
+    end
+  end
+
+  # An error after earlier calls returned data: hand that data back now and
+  # leave the error to the next read, which hits it again if it persists.
+  defp read_error(error, stdout_acc, stderr_acc) do
+    if IO.iodata_length(stdout_acc) + IO.iodata_length(stderr_acc) == 0 do
+      error
+    else
+      {IO.iodata_to_binary(stdout_acc), IO.iodata_to_binary(stderr_acc)}
+    end
+  end
+
+  @doc """
+  Read from one stream before the other, up to a byte limit in total.
+
+  Drains `primary` (`:stdout` or `:stderr`) until it has nothing ready, or
+  `:primary_max_bytes` have been read from it, and only then reads the other
+  stream with what is left of `:max_bytes`. Use it where one stream must not
+  wait behind the other, say diagnostics on stderr behind a flood of stdout;
+  `read_balanced/3` is the fair alternative. Streams that are not piped are
+  treated as empty, and large reads are split across NIF calls as in
+  `read_balanced/3`.
+
+  ## Options
+
+  - `:max_bytes` - most bytes to read in total (default: `65_536`)
+  - `:primary_max_bytes` - most bytes to read from `primary` (default:
+    `:max_bytes`)
+  - `:max_reads` - most `read` calls one NIF call may make, as in
+    `read_balanced/3` (default: `64`)
+
+  Returns `{stdout, stderr}`, in that order whichever stream is `primary`,
+  or `{:error, reason}` on a read error or a failed allocation, as for
+  `read_balanced/3`.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "echo out; echo err >&2"], stdout: :pipe, stderr: :pipe)
+      iex> Px.wait(p)
+      iex> Px.read_prioritized(p, :stderr, max_bytes: 6)
+      {"ou", "err\\n"}
+  """
+  def read_prioritized(%__MODULE__{resource: resource}, primary, opts \\ [])
+      when primary in [:stdout, :stderr] do
+    max_bytes = Keyword.get(opts, :max_bytes, 65_536)
+    primary_max_bytes = Keyword.get(opts, :primary_max_bytes, max_bytes)
+    limits = {max_bytes, primary_max_bytes, max_reads(opts)}
+    read_prioritized(resource, primary, limits, {[], []})
+  end
+
+  defp read_prioritized(resource, primary, limits, acc) do
+    {max_bytes, primary_max_bytes, max_reads} = limits
+    {stdout_acc, stderr_acc} = acc
+
+    case read_prioritized_nif(resource, primary, max_bytes, primary_max_bytes, max_reads) do
+      {:more, stdout, stderr} ->
+        primary_read = byte_size(if primary == :stdout, do: stdout, else: stderr)
+        remaining = max_bytes - byte_size(stdout) - byte_size(stderr)
+        primary_remaining = max(primary_max_bytes - primary_read, 0)
+        acc = {[stdout_acc, stdout], [stderr_acc, stderr]}
+        read_prioritized(resource, primary, {remaining, primary_remaining, max_reads}, acc)
+
+      {:error, _reason} = error ->
+        read_error(error, stdout_acc, stderr_acc)
+
+      {stdout, stderr} ->
+        {IO.iodata_to_binary([stdout_acc, stdout]), IO.iodata_to_binary([stderr_acc, stderr])}
+    end
+  end
+
+  defp max_reads(opts), do: Keyword.get(opts, :max_reads, 64)
+
+  @doc """
+  Read several chunks from stdout in one call.
+
+  Makes up to `:max_chunks` non-blocking reads, for children that produce
+  bursts of small writes, and returns the chunks as read rather than joined
+  into one binary, so they can be consumed as iodata.
+
+  ## Options
+
+  - `:max_chunks` - most reads to make (default: `64`)
+  - `:max_bytes` - stop once this many bytes have been read in total
+    (default: `65_536`)
+
+  ## Returns
+
+  `{chunks, status}`, where `status` is:
+
+  - `:more` - a limit was reached (or the call used up its scheduler
+    timeslice); more data may be ready
+  - `:would_block` - no more data right now
+  - `:eof` - stdout is closed
+  - `{:error, reason}` - IO error after the chunks were read
+
+  or `{:error, :not_piped}` if stdout is not piped.
+
+  ## Examples
+
+      iex> p = Px.spawn!("echo", ["hi"], stdout: :pipe) |> Px.wait()
+      iex> Px.read_chunks(p)
+      {["hi\\n"], :eof}
+  """
+  def read_chunks(%__MODULE__{resource: resource}, opts \\ []) do
+    max_chunks = Keyword.get(opts, :max_chunks, 64)
+    max_bytes = Keyword.get(opts, :max_bytes, 65_536)
+    read_stdout_chunks_nif(resource, max_chunks, max_bytes)
+  end
+
+  @doc """
+  Return how long the process has been running, in milliseconds.
+
+  While the process runs this is the time since it was spawned. Once it has
+  been reaped (by `wait/1`, `alive?/1`, etc.) the value is frozen at its total
+  runtime. Both ends are measured with a monotonic clock inside the NIF, so
+  message-passing latency does not skew the result.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["0.1"])
+      iex> p = Px.wait(p)
+      iex> Px.runtime(p) >= 100
+      true
+  """
+  def runtime(%__MODULE__{resource: resource}) do
+    runtime_ms_nif(resource)
+  end
+
+  @doc """
+  Report which signal killed the process, and whether Px sent it.
+
+  Tells apart a child killed through `signal/3`, `destroy/1` or a
+  `communicate/3` timeout from one killed from outside, e.g. by the OOM
+  killer. `by_us?` is `true` when the terminating signal is the last one Px
+  delivered to the child.
+
+  ## Returns
+
+  - `{:signaled, signal, by_us?}` - the child was killed by signal number
+    `signal`
+  - `:not_signaled` - the child exited normally, or was reaped by someone
+    else so its status is unknown
+  - `{:error, :running}` - the child has not been reaped yet
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> {:ok, p} = Px.signal(p, :sigterm)
+      iex> Px.wait(p).status
+      {:exited, 143}
+      iex> Px.termination_signal(p)
+      {:signaled, 15, true}
+  """
+  def termination_signal(%__MODULE__{resource: resource}) do
+    termination_signal_nif(resource)
+  end
+
+  @doc """
+  Return how the process was started: `:posix_spawn` or `:fork_exec`, or
+  `:adopted` for a process taken over with `adopt/1`.
+
+  `posix_spawn` avoids copying the BEAM's page tables, which makes spawning
+  noticeably cheaper when the VM has a large heap. It is used whenever the
+  spawn needs no setup step in the child. Each of these needs one and so
+  forces fork + exec: `pdeathsig: true` (on Linux), `pty: true`, `:cgroup`,
+  `:file_actions`, `:namespaces`, `:pgid`, `start_stopped: true`, and
+  spawning through `spawn_at/4` or `spawn_fd/4`. Overriding `PATH` in `:env`
+  while passing a bare command name does too, as does `:cd` where libc lacks
+  `posix_spawn_file_actions_addchdir_np`.
+
+  `:fork_exec` is exact: setup steps are recorded as they are added. The
+  standard library decides the rest, so `:posix_spawn` is best-effort and
+  could be wrong should it fall back to fork + exec for reasons of its own.
+
+  ## Examples
+
+      iex> p = Px.spawn!("true", [], pdeathsig: false)
+      iex> Px.spawn_method(p)
+      :posix_spawn
+  """
+  def spawn_method(%__MODULE__{resource: resource}) do
+    spawn_method_nif(resource)
+  end
+
+  @doc """
+  Return the milliseconds since the last I/O with the process.
+
+  A read from stdout or stderr that returns data, or a write to stdin that
+  accepts at least one byte, resets the clock. Before any I/O it counts from
+  spawn. Useful for killing children that have gone silent:
+
+      if Px.idle_time(p) > 30_000, do: Px.signal!(p, :sigkill)
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe)
+      iex> Px.write(p, "ping")
+      :ok
+      iex> Px.idle_time(p) < 1_000
+      true
+  """
+  def idle_time(%__MODULE__{resource: resource}) do
+    idle_ms_nif(resource)
+  end
+
+  @doc """
+  Return the bytes moved through the child's stdio so far, as a map with
+  `:stdin_written`, `:stdout_read` and `:stderr_read`.
+
+  The totals cover every read and write made through this module, including
+  `communicate/3`, `request/3` and `splice/3`, and stay readable after the child
+  is reaped. Output consumed by a `{:tail, bytes}` ring or a `:fanout` reader
+  is not counted. Bytes held back for a later line or frame count when they
+  are read from the pipe.
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.write(p, "ping")
+      :ok
+      iex> Px.io_counters(p).stdin_written
+      4
+  """
+  def io_counters(%__MODULE__{resource: resource}) do
+    {stdin_written, stdout_read, stderr_read} = io_counters_nif(resource)
+    %{stdin_written: stdin_written, stdout_read: stdout_read, stderr_read: stderr_read}
+  end
+
+  @doc """
+  Return how each stream was wired at spawn time, as a map with `:stdin`,
+  `:stdout` and `:stderr`.
+
+  Each mode is one of `:null`, `:pipe`, `:socket`, `:inherit`, `:file` or
+  `:pty`, or for a pipe handed to a background reader or writer, `:tail`,
+  `:fanout`, `:log`, `:active` or `:repeat`. Only `:pipe`, `:socket` and `:pty` streams can be
+  read or written through this module, so this tells up front whether
+  `write/2` or `read/2` can work. The modes do not change when a stream is
+  later closed. The spawn NIFs return the same modes alongside the
+  resource, which is how the `:stdin`, `:stdout` and `:stderr` fields of a
+  new process are filled in.
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: {:tail, 1024})
+      iex> Px.stdio_modes(p)
+      %{stdin: :pipe, stdout: :tail, stderr: :null}
+  """
+  def stdio_modes(%__MODULE__{resource: resource}) do
+    {stdin, stdout, stderr, _nonblocking} = stdio_modes_nif(resource)
+    %{stdin: stdin, stdout: stdout, stderr: stderr}
+  end
+
+  @doc """
+  Return the piped streams that are in non-blocking mode.
+
+  Normally every piped stream, or none with `nonblocking: false`. With
+  `tolerate_nonblock_errors: true` a stream that could not be switched at
+  spawn is missing here and its reads and writes block.
+  `reassert_nonblocking/1` adds the streams it switches.
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.nonblocking_streams(p)
+      [:stdin, :stdout]
+  """
+  def nonblocking_streams(%__MODULE__{resource: resource}) do
+    {_stdin, _stdout, _stderr, nonblocking} = stdio_modes_nif(resource)
+    nonblocking
+  end
+
+  @doc """
+  Count the file descriptors the child has open. Linux only.
+
+  Reads `/proc/<pid>/fd`, so it needs no cooperation from the child. A
+  count that keeps climbing in a long-running child usually means it leaks
+  descriptors. Reading another user's process may fail with a permission
+  error.
+
+  Returns `{:ok, count}`, `{:error, :already_exited}` once the child has
+  been reaped, or `{:error, reason}`.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"])
+      iex> {:ok, count} = Px.fd_count(p)
+      iex> count >= 3
+      true
+  """
+  def fd_count(%__MODULE__{resource: resource}), do: fd_count_nif(resource)
+
+  @doc """
+  Report the child's controlling terminal. Linux only.
+
+  Reads `tty_nr` from `/proc/<pid>/stat`. Whether a program has a
+  controlling terminal, and whether its stdio is a terminal, are what it
+  usually checks to decide if it is interactive. Without `pty: true` the
+  child shares the BEAM's session, so it inherits the BEAM's controlling
+  terminal, if any, even when its stdio is piped; with `pty: true` the pty
+  is its controlling terminal.
+
+  ## Returns
+
+  - `{:ok, path}` - the terminal's device, e.g. `"/dev/pts/3"`
+  - `{:ok, {major, minor}}` - a terminal with no device node under `/dev`
+  - `{:ok, :none}` - the child has no controlling terminal
+  - `{:error, :already_exited}` - the child has been reaped
+  - `{:error, reason}` - `/proc` could not be read
+
+  ## Examples
+
+      iex> p = Px.spawn!("setsid", ["sleep", "10"])
+      iex> Process.sleep(50)
+      iex> Px.controlling_tty(p)
+      {:ok, :none}
+      iex> Px.destroy(p)
+  """
+  def controlling_tty(%__MODULE__{resource: resource}), do: controlling_tty_nif(resource)
+
+  @doc """
+  Report the child's scheduling settings. Linux only.
+
+  Reads back the nice value (`getpriority(2)`), scheduling policy
+  (`sched_getscheduler(2)`) and CPU affinity (`sched_getaffinity(2)`) the
+  child runs with now, so settings that need privileges can be checked
+  rather than assumed: the kernel refuses or clamps them without the child
+  necessarily noticing.
+
+  ## Returns
+
+  - `{:ok, %{nice: nice, policy: policy, cpus: cpus}}` - `policy` is one of
+    `:other`, `:batch`, `:idle`, `:fifo`, `:rr` and `:deadline`, and `cpus`
+    lists the CPUs the child may run on
+  - `{:error, :already_exited}` - the child has been reaped
+  - `{:error, reason}` - the settings could not be read
+
+  ## Examples
+
+      iex> p = Px.spawn!("nice", ["-n", "5", "sleep", "10"])
+      iex> Process.sleep(50)
+      iex> {:ok, %{nice: nice, policy: :other}} = Px.sched_info(p)
+      iex> nice >= 5
+      true
+      iex> Px.destroy(p)
+  """
+  def sched_info(%__MODULE__{resource: resource}), do: sched_info_nif(resource)
+
+  @doc """
+  Subscribe a process to the stdout of a process spawned with
+  `stdout: :fanout`. See "Fan-out" in `spawn/3`.
+
+  ## Options
+
+  - `:pid` - the subscriber (default: `self()`). It must call `ack/2` and
+    `unsubscribe/1` itself
+  - `:window` - chunks it may be sent before acknowledging them
+    (default: `16`)
+
+  ## Returns
+
+  - `:ok` - subscribed; output arrives as `{:px_stdout, os_pid, data}`
+    messages, then `{:px_stdout, os_pid, :eof}`
+  - `{:error, :already_subscribed}` - the process is already subscribed
+  - `{:error, :not_fanout}` - stdout was not spawned with `:fanout`
+
+  ## Examples
+
+      iex> p = Px.spawn!("echo", ["hi"], stdout: :fanout)
+      iex> Px.subscribe(p)
+      :ok
+      iex> os_pid = p.pid
+      iex> receive do {:px_stdout, ^os_pid, data} -> data end
+      "hi\\n"
+  """
+  def subscribe(%__MODULE__{resource: resource}, opts \\ []) do
+    subscribe_nif(resource, Keyword.get(opts, :pid, self()), Keyword.get(opts, :window, 16))
+  end
+
+  @doc """
+  Acknowledge `count` chunks received as a fan-out subscriber, letting Px
+  send that many more. Returns `{:error, :not_subscribed}` if the calling
+  process is not subscribed.
+  """
+  def ack(%__MODULE__{resource: resource}, count \\ 1) when is_integer(count) and count > 0 do
+    ack_nif(resource, count)
+  end
+
+  @doc """
+  Unsubscribe the calling process from fan-out stdout. Messages already
+  sent stay in its mailbox. Returns `{:error, :not_subscribed}` if it is not
+  subscribed.
+  """
+  def unsubscribe(%__MODULE__{resource: resource}), do: unsubscribe_nif(resource)
+
+  @doc """
+  Return the last output of a stream spawned with `{:tail, bytes}`.
+
+  Returns `{:ok, data}` with at most `bytes` bytes: the most recent output,
+  oldest first. Earlier output has been discarded. The ring keeps filling
+  until the child and any descendants sharing the stream close it, and
+  stays readable after the process exits.
+
+  Returns `{:error, :not_tailed}` for streams spawned with another config.
+
+  ## Examples
+
+      iex> p = Px.spawn!("sh", ["-c", "seq 1000"], stdout: {:tail, 8})
+      iex> p = Px.wait(p)
+      iex> Process.sleep(50)
+      iex> Px.tail_output(p, :stdout)
+      {:ok, "99\n1000\n"}
+  """
+  def tail_output(%__MODULE__{resource: resource}, stream) when stream in [:stdout, :stderr] do
+    tail_output_nif(resource, stream)
+  end
+
+  @doc """
+  Return stdout and stderr interleaved in the order they were read, for a
+  process spawned with `:interleaved_log` and `{:tail, bytes}` streams.
+
+  Returns `{:ok, chunks}`, oldest first, where each chunk is
+  `{stream, monotonic_ns, data}`: the stream it came from, when the tail
+  thread read it on the clock of `monotonic_ns/0`, and the bytes read.
+  Once the chunks add up to more than the `:interleaved_log` size, the
+  oldest are dropped. The separate rings of `tail_output/2` are unaffected.
+
+  The order is approximate. The two pipes are read by separate threads as
+  data becomes available, so output the child wrote to one stream just
+  before the other may be read after it, several writes may arrive as one
+  chunk, and a chunk's timestamp is when it was read, not written. A child
+  that buffers its own output, as stdio does for pipes, adds to this.
+
+  Returns `{:error, :not_interleaved}` for a process spawned without
+  `:interleaved_log`.
+
+  ## Examples
+
+      iex> script = "echo out; sleep 0.1; echo err >&2"
+      iex> p = Px.spawn!("sh", ["-c", script],
+      ...>   stdout: {:tail, 64}, stderr: {:tail, 64}, interleaved_log: 1024)
+      iex> Px.wait(p)
+      iex> Process.sleep(50)
+      iex> {:ok, chunks} = Px.interleaved_log(p)
+      iex> Enum.map(chunks, fn {stream, _ns, data} -> {stream, data} end)
+      [stdout: "out\\n", stderr: "err\\n"]
+  """
+  def interleaved_log(%__MODULE__{resource: resource}), do: interleaved_log_nif(resource)
+
+  @doc """
+  Switch a stream spawned with `{:log, path}` to appending to `path`.
+
+  The BEAM holds the log file, so the swap needs nothing from the child:
+  output written before the call lands in the old file and output after it
+  in the new one. To rotate, rename the current file and reopen the
+  original path:
+
+      File.rename!("/var/log/app.log", "/var/log/app.log.1")
+      :ok = Px.reopen_output(p, :stdout, "/var/log/app.log")
+
+  With `{:file, path}` the child holds the file itself and only the child
+  can reopen it, commonly on SIGHUP.
+
+  ## Returns
+
+  - `:ok` - later output goes to `path`
+  - `{:error, :not_supported}` - the stream was not spawned with `{:log, path}`
+  - `{:error, reason}` - `path` could not be opened; the old file stays in use
+  """
+  def reopen_output(%__MODULE__{resource: resource}, stream, path)
+      when stream in [:stdout, :stderr] and is_binary(path) do
+    reopen_output_nif(resource, stream, path)
+  end
+
+  @doc """
+  Switch piped stdin over to the contents of the file at `path`.
+
+  The child keeps reading the same pipe, so it sees no change: a thread
+  takes over the BEAM's end, writes any bytes a previous `write/2` left
+  pending, copies the file in (with `splice`/`sendfile` on Linux) and
+  closes stdin at EOF. This suits an interactive preamble followed by a
+  bulk feed:
+
+      :ok = Px.write(p, "BEGIN\\n")
+      :ok = Px.stdin_from_file(p, "/data/batch.csv")
+
+  Afterwards stdin belongs to the feeder, so `write/2` and `close/2` on it
+  return `{:error, :not_piped}`. Bytes it feeds are not counted by
+  `io_counters/1`.
+
+  ## Returns
+
+  - `:ok` - the feeder has taken over stdin
+  - `{:error, :not_piped}` - stdin is not piped or was already closed
+  - `{:error, :not_supported}` - stdin is a pty, whose input is shared with
+    its output
+  - `{:error, reason}` - `path` could not be opened; stdin is left as it was
+  """
+  def stdin_from_file(%__MODULE__{resource: resource}, path) when is_binary(path) do
+    stdin_from_file_nif(resource, path)
+  end
+
+  @doc """
+  Read up to `length` bytes at `offset` from a stream spawned with
+  `:tmpfile`.
+
+  The file is anonymous: on Linux it is created with `O_TMPFILE`, elsewhere
+  it is unlinked right after creation, and it is freed once the process
+  resource is garbage collected. The child never blocks on a reader, so
+  output of any size can be captured and then processed in chunks. Reads
+  may happen while the child is still writing; they see what it has
+  written so far.
+
+  ## Returns
+
+  - `{:ok, data}` - up to `length` bytes; fewer near the end of the file
+  - `:eof` - `offset` is at or past the end of what has been written
+  - `{:error, :not_supported}` - the stream was not spawned with `:tmpfile`
+  - `{:error, reason}` - reading the file failed
+
+  ## Examples
+
+      iex> p = Px.spawn!("seq", ["3"], stdout: :tmpfile)
+      iex> p = Px.wait(p)
+      iex> Px.read_output_file(p, :stdout, 2, 100)
+      {:ok, "2\n3\n"}
+      iex> Px.read_output_file(p, :stdout, 6, 100)
+      :eof
+  """
+  def read_output_file(%__MODULE__{resource: resource}, stream, offset, length)
+      when stream in [:stdout, :stderr] and is_integer(offset) and offset >= 0 and
+             is_integer(length) and length > 0 do
+    read_output_file_nif(resource, stream, offset, length)
+  end
+
+  @doc """
+  Report the buffer capacity and fill level of a piped stream. Linux only.
+
+  Returns `{:ok, %{capacity: bytes, buffered: bytes}}`. For stdout and
+  stderr, `buffered` is output the child has written that has not been read
+  yet; a stream that stays near `capacity` means the reader is the
+  bottleneck. For stdin, `capacity - buffered` is the room left before
+  writes return `:would_block`.
+
+  Returns `{:error, :not_piped}` for streams that are not plain pipes
+  (including `:socket` and `:pty` streams).
+
+  ## Examples
+
+      iex> p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      iex> Px.write(p, "hello")
+      :ok
+      iex> {:ok, %{buffered: buffered}} = Px.pipe_stats(p, :stdin)
+      iex> buffered
+      5
+  """
+  def pipe_stats(%__MODULE__{resource: resource}, stream)
+      when stream in [:stdin, :stdout, :stderr] do
+    pipe_stats_nif(resource, stream)
+  end
+
+  @doc """
+  Move up to `max_bytes` of `src`'s stdout into `dst`'s stdin without
+  copying them through the BEAM.
+
+  This connects processes that were spawned separately into a pipeline. On
+  Linux the bytes move with `splice(2)`; elsewhere, or between `:socket` and
+  `:pty` streams, with a read and a write inside the NIF. Bytes `dst` cannot
+  take yet are kept as its pending write (see `pending_write/1`) and go out
+  before anything else on the next call.
+
+  Returns `{:ok, moved}`, `:would_block` when `src` has nothing to read or
+  `dst` is full, `:eof` once `src`'s stdout is exhausted, or
+  `{:error, reason}`. `src` needs `stdout: :pipe` and `dst` `stdin: :pipe`
+  (or the socket or pty equivalents), otherwise `{:error, :not_piped}`.
+
+  ## Examples
+
+      iex> src = Px.spawn!("echo", ["hello"], stdout: :pipe)
+      iex> dst = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Process.sleep(50)
+      iex> Px.splice(src, dst)
+      {:ok, 6}
+  """
+  def splice(%__MODULE__{resource: src}, %__MODULE__{resource: dst}, max_bytes \\ 65_536)
+      when is_integer(max_bytes) and max_bytes > 0 do
+    splice_nif(src, dst, max_bytes)
+  end
+
+  @doc """
+  Restore non-blocking mode on every piped stream.
+
+  `read/2` and `write/2` rely on the pipes being non-blocking. If the
+  underlying descriptors were handed to other code that cleared `O_NONBLOCK`,
+  this puts it back. Streams that are not piped are left alone, so it is
+  always safe to call. This also switches streams of a process spawned with
+  `nonblocking: false` to non-blocking mode.
+
+  ## Returns
+
+  - `{:ok, streams}` - the streams that had to be fixed (empty if none)
+  - `{:error, stream, reason}` - restoring `stream` failed
+
+  ## Examples
+
+      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      iex> Px.reassert_nonblocking(p)
+      {:ok, []}
+  """
+  def reassert_nonblocking(%__MODULE__{resource: resource}) do
+    reassert_nonblocking_nif(resource)
+  end
+
+  @doc false
+  def spawn_nif(
+        _cmd,
+        _args,
+        _stdin_mode,
+        _stdin_path,
+        _stdout_mode,
+        _stdout_path,
+        _stderr_mode,
+        _stderr_path,
+        _env,
+        _cd,
+        _options
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def spawn_parse_nif(
+        _command_line,
+        _stdin_mode,
+        _stdin_path,
+        _stdout_mode,
+        _stdout_path,
+        _stderr_mode,
+        _stderr_path,
+        _env,
+        _cd,
+        _options
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def spawn_compat_nif(
+        _cmd,
+        _args,
+        _stdin_mode,
+        _stdin_path,
+        _stdout_mode,
+        _stdout_path,
+        _stderr_mode,
+        _stderr_path,
+        _env,
+        _cd,
+        _options
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def adopt_nif(_os_pid), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def spawn_shell_nif(
+        _command,
+        _shell,
+        _stdin_mode,
+        _stdin_path,
+        _stdout_mode,
+        _stdout_path,
+        _stderr_mode,
+        _stderr_path,
+        _env,
+        _cd,
+        _options
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def build_command_nif(
+        _cmd,
+        _stdin_mode,
+        _stdin_path,
+        _stdout_mode,
+        _stdout_path,
+        _stderr_mode,
+        _stderr_path,
+        _env,
+        _cd,
+        _options
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
 
-  - `:stderr` - Closes the read end of stderr. Same behavior as stdout.
+  @doc false
+  def spawn_from_nif(_template, _args), do: :erlang.nif_error(:nif_not_loaded)
 
-  ## Warning
+  @doc false
+  def validate_spawn_nif(
+        _cmd,
+        _args,
+        _stdin_mode,
+        _stdin_path,
+        _stdout_mode,
+        _stdout_path,
+        _stderr_mode,
+        _stderr_path,
+        _env,
+        _cd,
+        _options
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
 
-  Closing `:stdout` or `:stderr` is a forceful operation. The child process
-  will receive SIGPIPE (default: terminate) when it tries to write. Only use
-  this when you intentionally want to signal the child to stop writing.
+  @doc false
+  def communicate_nif(_resource, _input, _timeout, _max_output, _kill_signal, _kill_timeout),
+    do: :erlang.nif_error(:nif_not_loaded)
 
-  ## Returns
+  @doc false
+  def wait_full_nif(_resource, _max_output), do: :erlang.nif_error(:nif_not_loaded)
 
-  - `:ok` - pipe closed successfully
-  - `{:error, :not_piped}` - stream was not configured as `:pipe`
+  @doc false
+  def destroy_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
-  ## Examples
+  @doc false
+  def shutdown_nif(_resource, _options), do: :erlang.nif_error(:nif_not_loaded)
 
-      iex> {:ok, p} = Px.spawn("cat", [], stdin: :pipe, stdout: :pipe)
-      iex> Px.write(p, "data")
-      :ok
-      iex> Px.close(p, :stdin)
-      :ok
-  """
-  def close(%__MODULE__{stdin: :pipe, resource: resource}, :stdin) do
-    close_stdin_nif(resource)
-  end
+  @doc false
+  def kill_tree_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
-  def close(%__MODULE__{stdout: :pipe, resource: resource}, :stdout) do
-    close_stdout_nif(resource)
-  end
+  @doc false
+  def notify_resize_nif(_resource, _rows, _cols), do: :erlang.nif_error(:nif_not_loaded)
 
-  def close(%__MODULE__{stderr: :pipe, resource: resource}, :stderr) do
-    close_stderr_nif(resource)
-  end
+  @doc false
+  def send_control_char_nif(_resource, _control), do: :erlang.nif_error(:nif_not_loaded)
 
-  def close(%__MODULE__{}, _stream), do: {:error, :not_piped}
+  @doc false
+  def set_term_mode_nif(_resource, _mode), do: :erlang.nif_error(:nif_not_loaded)
 
-  @doc """
-  Close a pipe to/from the child process, raising on failure.
+  @doc false
+  def stdin_from_file_nif(_resource, _path), do: :erlang.nif_error(:nif_not_loaded)
 
-  Same as `close/2` but raises on error instead of returning `{:error, reason}`.
+  @doc false
+  def request_nif(_resource, _input, _timeout, _line, _max_bytes),
+    do: :erlang.nif_error(:nif_not_loaded)
 
-  ## Examples
+  @doc false
+  def set_max_children_nif(_max), do: :erlang.nif_error(:nif_not_loaded)
 
-      iex> p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
-      iex> Px.write(p, "data")
-      :ok
-      iex> Px.close!(p, :stdin)
-      :ok
-  """
-  def close!(%__MODULE__{} = process, stream) do
-    case close(process, stream) do
-      :ok -> :ok
-      {:error, reason} -> raise "Failed to close #{stream}: #{inspect(reason)}"
-    end
-  end
+  @doc false
+  def live_children_nif, do: :erlang.nif_error(:nif_not_loaded)
 
-  @doc """
-  Read from the process stdout or stderr.
+  @doc false
+  def live_child_pids_nif, do: :erlang.nif_error(:nif_not_loaded)
 
-  Requires the process to be spawned with `stdout: :pipe` or `stderr: :pipe`.
+  # Only built with the `fault_injection` cargo feature, which the test
+  # environment enables in config/config.exs.
+  @doc false
+  def inject_fault_nif(_site, _nth, _errno), do: :erlang.nif_error(:nif_not_loaded)
 
-  ## Returns
+  @doc false
+  def clear_faults_nif, do: :erlang.nif_error(:nif_not_loaded)
 
-  - `{:ok, binary}` - data was read successfully
-  - `:eof` - the stream has been closed
-  - `:would_block` - no data available right now (non-blocking)
-  - `{:error, :not_piped}` - stream was not configured as `:pipe`
-  - `{:error, reason}` - an error occurred
+  @doc false
+  def decode_exit_status_nif(_raw_status), do: :erlang.nif_error(:nif_not_loaded)
 
-  ## Examples
+  @doc false
+  def read_stdout_chunks_nif(_resource, _max_chunks, _max_total_bytes),
+    do: :erlang.nif_error(:nif_not_loaded)
 
-      iex> p = Px.spawn!("echo", ["hello"], stdout: :pipe)
-      iex> Process.sleep(50)
-      iex> Px.read(p, :stdout)
-      {:ok, "hello\\n"}
+  @doc false
+  def read_balanced_nif(_resource, _max_bytes, _max_reads),
+    do: :erlang.nif_error(:nif_not_loaded)
 
-      iex> p = Px.spawn!("sh", ["-c", "echo error >&2"], stderr: :pipe)
-      iex> Process.sleep(50)
-      iex> Px.read(p, :stderr)
-      {:ok, "error\\n"}
+  @doc false
+  def read_prioritized_nif(_resource, _primary, _max_bytes, _primary_max_bytes, _max_reads),
+    do: :erlang.nif_error(:nif_not_loaded)
 
-      iex> {:ok, p} = Px.spawn("echo", ["hello"])  # no pipe configured
-      iex> Px.read(p, :stdout)
-      {:error, :not_piped}
-  """
-  def read(%__MODULE__{stdout: :pipe, resource: resource}, :stdout) do
-    read_stdout_nif(resource)
-  end
+  @doc false
+  def runtime_ms_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
-  def read(%__MODULE__{stderr: :pipe, resource: resource}, :stderr) do
-    read_stderr_nif(resource)
-  end
+  @doc false
+  def idle_ms_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
-  def read(%__MODULE__{}, _stream), do: {:error, :not_piped}
+  @doc false
+  def io_counters_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc false
-  def spawn_nif(
-        _cmd,
-        _args,
-        _stdin_mode,
-        _stdin_path,
-        _stdout_mode,
-        _stdout_path,
-        _stderr_mode,
-        _stderr_path,
-        _env,
-        _cd
-      ),
-      do: :erlang.nif_error(:nif_not_loaded)
+  def stdio_modes_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def reopen_output_nif(_resource, _stream, _path), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_output_file_nif(_resource, _stream, _offset, _length),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def termination_signal_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def spawn_method_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def fd_count_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def controlling_tty_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def sched_info_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_chunk_timeout_nif(_resource, _timeout, _max_bytes),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_frame_nif(_resource, _length_bytes, _endianness, _timeout),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def subscribe_nif(_resource, _pid, _window), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def ack_nif(_resource, _count), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def unsubscribe_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def write_line_nif(_resource, _data), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def tail_output_nif(_resource, _stream), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def interleaved_log_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def pipe_stats_nif(_resource, _stream), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def wait_stdout_eof_nif(_resource, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def monotonic_ns_nif, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def splice_nif(_src, _dst, _max_bytes), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def reassert_nonblocking_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def signal_nif(_resource, _signal, _verify_identity), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def signal_if_nif(_resource, _expected, _signal), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def sigqueue_nif(_resource, _signal, _value), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc false
-  def signal_nif(_resource, _signal), do: :erlang.nif_error(:nif_not_loaded)
+  def signal_group_nif(_pgid, _signal), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc false
   def wait_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc false
+  def try_wait_usage_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def exit_info_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def signal_info_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def is_pid_alive_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def cancel_wait_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def state_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def cancel_timeout_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def extend_timeout_nif(_resource, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def set_max_lifetime_nif(_resource, _lifetime_ms, _grace_ms),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   @doc false
   def alive_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc false
   def write_stdin_nif(_resource, _data), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc false
+  def write_stdin_iodata_nif(_resource, _data), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def write_stdin_close_nif(_resource, _data, _timeout), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def race_nif(_resources, _timeout), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def write_stdin_continue_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def pending_write_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def flush_nif(_resource, _timeout), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def take_fd_nif(_resource, _stream), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def dup_fd_nif(_resource, _stream), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def dup_fd_read_nif(_dup, _max_bytes), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def dup_fd_into_nif(_dup), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def open_exec_nif(_path), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def close_fd_nif(_fd), do: :erlang.nif_error(:nif_not_loaded)
+
   @doc false
   def close_stdin_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
@@ -575,10 +3643,90 @@ defmodule Px do
   def close_stderr_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc false
-  def read_stdout_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+  def read_stdout_nif(_resource, _eof_reason), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_stderr_nif(_resource, _eof_reason), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_stdout_text_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_stderr_text_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc false
+  def read_stdout_buffered_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc false
-  def read_stderr_nif(_resource), do: :erlang.nif_error(:nif_not_loaded)
+  def read_stdout_vectored_nif(_resource, _sizes), do: :erlang.nif_error(:nif_not_loaded)
+
+  defp do_spawn(cmd, args, opts, spawn_fun) do
+    ensure_sigchild()
+
+    opts
+    |> encode_spawn_args()
+    |> spawn_fun.()
+    |> spawned(cmd, args, opts)
+  end
+
+  # The NIF reports how each stream was actually wired. Streams this module
+  # reads or writes take that mode; the rest keep their config, which
+  # carries details such as a tail size or log path.
+  defp spawned({resource, pid, stdio}, cmd, args, opts)
+       when is_reference(resource) and is_integer(pid) and is_map(stdio) do
+    {stdin, stdout, stderr} = stdio_configs(opts)
+
+    {:ok,
+     struct(__MODULE__,
+       cmd: cmd,
+       args: args,
+       pid: pid,
+       resource: resource,
+       status: :running,
+       stdin: spawned_stdio(stdio.stdin, stdin),
+       stdout: spawned_stdio(stdio.stdout, stdout),
+       stderr: spawned_stdio(stdio.stderr, stderr)
+     )}
+  end
+
+  defp spawned(error, _cmd, _args, _opts), do: error
+
+  defp spawned_stdio(mode, _config) when mode in @piped, do: mode
+  defp spawned_stdio(_mode, config), do: config
+
+  # With :inherit_or_pty the NIF decides between these and a pty.
+  defp stdio_configs(opts) do
+    cond do
+      Keyword.get(opts, :pty, false) ->
+        {:pty, :pty, :pty}
+
+      Keyword.get(opts, :inherit_or_pty, false) ->
+        {:inherit, :inherit, :inherit}
+
+      true ->
+        {Keyword.get(opts, :stdin), Keyword.get(opts, :stdout), Keyword.get(opts, :stderr)}
+    end
+  end
+
+  # Everything the spawn NIFs take after the command and its arguments.
+  defp encode_spawn_args(opts) do
+    {stdin, stdout, stderr} = stdio_configs(opts)
+    {stdin_mode, stdin_path} = encode_stdio(input_stdio(stdin))
+    {stdout_mode, stdout_path} = encode_stdio(output_stdio(stdout))
+    {stderr_mode, stderr_path} = encode_stdio(output_stdio(stderr))
+
+    [
+      stdin_mode,
+      stdin_path,
+      stdout_mode,
+      stdout_path,
+      stderr_mode,
+      stderr_path,
+      encode_env(Keyword.get(opts, :env, %{})),
+      Keyword.get(opts, :cd) || "",
+      encode_spawn_options(opts)
+    ]
+  end
 
   defp ensure_sigchild() do
     with nil <- :persistent_term.get({__MODULE__, :sigchld}, nil) do
@@ -593,17 +3741,140 @@ defmodule Px do
 
   defp encode_stdio(nil), do: {"null", ""}
   defp encode_stdio(:pipe), do: {"pipe", ""}
+  defp encode_stdio(:socket), do: {"socket", ""}
   defp encode_stdio(:inherit), do: {"inherit", ""}
+  defp encode_stdio(:pty), do: {"null", ""}
   defp encode_stdio({:file, path}) when is_binary(path), do: {"file", path}
+  defp encode_stdio(:tmpfile), do: {"tmpfile", ""}
+
+  defp encode_spawn_options(opts) do
+    cleanup_signal = Keyword.get(opts, :cleanup_signal)
+    {stdin, stdout, stderr} = stdio_configs(opts)
+
+    %{
+      pdeathsig: Keyword.get(opts, :pdeathsig, true),
+      cleanup_signal: cleanup_signal && signal_int(cleanup_signal),
+      lines: Keyword.get(opts, :lines, false),
+      pty: Keyword.get(opts, :pty, false),
+      inherit_or_pty: Keyword.get(opts, :inherit_or_pty, false),
+      exec_dirfd: Keyword.get(opts, :exec_dirfd),
+      exec_fd: Keyword.get(opts, :exec_fd),
+      clear_env: Keyword.get(opts, :clear_env, false),
+      inherit_env: Enum.map(Keyword.get(opts, :inherit_env, []), &to_string/1),
+      env_file: Keyword.get(opts, :env_file),
+      env_defaults: encode_env_defaults(Keyword.get(opts, :env, [])),
+      stdout_file: file_options(stdout),
+      stderr_file: file_options(stderr),
+      start_stopped: Keyword.get(opts, :start_stopped, false),
+      stdout_tail: tail_size(stdout),
+      stderr_tail: tail_size(stderr),
+      interleaved_log: Keyword.get(opts, :interleaved_log),
+      stdout_log: log_path(stdout),
+      stderr_log: log_path(stderr),
+      nonblocking: Keyword.get(opts, :nonblocking, true),
+      tolerate_nonblock_errors: Keyword.get(opts, :tolerate_nonblock_errors, false),
+      stdout_fanout: stdout == :fanout,
+      stdout_active: stdout == :active,
+      stderr_active: stderr == :active,
+      owner: owner(stdout, stderr, opts),
+      ready: Keyword.get(opts, :ready),
+      ready_timeout_ms: spawn_timeout(Keyword.get(opts, :ready_timeout, :infinity)),
+      oom_score_adj: Keyword.get(opts, :oom_score_adj),
+      notify_exit: Keyword.get(opts, :notify_exit),
+      file_actions: Keyword.get(opts, :file_actions, []),
+      search_path: search_path(Keyword.get(opts, :search_path)),
+      namespaces: Keyword.get(opts, :namespaces, []),
+      pgid: Keyword.get(opts, :pgid),
+      kill_group_on_drop: Keyword.get(opts, :kill_group_on_drop, false),
+      cgroup: Keyword.get(opts, :cgroup),
+      timeout_ms: spawn_timeout(Keyword.get(opts, :timeout, :infinity)),
+      drain_buf_bytes: drain_buf_bytes(Keyword.get(opts, :drain_buf_bytes)),
+      stdin_repeat: stdin_repeat(stdin)
+    }
+  end
+
+  defp input_stdio(:zero), do: {:file, "/dev/zero"}
+
+  defp input_stdio({:repeat, pattern}) when is_binary(pattern) and byte_size(pattern) > 0,
+    do: :pipe
+
+  defp input_stdio({:repeat, pattern, bytes})
+       when is_binary(pattern) and byte_size(pattern) > 0 and is_integer(bytes) and bytes >= 0,
+       do: :pipe
+
+  defp input_stdio(stdio), do: stdio
+
+  defp stdin_repeat({:repeat, pattern}), do: %{pattern: :binary.bin_to_list(pattern), limit: nil}
+
+  defp stdin_repeat({:repeat, pattern, bytes}),
+    do: %{pattern: :binary.bin_to_list(pattern), limit: bytes}
+
+  defp stdin_repeat(_stdio), do: nil
+
+  defp output_stdio({:file, path, opts}) when is_list(opts), do: {:file, path}
+  defp output_stdio({:tail, bytes}) when is_integer(bytes) and bytes > 0, do: :pipe
+  defp output_stdio(:fanout), do: :pipe
+  defp output_stdio(:active), do: :pipe
+  defp output_stdio({:log, path}) when is_binary(path), do: :pipe
+  defp output_stdio(stdio), do: stdio
+
+  defp file_options({:file, _path, opts}) when is_list(opts) do
+    %{flags: Keyword.get(opts, :flags, []), mode: Keyword.get(opts, :mode)}
+  end
+
+  defp file_options(_stdio), do: nil
+
+  defp spawn_timeout(:infinity), do: nil
+  defp spawn_timeout(ms) when is_integer(ms) and ms > 0, do: ms
+
+  defp owner(stdout, stderr, opts) do
+    if :active in [stdout, stderr] or Keyword.has_key?(opts, :ready),
+      do: Keyword.get(opts, :owner, self())
+  end
+
+  defp drain_buf_bytes(nil), do: nil
+  defp drain_buf_bytes(bytes) when is_integer(bytes) and bytes > 0, do: bytes
+
+  defp search_path(nil), do: nil
+  defp search_path(dirs) when is_list(dirs), do: Enum.map(dirs, &to_string/1)
+
+  defp tail_size({:tail, bytes}), do: bytes
+  defp tail_size(_stdio), do: nil
 
-  defp encode_env(env) when is_map(env) do
-    Enum.map(env, fn {k, v} -> {to_string(k), to_string(v)} end)
+  defp log_path({:log, path}), do: path
+  defp log_path(_stdio), do: nil
+
+  # `{key, value}` and `{key, value, :force}` entries are passed as `env`,
+  # `{key, value, :default}` ones as the `env_defaults` option.
+  defp encode_env(env) do
+    for entry <- env, env_precedence(entry) == :force, do: env_pair(entry)
   end
 
-  defp encode_env(env) when is_list(env) do
-    Enum.map(env, fn {k, v} -> {to_string(k), to_string(v)} end)
+  defp encode_env_defaults(env) do
+    for entry <- env, env_precedence(entry) == :default, do: env_pair(entry)
   end
 
+  defp env_precedence({_key, _value}), do: :force
+
+  defp env_precedence({_key, _value, precedence}) when precedence in [:force, :default],
+    do: precedence
+
+  defp env_pair({key, value}), do: {to_string(key), to_string(value)}
+  defp env_pair({key, value, _precedence}), do: {to_string(key), to_string(value)}
+
+  defp race_spec({cmd, args}), do: {cmd, args, []}
+  defp race_spec({cmd, args, opts}) when is_list(opts), do: {cmd, args, opts}
+
+  defp timeout_arg({:deadline, ns} = deadline) when is_integer(ns), do: deadline
+  defp timeout_arg(timeout), do: limit_arg(timeout)
+
+  defp limit_arg(:infinity), do: 0
+  defp limit_arg(value) when is_integer(value) and value > 0, do: value
+
+  defp exit_code({:exited, code}), do: code
+  defp exit_code({:signaled, signal, _core_dumped}), do: 128 + signal
+  defp exit_code(:reaped_externally), do: :reaped_externally
+
   defp signal_int(value) when is_integer(value), do: value
   defp signal_int(:sighup), do: 1
   defp signal_int(:sigint), do: 2
@@ -0,0 +1,14 @@
+defmodule Px.Template do
+  @moduledoc """
+  A spawn configuration prepared by `Px.template/2`, for spawning children
+  with `Px.spawn_from/2`.
+  """
+
+  defstruct [:cmd, :opts, :resource]
+
+  @type t :: %__MODULE__{
+          cmd: String.t(),
+          opts: keyword(),
+          resource: reference()
+        }
+end
@@ -0,0 +1,59 @@
+//! Output streamed to an owner process, for `stdout: :active` and
+//! `stderr: :active`.
+//!
+//! One thread reads every active stream and hands each chunk on as soon as
+//! it arrives, so the child never blocks on a full pipe and nothing piles up
+//! in the pipe. Once every active stream has reached EOF the caller reaps
+//! the child from the same thread, so the exit is only reported after the
+//! last of the output. Chunks are read without backpressure: an owner that
+//! falls behind grows its mailbox, not the pipe.
+
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::AsFd;
+
+/// Reads `streams` (stdout and stderr, either absent) until both reach EOF,
+/// up to `buf_size` bytes at a time, calling `deliver` with the index of
+/// the stream and each chunk. Read errors count as EOF.
+pub fn pump(streams: [Option<File>; 2], buf_size: usize, mut deliver: impl FnMut(usize, &[u8])) {
+    let mut streams = streams;
+    let mut buf = vec![0u8; buf_size];
+
+    loop {
+        let open: Vec<usize> = (0..2).filter(|&i| streams[i].is_some()).collect();
+        if open.is_empty() {
+            return;
+        }
+
+        let ready: Vec<usize> = {
+            let mut fds: Vec<PollFd> = open
+                .iter()
+                .filter_map(|&i| streams[i].as_ref())
+                .map(|file| PollFd::new(file.as_fd(), PollFlags::POLLIN))
+                .collect();
+            match poll(&mut fds, PollTimeout::NONE) {
+                Ok(_) => {}
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(_) => return,
+            }
+            open.iter()
+                .zip(&fds)
+                .filter(|(_, fd)| fd.revents().is_some_and(|r| !r.is_empty()))
+                .map(|(&i, _)| i)
+                .collect()
+        };
+
+        for i in ready {
+            let Some(file) = streams[i].as_mut() else {
+                continue;
+            };
+            match file.read(&mut buf) {
+                Ok(0) => streams[i] = None,
+                Ok(n) => deliver(i, &buf[..n]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => streams[i] = None,
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Placing a child in a cgroup v2 and killing everything in it, for
+//! `cgroup: path` and `kill_tree_nif`.
+//!
+//! The child joins the cgroup between fork and exec, by writing `0` to its
+//! `cgroup.procs`, so it never runs a single instruction of the program
+//! outside it and every process it forks starts inside too. The cgroup
+//! itself is created, delegated and removed by the caller.
+//!
+//! Killing a tree by walking pids or signalling a process group races with
+//! processes that fork faster than they are signalled, or leave the group.
+//! A frozen cgroup can't fork at all: the tree is frozen, every process in
+//! it and its descendant cgroups is sent SIGKILL, which even frozen
+//! processes act on, and the cgroup is thawed again so it can be reused.
+
+use crate::pre_exec::{Reporter, Step};
+use rustler::{Error, NifResult};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long `kill` waits for the kernel to report the cgroup frozen before
+/// signalling anyway.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What the child does before exec: the `cgroup.procs` path to write to,
+/// built in the parent.
+pub struct Join {
+    procs: CString,
+}
+
+impl Join {
+    /// Checks that `dir` is a cgroup v2 directory, with the `cgroup.procs`
+    /// and `cgroup.freeze` files `kill` relies on; the root cgroup has no
+    /// `cgroup.freeze` and is rejected.
+    pub fn new(dir: &Path) -> NifResult<Join> {
+        if !dir.join("cgroup.procs").exists() || !dir.join("cgroup.freeze").exists() {
+            return Err(Error::Term(Box::new(format!(
+                "not a non-root cgroup v2 directory: {}",
+                dir.display()
+            ))));
+        }
+        let procs = CString::new(dir.join("cgroup.procs").as_os_str().as_bytes())
+            .map_err(|_| Error::Term(Box::new("invalid cgroup path")))?;
+        Ok(Join { procs })
+    }
+
+    /// Runs in the child. Async-signal-safe: only `open`, `write` and
+    /// `close`.
+    pub fn enter(&self, reporter: Reporter) -> std::io::Result<()> {
+        unsafe {
+            let fd = libc::open(self.procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd == -1 {
+                return Err(reporter.fail(Step::Cgroup));
+            }
+            let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+            if written != 1 {
+                let error = reporter.fail(Step::Cgroup);
+                libc::close(fd);
+                return Err(error);
+            }
+            libc::close(fd);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the calling process is in `dir` or one of its descendants, in
+/// which case `kill` would take it down too.
+pub fn contains_self(dir: &Path) -> std::io::Result<bool> {
+    let own = std::process::id() as i32;
+    Ok(procs(dir)?.contains(&own))
+}
+
+/// Freezes `dir`, SIGKILLs every process in it and its descendant cgroups,
+/// and thaws it.
+pub fn kill(dir: &Path) -> std::io::Result<()> {
+    std::fs::write(dir.join("cgroup.freeze"), "1")?;
+    let result = wait_frozen(dir).and_then(|()| {
+        for pid in procs(dir)? {
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+        }
+        Ok(())
+    });
+    std::fs::write(dir.join("cgroup.freeze"), "0")?;
+    result
+}
+
+/// Waits until `cgroup.events` reports the cgroup frozen, or
+/// `FREEZE_TIMEOUT` passes: a process stuck in the kernel can delay the
+/// freeze, and SIGKILL reaches it regardless.
+fn wait_frozen(dir: &Path) -> std::io::Result<()> {
+    let deadline = Instant::now() + FREEZE_TIMEOUT;
+    let events = dir.join("cgroup.events");
+    loop {
+        let contents = std::fs::read_to_string(&events)?;
+        if contents.lines().any(|line| line == "frozen 1") || Instant::now() >= deadline {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// The pids in `dir` and every cgroup below it.
+fn procs(dir: &Path) -> std::io::Result<Vec<i32>> {
+    let mut pids = Vec::new();
+    let mut dirs: Vec<PathBuf> = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let contents = std::fs::read_to_string(dir.join("cgroup.procs"))?;
+        pids.extend(
+            contents
+                .lines()
+                .filter_map(|line| line.trim().parse::<i32>().ok()),
+        );
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    Ok(pids)
+}
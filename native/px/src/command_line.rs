@@ -0,0 +1,60 @@
+//! Splitting of a command line into words for `spawn_parse`, the way a
+//! POSIX shell would, without running one.
+//!
+//! Words are separated by unquoted blanks (space, tab, newline). Single
+//! quotes keep everything up to the next `'` literally. Double quotes do
+//! too, except that `\` escapes `$`, `` ` ``, `"`, `\` and a newline. An
+//! unquoted `\` keeps the next character literally, and `\` followed by a
+//! newline joins the lines. Nothing else is special: no expansion,
+//! globbing, redirection or comments.
+
+/// Splits `line` into words, or returns the byte offset of the quote left
+/// open or the `\` with nothing after it.
+pub fn split(line: &str) -> Result<Vec<String>, usize> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.char_indices();
+
+    while let Some((at, c)) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c),
+                        None => return Err(at),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, '\n')) => {}
+                            Some((_, c @ ('$' | '`' | '"' | '\\'))) => word.push(c),
+                            Some((_, c)) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(at),
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => return Err(at),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some((_, '\n')) => {}
+                Some((_, c)) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(at),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
@@ -0,0 +1,79 @@
+//! Parsing of dotenv-style environment files for the `env_file` option.
+//!
+//! Each non-blank line not starting with `#` is `KEY=VALUE`, optionally
+//! prefixed with `export`. Values may be unquoted (trailing ` #` comments are
+//! stripped), single-quoted (taken literally) or double-quoted (with `\n`,
+//! `\t`, `\"` and `\\` escapes). Quoted values cannot span lines.
+
+/// Parses `contents`, returning the variables in file order, or the 1-based
+/// number of the first line that could not be parsed.
+pub fn parse(contents: &str) -> Result<Vec<(String, String)>, usize> {
+    let mut vars = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        vars.push(parse_line(line).ok_or(index + 1)?);
+    }
+    Ok(vars)
+}
+
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    if !is_valid_key(key) {
+        return None;
+    }
+
+    let value = value.trim_start();
+    let (value, rest) = match value.chars().next() {
+        Some('"') => double_quoted(&value[1..])?,
+        Some('\'') => {
+            let end = value[1..].find('\'')?;
+            (value[1..end + 1].to_string(), &value[end + 2..])
+        }
+        _ => (unquoted(value).to_string(), ""),
+    };
+
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return None;
+    }
+    Some((key.to_string(), value))
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// An unquoted value ends at a `#` preceded by whitespace.
+fn unquoted(value: &str) -> &str {
+    let end = value
+        .char_indices()
+        .find(|&(i, c)| c == '#' && value[..i].ends_with(char::is_whitespace))
+        .map_or(value.len(), |(i, _)| i);
+    value[..end].trim_end()
+}
+
+/// Decodes a double-quoted value (after the opening quote), returning it and
+/// whatever follows the closing quote.
+fn double_quoted(value: &str) -> Option<(String, &str)> {
+    let mut decoded = String::new();
+    let mut chars = value.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((decoded, &value[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => decoded.push('\n'),
+                't' => decoded.push('\t'),
+                other => decoded.push(other),
+            },
+            other => decoded.push(other),
+        }
+    }
+    None
+}
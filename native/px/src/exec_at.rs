@@ -0,0 +1,128 @@
+//! Executing a program relative to a directory fd with `execveat(2)`, or
+//! an already open executable with `fexecve(3)`.
+//!
+//! `Command` only execs by path, which leaves a window between resolving a
+//! directory and the exec in which the directory can be swapped. With an
+//! exec dirfd, the child instead execs from a `pre_exec` hook, relative to a
+//! directory fd the caller already holds; std's own exec is never reached
+//! unless that fails. An exec fd closes the window entirely: the file the
+//! caller opened, and possibly verified, is the one that runs.
+
+use crate::pre_exec::{Reporter, Step};
+use std::collections::BTreeMap;
+use std::ffi::{CString, NulError, OsString};
+use std::os::fd::RawFd;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStringExt;
+
+/// What to exec.
+enum Target {
+    /// A path relative to a directory fd, with `execveat`.
+    At(RawFd, CString),
+    /// An open executable, with `fexecve`.
+    Fd(RawFd),
+}
+
+/// Everything `execveat` or `fexecve` needs, built in the parent so the
+/// child only makes the syscall.
+pub struct ExecAt {
+    target: Target,
+    _strings: Vec<CString>,
+    argv: Vec<*const c_char>,
+    envp: Vec<*const c_char>,
+}
+
+// The pointers refer into the `CString`s owned by the same value and are
+// only read.
+unsafe impl Send for ExecAt {}
+unsafe impl Sync for ExecAt {}
+
+impl ExecAt {
+    /// Prepares to exec `name` relative to `dirfd` with `args` and the
+    /// child's complete `environment`.
+    pub fn new(
+        dirfd: RawFd,
+        name: &str,
+        args: &[String],
+        environment: BTreeMap<OsString, OsString>,
+    ) -> Result<ExecAt, NulError> {
+        let target = Target::At(dirfd, CString::new(name)?);
+        ExecAt::build(target, name, args, environment)
+    }
+
+    /// Prepares to exec the executable open as `fd`, with `name` as
+    /// `argv[0]`, `args` and the child's complete `environment`.
+    pub fn fd(
+        fd: RawFd,
+        name: &str,
+        args: &[String],
+        environment: BTreeMap<OsString, OsString>,
+    ) -> Result<ExecAt, NulError> {
+        ExecAt::build(Target::Fd(fd), name, args, environment)
+    }
+
+    fn build(
+        target: Target,
+        name: &str,
+        args: &[String],
+        environment: BTreeMap<OsString, OsString>,
+    ) -> Result<ExecAt, NulError> {
+        let argv = std::iter::once(name.as_bytes().to_vec())
+            .chain(args.iter().map(|arg| arg.as_bytes().to_vec()))
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let envp = environment
+            .into_iter()
+            .map(|(key, value)| {
+                let mut entry = key.into_vec();
+                entry.push(b'=');
+                entry.extend(value.into_vec());
+                CString::new(entry)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let argv_ptrs = pointers(&argv);
+        let envp_ptrs = pointers(&envp);
+        Ok(ExecAt {
+            target,
+            _strings: argv.into_iter().chain(envp).collect(),
+            argv: argv_ptrs,
+            envp: envp_ptrs,
+        })
+    }
+
+    /// Replaces the child with the program. Only returns if the exec
+    /// failed, with the error to propagate from the `pre_exec` hook.
+    pub fn exec(&self, reporter: Reporter) -> std::io::Error {
+        match &self.target {
+            Target::At(dirfd, path) => {
+                unsafe {
+                    libc::syscall(
+                        libc::SYS_execveat,
+                        *dirfd,
+                        path.as_ptr(),
+                        self.argv.as_ptr(),
+                        self.envp.as_ptr(),
+                        0,
+                    );
+                }
+                reporter.fail(Step::ExecAt)
+            }
+            Target::Fd(fd) => {
+                unsafe {
+                    libc::fexecve(*fd, self.argv.as_ptr(), self.envp.as_ptr());
+                }
+                reporter.fail(Step::Fexecve)
+            }
+        }
+    }
+}
+
+/// A null-terminated array of pointers into `strings`.
+fn pointers(strings: &[CString]) -> Vec<*const c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
@@ -0,0 +1,185 @@
+//! Fan-out of stdout to subscribed BEAM processes, for `stdout: :fanout`.
+//!
+//! One drain thread reads the pipe and sends each chunk to every subscriber
+//! as `{:px_stdout, os_pid, data}`, then `{:px_stdout, os_pid, :eof}` once
+//! the stream closes. Each subscriber has a window of chunks it may be sent
+//! without acknowledging them; reading pauses while any subscriber has used
+//! up its window, or while there are no subscribers, so the child blocks on
+//! the pipe instead of output piling up in a mailbox. Subscribers are
+//! monitored and dropped when they exit.
+
+use rustler::types::binary::NewBinary;
+use rustler::{Binary, Encoder, Env, LocalPid, Monitor, OwnedEnv, Resource, ResourceArc};
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+
+mod atoms {
+    rustler::atoms! {
+        px_stdout,
+        eof,
+    }
+}
+
+pub struct Hub {
+    /// OS pid of the child, included in every message.
+    pid: i32,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    subscribers: Vec<Subscriber>,
+    eof: bool,
+    /// The process resource is gone; the drain thread should stop.
+    closed: bool,
+}
+
+struct Subscriber {
+    pid: LocalPid,
+    monitor: Monitor,
+    /// Chunks that may still be sent before the next acknowledgement.
+    credit: usize,
+}
+
+#[rustler::resource_impl]
+impl Resource for Hub {
+    fn down<'a>(&'a self, _env: Env<'a>, pid: LocalPid, _monitor: Monitor) {
+        self.lock().subscribers.retain(|s| s.pid != pid);
+        self.changed.notify_all();
+    }
+}
+
+/// Owned by the process resource: stops the drain thread when dropped.
+pub struct Fanout {
+    hub: ResourceArc<Hub>,
+}
+
+impl Drop for Fanout {
+    fn drop(&mut self) {
+        self.hub.lock().closed = true;
+        self.hub.changed.notify_all();
+    }
+}
+
+impl Fanout {
+    /// Starts draining `file`, the parent's end of the stdout pipe of `pid`,
+    /// in chunks of up to `chunk` bytes.
+    pub fn start(file: File, pid: i32, chunk: usize) -> std::io::Result<Fanout> {
+        let hub = ResourceArc::new(Hub {
+            pid,
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        let drain = hub.clone();
+        thread::Builder::new()
+            .name("px-fanout".to_string())
+            .spawn(move || drain.drain(file, chunk))?;
+        Ok(Fanout { hub })
+    }
+
+    /// Subscribes `pid` with a window of `window` unacknowledged chunks. A
+    /// subscriber joining after EOF is sent the EOF message right away.
+    /// Returns false if `pid` is already subscribed.
+    pub fn subscribe(&self, env: Env, pid: LocalPid, window: usize) -> bool {
+        let mut state = self.hub.lock();
+        if state.subscribers.iter().any(|s| s.pid == pid) {
+            return false;
+        }
+        if state.eof {
+            let _ = env.send(&pid, message(env, self.hub.pid, &[]));
+            return true;
+        }
+        // Monitoring fails only if `pid` is already dead.
+        if let Some(monitor) = env.monitor(&self.hub, &pid) {
+            state.subscribers.push(Subscriber {
+                pid,
+                monitor,
+                credit: window,
+            });
+            self.hub.changed.notify_all();
+        }
+        true
+    }
+
+    /// Returns `count` chunks of credit to `pid`. Returns false if it is not
+    /// subscribed.
+    pub fn ack(&self, pid: LocalPid, count: usize) -> bool {
+        let mut state = self.hub.lock();
+        let Some(subscriber) = state.subscribers.iter_mut().find(|s| s.pid == pid) else {
+            return false;
+        };
+        subscriber.credit = subscriber.credit.saturating_add(count);
+        self.hub.changed.notify_all();
+        true
+    }
+
+    /// Removes `pid`. Returns false if it was not subscribed.
+    pub fn unsubscribe(&self, env: Env, pid: LocalPid) -> bool {
+        let mut state = self.hub.lock();
+        let Some(index) = state.subscribers.iter().position(|s| s.pid == pid) else {
+            return false;
+        };
+        let subscriber = state.subscribers.remove(index);
+        env.demonitor(&self.hub, &subscriber.monitor);
+        self.hub.changed.notify_all();
+        true
+    }
+}
+
+impl Hub {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn drain(&self, mut file: File, chunk: usize) {
+        let mut buf = vec![0u8; chunk];
+        let mut msg_env = OwnedEnv::new();
+        loop {
+            {
+                let mut state = self.lock();
+                while !state.closed
+                    && (state.subscribers.is_empty()
+                        || state.subscribers.iter().any(|s| s.credit == 0))
+                {
+                    state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                if state.closed {
+                    return;
+                }
+            }
+
+            let n = match file.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => 0,
+            };
+
+            let mut state = self.lock();
+            if n == 0 {
+                state.eof = true;
+            }
+            state.subscribers.retain_mut(|subscriber| {
+                subscriber.credit = subscriber.credit.saturating_sub(1);
+                msg_env
+                    .send_and_clear(&subscriber.pid, |env| message(env, self.pid, &buf[..n]))
+                    .is_ok()
+            });
+            if n == 0 {
+                return;
+            }
+        }
+    }
+}
+
+/// `{:px_stdout, os_pid, data}`, or with no data, `{:px_stdout, os_pid, :eof}`.
+fn message<'a>(env: Env<'a>, pid: i32, data: &[u8]) -> rustler::Term<'a> {
+    if data.is_empty() {
+        return (atoms::px_stdout(), pid, atoms::eof()).encode(env);
+    }
+    let mut binary = NewBinary::new(env, data.len());
+    binary.as_mut_slice().copy_from_slice(data);
+    (atoms::px_stdout(), pid, Binary::from(binary)).encode(env)
+}
@@ -0,0 +1,81 @@
+//! Deterministic fault injection for the error paths of reads, writes,
+//! waits, binary allocation and locking. Only compiled in with the
+//! `fault_injection` cargo feature, which the Elixir test suite turns on.
+//!
+//! Each instrumented call site asks `inject` first. A test arms a fault
+//! with `arm(site, nth, errno)`: the `nth` call at `site` from then on
+//! fails with `errno` instead of doing the real work, once. Allocation and
+//! lock faults ignore `errno`; they fail the way a failed allocation or a
+//! poisoned lock does. Without the feature `inject` is an empty inline
+//! function, so release builds pay nothing for it.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Site {
+    /// `read` on a piped output stream.
+    Read,
+    /// `write`, `writev` or `splice` into piped stdin.
+    Write,
+    /// Reaping the child with `waitpid`.
+    Wait,
+    /// Allocating a result binary.
+    Alloc,
+    /// Taking one of the resource's mutexes.
+    Lock,
+}
+
+#[cfg(feature = "fault_injection")]
+mod armed {
+    use super::Site;
+    use std::sync::Mutex;
+
+    struct Fault {
+        site: Site,
+        /// Calls at `site` left until this one fails, counting that one.
+        remaining: u64,
+        errno: i32,
+    }
+
+    static FAULTS: Mutex<Vec<Fault>> = Mutex::new(Vec::new());
+
+    pub fn arm(site: Site, nth: u64, errno: i32) {
+        let mut faults = FAULTS.lock().unwrap_or_else(|e| e.into_inner());
+        faults.push(Fault {
+            site,
+            remaining: nth,
+            errno,
+        });
+    }
+
+    pub fn clear() {
+        FAULTS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    pub fn inject(site: Site) -> std::io::Result<()> {
+        let mut faults = FAULTS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut errno = None;
+        faults.retain_mut(|fault| {
+            if fault.site != site {
+                return true;
+            }
+            fault.remaining -= 1;
+            if fault.remaining > 0 {
+                return true;
+            }
+            errno.get_or_insert(fault.errno);
+            false
+        });
+        match errno {
+            Some(errno) => Err(std::io::Error::from_raw_os_error(errno)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "fault_injection")]
+pub use armed::{arm, clear, inject};
+
+#[cfg(not(feature = "fault_injection"))]
+#[inline(always)]
+pub fn inject(_site: Site) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! Explicit descriptor operations for the child, for `file_actions:`.
+//!
+//! Each action mirrors a `posix_spawn_file_actions_*` call and they run in
+//! order in a `pre_exec` hook, after the stdio configs have been applied, so
+//! later actions see the descriptors earlier ones set up. Everything that
+//! allocates (paths, flags) is decoded and checked in the parent; the hook
+//! itself only calls `open`, `dup2`, `fcntl` and `close`.
+
+use crate::pre_exec::{Reporter, Step};
+use rustler::{Atom, Encoder, Env, Error, NifResult, Term};
+use std::ffi::CString;
+
+mod atoms {
+    rustler::atoms! {
+        dup2,
+        open,
+        close,
+        read,
+        write,
+        append,
+        create,
+        truncate,
+        exclusive,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum FileAction {
+    /// `{:dup2, src, dst}`: make `dst` a copy of `src`. With `src == dst`
+    /// the descriptor is kept open across the exec instead.
+    Dup2 { src: i32, dst: i32 },
+    /// `{:open, fd, path, flags, mode}`: open `path` as `fd`.
+    Open {
+        fd: i32,
+        path: CString,
+        /// The flags as given, kept for encoding.
+        flags: Vec<Atom>,
+        /// `flags` translated for `open(2)`.
+        oflag: i32,
+        mode: u32,
+    },
+    /// `{:close, fd}`.
+    Close { fd: i32 },
+}
+
+fn invalid(message: String) -> Error {
+    Error::Term(Box::new(format!("invalid file action: {}", message)))
+}
+
+fn check_fd(fd: i32) -> NifResult<i32> {
+    if fd < 0 {
+        return Err(invalid(format!("negative descriptor {}", fd)));
+    }
+    Ok(fd)
+}
+
+/// Translates `:read`, `:write`, `:append`, `:create`, `:truncate` and
+/// `:exclusive` into `open(2)` flags. `:append` implies `:write`.
+fn open_flags(flags: &[Atom]) -> NifResult<i32> {
+    let (mut read, mut write, mut extra) = (false, false, 0);
+    for &flag in flags {
+        if flag == atoms::read() {
+            read = true;
+        } else if flag == atoms::write() {
+            write = true;
+        } else if flag == atoms::append() {
+            write = true;
+            extra |= libc::O_APPEND;
+        } else if flag == atoms::create() {
+            extra |= libc::O_CREAT;
+        } else if flag == atoms::truncate() {
+            extra |= libc::O_TRUNC;
+        } else if flag == atoms::exclusive() {
+            extra |= libc::O_CREAT | libc::O_EXCL;
+        } else {
+            return Err(invalid(
+                "expected open flags read, write, append, create, truncate or exclusive"
+                    .to_string(),
+            ));
+        }
+    }
+    let access = match (read, write) {
+        (_, false) => libc::O_RDONLY,
+        (false, true) => libc::O_WRONLY,
+        (true, true) => libc::O_RDWR,
+    };
+    Ok(access | extra)
+}
+
+impl<'a> rustler::Decoder<'a> for FileAction {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if let Ok((tag, src, dst)) = term.decode::<(Atom, i32, i32)>() {
+            if tag == atoms::dup2() {
+                return Ok(FileAction::Dup2 {
+                    src: check_fd(src)?,
+                    dst: check_fd(dst)?,
+                });
+            }
+        }
+        if let Ok((tag, fd)) = term.decode::<(Atom, i32)>() {
+            if tag == atoms::close() {
+                return Ok(FileAction::Close { fd: check_fd(fd)? });
+            }
+        }
+        if let Ok((tag, fd, path, flags, mode)) =
+            term.decode::<(Atom, i32, String, Vec<Atom>, u32)>()
+        {
+            if tag == atoms::open() {
+                if mode > 0o7777 {
+                    return Err(invalid(format!("file mode {:o}", mode)));
+                }
+                let path = CString::new(path)
+                    .map_err(|_| invalid("path contains a NUL byte".to_string()))?;
+                return Ok(FileAction::Open {
+                    fd: check_fd(fd)?,
+                    path,
+                    oflag: open_flags(&flags)?,
+                    flags,
+                    mode,
+                });
+            }
+        }
+        Err(invalid(
+            "expected {:dup2, src, dst}, {:open, fd, path, flags, mode} or {:close, fd}"
+                .to_string(),
+        ))
+    }
+}
+
+impl Encoder for FileAction {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            FileAction::Dup2 { src, dst } => (atoms::dup2(), src, dst).encode(env),
+            FileAction::Open {
+                fd,
+                path,
+                flags,
+                mode,
+                ..
+            } => {
+                let path = path.to_string_lossy().into_owned();
+                (atoms::open(), fd, path, flags, mode).encode(env)
+            }
+            FileAction::Close { fd } => (atoms::close(), fd).encode(env),
+        }
+    }
+}
+
+/// Applies `actions` in order. Runs in the child between fork and exec, so
+/// it only makes async-signal-safe calls.
+pub fn apply(actions: &[FileAction], reporter: Reporter) -> std::io::Result<()> {
+    for action in actions {
+        let ok = match *action {
+            FileAction::Dup2 { src, dst } if src == dst => keep_open(src),
+            FileAction::Dup2 { src, dst } => unsafe { libc::dup2(src, dst) != -1 },
+            FileAction::Open {
+                fd,
+                ref path,
+                oflag,
+                mode,
+                ..
+            } => open_as(fd, path, oflag, mode),
+            FileAction::Close { fd } => unsafe { libc::close(fd) != -1 },
+        };
+        if !ok {
+            return Err(reporter.fail(Step::FileAction));
+        }
+    }
+    Ok(())
+}
+
+/// Clears close-on-exec on `fd`, as `dup2` would for a distinct target.
+fn keep_open(fd: i32) -> bool {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        flags != -1 && libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) != -1
+    }
+}
+
+fn open_as(fd: i32, path: &CString, oflag: i32, mode: u32) -> bool {
+    unsafe {
+        let opened = libc::open(path.as_ptr(), oflag, mode as libc::c_uint);
+        if opened == -1 {
+            return false;
+        }
+        if opened == fd {
+            return true;
+        }
+        // Leave errno from a failed dup2 for the reporter.
+        if libc::dup2(opened, fd) == -1 {
+            return false;
+        }
+        libc::close(opened);
+        true
+    }
+}
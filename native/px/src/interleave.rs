@@ -0,0 +1,74 @@
+//! A combined, timestamped record of stdout and stderr, for
+//! `interleaved_log: bytes` with `{:tail, bytes}` streams.
+//!
+//! Each tail thread stamps every chunk it reads with `CLOCK_MONOTONIC` and
+//! the stream it came from before adding it to the shared log, so the
+//! relative order of the two streams can be reconstructed after the fact.
+//! The order is only as fine as the reads: bytes the child wrote to one pipe
+//! before the other may be read, and so stamped, after them, and a chunk
+//! carries the time it was read, not written. The log holds at most
+//! `capacity` bytes of data, dropping its oldest chunks first.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// One chunk as read: its stream, when it was read and its bytes.
+#[derive(Clone)]
+pub struct Chunk {
+    pub stream: Stream,
+    pub monotonic_ns: i64,
+    pub data: Vec<u8>,
+}
+
+pub struct Interleaved {
+    capacity: usize,
+    log: Mutex<Log>,
+}
+
+#[derive(Default)]
+struct Log {
+    chunks: VecDeque<Chunk>,
+    bytes: usize,
+}
+
+impl Interleaved {
+    pub fn new(capacity: usize) -> Arc<Interleaved> {
+        Arc::new(Interleaved {
+            capacity,
+            log: Mutex::new(Log::default()),
+        })
+    }
+
+    /// Records `data` as just read from `stream`. A chunk larger than the
+    /// whole log keeps only its last `capacity` bytes.
+    pub fn push(&self, stream: Stream, data: &[u8]) {
+        let data = data[data.len().saturating_sub(self.capacity)..].to_vec();
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        // Stamped under the lock, so the log is in timestamp order.
+        let chunk = Chunk {
+            stream,
+            monotonic_ns: crate::monotonic_ns(),
+            data,
+        };
+        log.bytes += chunk.data.len();
+        log.chunks.push_back(chunk);
+        while log.bytes > self.capacity {
+            let Some(oldest) = log.chunks.pop_front() else {
+                break;
+            };
+            log.bytes -= oldest.data.len();
+        }
+    }
+
+    /// The chunks still held, oldest first.
+    pub fn chunks(&self) -> Vec<Chunk> {
+        let log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        log.chunks.iter().cloned().collect()
+    }
+}
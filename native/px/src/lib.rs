@@ -1,17 +1,56 @@
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::sys::signal::{kill, Signal};
-use nix::unistd::Pid;
-use rustler::types::binary::OwnedBinary;
-use rustler::{Binary, Encoder, Env, Error, NifResult, ResourceArc, Term};
-use std::fs::File;
-use std::io::{Read, Write};
+use nix::unistd::{access, AccessFlags, Pid};
+use rustler::types::binary::{NewBinary, OwnedBinary};
+use rustler::{Binary, Encoder, Env, Error, NifMap, NifResult, ResourceArc, Term};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::os::unix::io::AsRawFd;
-use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
-use std::sync::Mutex;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[cfg(target_os = "linux")]
 use std::os::unix::process::CommandExt;
 
+mod active;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod command_line;
+mod env_file;
+#[cfg(target_os = "linux")]
+mod exec_at;
+mod fanout;
+mod fault;
+mod file_actions;
+mod interleave;
+mod log;
+#[cfg(target_os = "linux")]
+mod namespaces;
+#[cfg(target_os = "macos")]
+mod parent_watch;
+mod pre_exec;
+mod pty;
+mod ready;
+mod registry;
+mod repeat;
+#[cfg(target_os = "linux")]
+mod start_stopped;
+mod stdin_file;
+mod tail;
+mod tmpfile;
+mod wait_cancel;
+mod watchdog;
+
 mod atoms {
     rustler::atoms! {
         ok,
@@ -20,11 +59,111 @@ mod atoms {
         error,
         partial,
         broken_pipe,
+        reader_gone,
         not_piped,
+        still_running,
+        not_tailed,
         already_exited,
         null,
         pipe,
         file,
+        exited,
+        closed,
+        stopped,
+        state_mismatch,
+        pending_data,
+        signaled,
+        timeout,
+        output_limit_exceeded,
+        stdin,
+        stdout,
+        stderr,
+        reaped_externally,
+        not_pty,
+        intr,
+        susp,
+        raw,
+        cooked,
+        no_echo,
+        posix_spawn,
+        fork_exec,
+        adopted,
+        not_found,
+        permission_denied,
+        not_executable,
+        too_many_files,
+        not_terminated,
+        too_many_children,
+        invalid_env_file,
+        bad_interpreter,
+        identity_mismatch,
+        append,
+        exclusive,
+        sync,
+        file_exists,
+        cancelled,
+        not_waiting,
+        running,
+        not_signaled,
+        killed,
+        dumped,
+        code,
+        status,
+        uid,
+        signal,
+        pid,
+        deadline,
+        more,
+        big,
+        little,
+        truncated_frame,
+        not_fanout,
+        already_subscribed,
+        not_subscribed,
+        is_a_directory,
+        oom_score_adj_failed,
+        parse_error,
+        nice,
+        policy,
+        cpus,
+        other,
+        batch,
+        idle,
+        fifo,
+        rr,
+        px_exit,
+        px_output,
+        active,
+        socket,
+        inherit,
+        pty,
+        pty_q = "pty?",
+        not_in_cgroup,
+        not_interleaved,
+        own_cgroup,
+        tail,
+        fanout,
+        repeat,
+        bad_cwd,
+        invalid_arg,
+        invalid_command,
+        invalid_env,
+        arg_list_too_long,
+        log,
+        not_supported,
+        tmpfile,
+        none,
+        zombie,
+        reaped,
+        all_failed,
+        no_timeout,
+        read,
+        write,
+        wait,
+        alloc,
+        lock,
+        px_ready,
+        px_ready_timeout,
     }
 }
 
@@ -32,15 +171,72 @@ mod atoms {
 enum StdioConfig {
     Null,
     Pipe,
+    Socket,
     Inherit,
     File(String),
+    Tmpfile,
+}
+
+impl StdioConfig {
+    fn mode(&self) -> StdioMode {
+        match self {
+            StdioConfig::Null => StdioMode::Null,
+            StdioConfig::Pipe => StdioMode::Pipe,
+            StdioConfig::Socket => StdioMode::Socket,
+            StdioConfig::Inherit => StdioMode::Inherit,
+            StdioConfig::File(_) => StdioMode::File,
+            StdioConfig::Tmpfile => StdioMode::Tmpfile,
+        }
+    }
+}
+
+/// How a stream was wired at spawn time, for `stdio_modes_nif`. Beyond the
+/// stdio configs, records a pty and the piped streams handed to a tail ring,
+/// a fan-out reader, a repeat feeder, a parent-held log file or an active
+/// output reader.
+#[derive(Clone, Copy, Debug)]
+enum StdioMode {
+    Null,
+    Pipe,
+    Socket,
+    Inherit,
+    File,
+    Pty,
+    Tail,
+    Fanout,
+    Repeat,
+    Log,
+    Tmpfile,
+    Active,
+}
+
+impl Encoder for StdioMode {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let atom = match self {
+            StdioMode::Null => atoms::null(),
+            StdioMode::Pipe => atoms::pipe(),
+            StdioMode::Socket => atoms::socket(),
+            StdioMode::Inherit => atoms::inherit(),
+            StdioMode::File => atoms::file(),
+            StdioMode::Pty => atoms::pty(),
+            StdioMode::Tail => atoms::tail(),
+            StdioMode::Fanout => atoms::fanout(),
+            StdioMode::Repeat => atoms::repeat(),
+            StdioMode::Log => atoms::log(),
+            StdioMode::Tmpfile => atoms::tmpfile(),
+            StdioMode::Active => atoms::active(),
+        };
+        atom.encode(env)
+    }
 }
 
 fn parse_stdio_config(mode: &str, path: &str) -> NifResult<StdioConfig> {
     match mode {
         "null" => Ok(StdioConfig::Null),
         "pipe" => Ok(StdioConfig::Pipe),
+        "socket" => Ok(StdioConfig::Socket),
         "inherit" => Ok(StdioConfig::Inherit),
+        "tmpfile" => Ok(StdioConfig::Tmpfile),
         "file" => {
             if path.is_empty() {
                 return Err(Error::Term(Box::new("file mode requires a path")));
@@ -48,450 +244,6059 @@ fn parse_stdio_config(mode: &str, path: &str) -> NifResult<StdioConfig> {
             Ok(StdioConfig::File(path.to_string()))
         }
         _ => Err(Error::Term(Box::new(format!(
-            "invalid stdio mode: {}, expected null, pipe, inherit, or file",
+            "invalid stdio mode: {}, expected null, pipe, socket, inherit, file or tmpfile",
             mode
         )))),
     }
 }
 
-pub struct ProcessResource {
-    child: Mutex<Option<Child>>,
-    cached_exit_code: Mutex<Option<i32>>,
-    stdin_pipe: Mutex<Option<ChildStdin>>,
-    stdout_pipe: Mutex<Option<ChildStdout>>,
-    stderr_pipe: Mutex<Option<ChildStderr>>,
+/// How a stdout or stderr file is opened, from `{:file, path, opts}`.
+/// Without it the file is created or truncated like `File::create`.
+#[derive(Clone, NifMap)]
+struct FileOptions {
+    /// Any of `:append` (keep existing contents), `:exclusive` (fail if the
+    /// file exists) and `:sync` (`O_SYNC`).
+    flags: Vec<rustler::Atom>,
+    /// Permission bits for a newly created file, before the umask.
+    mode: Option<u32>,
 }
 
-fn set_nonblocking<T: AsRawFd>(stream: &T) -> Result<(), nix::Error> {
-    let fd = stream.as_raw_fd();
-    let flags = fcntl(fd, FcntlArg::F_GETFL)?;
-    let new_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
-    fcntl(fd, FcntlArg::F_SETFL(new_flags))?;
-    Ok(())
-}
+impl FileOptions {
+    fn open_options(&self) -> NifResult<OpenOptions> {
+        let mode = self.mode.unwrap_or(0o666);
+        if mode > 0o7777 {
+            return Err(Error::Term(Box::new(format!(
+                "invalid file mode: {:o}",
+                mode
+            ))));
+        }
 
-fn exit_status_to_code(status: std::process::ExitStatus) -> i32 {
-    if let Some(code) = status.code() {
-        code
-    } else {
-        #[cfg(unix)]
-        {
-            use std::os::unix::process::ExitStatusExt;
-            if let Some(signal) = status.signal() {
-                return 128 + signal;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true).mode(mode);
+        let mut custom_flags = 0;
+        for &flag in &self.flags {
+            if flag == atoms::append() {
+                options.append(true).truncate(false);
+            } else if flag == atoms::exclusive() {
+                options.create_new(true);
+            } else if flag == atoms::sync() {
+                custom_flags |= libc::O_SYNC;
+            } else {
+                return Err(Error::Term(Box::new(
+                    "invalid file flag, expected append, exclusive or sync",
+                )));
             }
         }
-        -1
+        options.custom_flags(custom_flags);
+        Ok(options)
     }
 }
 
-#[allow(non_local_definitions)]
-fn load(env: Env, _info: rustler::Term) -> bool {
-    rustler::resource!(ProcessResource, env)
+/// Spawn settings beyond stdio, env and cd, passed from Elixir as a map.
+#[derive(Clone, NifMap)]
+struct SpawnOptions {
+    /// Kill the child with SIGKILL if the BEAM thread that spawned it dies
+    /// (Linux), or if the BEAM process exits (macOS).
+    pdeathsig: bool,
+    /// Signal sent to the child if it is still running when the VM exits.
+    cleanup_signal: Option<i32>,
+    /// Make reads from piped stdout/stderr return complete lines only.
+    lines: bool,
+    /// Run the child on a pseudo-terminal instead of the stdio configs.
+    pty: bool,
+    /// Inherit the BEAM's stdio if it is all terminals, and run on a
+    /// pseudo-terminal otherwise; see `resolve_inherit_or_pty`.
+    inherit_or_pty: bool,
+    /// Exec the command name relative to this directory fd with `execveat`
+    /// (Linux only).
+    exec_dirfd: Option<i32>,
+    /// Exec this open executable with `fexecve` instead of resolving the
+    /// command name, which is only `argv[0]` (Linux only).
+    exec_fd: Option<i32>,
+    /// Start from an empty environment instead of the BEAM's.
+    clear_env: bool,
+    /// With `clear_env`, variables to carry over from the BEAM's environment.
+    inherit_env: Vec<String>,
+    /// Path of a dotenv-style file whose variables are applied before `env`.
+    env_file: Option<String>,
+    /// `{key, value, :default}` entries of `env`: set only when the key is
+    /// in neither the inherited environment, `env_file` nor `env`.
+    env_defaults: Vec<(String, String)>,
+    /// How stdout and stderr files are opened, for `{:file, path, opts}`.
+    stdout_file: Option<FileOptions>,
+    stderr_file: Option<FileOptions>,
+    /// Leave the child stopped right after exec, until SIGCONT (Linux only).
+    start_stopped: bool,
+    /// Keep only the last this many bytes of piped stdout or stderr, for
+    /// `{:tail, bytes}`.
+    stdout_tail: Option<usize>,
+    stderr_tail: Option<usize>,
+    /// Also record the chunks of `{:tail, bytes}` streams, timestamped, in
+    /// one log of at most this many bytes, for `interleaved_log_nif`.
+    interleaved_log: Option<usize>,
+    /// Copy piped stdout or stderr into a file held by the parent, which
+    /// `reopen_output_nif` can swap, for `{:log, path}`.
+    stdout_log: Option<String>,
+    stderr_log: Option<String>,
+    /// Put the parent's ends of piped streams in non-blocking mode. When
+    /// false, reads and writes on them block.
+    nonblocking: bool,
+    /// Keep the child when `nonblocking` can't be applied to a stream,
+    /// leaving that stream blocking, instead of failing the spawn.
+    tolerate_nonblock_errors: bool,
+    /// Drain piped stdout to subscribed processes, for `stdout: :fanout`.
+    stdout_fanout: bool,
+    /// Stream piped stdout or stderr to `owner` and report the exit after
+    /// it, for `:active`.
+    stdout_active: bool,
+    stderr_active: bool,
+    /// Process sent the output of `:active` streams, then the exit, and the
+    /// outcome of the `ready` probe.
+    owner: Option<rustler::LocalPid>,
+    /// Probe that tells `owner` when the child is ready for work.
+    ready: Option<ready::Probe>,
+    /// How long the `ready` probe may take before it gives up.
+    ready_timeout_ms: Option<u64>,
+    /// Written to `/proc/<pid>/oom_score_adj` right after spawning (Linux
+    /// only).
+    oom_score_adj: Option<i32>,
+    /// Process to send `{:px_exit, resource, exit}` when the child exits.
+    notify_exit: Option<rustler::LocalPid>,
+    /// Descriptor operations applied in order after the stdio configs.
+    file_actions: Vec<file_actions::FileAction>,
+    /// Directories to look a bare command name up in, instead of `PATH`.
+    search_path: Option<Vec<String>>,
+    /// Namespaces to unshare in the child before exec (Linux only).
+    namespaces: Vec<rustler::Atom>,
+    /// Feed piped stdin from a pattern repeated by a thread, for
+    /// `stdin: {:repeat, pattern}`.
+    stdin_repeat: Option<repeat::Repeat>,
+    /// Process group for the child to join with `setpgid`; zero makes it
+    /// the leader of a new group.
+    pgid: Option<i32>,
+    /// SIGKILL the child if it is still running this many milliseconds
+    /// after spawning, unless `cancel_timeout_nif` disarms it first.
+    timeout_ms: Option<u64>,
+    /// Size of the buffer the drain threads (`{:tail, bytes}`, `{:log,
+    /// path}`, `:fanout`, `:active`) and `communicate_nif` read into;
+    /// `READ_CHUNK` when unset.
+    drain_buf_bytes: Option<usize>,
+    /// SIGKILL the child's whole process group when the resource is
+    /// dropped before the child is reaped. The child must lead its group,
+    /// with `pgid: 0` or `pty`.
+    kill_group_on_drop: bool,
+    /// cgroup v2 directory the child joins before exec, for `kill_tree_nif`
+    /// (Linux only).
+    cgroup: Option<String>,
 }
 
-#[rustler::nif]
-fn spawn_nif(
-    cmd: String,
-    arguments: Vec<String>,
-    stdin_mode: String,
-    stdin_path: String,
-    stdout_mode: String,
-    stdout_path: String,
-    stderr_mode: String,
-    stderr_path: String,
-    env: Vec<(String, String)>,
-    cd: String,
-) -> NifResult<(ResourceArc<ProcessResource>, i32)> {
-    let stdin_config = parse_stdio_config(&stdin_mode, &stdin_path)?;
-    let stdout_config = parse_stdio_config(&stdout_mode, &stdout_path)?;
-    let stderr_config = parse_stdio_config(&stderr_mode, &stderr_path)?;
+impl SpawnOptions {
+    /// With `inherit_or_pty`, turns on `pty` unless the BEAM's stdin,
+    /// stdout and stderr are all terminals, which the child then inherits.
+    /// A daemonized BEAM has none, and a child that checks `isatty` would
+    /// otherwise fall back to its non-interactive behaviour.
+    fn resolve_inherit_or_pty(&mut self) {
+        let on_terminal = || (0..=2).all(|fd| unsafe { libc::isatty(fd) } == 1);
+        if self.inherit_or_pty && !self.pty && !on_terminal() {
+            self.pty = true;
+        }
+    }
 
-    let mut command = Command::new(&cmd);
-    command.args(&arguments);
+    /// Runs every check of option values and combinations that needs no
+    /// system state, for spawning, templates and `validate_spawn_nif` alike.
+    fn validate(&self) -> NifResult<()> {
+        self.check_oom_score_adj()?;
+        self.check_pgid()?;
+        self.check_drain_buf_bytes()?;
+        self.check_active()?;
+        self.check_kill_group_on_drop()?;
+        self.check_exec_fd()?;
+        self.check_interleaved_log()?;
+        self.check_ready()?;
+        if let Some(repeat) = &self.stdin_repeat {
+            repeat.validate()?;
+        }
+        Ok(())
+    }
 
-    for (key, value) in env {
-        command.env(key, value);
+    /// Rejects an `oom_score_adj` outside -1000..=1000, or on a platform
+    /// without one.
+    fn check_oom_score_adj(&self) -> NifResult<()> {
+        match self.oom_score_adj {
+            Some(_) if cfg!(not(target_os = "linux")) => Err(Error::Term(Box::new(
+                "oom_score_adj is only supported on Linux",
+            ))),
+            Some(adj) if !(-1000..=1000).contains(&adj) => Err(Error::Term(Box::new(format!(
+                "invalid oom_score_adj: {}, expected -1000..1000",
+                adj
+            )))),
+            _ => Ok(()),
+        }
     }
 
-    if !cd.is_empty() {
-        command.current_dir(&cd);
+    /// Rejects a negative `pgid`, and one combined with `pty`, whose new
+    /// session leaves the child unable to join any existing group.
+    fn check_pgid(&self) -> NifResult<()> {
+        match self.pgid {
+            Some(pgid) if pgid < 0 => Err(Error::Term(Box::new(format!(
+                "invalid pgid: {}, expected a non-negative integer",
+                pgid
+            )))),
+            Some(_) if self.pty => Err(Error::Term(Box::new("pgid cannot be combined with pty"))),
+            _ => Ok(()),
+        }
     }
 
-    match &stdin_config {
-        StdioConfig::Null => {
-            command.stdin(Stdio::null());
+    /// Rejects an `:active` stream without an `owner` to send it to.
+    fn check_active(&self) -> NifResult<()> {
+        if (self.stdout_active || self.stderr_active) && self.owner.is_none() {
+            return Err(Error::Term(Box::new("active output requires an owner")));
         }
-        StdioConfig::Pipe => {
-            command.stdin(Stdio::piped());
+        Ok(())
+    }
+
+    /// Rejects `kill_group_on_drop` unless the child leads a group of its
+    /// own, so the signal never reaches a group something else started.
+    fn check_kill_group_on_drop(&self) -> NifResult<()> {
+        if self.kill_group_on_drop && !self.pty && self.pgid != Some(0) {
+            return Err(Error::Term(Box::new(
+                "kill_group_on_drop requires pgid: 0 or pty",
+            )));
         }
-        StdioConfig::Inherit => {
-            command.stdin(Stdio::inherit());
+        Ok(())
+    }
+
+    /// Rejects an `exec_fd` combined with `exec_dirfd`, or on a platform
+    /// without `fexecve`.
+    fn check_exec_fd(&self) -> NifResult<()> {
+        match self.exec_fd {
+            Some(_) if cfg!(not(target_os = "linux")) => {
+                Err(Error::Term(Box::new("exec_fd is only supported on Linux")))
+            }
+            Some(_) if self.exec_dirfd.is_some() => Err(Error::Term(Box::new(
+                "exec_fd cannot be combined with exec_dirfd",
+            ))),
+            _ => Ok(()),
         }
-        StdioConfig::File(path) => {
-            let file = File::open(path).map_err(|e| {
-                Error::Term(Box::new(format!(
-                    "Failed to open stdin file {}: {}",
-                    path, e
-                )))
-            })?;
-            command.stdin(Stdio::from(file));
+    }
+
+    /// Rejects an `interleaved_log` of zero bytes, or without a
+    /// `{:tail, bytes}` stream to record.
+    fn check_interleaved_log(&self) -> NifResult<()> {
+        match self.interleaved_log {
+            Some(0) => Err(Error::Term(Box::new(
+                "invalid interleaved_log: 0, expected a positive integer",
+            ))),
+            Some(_) if self.stdout_tail.is_none() && self.stderr_tail.is_none() => Err(
+                Error::Term(Box::new("interleaved_log requires a {:tail, bytes} stream")),
+            ),
+            _ => Ok(()),
         }
     }
 
-    match &stdout_config {
-        StdioConfig::Null => {
-            command.stdout(Stdio::null());
+    /// Rejects a `ready` probe without an owner to report to.
+    fn check_ready(&self) -> NifResult<()> {
+        if self.ready.is_some() && self.owner.is_none() {
+            return Err(Error::Term(Box::new("ready requires an owner")));
         }
-        StdioConfig::Pipe => {
-            command.stdout(Stdio::piped());
+        Ok(())
+    }
+
+    /// Rejects a zero `drain_buf_bytes`.
+    fn check_drain_buf_bytes(&self) -> NifResult<()> {
+        match self.drain_buf_bytes {
+            Some(0) => Err(Error::Term(Box::new(
+                "invalid drain_buf_bytes: 0, expected a positive integer",
+            ))),
+            _ => Ok(()),
         }
-        StdioConfig::Inherit => {
-            command.stdout(Stdio::inherit());
+    }
+
+    /// With `search_path`, resolves a bare `cmd` to the first executable
+    /// match in those directories (relative ones against `cd`), as an
+    /// absolute path. `None` without `search_path` or for a name with a
+    /// slash or with an `exec_fd`; `{:error, :not_found}` if no directory
+    /// has a match.
+    fn search(&self, cmd: &str, cd: &str) -> NifResult<Option<String>> {
+        let Some(dirs) = &self.search_path else {
+            return Ok(None);
+        };
+        if cmd.contains('/') || self.exec_fd.is_some() {
+            return Ok(None);
         }
-        StdioConfig::File(path) => {
-            let file = File::create(path).map_err(|e| {
-                Error::Term(Box::new(format!(
-                    "Failed to create stdout file {}: {}",
-                    path, e
-                )))
-            })?;
-            command.stdout(Stdio::from(file));
+        let path = std::env::join_paths(dirs)
+            .map_err(|e| Error::Term(Box::new(format!("invalid search_path: {}", e))))?;
+        let cwd = (!cd.is_empty()).then(|| Path::new(cd));
+        let program = resolve_program(Path::new(cmd), Some(path), cwd)
+            .ok_or_else(|| Error::Term(Box::new(atoms::not_found())))?;
+        let program = std::path::absolute(&program)
+            .map_err(|e| Error::Term(Box::new(format!("Failed to resolve {}: {}", cmd, e))))?;
+        Ok(Some(program.to_string_lossy().into_owned()))
+    }
+}
+
+fn parse_signal(signal: i32) -> NifResult<Signal> {
+    Signal::try_from(signal).map_err(|_| Error::Term(Box::new("Invalid signal")))
+}
+
+/// Parent-side end of a piped or socket-backed stdio stream.
+pub struct StdioStream {
+    file: File,
+    socket: bool,
+    /// For stdin, the unwritten tail of the last partial or blocked write.
+    /// For output streams, bytes read past the end of the last line
+    /// returned; the next read returns them first.
+    buffer: Vec<u8>,
+    /// Output stream in line mode: reads return one complete line at a time.
+    lines: bool,
+    /// Master side of a pseudo-terminal.
+    pty: bool,
+    /// Output binary allocated ahead of the next read; kept while reads
+    /// return nothing.
+    scratch: Option<OwnedBinary>,
+}
+
+impl StdioStream {
+    fn pipe<T: Into<OwnedFd>>(end: T) -> Self {
+        StdioStream {
+            file: File::from(end.into()),
+            socket: false,
+            buffer: Vec::new(),
+            lines: false,
+            pty: false,
+            scratch: None,
         }
     }
 
-    match &stderr_config {
-        StdioConfig::Null => {
-            command.stderr(Stdio::null());
+    fn socket(end: UnixStream) -> Self {
+        StdioStream {
+            file: File::from(OwnedFd::from(end)),
+            socket: true,
+            buffer: Vec::new(),
+            lines: false,
+            pty: false,
+            scratch: None,
         }
-        StdioConfig::Pipe => {
-            command.stderr(Stdio::piped());
+    }
+
+    fn pty(master: File) -> Self {
+        StdioStream {
+            file: master,
+            socket: false,
+            buffer: Vec::new(),
+            lines: false,
+            pty: true,
+            scratch: None,
         }
-        StdioConfig::Inherit => {
-            command.stderr(Stdio::inherit());
+    }
+
+    /// Reads from the stream. On Linux, reading a pty master fails with EIO
+    /// once every slave fd is closed; that is reported as end of stream.
+    fn read_some(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match fault::inject(fault::Site::Read).and_then(|()| (&self.file).read(buf)) {
+            Err(ref e) if self.pty && e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
         }
-        StdioConfig::File(path) => {
-            let file = File::create(path).map_err(|e| {
-                Error::Term(Box::new(format!(
-                    "Failed to create stderr file {}: {}",
-                    path, e
-                )))
-            })?;
-            command.stderr(Stdio::from(file));
+    }
+
+    /// `read_some` with `readv`, filling `bufs` in order.
+    fn read_vectored_some(&self, bufs: &mut [IoSliceMut]) -> std::io::Result<usize> {
+        let read = |()| (&self.file).read_vectored(bufs);
+        match fault::inject(fault::Site::Read).and_then(read) {
+            Err(ref e) if self.pty && e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
         }
     }
 
-    #[cfg(target_os = "linux")]
-    unsafe {
-        command.pre_exec(|| {
-            let result = libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
-            if result == -1 {
-                return Err(std::io::Error::last_os_error());
-            }
-            Ok(())
-        });
+    /// Writes to the stream, through the same fault hook as vectored writes.
+    fn write_some(&self, buf: &[u8]) -> std::io::Result<usize> {
+        fault::inject(fault::Site::Write).and_then(|()| (&self.file).write(buf))
     }
+}
 
-    match command.spawn() {
-        Ok(mut child) => {
-            let pid = child.id() as i32;
+impl AsRawFd for StdioStream {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.file.as_raw_fd()
+    }
+}
 
-            let stdin_pipe = child.stdin.take();
-            let stdout_pipe = child.stdout.take();
-            let stderr_pipe = child.stderr.take();
+/// How the child's exit was observed.
+#[derive(Clone, Copy, Debug)]
+enum Exit {
+    Status(ExitStatus),
+    /// Waiting failed with ECHILD: something else (typically a SIGCHLD
+    /// handler installed by the VM) reaped the child first, so its status is
+    /// lost.
+    ReapedExternally,
+}
 
-            if let Some(ref stdout) = stdout_pipe {
-                if let Err(e) = set_nonblocking(stdout) {
-                    return Err(Error::Term(Box::new(format!(
-                        "Failed to set stdout non-blocking: {}",
-                        e
-                    ))));
-                }
-            }
-            if let Some(ref stderr) = stderr_pipe {
-                if let Err(e) = set_nonblocking(stderr) {
-                    return Err(Error::Term(Box::new(format!(
-                        "Failed to set stderr non-blocking: {}",
-                        e
-                    ))));
-                }
-            }
-            if let Some(ref stdin) = stdin_pipe {
-                if let Err(e) = set_nonblocking(stdin) {
-                    return Err(Error::Term(Box::new(format!(
-                        "Failed to set stdin non-blocking: {}",
-                        e
-                    ))));
-                }
-            }
+pub struct ProcessResource {
+    pid: i32,
+    child: Mutex<Option<Child>>,
+    exit_status: Mutex<Option<Exit>>,
+    /// What `waitid` reported for the exit, read just before the reap.
+    siginfo: Mutex<Option<ChildSiginfo>>,
+    /// Set once `exit_status` is, so `alive_nif` can answer for an exited
+    /// child without taking a lock. Always written under the child lock.
+    exited: AtomicBool,
+    spawned_at: Instant,
+    exited_at: Mutex<Option<Instant>>,
+    /// Nanoseconds after `spawned_at` of the last successful read or write.
+    last_io_ns: AtomicU64,
+    /// Bytes written to stdin and read from stdout and stderr, for
+    /// `io_counters_nif`. Kept outside the stream mutexes so they outlive
+    /// closed streams and the reap.
+    stdin_written: AtomicU64,
+    stdout_read: AtomicU64,
+    stderr_read: AtomicU64,
+    registration: Arc<registry::Registration>,
+    stdin_pipe: Mutex<Option<StdioStream>>,
+    stdout_pipe: Mutex<Option<StdioStream>>,
+    stderr_pipe: Mutex<Option<StdioStream>>,
+    /// Master side of the child's pseudo-terminal, kept for terminal control
+    /// even after the stdin/stdout streams are closed.
+    pty_master: Option<File>,
+    method: SpawnMethod,
+    /// How stdin, stdout and stderr were wired at spawn time.
+    stdio_modes: [StdioMode; 3],
+    /// Whether stdin, stdout and stderr are known to be non-blocking: set
+    /// at spawn and by `reassert_nonblocking_nif`.
+    nonblocking: [AtomicBool; 3],
+    /// Start time of the child in clock ticks since boot, read from
+    /// `/proc/<pid>/stat` right after spawning (Linux only).
+    start_time: Option<u64>,
+    /// `wait_nif` calls blocked on this child, for `cancel_wait_nif`.
+    waiters: wait_cancel::Waiters,
+    /// Opened right after spawning, so it refers to this child even once
+    /// something else has reaped it and its PID is reused (Linux 5.3+).
+    pidfd: Option<OwnedFd>,
+    /// The last signal we delivered to the child; zero if none.
+    last_signal: AtomicI32,
+    /// Rings holding the tail of stdout and stderr, for `{:tail, bytes}`.
+    stdout_tail: Option<Arc<tail::Tail>>,
+    stderr_tail: Option<Arc<tail::Tail>>,
+    /// Chunks of both tailed streams in the order they were read, from
+    /// `interleaved_log`.
+    interleaved: Option<Arc<interleave::Interleaved>>,
+    /// Parent-held files stdout and stderr are copied into, for
+    /// `{:log, path}`.
+    stdout_log: Option<Arc<log::Log>>,
+    stderr_log: Option<Arc<log::Log>>,
+    /// Anonymous files stdout and stderr write to, for `:tmpfile`.
+    stdout_tmpfile: Option<File>,
+    stderr_tmpfile: Option<File>,
+    /// Subscribers to stdout, for `stdout: :fanout`.
+    stdout_fanout: Option<fanout::Fanout>,
+    /// Deadline for the `timeout:` kill; disarmed when the child is reaped.
+    watchdog: watchdog::Watchdog,
+    /// Deadline set by `set_max_lifetime_nif`, and the grace its SIGTERM
+    /// gets before SIGKILL; disarmed when the child is reaped.
+    lifetime: watchdog::Watchdog,
+    lifetime_grace_ms: AtomicU64,
+    /// Read buffer size for `communicate_nif` and `:active` output, from
+    /// `drain_buf_bytes`.
+    drain_buf: usize,
+    /// Taken over with `adopt_nif` rather than spawned: there is no `child`,
+    /// and the process is watched and signalled through `pidfd`.
+    adopted: bool,
+    /// From `kill_group_on_drop`: the child leads its own process group.
+    kill_group_on_drop: bool,
+    /// The cgroup the child was placed in with `cgroup`, for
+    /// `kill_tree_nif`.
+    cgroup: Option<PathBuf>,
+}
 
-            let resource = ResourceArc::new(ProcessResource {
-                child: Mutex::new(Some(child)),
-                cached_exit_code: Mutex::new(None),
-                stdin_pipe: Mutex::new(stdin_pipe),
-                stdout_pipe: Mutex::new(stdout_pipe),
-                stderr_pipe: Mutex::new(stderr_pipe),
-            });
-            Ok((resource, pid))
+/// With `kill_group_on_drop`, SIGKILLs the whole group of a child that was
+/// never reaped, then reaps the child on a detached thread so it does not
+/// linger as a zombie. Grandchildren the child left in its group go with
+/// it. Until the child is reaped its PID, and so the group ID, cannot be
+/// reused; once it has been, nothing is sent, since the ID may belong to
+/// another group by then.
+impl Drop for ProcessResource {
+    fn drop(&mut self) {
+        if !self.kill_group_on_drop {
+            return;
         }
-        Err(e) => Err(Error::Term(Box::new(format!("Failed to spawn: {}", e)))),
+        let child = self.child.get_mut().unwrap_or_else(|e| e.into_inner());
+        let exited = self
+            .exit_status
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        let (Some(mut child), None) = (child.take(), *exited) else {
+            return;
+        };
+        let _ = nix::sys::signal::killpg(Pid::from_raw(self.pid), Signal::SIGKILL);
+        let _ = thread::Builder::new()
+            .name("px-reap".to_string())
+            .spawn(move || child.wait());
     }
 }
 
-#[rustler::nif]
-fn signal_nif<'a>(
-    env: Env<'a>,
-    resource: ResourceArc<ProcessResource>,
-    signal: i32,
-) -> NifResult<Term<'a>> {
-    let cached = resource
-        .cached_exit_code
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-
-    if cached.is_some() {
-        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+impl ProcessResource {
+    fn touch_io(&self) {
+        let elapsed = self.spawned_at.elapsed().as_nanos() as u64;
+        self.last_io_ns.fetch_max(elapsed, Ordering::Relaxed);
     }
+}
 
-    let child_lock = resource
-        .child
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+/// Adds `n` bytes to one of a resource's I/O counters.
+fn count_io(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
 
-    let pid = if let Some(child) = child_lock.as_ref() {
-        child.id() as i32
-    } else {
-        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+/// Opens the file for `{:log, path}` before spawning, so a bad path fails
+/// the spawn instead of leaving a child with nowhere to write.
+fn open_log(path: Option<&str>, name: &str) -> NifResult<Option<File>> {
+    path.map(|path| {
+        log::open(path).map_err(|e| {
+            Error::Term(Box::new(format!(
+                "Failed to open {} log {}: {}",
+                name, path, e
+            )))
+        })
+    })
+    .transpose()
+}
+
+/// Hands a piped output stream over to a thread copying it into `file`
+/// through a `buf_size` buffer, leaving nothing in `pipe` for `read_nif`.
+fn drain_log(
+    pipe: &mut Option<StdioStream>,
+    file: Option<File>,
+    buf_size: usize,
+    name: &str,
+) -> NifResult<Option<Arc<log::Log>>> {
+    let (Some(file), Some(stream)) = (file, pipe.take()) else {
+        return Ok(None);
     };
+    log::Log::drain(stream.file, file, buf_size)
+        .map(Some)
+        .map_err(|e| Error::Term(Box::new(format!("Failed to start {} log: {}", name, e))))
+}
 
-    drop(child_lock);
+/// Hands a piped output stream over to a tail ring when `capacity` is set,
+/// read through a `buf_size` buffer, leaving nothing in `pipe` for
+/// `read_nif`.
+fn drain_tail(
+    pipe: &mut Option<StdioStream>,
+    capacity: Option<usize>,
+    buf_size: usize,
+    interleaved: Option<(Arc<interleave::Interleaved>, interleave::Stream)>,
+    name: &str,
+) -> NifResult<Option<Arc<tail::Tail>>> {
+    let Some(capacity) = capacity else {
+        return Ok(None);
+    };
+    let Some(stream) = pipe.take() else {
+        return Ok(None);
+    };
+    tail::Tail::drain(stream.file, capacity, buf_size, interleaved)
+        .map(Some)
+        .map_err(|e| Error::Term(Box::new(format!("Failed to start {} tail: {}", name, e))))
+}
 
-    let sig = Signal::try_from(signal).map_err(|_| Error::Term(Box::new("Invalid signal")))?;
+fn set_nonblocking<T: AsRawFd>(stream: &T) -> Result<(), nix::Error> {
+    let fd = stream.as_raw_fd();
+    let flags = fcntl(fd, FcntlArg::F_GETFL)?;
+    let new_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+    fcntl(fd, FcntlArg::F_SETFL(new_flags))?;
+    Ok(())
+}
 
-    match kill(Pid::from_raw(pid), sig) {
-        Ok(()) => Ok(atoms::ok().encode(env)),
-        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
-    }
+fn set_blocking<T: AsRawFd>(stream: &T) -> Result<(), nix::Error> {
+    let fd = stream.as_raw_fd();
+    let flags = fcntl(fd, FcntlArg::F_GETFL)?;
+    let new_flags = OFlag::from_bits_truncate(flags) - OFlag::O_NONBLOCK;
+    fcntl(fd, FcntlArg::F_SETFL(new_flags))?;
+    Ok(())
 }
 
-#[rustler::nif(schedule = "DirtyIo")]
-fn wait_nif(resource: ResourceArc<ProcessResource>) -> NifResult<i32> {
-    {
-        let cached = resource
-            .cached_exit_code
-            .lock()
-            .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-        if let Some(code) = *cached {
-            return Ok(code);
-        }
+/// Re-applies O_NONBLOCK to a stream, returning whether it had been cleared.
+fn reassert_nonblocking<T: AsRawFd>(stream: &T) -> Result<bool, nix::Error> {
+    let flags = OFlag::from_bits_truncate(fcntl(stream.as_raw_fd(), FcntlArg::F_GETFL)?);
+    if flags.contains(OFlag::O_NONBLOCK) {
+        return Ok(false);
     }
+    set_nonblocking(stream)?;
+    Ok(true)
+}
 
-    let mut child_lock = resource
-        .child
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+/// Whether a failed non-blocking read or write should be retried later.
+///
+/// Checks the raw errno for both EAGAIN and EWOULDBLOCK instead of relying
+/// on `ErrorKind::WouldBlock` alone: the two may differ on some platforms,
+/// and std's errno-to-kind mapping is not guaranteed to cover both.
+fn is_would_block(error: &std::io::Error) -> bool {
+    let errno = error.raw_os_error();
+    error.kind() == std::io::ErrorKind::WouldBlock
+        || errno == Some(libc::EAGAIN)
+        || errno == Some(libc::EWOULDBLOCK)
+}
 
-    if let Some(child) = child_lock.as_mut() {
-        match child.wait() {
-            Ok(status) => {
-                let code = exit_status_to_code(status);
-                let mut cached = resource
-                    .cached_exit_code
-                    .lock()
-                    .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-                *cached = Some(code);
-                Ok(code)
-            }
-            Err(e) => Err(Error::Term(Box::new(format!("Failed to wait: {}", e)))),
-        }
+/// The legacy integer exit code: the exit status, or `128 + signal` for a
+/// signal death, as a shell reports it. A child that runs `exit 137` and one
+/// killed by SIGKILL are indistinguishable here; `exit_info_nif` keeps them
+/// apart. Kept for `wait_nif`, whose callers expect a single integer.
+fn exit_status_to_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        code
+    } else if let Some(signal) = status.signal() {
+        128 + signal
     } else {
-        let cached = resource
-            .cached_exit_code
-            .lock()
-            .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-        if let Some(code) = *cached {
-            return Ok(code);
-        }
-        Err(Error::Term(Box::new("Process already reaped")))
+        -1
     }
 }
 
-#[rustler::nif]
-fn alive_nif(resource: ResourceArc<ProcessResource>) -> NifResult<bool> {
-    {
-        let cached = resource
-            .cached_exit_code
-            .lock()
-            .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-        if cached.is_some() {
-            return Ok(false);
-        }
+/// Encodes an exit as `{:exited, code}`, `{:signaled, signal, core_dumped}`
+/// or `:reaped_externally`.
+fn encode_exit(env: Env, exit: Exit) -> Term {
+    match exit {
+        Exit::Status(status) => match status.signal() {
+            Some(signal) => (atoms::signaled(), signal, status.core_dumped()).encode(env),
+            None => (atoms::exited(), status.code().unwrap_or(-1)).encode(env),
+        },
+        Exit::ReapedExternally => atoms::reaped_externally().encode(env),
     }
+}
 
-    let mut child_lock = resource
-        .child
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-
-    if let Some(child) = child_lock.as_mut() {
-        match child.try_wait() {
-            Ok(Some(status)) => {
-                let code = exit_status_to_code(status);
-                let mut cached = resource
-                    .cached_exit_code
-                    .lock()
-                    .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-                *cached = Some(code);
-                Ok(false)
-            }
-            Ok(None) => Ok(true),
-            Err(_) => Ok(false),
-        }
-    } else {
-        Ok(false)
+/// Encodes an exit the way `wait_nif` reports it: the legacy integer code,
+/// or `:reaped_externally`.
+fn encode_exit_code(env: Env, exit: Exit) -> Term {
+    match exit {
+        Exit::Status(status) => exit_status_to_code(status).encode(env),
+        Exit::ReapedExternally => atoms::reaped_externally().encode(env),
     }
 }
 
-#[rustler::nif]
-fn write_stdin_nif<'a>(
-    env: Env<'a>,
-    resource: ResourceArc<ProcessResource>,
-    data: Binary<'a>,
-) -> NifResult<Term<'a>> {
-    let mut stdin_lock = resource
-        .stdin_pipe
+fn is_echild(error: &std::io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ECHILD)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> NifResult<MutexGuard<'_, T>> {
+    if fault::inject(fault::Site::Lock).is_err() {
+        return Err(Error::Term(Box::new(
+            "Lock failed: poisoned lock: another task failed inside",
+        )));
+    }
+    mutex
         .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))
+}
 
-    if let Some(stdin) = stdin_lock.as_mut() {
-        match stdin.write(data.as_slice()) {
-            Ok(n) if n == data.len() => Ok(atoms::ok().encode(env)),
-            Ok(n) => Ok((atoms::partial(), n as i64).encode(env)),
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                Ok(atoms::would_block().encode(env))
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
-                Ok((atoms::error(), atoms::broken_pipe()).encode(env))
-            }
-            Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
-        }
-    } else {
-        Ok((atoms::error(), atoms::not_piped()).encode(env))
+/// Caches how a child exited so later calls never touch the (possibly
+/// recycled) PID again. Must be called under the child lock.
+fn record_exit(resource: &ProcessResource, exit: Exit) -> NifResult<Exit> {
+    *lock(&resource.exit_status)? = Some(exit);
+    *lock(&resource.exited_at)? = Some(Instant::now());
+    resource.exited.store(true, Ordering::Release);
+    resource.registration.mark_reaped();
+    resource.watchdog.cancel();
+    resource.lifetime.cancel();
+    Ok(exit)
+}
+
+/// Turns the result of `wait`/`try_wait` into an exit. ECHILD means the
+/// child was already reaped elsewhere, which is recorded rather than
+/// reported as an error.
+fn record_wait(
+    resource: &ProcessResource,
+    result: std::io::Result<Option<ExitStatus>>,
+) -> NifResult<Option<Exit>> {
+    match result {
+        Ok(Some(status)) => record_exit(resource, Exit::Status(status)).map(Some),
+        Ok(None) => Ok(None),
+        Err(ref e) if is_echild(e) => record_exit(resource, Exit::ReapedExternally).map(Some),
+        Err(e) => Err(Error::Term(Box::new(format!("Failed to wait: {}", e)))),
     }
 }
 
-#[rustler::nif]
-fn close_stdin_nif<'a>(
-    env: Env<'a>,
-    resource: ResourceArc<ProcessResource>,
-) -> NifResult<Term<'a>> {
-    let mut stdin_lock = resource
-        .stdin_pipe
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+/// The `siginfo_t` of a child's exit, as `waitid(WEXITED)` fills it in.
+#[derive(Clone, Copy)]
+struct ChildSiginfo {
+    /// `CLD_EXITED`, `CLD_KILLED` or `CLD_DUMPED`.
+    code: i32,
+    /// The exit status, or the signal for `CLD_KILLED` and `CLD_DUMPED`.
+    status: i32,
+    pid: i32,
+    /// Real user ID of the child when it exited.
+    uid: u32,
+}
 
-    if stdin_lock.is_some() {
-        *stdin_lock = None;
-        Ok(atoms::ok().encode(env))
-    } else {
-        Ok((atoms::error(), atoms::not_piped()).encode(env))
+impl ChildSiginfo {
+    /// The exit `info` describes, or `None` if `waitid` found no child.
+    fn from_info(info: &libc::siginfo_t) -> Option<ChildSiginfo> {
+        let pid = unsafe { info.si_pid() };
+        (pid != 0).then(|| ChildSiginfo {
+            code: info.si_code,
+            status: unsafe { info.si_status() },
+            pid,
+            uid: unsafe { info.si_uid() },
+        })
+    }
+
+    /// The same exit as a `waitpid` status word.
+    fn exit_status(self) -> ExitStatus {
+        let raw = match self.code {
+            libc::CLD_EXITED => (self.status & 0xff) << 8,
+            libc::CLD_DUMPED => self.status | 0x80,
+            _ => self.status,
+        };
+        ExitStatus::from_raw(raw)
     }
 }
 
-#[rustler::nif]
-fn close_stdout_nif<'a>(
-    env: Env<'a>,
-    resource: ResourceArc<ProcessResource>,
-) -> NifResult<Term<'a>> {
-    let mut stdout_lock = resource
-        .stdout_pipe
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+/// Records the `siginfo_t` of the child's exit if it has exited, without
+/// reaping it, so the reap that follows can be described beyond what the
+/// wait status holds. Call under the child lock, just before reaping.
+fn capture_siginfo(resource: &ProcessResource) {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    let result = unsafe { libc::waitid(libc::P_PID, resource.pid as libc::id_t, &mut info, flags) };
+    if result == -1 {
+        return;
+    }
+    if let (Some(siginfo), Ok(mut slot)) = (ChildSiginfo::from_info(&info), resource.siginfo.lock())
+    {
+        *slot = Some(siginfo);
+    }
+}
 
-    if stdout_lock.is_some() {
-        *stdout_lock = None;
-        Ok(atoms::ok().encode(env))
-    } else {
-        Ok((atoms::error(), atoms::not_piped()).encode(env))
+/// `Child::try_wait` that records the exit's `siginfo_t` along the way. A
+/// single `waitid(WEXITED | WNOHANG)` both reaps and describes the child,
+/// so polling a running child costs one syscall. Std's `Child` is not told;
+/// callers record the exit, after which nothing waits on it again. Call
+/// under the child lock.
+fn try_wait_siginfo(resource: &ProcessResource) -> std::io::Result<Option<ExitStatus>> {
+    fault::inject(fault::Site::Wait)?;
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        let flags = libc::WEXITED | libc::WNOHANG;
+        let pid = resource.pid as libc::id_t;
+        if unsafe { libc::waitid(libc::P_PID, pid, &mut info, flags) } == 0 {
+            break;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
     }
+    let Some(siginfo) = ChildSiginfo::from_info(&info) else {
+        return Ok(None);
+    };
+    if let Ok(mut slot) = resource.siginfo.lock() {
+        *slot = Some(siginfo);
+    }
+    Ok(Some(siginfo.exit_status()))
 }
 
-#[rustler::nif]
-fn close_stderr_nif<'a>(
-    env: Env<'a>,
-    resource: ResourceArc<ProcessResource>,
-) -> NifResult<Term<'a>> {
-    let mut stderr_lock = resource
-        .stderr_pipe
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+/// Blocks until the child has exited without reaping it, so the PID stays
+/// reserved (as a zombie) until the caller reaps under the child lock.
+fn wait_exited_no_reap(pid: i32) {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if result == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            return;
+        }
+    }
+}
 
-    if stderr_lock.is_some() {
-        *stderr_lock = None;
-        Ok(atoms::ok().encode(env))
-    } else {
-        Ok((atoms::error(), atoms::not_piped()).encode(env))
+/// Blocks until the process has exited, leaving a child unreaped. An
+/// adopted process is waited for through its pidfd.
+fn wait_exited(resource: &ProcessResource) {
+    match &resource.pidfd {
+        Some(pidfd) if resource.adopted => wait_cancel::wait_pidfd(pidfd.as_fd()),
+        _ => wait_exited_no_reap(resource.pid),
     }
 }
 
-#[rustler::nif]
-fn read_stdout_nif<'a>(
-    env: Env<'a>,
+/// Starts a thread that waits for the child to exit, reaps it (or picks up
+/// the exit a `wait_nif` call already reaped) and sends `owner`
+/// `{:px_exit, resource, exit}`. Like a blocked `wait_nif`, it only takes
+/// the child lock once the child has exited. It holds a reference to the
+/// resource until then.
+fn notify_exit(
     resource: ResourceArc<ProcessResource>,
-) -> NifResult<Term<'a>> {
-    let mut stdout_lock = resource
-        .stdout_pipe
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
-
-    if let Some(stdout) = stdout_lock.as_mut() {
-        let mut buf = [0u8; 4096];
-        match stdout.read(&mut buf) {
-            Ok(0) => Ok(atoms::eof().encode(env)),
-            Ok(n) => {
-                let mut binary = OwnedBinary::new(n)
-                    .ok_or_else(|| Error::Term(Box::new("Failed to allocate binary")))?;
-                binary.as_mut_slice().copy_from_slice(&buf[..n]);
-                Ok((atoms::ok(), binary.release(env)).encode(env))
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                Ok(atoms::would_block().encode(env))
+    owner: rustler::LocalPid,
+) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("px-exit".to_string())
+        .spawn(move || {
+            wait_exited(&resource);
+            if let Ok(exit) = reap_exited(&resource) {
+                let _ = rustler::OwnedEnv::new().send_and_clear(&owner, |env| {
+                    (atoms::px_exit(), resource.clone(), encode_exit(env, exit)).encode(env)
+                });
             }
-            Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
-        }
-    } else {
-        Ok((atoms::error(), atoms::not_piped()).encode(env))
-    }
+        })?;
+    Ok(())
 }
 
-#[rustler::nif]
-fn read_stderr_nif<'a>(
-    env: Env<'a>,
+/// Starts a thread that sends `owner` the output of the `:active` streams,
+/// stdout and stderr, as `{:px_output, resource, stream, data}` while the
+/// child runs. Once both are at EOF it reaps the child and sends
+/// `{:px_exit, resource, exit}`, which therefore always follows the last
+/// output. Like `notify_exit`, it holds a reference to the resource until
+/// then; a grandchild that inherited the pipes holds it, and the exit
+/// message, until it closes them too.
+fn stream_active(
     resource: ResourceArc<ProcessResource>,
+    streams: [Option<File>; 2],
+    owner: rustler::LocalPid,
+) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("px-active".to_string())
+        .spawn(move || {
+            let mut msg_env = rustler::OwnedEnv::new();
+            let names = [atoms::stdout(), atoms::stderr()];
+            let counters = [&resource.stdout_read, &resource.stderr_read];
+            active::pump(streams, resource.drain_buf, |i, data| {
+                resource.touch_io();
+                count_io(counters[i], data.len());
+                let _ = msg_env.send_and_clear(&owner, |env| {
+                    let mut binary = NewBinary::new(env, data.len());
+                    binary.as_mut_slice().copy_from_slice(data);
+                    let data = Binary::from(binary);
+                    (atoms::px_output(), resource.clone(), names[i], data).encode(env)
+                });
+            });
+            if let Ok(exit) = wait_exit(&resource) {
+                let _ = msg_env.send_and_clear(&owner, |env| {
+                    (atoms::px_exit(), resource.clone(), encode_exit(env, exit)).encode(env)
+                });
+            }
+        })?;
+    Ok(())
+}
+
+/// Starts the thread running the `ready` probe, which sends `owner`
+/// `{:px_ready, resource}` once the child is ready, or
+/// `{:px_ready_timeout, resource}` if it gives up first. It holds a
+/// reference to the resource until then.
+fn start_ready_probe(
+    resource: ResourceArc<ProcessResource>,
+    probe: ready::Probe,
+    splice: Option<ready::Splice>,
+    deadline: Option<Instant>,
+    owner: rustler::LocalPid,
+) -> std::io::Result<()> {
+    let pid = resource.pid;
+    let buf_size = resource.drain_buf;
+    let exited = move || pending_wait(pid, libc::WEXITED);
+    probe.start(splice, deadline, buf_size, exited, move |ready| {
+        let tag = if ready {
+            atoms::px_ready()
+        } else {
+            atoms::px_ready_timeout()
+        };
+        let _ = rustler::OwnedEnv::new().send_and_clear(&owner, |env| (tag, resource).encode(env));
+    })
+}
+
+/// Arms the child's watchdog and starts a thread that SIGKILLs the child
+/// once `deadline` passes. The thread holds a reference to the resource
+/// until the watchdog fires or is disarmed, which reaping does.
+fn start_watchdog(
+    resource: ResourceArc<ProcessResource>,
+    deadline: Instant,
+) -> std::io::Result<()> {
+    if !resource.watchdog.arm(deadline) {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("px-watchdog".to_string())
+        .spawn(move || {
+            if resource.watchdog.expired() {
+                let _ = kill_if_running(&resource, Signal::SIGKILL);
+            }
+        })?;
+    Ok(())
+}
+
+/// Arms the child's lifetime watchdog for `deadline`, starting its thread
+/// unless one is already waiting on it, which then just takes the new
+/// deadline. When it fires, the thread SIGTERMs the child and SIGKILLs it
+/// if it has not exited after `lifetime_grace_ms`. It does not reap.
+fn start_lifetime(
+    resource: ResourceArc<ProcessResource>,
+    deadline: Instant,
+) -> std::io::Result<()> {
+    if !resource.lifetime.arm(deadline) {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("px-lifetime".to_string())
+        .spawn(move || {
+            if !resource.lifetime.expired() {
+                return;
+            }
+            let _ = kill_if_running(&resource, Signal::SIGTERM);
+            // Exited, even if not reaped yet: a SIGKILL now would only
+            // muddle which signal the exit is put down to.
+            let gone = || {
+                resource.exited.load(Ordering::Acquire)
+                    || (!resource.adopted && pending_wait(resource.pid, libc::WEXITED))
+            };
+            let grace = Duration::from_millis(resource.lifetime_grace_ms.load(Ordering::Relaxed));
+            let deadline = Some(Instant::now() + grace);
+            while !gone() && !deadline_passed(deadline) {
+                thread::sleep(LIFETIME_POLL);
+            }
+            if !gone() {
+                let _ = kill_if_running(&resource, Signal::SIGKILL);
+            }
+        })?;
+    Ok(())
+}
+
+/// How often a fired lifetime watchdog checks whether the child has exited
+/// during its grace period.
+const LIFETIME_POLL: Duration = Duration::from_millis(10);
+
+/// Blocks until the child exits, reaping it, or returns the cached status.
+///
+/// The child lock is only taken once the child is known to have exited, so
+/// a blocked waiter never stalls `signal_nif` or `alive_nif`.
+fn wait_exit(resource: &ProcessResource) -> NifResult<Exit> {
+    if let Some(exit) = *lock(&resource.exit_status)? {
+        return Ok(exit);
+    }
+
+    wait_exited(resource);
+    reap_exited(resource)
+}
+
+/// Like `wait_exit`, but returns `None` if the wait is cancelled with
+/// `cancel_wait_nif` before the child exits.
+fn wait_exit_cancellable(resource: &ProcessResource) -> NifResult<Option<Exit>> {
+    if let Some(exit) = *lock(&resource.exit_status)? {
+        return Ok(Some(exit));
+    }
+
+    let exited = wait_cancel::wait_exited_or_cancelled(
+        (!resource.adopted).then_some(resource.pid),
+        resource.pidfd.as_ref().map(|fd| fd.as_fd()),
+        &resource.waiters,
+    )
+    .map_err(|e| Error::Term(Box::new(format!("Failed to wait: {}", e))))?;
+    if !exited {
+        return Ok(None);
+    }
+    reap_exited(resource).map(Some)
+}
+
+/// Reaps a child known to have exited, or returns the cached status.
+fn reap_exited(resource: &ProcessResource) -> NifResult<Exit> {
+    let mut child_lock = lock(&resource.child)?;
+
+    if let Some(exit) = *lock(&resource.exit_status)? {
+        return Ok(exit);
+    }
+
+    match child_lock.as_mut() {
+        Some(child) => {
+            capture_siginfo(resource);
+            let result = fault::inject(fault::Site::Wait).and_then(|()| child.wait());
+            let exit = record_wait(resource, result.map(Some))?;
+            Ok(exit.unwrap_or(Exit::ReapedExternally))
+        }
+        None if resource.adopted => record_exit(resource, Exit::ReapedExternally),
+        None => Err(Error::Term(Box::new("Process already reaped"))),
+    }
+}
+
+/// Reaps the child if it has exited, without blocking.
+fn try_reap(resource: &ProcessResource) -> NifResult<Option<Exit>> {
+    if let Some(exit) = *lock(&resource.exit_status)? {
+        return Ok(Some(exit));
+    }
+
+    let child_lock = lock(&resource.child)?;
+
+    if let Some(exit) = *lock(&resource.exit_status)? {
+        return Ok(Some(exit));
+    }
+
+    if child_lock.is_some() {
+        return record_wait(resource, try_wait_siginfo(resource));
+    }
+    match released(resource, &child_lock)? {
+        true => Ok(*lock(&resource.exit_status)?),
+        false => Ok(None),
+    }
+}
+
+/// Whether the PID no longer names this process, so nothing may be done to
+/// it: its exit is recorded, or an adopted process's pidfd reports that it
+/// has exited, which is then recorded. Call under the child lock.
+fn released(resource: &ProcessResource, child: &Option<Child>) -> NifResult<bool> {
+    if lock(&resource.exit_status)?.is_some() {
+        return Ok(true);
+    }
+    if !resource.adopted {
+        return Ok(child.is_none());
+    }
+    let exited = resource
+        .pidfd
+        .as_ref()
+        .is_none_or(|pidfd| wait_cancel::pidfd_exited(pidfd.as_fd()));
+    if exited {
+        record_exit(resource, Exit::ReapedExternally)?;
+    }
+    Ok(exited)
+}
+
+/// Sends `signal` unless the child has already been reaped. The child lock is
+/// held across the check and the `kill`, so the PID cannot be recycled in
+/// between.
+fn kill_if_running(resource: &ProcessResource, signal: Signal) -> NifResult<()> {
+    let child_lock = lock(&resource.child)?;
+    if !released(resource, &child_lock)? {
+        let _ = send_signal(resource, signal);
+    }
+    Ok(())
+}
+
+/// Sends `signal` to the child, remembering it if delivered so a later death
+/// by that signal can be attributed to us. Call under the child lock. An
+/// adopted process is signalled through its pidfd, since nothing keeps its
+/// PID from being reused.
+fn send_signal(resource: &ProcessResource, signal: Signal) -> nix::Result<()> {
+    match &resource.pidfd {
+        #[cfg(target_os = "linux")]
+        Some(pidfd) if resource.adopted => {
+            wait_cancel::pidfd_send_signal(pidfd.as_fd(), signal as i32)?
+        }
+        _ => kill(Pid::from_raw(resource.pid), signal)?,
+    }
+    resource.last_signal.store(signal as i32, Ordering::Release);
+    Ok(())
+}
+
+#[allow(non_local_definitions)]
+fn load(env: Env, _info: rustler::Term) -> bool {
+    rustler::resource!(ProcessResource, env)
+        && rustler::resource!(CommandTemplate, env)
+        && rustler::resource!(DupFd, env)
+}
+
+/// How std started the child. `Command::spawn` uses `posix_spawn` (which
+/// glibc implements with `clone(CLONE_VFORK)`, avoiding page-table copies of
+/// a large heap) unless the command needs something it can't express, most
+/// notably a `pre_exec` hook. Otherwise it falls back to fork + exec.
+#[derive(Clone, Copy)]
+enum SpawnMethod {
+    PosixSpawn,
+    ForkExec,
+    /// Not spawned by Px at all; see `adopt_nif`.
+    Adopted,
+}
+
+impl SpawnMethod {
+    /// Mirrors the checks `Command::spawn` makes before taking the
+    /// `posix_spawn` path, for the subset of `Command` features used here.
+    /// `pre_exec` is whether any hook was actually registered; the rest are
+    /// std internals, so `PosixSpawn` is best-effort should std add a
+    /// fallback of its own.
+    fn predict(cmd: &str, env: &[(OsString, OsString)], cd: &str, pre_exec: bool) -> SpawnMethod {
+        // A PATH override makes std resolve a bare program name itself,
+        // which it only does on the fork path.
+        let path_lookup = env.iter().any(|(key, _)| key == "PATH") && !cmd.contains('/');
+        let chdir = cd.is_empty() || posix_spawn_can_chdir();
+
+        if pre_exec || path_lookup || !chdir {
+            SpawnMethod::ForkExec
+        } else {
+            SpawnMethod::PosixSpawn
+        }
+    }
+
+    fn atom(self) -> rustler::Atom {
+        match self {
+            SpawnMethod::PosixSpawn => atoms::posix_spawn(),
+            SpawnMethod::ForkExec => atoms::fork_exec(),
+            SpawnMethod::Adopted => atoms::adopted(),
+        }
+    }
+}
+
+/// std only changes directory through `posix_spawn` when libc provides
+/// `posix_spawn_file_actions_addchdir_np` (glibc 2.29+, macOS 10.15+), which
+/// it looks up at runtime; so does this.
+fn posix_spawn_can_chdir() -> bool {
+    let symbol = c"posix_spawn_file_actions_addchdir_np";
+    !unsafe { libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr()) }.is_null()
+}
+
+/// A `Command` plus the parent-side ends of any socket-backed stdio streams,
+/// which have to be created before the child is spawned.
+struct PreparedCommand {
+    command: Command,
+    stdin: Option<StdioStream>,
+    stdout: Option<StdioStream>,
+    stderr: Option<StdioStream>,
+    pty_master: Option<File>,
+    method: SpawnMethod,
+    error_pipe: pre_exec::ErrorPipe,
+    modes: [StdioMode; 3],
+    /// The parent's descriptors for `:tmpfile` stdout and stderr.
+    stdout_tmpfile: Option<File>,
+    stderr_tmpfile: Option<File>,
+}
+
+fn stdio_for(
+    config: &StdioConfig,
+    name: &str,
+    input: bool,
+    file_options: Option<&FileOptions>,
+) -> NifResult<(Stdio, Option<StdioStream>)> {
+    match config {
+        StdioConfig::Null => Ok((Stdio::null(), None)),
+        StdioConfig::Pipe => Ok((Stdio::piped(), None)),
+        StdioConfig::Socket => {
+            let (ours, theirs) = UnixStream::pair().map_err(|e| {
+                Error::Term(Box::new(format!(
+                    "Failed to create {} socket pair: {}",
+                    name, e
+                )))
+            })?;
+            Ok((
+                Stdio::from(OwnedFd::from(theirs)),
+                Some(StdioStream::socket(ours)),
+            ))
+        }
+        StdioConfig::Inherit => Ok((Stdio::inherit(), None)),
+        StdioConfig::File(path) => {
+            let file = if input {
+                File::open(path).map_err(|e| {
+                    Error::Term(Box::new(format!(
+                        "Failed to open {} file {}: {}",
+                        name, path, e
+                    )))
+                })?
+            } else {
+                let opened = match file_options {
+                    Some(file_options) => file_options.open_options()?.open(path),
+                    None => File::create(path),
+                };
+                opened.map_err(|e| match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => {
+                        Error::Term(Box::new((atoms::file_exists(), path.clone())))
+                    }
+                    _ => Error::Term(Box::new(format!(
+                        "Failed to create {} file {}: {}",
+                        name, path, e
+                    ))),
+                })?
+            };
+            Ok((Stdio::from(file), None))
+        }
+        StdioConfig::Tmpfile => Err(Error::Term(Box::new(format!(
+            "tmpfile mode is not supported for {}",
+            name
+        )))),
+    }
+}
+
+/// `stdio_for` for stdout and stderr, which may also be `:tmpfile`: the
+/// child gets the anonymous file, and the parent keeps a descriptor for it.
+fn output_stdio_for(
+    config: &StdioConfig,
+    name: &str,
+    file_options: Option<&FileOptions>,
+) -> NifResult<(Stdio, Option<StdioStream>, Option<File>)> {
+    let StdioConfig::Tmpfile = config else {
+        let (stdio, stream) = stdio_for(config, name, false, file_options)?;
+        return Ok((stdio, stream, None));
+    };
+    let tmpfile_error = |e: std::io::Error| {
+        Error::Term(Box::new(format!(
+            "Failed to create {} tmpfile: {}",
+            name, e
+        )))
+    };
+    let file = tmpfile::create().map_err(tmpfile_error)?;
+    let theirs = file.try_clone().map_err(tmpfile_error)?;
+    Ok((Stdio::from(theirs), None, Some(file)))
+}
+
+/// Variables set explicitly on the child: with `clear_env`, the whitelisted
+/// `inherit_env` variables that exist in the BEAM's environment, then those
+/// from `env_file`, then the `env` additions. Later entries win. Last come
+/// the `env_defaults` whose key the child would not otherwise have.
+fn explicit_env(
+    env: Vec<(String, String)>,
+    options: &SpawnOptions,
+) -> NifResult<Vec<(OsString, OsString)>> {
+    let inherited = options
+        .inherit_env
+        .iter()
+        .filter(|_| options.clear_env)
+        .filter_map(|key| std::env::var_os(key).map(|value| (OsString::from(key), value)));
+    let from_file = match &options.env_file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path).map_err(|e| {
+                Error::Term(Box::new(format!("Failed to read env file {}: {}", path, e)))
+            })?;
+            env_file::parse(&contents)
+                .map_err(|line| Error::Term(Box::new((atoms::invalid_env_file(), line))))?
+        }
+        None => Vec::new(),
+    };
+    let added = from_file
+        .into_iter()
+        .chain(env)
+        .map(|(key, value)| (OsString::from(key), OsString::from(value)));
+    let mut explicit: Vec<(OsString, OsString)> = inherited.chain(added).collect();
+    for (key, value) in &options.env_defaults {
+        let key = OsString::from(key);
+        let inherits = !options.clear_env && std::env::var_os(&key).is_some();
+        if !inherits && !explicit.iter().any(|(set, _)| *set == key) {
+            explicit.push((key, OsString::from(value)));
+        }
+    }
+    Ok(explicit)
+}
+
+/// Checks the command line and environment for what `execve` would reject,
+/// so it is reported before anything is created rather than as a generic
+/// spawn failure: a NUL byte in `cmd`, an argument or a variable, which no C
+/// string can carry, or strings adding up to more than `ARG_MAX` (on Linux,
+/// also a single string over `MAX_ARG_STRLEN`). `explicit` is what
+/// `explicit_env` returns, so `env_file` variables are checked too. Returns
+/// the error for the first problem found.
+fn exec_strings_error<'a>(
+    env: Env<'a>,
+    cmd: &str,
+    arguments: &[String],
+    explicit: &[(OsString, OsString)],
+    options: &SpawnOptions,
+) -> Option<Term<'a>> {
+    if cmd.contains('\0') {
+        return Some((atoms::error(), atoms::invalid_command()).encode(env));
+    }
+    if let Some(index) = arguments.iter().position(|arg| arg.contains('\0')) {
+        return Some((atoms::error(), atoms::invalid_arg(), index).encode(env));
+    }
+    let nul = |s: &OsString| s.as_bytes().contains(&0);
+    if let Some((key, _)) = explicit.iter().find(|(key, value)| nul(key) || nul(value)) {
+        let key = key.to_string_lossy();
+        return Some((atoms::error(), atoms::invalid_env(), key.as_ref()).encode(env));
+    }
+
+    let mut environment: std::collections::BTreeMap<OsString, OsString> = if options.clear_env {
+        Default::default()
+    } else {
+        std::env::vars_os().collect()
+    };
+    environment.extend(explicit.iter().cloned());
+
+    // Each string is copied with its NUL, and argv and envp are arrays of
+    // pointers ending in a null one.
+    let argv = std::iter::once(cmd.len()).chain(arguments.iter().map(String::len));
+    let envp = environment
+        .iter()
+        .map(|(key, value)| key.len() + 1 + value.len());
+    let lengths: Vec<usize> = argv.chain(envp).collect();
+    let pointers = (lengths.len() + 2) * std::mem::size_of::<usize>();
+    let total: usize = lengths.iter().map(|len| len + 1).sum::<usize>() + pointers;
+
+    // Linux caps each string at 32 pages, whatever ARG_MAX allows.
+    let too_long_string = cfg!(target_os = "linux") && lengths.iter().any(|&len| len >= 32 * 4096);
+    let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    if too_long_string || (arg_max > 0 && total > arg_max as usize) {
+        return Some((atoms::error(), atoms::arg_list_too_long()).encode(env));
+    }
+    None
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_command(
+    cmd: &str,
+    arguments: &[String],
+    stdin_mode: &str,
+    stdin_path: &str,
+    stdout_mode: &str,
+    stdout_path: &str,
+    stderr_mode: &str,
+    stderr_path: &str,
+    env: Vec<(OsString, OsString)>,
+    cd: &str,
+    options: &SpawnOptions,
+) -> NifResult<PreparedCommand> {
+    let stdin_config = parse_stdio_config(stdin_mode, stdin_path)?;
+    let stdout_config = parse_stdio_config(stdout_mode, stdout_path)?;
+    let stderr_config = parse_stdio_config(stderr_mode, stderr_path)?;
+
+    let name = cmd;
+    let resolved = options.search(cmd, cd)?;
+    let cmd = resolved.as_deref().unwrap_or(cmd);
+
+    #[cfg(target_os = "linux")]
+    let exec_at = (options.exec_dirfd.is_some() || options.exec_fd.is_some())
+        .then(|| {
+            let mut environment: std::collections::BTreeMap<_, _> = if options.clear_env {
+                Default::default()
+            } else {
+                std::env::vars_os().collect()
+            };
+            environment.extend(env.iter().cloned());
+            match options.exec_fd {
+                Some(fd) => exec_at::ExecAt::fd(fd, cmd, arguments, environment),
+                None => exec_at::ExecAt::new(
+                    options.exec_dirfd.unwrap_or_default(),
+                    cmd,
+                    arguments,
+                    environment,
+                ),
+            }
+        })
+        .transpose()
+        .map_err(|e| Error::Term(Box::new(format!("Invalid exec argument: {}", e))))?;
+    #[cfg(not(target_os = "linux"))]
+    if options.exec_dirfd.is_some() {
+        return Err(Error::Term(Box::new(
+            "exec_dirfd is only supported on Linux",
+        )));
+    }
+    #[cfg(not(target_os = "linux"))]
+    if options.start_stopped {
+        return Err(Error::Term(Box::new(
+            "start_stopped is only supported on Linux",
+        )));
+    }
+    #[cfg(target_os = "linux")]
+    let unshare = (!options.namespaces.is_empty())
+        .then(|| namespaces::Unshare::new(&options.namespaces))
+        .transpose()?;
+    #[cfg(not(target_os = "linux"))]
+    if !options.namespaces.is_empty() {
+        return Err(Error::Term(Box::new(
+            "namespaces is only supported on Linux",
+        )));
+    }
+    #[cfg(target_os = "linux")]
+    let join_cgroup = options
+        .cgroup
+        .as_deref()
+        .map(|dir| cgroup::Join::new(Path::new(dir)))
+        .transpose()?;
+    #[cfg(not(target_os = "linux"))]
+    if options.cgroup.is_some() {
+        return Err(Error::Term(Box::new("cgroup is only supported on Linux")));
+    }
+    options.validate()?;
+
+    let mut command = Command::new(cmd);
+    if resolved.is_some() {
+        command.arg0(name);
+    }
+    command.args(arguments);
+
+    if options.clear_env {
+        command.env_clear();
+    }
+    for (key, value) in &env {
+        command.env(key, value);
+    }
+
+    if !cd.is_empty() {
+        command.current_dir(cd);
+    }
+
+    let error_pipe = pre_exec::ErrorPipe::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create error pipe: {}", e))))?;
+    let reporter = error_pipe.reporter();
+    // Set wherever a `pre_exec` hook is registered, each of which sends std
+    // down the fork path.
+    let mut pre_exec = false;
+
+    let (stdin, stdout, stderr, pty_master, stdout_tmpfile, stderr_tmpfile) = if options.pty {
+        let pty_error =
+            |e: std::io::Error| Error::Term(Box::new(format!("Failed to open pty: {}", e)));
+        let pty::Pty { master, slave } = pty::open().map_err(pty_error)?;
+        let clone = |file: &File| file.try_clone().map_err(pty_error);
+
+        command.stdin(Stdio::from(clone(&slave)?));
+        command.stdout(Stdio::from(clone(&slave)?));
+        command.stderr(Stdio::from(slave));
+        unsafe {
+            command.pre_exec(move || pty::attach_controlling_terminal(reporter));
+        }
+        pre_exec = true;
+
+        let stdin = StdioStream::pty(clone(&master)?);
+        let stdout = StdioStream::pty(clone(&master)?);
+        (Some(stdin), Some(stdout), None, Some(master), None, None)
+    } else {
+        let (stdin_stdio, stdin) = stdio_for(&stdin_config, "stdin", true, None)?;
+        let (stdout_stdio, stdout, stdout_tmpfile) =
+            output_stdio_for(&stdout_config, "stdout", options.stdout_file.as_ref())?;
+        let (stderr_stdio, stderr, stderr_tmpfile) =
+            output_stdio_for(&stderr_config, "stderr", options.stderr_file.as_ref())?;
+        command.stdin(stdin_stdio);
+        command.stdout(stdout_stdio);
+        command.stderr(stderr_stdio);
+        (stdin, stdout, stderr, None, stdout_tmpfile, stderr_tmpfile)
+    };
+
+    // Joined first, so everything after, including a new user namespace,
+    // happens inside the cgroup.
+    #[cfg(target_os = "linux")]
+    if let Some(join_cgroup) = join_cgroup {
+        unsafe {
+            command.pre_exec(move || join_cgroup.enter(reporter));
+        }
+        pre_exec = true;
+    }
+
+    if !options.file_actions.is_empty() {
+        let actions = options.file_actions.clone();
+        unsafe {
+            command.pre_exec(move || file_actions::apply(&actions, reporter));
+        }
+        pre_exec = true;
+    }
+
+    if let Some(pgid) = options.pgid {
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpgid(0, pgid) == -1 {
+                    return Err(reporter.fail(pre_exec::Step::ProcessGroup));
+                }
+                Ok(())
+            });
+        }
+        pre_exec = true;
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(unshare) = unshare {
+        unsafe {
+            command.pre_exec(move || unshare.enter(reporter));
+        }
+        pre_exec = true;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.pdeathsig {
+        let parent = unsafe { libc::getpid() };
+        unsafe {
+            command.pre_exec(move || {
+                let result = libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                if result == -1 {
+                    return Err(reporter.fail(pre_exec::Step::Pdeathsig));
+                }
+                // If the BEAM died between fork and prctl, the signal was
+                // armed against whoever adopted us and will never fire.
+                if libc::getppid() != parent {
+                    return Err(reporter.fail_with(pre_exec::Step::Pdeathsig, libc::ESRCH));
+                }
+                Ok(())
+            });
+        }
+        pre_exec = true;
+    }
+
+    #[cfg(target_os = "linux")]
+    if options.start_stopped {
+        unsafe {
+            command.pre_exec(move || start_stopped::trace_me(reporter));
+        }
+        pre_exec = true;
+    }
+
+    // Registered last so every other setup step has run before the exec.
+    #[cfg(target_os = "linux")]
+    if let Some(exec_at) = exec_at {
+        unsafe {
+            command.pre_exec(move || Err(exec_at.exec(reporter)));
+        }
+        pre_exec = true;
+    }
+    let method = SpawnMethod::predict(cmd, &env, cd, pre_exec);
+
+    let modes = if options.pty {
+        [StdioMode::Pty; 3]
+    } else {
+        let stdout_mode = if options.stdout_tail.is_some() {
+            StdioMode::Tail
+        } else if options.stdout_log.is_some() {
+            StdioMode::Log
+        } else if options.stdout_fanout {
+            StdioMode::Fanout
+        } else if options.stdout_active {
+            StdioMode::Active
+        } else {
+            stdout_config.mode()
+        };
+        [
+            match options.stdin_repeat {
+                Some(_) => StdioMode::Repeat,
+                None => stdin_config.mode(),
+            },
+            stdout_mode,
+            if options.stderr_tail.is_some() {
+                StdioMode::Tail
+            } else if options.stderr_log.is_some() {
+                StdioMode::Log
+            } else if options.stderr_active {
+                StdioMode::Active
+            } else {
+                stderr_config.mode()
+            },
+        ]
+    };
+
+    if let Some(i) = options.ready.as_ref().and_then(ready::Probe::stream) {
+        if !matches!(modes[i + 1], StdioMode::Pipe) {
+            return Err(Error::Term(Box::new(
+                "a ready output probe requires its stream to be :pipe",
+            )));
+        }
+    }
+
+    Ok(PreparedCommand {
+        command,
+        stdin,
+        stdout,
+        stderr,
+        pty_master,
+        method,
+        error_pipe,
+        modes,
+        stdout_tmpfile,
+        stderr_tmpfile,
+    })
+}
+
+/// The result of a successful spawn, `{resource, pid, stdio}`, where
+/// `stdio` is `%{stdin: mode, stdout: mode, stderr: mode, pty?: bool}` with
+/// the modes `stdio_modes_nif` reports. With `stdio_info` false, the older
+/// `{resource, pid}` of `spawn_compat_nif`.
+fn encode_spawned<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    pid: i32,
+    stdio_info: bool,
+) -> NifResult<Term<'a>> {
+    if !stdio_info {
+        return Ok((resource, pid).encode(env));
+    }
+    let [stdin, stdout, stderr] = resource.stdio_modes;
+    let keys = [
+        atoms::stdin(),
+        atoms::stdout(),
+        atoms::stderr(),
+        atoms::pty_q(),
+    ];
+    let values = [
+        stdin.encode(env),
+        stdout.encode(env),
+        stderr.encode(env),
+        resource.pty_master.is_some().encode(env),
+    ];
+    let keys: Vec<Term> = keys.iter().map(|key| key.encode(env)).collect();
+    let stdio = Term::map_from_term_arrays(env, &keys, &values)?;
+    Ok((resource, pid, stdio).encode(env))
+}
+
+fn spawn_command<'a>(
+    env: Env<'a>,
+    prepared: PreparedCommand,
+    options: &SpawnOptions,
+    stdio_info: bool,
+) -> NifResult<Term<'a>> {
+    let cleanup_signal = options.cleanup_signal.map(parse_signal).transpose()?;
+
+    let PreparedCommand {
+        mut command,
+        stdin,
+        stdout,
+        stderr,
+        pty_master,
+        method,
+        error_pipe,
+        modes,
+        stdout_tmpfile,
+        stderr_tmpfile,
+    } = prepared;
+
+    let stdout_log_file = open_log(options.stdout_log.as_deref(), "stdout")?;
+    let stderr_log_file = open_log(options.stderr_log.as_deref(), "stderr")?;
+
+    let Some(slot) = registry::acquire_slot() else {
+        return Ok((atoms::error(), atoms::too_many_children()).encode(env));
+    };
+
+    let spawned_at = Instant::now();
+
+    // `spawn` returns only once the exec has happened or failed: on the fork
+    // path std waits on its own close-on-exec pipe, which a successful exec
+    // closes and a failed one (or a failed `pre_exec` step) writes an errno
+    // to, and posix_spawn reports exec errors itself. A child we get back is
+    // already running the target program.
+    match command.spawn() {
+        Ok(mut child) => {
+            let pid = child.id() as i32;
+
+            // The child's copies of the socket ends were dropped along with
+            // `command`'s Stdio; only our ends remain.
+            drop(command);
+
+            #[cfg(target_os = "linux")]
+            if options.start_stopped {
+                if let Err(e) = start_stopped::detach_stopped(pid) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    let errno = e.raw_os_error().unwrap_or(0);
+                    let step = pre_exec::Step::TraceMe.atom();
+                    return Ok((atoms::error(), step, errno).encode(env));
+                }
+            }
+
+            if let Some(adj) = options.oom_score_adj {
+                let path = format!("/proc/{}/oom_score_adj", pid);
+                if let Err(e) = std::fs::write(path, adj.to_string()) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    let errno = e.raw_os_error().unwrap_or(0);
+                    return Ok((atoms::error(), atoms::oom_score_adj_failed(), errno).encode(env));
+                }
+            }
+
+            // macOS has no PDEATHSIG; a watchdog process stands in for it.
+            #[cfg(target_os = "macos")]
+            if options.pdeathsig {
+                if let Err(e) = parent_watch::watch(pid) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    let errno = e.raw_os_error().unwrap_or(0);
+                    let step = pre_exec::Step::Pdeathsig.atom();
+                    return Ok((atoms::error(), step, errno).encode(env));
+                }
+            }
+
+            let mut stdin_pipe = stdin.or_else(|| child.stdin.take().map(StdioStream::pipe));
+            if let Some(repeat) = &options.stdin_repeat {
+                if let Some(stream) = stdin_pipe.take() {
+                    let fed = repeat.feed(stream.file).map_err(|e| {
+                        Error::Term(Box::new(format!("Failed to start stdin repeat: {}", e)))
+                    });
+                    abandon(&mut child, fed)?;
+                }
+            }
+            let mut stdout_pipe = stdout.or_else(|| child.stdout.take().map(StdioStream::pipe));
+            let mut stderr_pipe = stderr.or_else(|| child.stderr.take().map(StdioStream::pipe));
+
+            for stream in [&mut stdout_pipe, &mut stderr_pipe].into_iter().flatten() {
+                stream.lines = options.lines;
+            }
+
+            let drain_buf = options.drain_buf_bytes.unwrap_or(READ_CHUNK);
+            let interleaved = options.interleaved_log.map(interleave::Interleaved::new);
+            let tagged = |stream| interleaved.clone().map(|log| (log, stream));
+            let stdout_tail = abandon(
+                &mut child,
+                drain_tail(
+                    &mut stdout_pipe,
+                    options.stdout_tail,
+                    drain_buf,
+                    tagged(interleave::Stream::Stdout),
+                    "stdout",
+                ),
+            )?;
+            let stderr_tail = abandon(
+                &mut child,
+                drain_tail(
+                    &mut stderr_pipe,
+                    options.stderr_tail,
+                    drain_buf,
+                    tagged(interleave::Stream::Stderr),
+                    "stderr",
+                ),
+            )?;
+            let stdout_log = abandon(
+                &mut child,
+                drain_log(&mut stdout_pipe, stdout_log_file, drain_buf, "stdout"),
+            )?;
+            let stderr_log = abandon(
+                &mut child,
+                drain_log(&mut stderr_pipe, stderr_log_file, drain_buf, "stderr"),
+            )?;
+            let stdout_fanout = match stdout_pipe.take_if(|_| options.stdout_fanout) {
+                Some(stream) => {
+                    let fanout = fanout::Fanout::start(stream.file, pid, drain_buf).map_err(|e| {
+                        Error::Term(Box::new(format!("Failed to start stdout fan-out: {}", e)))
+                    });
+                    Some(abandon(&mut child, fanout)?)
+                }
+                None => None,
+            };
+            let active = [
+                stdout_pipe.take_if(|_| options.stdout_active),
+                stderr_pipe.take_if(|_| options.stderr_active),
+            ]
+            .map(|stream| stream.map(|stream| stream.file));
+            let mut ready_splice = None;
+            if let Some(i) = options.ready.as_ref().and_then(ready::Probe::stream) {
+                let pipe = if i == 0 {
+                    &mut stdout_pipe
+                } else {
+                    &mut stderr_pipe
+                };
+                if let Some(stream) = pipe.take() {
+                    let spliced = ready::splice(stream.file).map_err(|e| {
+                        Error::Term(Box::new(format!("Failed to start ready probe: {}", e)))
+                    });
+                    let (parent_end, splice) = abandon(&mut child, spliced)?;
+                    *pipe = Some(StdioStream {
+                        file: parent_end,
+                        ..stream
+                    });
+                    ready_splice = Some(splice);
+                }
+            }
+
+            let mut nonblocking = [false; 3];
+            if options.nonblocking {
+                let streams = [
+                    ("stdin", stdin_pipe.as_ref()),
+                    ("stdout", stdout_pipe.as_ref()),
+                    ("stderr", stderr_pipe.as_ref()),
+                ];
+                for (i, (name, stream)) in streams.into_iter().enumerate() {
+                    let Some(stream) = stream else { continue };
+                    match set_nonblocking(stream) {
+                        Ok(()) => nonblocking[i] = true,
+                        Err(_) if options.tolerate_nonblock_errors => {}
+                        Err(e) => {
+                            return Err(Error::Term(Box::new(format!(
+                                "Failed to set {} non-blocking: {}",
+                                name, e
+                            ))))
+                        }
+                    }
+                }
+            }
+
+            let resource = ResourceArc::new(ProcessResource {
+                pid,
+                child: Mutex::new(Some(child)),
+                exit_status: Mutex::new(None),
+                siginfo: Mutex::new(None),
+                exited: AtomicBool::new(false),
+                spawned_at,
+                exited_at: Mutex::new(None),
+                last_io_ns: AtomicU64::new(0),
+                stdin_written: AtomicU64::new(0),
+                stdout_read: AtomicU64::new(0),
+                stderr_read: AtomicU64::new(0),
+                registration: registry::register(pid, cleanup_signal, slot),
+                stdin_pipe: Mutex::new(stdin_pipe),
+                stdout_pipe: Mutex::new(stdout_pipe),
+                stderr_pipe: Mutex::new(stderr_pipe),
+                pty_master,
+                method,
+                stdio_modes: modes,
+                nonblocking: nonblocking.map(AtomicBool::new),
+                start_time: start_time(pid),
+                waiters: Default::default(),
+                pidfd: wait_cancel::pidfd_open(pid),
+                last_signal: AtomicI32::new(0),
+                stdout_tail,
+                stderr_tail,
+                interleaved,
+                stdout_log,
+                stderr_log,
+                stdout_tmpfile,
+                stderr_tmpfile,
+                stdout_fanout,
+                watchdog: Default::default(),
+                lifetime: Default::default(),
+                lifetime_grace_ms: AtomicU64::new(0),
+                drain_buf,
+                adopted: false,
+                kill_group_on_drop: options.kill_group_on_drop,
+                cgroup: options.cgroup.as_ref().map(PathBuf::from),
+            });
+            if let Some(deadline) = options.timeout_ms.and_then(deadline_after) {
+                let watchdog = start_watchdog(resource.clone(), deadline)
+                    .map_err(|e| Error::Term(Box::new(format!("Failed to start watchdog: {}", e))));
+                abandon_resource(&resource, watchdog)?;
+            }
+            if let (Some(owner), true) = (options.owner, active.iter().any(Option::is_some)) {
+                let streaming = stream_active(resource.clone(), active, owner).map_err(|e| {
+                    Error::Term(Box::new(format!("Failed to start active output: {}", e)))
+                });
+                abandon_resource(&resource, streaming)?;
+            }
+            if let (Some(probe), Some(owner)) = (options.ready.clone(), options.owner) {
+                let deadline = options.ready_timeout_ms.and_then(deadline_after);
+                let probing =
+                    start_ready_probe(resource.clone(), probe, ready_splice, deadline, owner)
+                        .map_err(|e| {
+                            Error::Term(Box::new(format!("Failed to start ready probe: {}", e)))
+                        });
+                abandon_resource(&resource, probing)?;
+            }
+            if let Some(owner) = options.notify_exit {
+                let watcher = notify_exit(resource.clone(), owner).map_err(|e| {
+                    Error::Term(Box::new(format!("Failed to start exit watcher: {}", e)))
+                });
+                abandon_resource(&resource, watcher)?;
+            }
+            encode_spawned(env, resource, pid, stdio_info)
+        }
+        Err(e) => match (error_pipe.into_failure(), spawn_error_kind(&e)) {
+            (Some((step, errno)), _) => Ok((atoms::error(), step.atom(), errno).encode(env)),
+            (None, Some(kind)) if e.raw_os_error() == Some(libc::ENOENT) => {
+                match missing_interpreter(&command) {
+                    Some(interpreter) => {
+                        let interpreter = interpreter.to_string_lossy().into_owned();
+                        Ok((atoms::error(), atoms::bad_interpreter(), interpreter).encode(env))
+                    }
+                    None => Ok((atoms::error(), kind, e.to_string()).encode(env)),
+                }
+            }
+            (None, Some(kind)) if e.raw_os_error() == Some(libc::EACCES) => {
+                match inaccessible_program(&command) {
+                    Some(Inaccessible::Directory) => {
+                        Ok((atoms::error(), atoms::is_a_directory()).encode(env))
+                    }
+                    Some(Inaccessible::NoExecuteBits(mode)) => {
+                        Ok((atoms::error(), atoms::not_executable(), mode).encode(env))
+                    }
+                    None => Ok((atoms::error(), kind, e.to_string()).encode(env)),
+                }
+            }
+            (None, Some(kind)) => Ok((atoms::error(), kind, e.to_string()).encode(env)),
+            (None, None) => Err(Error::Term(Box::new(format!("Failed to spawn: {}", e)))),
+        },
+    }
+}
+
+/// Passes `result` through, first killing and reaping `child` if it is an
+/// error: a spawn that fails after the fork must not leave the child running
+/// with nothing left to own it.
+fn abandon<T>(child: &mut Child, result: NifResult<T>) -> NifResult<T> {
+    if result.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    result
+}
+
+/// `abandon` for a child that already belongs to a resource. No handle
+/// reaches Elixir on error, and threads started so far may hold the
+/// resource open, so the child is terminated here rather than on drop.
+fn abandon_resource<T>(resource: &ProcessResource, result: NifResult<T>) -> NifResult<T> {
+    if result.is_err() {
+        let _ = terminate(resource, None, Duration::ZERO);
+    }
+    result
+}
+
+/// A working directory that is missing or not a directory, as the path to
+/// report in `{:error, :bad_cwd, path}`. Checked up front because exec would
+/// fail with the same ENOENT as a missing program, and before any stdio is
+/// opened, so a failed spawn leaves `{:file, path}` outputs untruncated.
+fn bad_cwd(cd: &str) -> Option<String> {
+    let dir = Path::new(cd);
+    (!cd.is_empty() && !dir.is_dir()).then(|| cd.to_string())
+}
+
+/// Classifies the common reasons `exec` fails into stable atoms, so callers
+/// need not match on OS messages.
+fn spawn_error_kind(error: &std::io::Error) -> Option<rustler::Atom> {
+    match error.raw_os_error()? {
+        libc::ENOENT => Some(atoms::not_found()),
+        libc::EACCES => Some(atoms::permission_denied()),
+        libc::ENOEXEC => Some(atoms::not_executable()),
+        libc::EMFILE | libc::ENFILE => Some(atoms::too_many_files()),
+        _ => None,
+    }
+}
+
+/// After an ENOENT from exec, tells a missing script apart from a script
+/// whose `#!` interpreter is missing: if the program resolves to an
+/// executable file starting with a shebang, returns the interpreter path
+/// when that doesn't exist.
+fn missing_interpreter(command: &Command) -> Option<PathBuf> {
+    let path = command
+        .get_envs()
+        .find(|(key, _)| *key == "PATH")
+        .and_then(|(_, value)| value.map(OsString::from));
+    let cwd = command.get_current_dir();
+    let script = resolve_program(Path::new(command.get_program()), path, cwd)?;
+    if !is_executable_file(&script) {
+        return None;
+    }
+    missing_shebang_interpreter(&script, cwd)
+}
+
+/// Why exec of an existing program failed with EACCES, when the program
+/// itself is the problem.
+enum Inaccessible {
+    Directory,
+    /// A regular file with no execute bits, with its permission bits.
+    NoExecuteBits(u32),
+}
+
+/// After an EACCES from exec, tells a directory or a file missing its
+/// execute bits apart from other causes (a `noexec` mount, an unsearchable
+/// directory on the way, execute bits for other users only). A bare name
+/// is looked up on the PATH like exec does, taking the first entry that
+/// exists at all.
+fn inaccessible_program(command: &Command) -> Option<Inaccessible> {
+    let program = Path::new(command.get_program());
+    let cwd = command.get_current_dir();
+    let in_cwd = |path: &Path| match cwd {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    };
+
+    let program = if program.as_os_str().as_bytes().contains(&b'/') {
+        in_cwd(program)
+    } else {
+        let path = command
+            .get_envs()
+            .find(|(key, _)| *key == "PATH")
+            .and_then(|(_, value)| value.map(OsString::from))
+            .or_else(|| std::env::var_os("PATH"))?;
+        std::env::split_paths(&path)
+            .map(|dir| in_cwd(&dir.join(program)))
+            .find(|candidate| candidate.exists())?
+    };
+    inaccessible(&program)
+}
+
+/// Whether `program` is a directory or a file without execute bits.
+fn inaccessible(program: &Path) -> Option<Inaccessible> {
+    let metadata = std::fs::metadata(program).ok()?;
+    let mode = metadata.permissions().mode();
+    if metadata.is_dir() {
+        Some(Inaccessible::Directory)
+    } else if metadata.is_file() && mode & 0o111 == 0 {
+        Some(Inaccessible::NoExecuteBits(mode & 0o7777))
+    } else {
+        None
+    }
+}
+
+/// Where exec looks for `program`: relative to `cwd` if the name contains a
+/// slash, otherwise the first executable match on `path` (the BEAM's `PATH`
+/// when not overridden). `None` if the PATH search finds nothing.
+fn resolve_program(program: &Path, path: Option<OsString>, cwd: Option<&Path>) -> Option<PathBuf> {
+    let in_cwd = |path: &Path| match cwd {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    };
+
+    if program.as_os_str().as_bytes().contains(&b'/') {
+        return Some(in_cwd(program));
+    }
+    let path = path.or_else(|| std::env::var_os("PATH"))?;
+    std::env::split_paths(&path)
+        .map(|dir| in_cwd(&dir.join(program)))
+        .find(|candidate| is_executable_file(candidate))
+}
+
+/// The interpreter on the `#!` line of `script`, if that doesn't exist.
+fn missing_shebang_interpreter(script: &Path, cwd: Option<&Path>) -> Option<PathBuf> {
+    let mut head = [0u8; 256];
+    let len = File::open(script).ok()?.read(&mut head).ok()?;
+    let line = head[..len].strip_prefix(b"#!")?;
+    let line = &line[..line.iter().position(|&b| b == b'\n').unwrap_or(line.len())];
+    let interpreter = line
+        .split(|b| b.is_ascii_whitespace())
+        .find(|word| !word.is_empty())?;
+    let interpreter = PathBuf::from(OsStr::from_bytes(interpreter));
+
+    let exists = match cwd {
+        Some(dir) if interpreter.is_relative() => dir.join(&interpreter).exists(),
+        _ => interpreter.exists(),
+    };
+    (!exists).then_some(interpreter)
+}
+
+fn is_executable_file(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Spawns `cmd`, returning `{resource, pid, stdio}` (see `encode_spawned`)
+/// or an error.
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+fn spawn_nif<'a>(
+    env: Env<'a>,
+    cmd: String,
+    arguments: Vec<String>,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+) -> NifResult<Term<'a>> {
+    spawn_args(
+        env,
+        cmd,
+        arguments,
+        [
+            stdin_mode,
+            stdin_path,
+            stdout_mode,
+            stdout_path,
+            stderr_mode,
+            stderr_path,
+        ],
+        env_vars,
+        cd,
+        options,
+        true,
+    )
+}
+
+/// Splits `command_line` into words like a shell would (see
+/// `command_line::split`) and spawns the first with the rest as arguments.
+/// An unbalanced quote or a trailing `\` is `{:error, :parse_error, offset}`.
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+fn spawn_parse_nif<'a>(
+    env: Env<'a>,
+    command_line: String,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+) -> NifResult<Term<'a>> {
+    let mut words = match command_line::split(&command_line) {
+        Ok(words) => words.into_iter(),
+        Err(offset) => return Ok((atoms::error(), atoms::parse_error(), offset).encode(env)),
+    };
+    let Some(cmd) = words.next() else {
+        return Err(Error::Term(Box::new("empty command line")));
+    };
+    spawn_args(
+        env,
+        cmd,
+        words.collect(),
+        [
+            stdin_mode,
+            stdin_path,
+            stdout_mode,
+            stdout_path,
+            stderr_mode,
+            stderr_path,
+        ],
+        env_vars,
+        cd,
+        options,
+        true,
+    )
+}
+
+/// `spawn_nif` returning the older `{resource, pid}`, without the stdio
+/// description, for callers written against it.
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+fn spawn_compat_nif<'a>(
+    env: Env<'a>,
+    cmd: String,
+    arguments: Vec<String>,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+) -> NifResult<Term<'a>> {
+    spawn_args(
+        env,
+        cmd,
+        arguments,
+        [
+            stdin_mode,
+            stdin_path,
+            stdout_mode,
+            stdout_path,
+            stderr_mode,
+            stderr_path,
+        ],
+        env_vars,
+        cd,
+        options,
+        false,
+    )
+}
+
+/// The body of `spawn_nif`, `spawn_compat_nif`, `spawn_parse_nif`,
+/// `spawn_shell_nif` and `spawn_from_nif`, with the stdio modes and paths in
+/// their argument order.
+#[allow(clippy::too_many_arguments)]
+fn spawn_args<'a>(
+    env: Env<'a>,
+    cmd: String,
+    arguments: Vec<String>,
+    stdio: [String; 6],
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+    stdio_info: bool,
+) -> NifResult<Term<'a>> {
+    let [stdin_mode, stdin_path, stdout_mode, stdout_path, stderr_mode, stderr_path] = stdio;
+    let mut options = options;
+    options.resolve_inherit_or_pty();
+    let child_env = explicit_env(env_vars, &options)?;
+    if let Some(error) = exec_strings_error(env, &cmd, &arguments, &child_env, &options) {
+        return Ok(error);
+    }
+    if let Some(path) = bad_cwd(&cd) {
+        return Ok((atoms::error(), atoms::bad_cwd(), path).encode(env));
+    }
+    let command = build_command(
+        &cmd,
+        &arguments,
+        &stdin_mode,
+        &stdin_path,
+        &stdout_mode,
+        &stdout_path,
+        &stderr_mode,
+        &stderr_path,
+        child_env,
+        &cd,
+        &options,
+    )?;
+    spawn_command(env, command, &options, stdio_info)
+}
+
+/// The `spawn_nif` configuration minus the arguments, kept by
+/// `build_command_nif` so `spawn_from_nif` only has to be passed those.
+/// `Command` is not `Clone`, so one is built from this at every spawn.
+pub struct CommandTemplate {
+    cmd: String,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+}
+
+/// Checks the parts of a spawn configuration that cannot change between
+/// spawns (stdio modes, signals, `oom_score_adj`) and keeps it as a
+/// template.
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+fn build_command_nif(
+    cmd: String,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+) -> NifResult<ResourceArc<CommandTemplate>> {
+    let mut options = options;
+    options.resolve_inherit_or_pty();
+    parse_stdio_config(&stdin_mode, &stdin_path)?;
+    parse_stdio_config(&stdout_mode, &stdout_path)?;
+    parse_stdio_config(&stderr_mode, &stderr_path)?;
+    options.cleanup_signal.map(parse_signal).transpose()?;
+    options.validate()?;
+
+    Ok(ResourceArc::new(CommandTemplate {
+        cmd,
+        stdin_mode,
+        stdin_path,
+        stdout_mode,
+        stdout_path,
+        stderr_mode,
+        stderr_path,
+        env_vars,
+        cd,
+        options,
+    }))
+}
+
+/// Takes over a running process Px did not spawn, so the rest of the API
+/// can manage it. It is watched and signalled through a pidfd (Linux 5.3+),
+/// which keeps naming it after its PID is reused. Not being its parent, Px
+/// can neither reap it nor learn its exit status, so its exit is recorded
+/// as `:reaped_externally`. It has no pipes, takes no `max_children` slot
+/// and is sent no cleanup signal when the VM exits.
+///
+/// Returns `{:error, :not_found}` if there is no such process, or
+/// `{:error, :not_supported}` where pidfds are unavailable.
+#[rustler::nif]
+fn adopt_nif<'a>(env: Env<'a>, pid: i32) -> NifResult<Term<'a>> {
+    if pid <= 0 {
+        return Err(Error::BadArg);
+    }
+    let Some(pidfd) = wait_cancel::pidfd_open(pid) else {
+        let reason = match kill(Pid::from_raw(pid), None) {
+            Err(nix::errno::Errno::ESRCH) => atoms::not_found(),
+            _ => atoms::not_supported(),
+        };
+        return Ok((atoms::error(), reason).encode(env));
+    };
+
+    let resource = ResourceArc::new(ProcessResource {
+        pid,
+        child: Mutex::new(None),
+        exit_status: Mutex::new(None),
+        siginfo: Mutex::new(None),
+        exited: AtomicBool::new(false),
+        spawned_at: Instant::now(),
+        exited_at: Mutex::new(None),
+        last_io_ns: AtomicU64::new(0),
+        stdin_written: AtomicU64::new(0),
+        stdout_read: AtomicU64::new(0),
+        stderr_read: AtomicU64::new(0),
+        registration: registry::adopted(pid),
+        stdin_pipe: Mutex::new(None),
+        stdout_pipe: Mutex::new(None),
+        stderr_pipe: Mutex::new(None),
+        pty_master: None,
+        method: SpawnMethod::Adopted,
+        stdio_modes: [StdioMode::Null; 3],
+        nonblocking: Default::default(),
+        start_time: start_time(pid),
+        waiters: Default::default(),
+        pidfd: Some(pidfd),
+        last_signal: AtomicI32::new(0),
+        stdout_tail: None,
+        stderr_tail: None,
+        interleaved: None,
+        stdout_log: None,
+        stderr_log: None,
+        stdout_tmpfile: None,
+        stderr_tmpfile: None,
+        stdout_fanout: None,
+        watchdog: Default::default(),
+        lifetime: Default::default(),
+        lifetime_grace_ms: AtomicU64::new(0),
+        drain_buf: READ_CHUNK,
+        adopted: true,
+        kill_group_on_drop: false,
+        cgroup: None,
+    });
+    Ok((atoms::ok(), resource).encode(env))
+}
+
+/// Spawns from a `build_command_nif` template with `arguments`. Returns the
+/// same as `spawn_nif`.
+#[rustler::nif]
+fn spawn_from_nif(
+    env: Env,
+    template: ResourceArc<CommandTemplate>,
+    arguments: Vec<String>,
+) -> NifResult<Term> {
+    spawn_args(
+        env,
+        template.cmd.clone(),
+        arguments,
+        [
+            template.stdin_mode.clone(),
+            template.stdin_path.clone(),
+            template.stdout_mode.clone(),
+            template.stdout_path.clone(),
+            template.stderr_mode.clone(),
+            template.stderr_path.clone(),
+        ],
+        template.env_vars.clone(),
+        template.cd.clone(),
+        template.options.clone(),
+        true,
+    )
+}
+
+/// Checks a `spawn_nif` configuration without creating anything: stdio
+/// modes and files, options, the command line's strings, the environment
+/// file, the working directory and the executable. Returns `:ok`, or the
+/// error `spawn_nif` would return for the first problem found.
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+fn validate_spawn_nif<'a>(
+    env: Env<'a>,
+    cmd: String,
+    arguments: Vec<String>,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+) -> NifResult<Term<'a>> {
+    let mut options = options;
+    options.resolve_inherit_or_pty();
+    let stdin_config = parse_stdio_config(&stdin_mode, &stdin_path)?;
+    let stdout_config = parse_stdio_config(&stdout_mode, &stdout_path)?;
+    let stderr_config = parse_stdio_config(&stderr_mode, &stderr_path)?;
+    if !options.pty {
+        validate_stdio_file(&stdin_config, "stdin", true, None)?;
+        validate_stdio_file(
+            &stdout_config,
+            "stdout",
+            false,
+            options.stdout_file.as_ref(),
+        )?;
+        validate_stdio_file(
+            &stderr_config,
+            "stderr",
+            false,
+            options.stderr_file.as_ref(),
+        )?;
+    }
+    options.cleanup_signal.map(parse_signal).transpose()?;
+    let child_env = explicit_env(env_vars, &options)?;
+    if let Some(error) = exec_strings_error(env, &cmd, &arguments, &child_env, &options) {
+        return Ok(error);
+    }
+
+    let exec_error = |e: std::io::Error| match spawn_error_kind(&e) {
+        Some(kind) => Ok((atoms::error(), kind, e.to_string()).encode(env)),
+        None => Err(Error::Term(Box::new(format!("Failed to spawn: {}", e)))),
+    };
+
+    let cwd = (!cd.is_empty()).then(|| Path::new(&cd));
+    if let Some(path) = bad_cwd(&cd) {
+        return Ok((atoms::error(), atoms::bad_cwd(), path).encode(env));
+    }
+
+    if options.start_stopped && cfg!(not(target_os = "linux")) {
+        return Err(Error::Term(Box::new(
+            "start_stopped is only supported on Linux",
+        )));
+    }
+    if !options.namespaces.is_empty() {
+        #[cfg(target_os = "linux")]
+        namespaces::Unshare::new(&options.namespaces)?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(Error::Term(Box::new(
+            "namespaces is only supported on Linux",
+        )));
+    }
+    if let Some(dir) = &options.cgroup {
+        #[cfg(target_os = "linux")]
+        cgroup::Join::new(Path::new(dir))?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(Error::Term(Box::new("cgroup is only supported on Linux")));
+    }
+    options.validate()?;
+
+    if let Some(dirfd) = options.exec_dirfd {
+        if cfg!(not(target_os = "linux")) {
+            return Err(Error::Term(Box::new(
+                "exec_dirfd is only supported on Linux",
+            )));
+        }
+        return match fcntl(dirfd, FcntlArg::F_GETFD) {
+            Ok(_) => Ok(atoms::ok().encode(env)),
+            Err(errno) => {
+                let step = pre_exec::Step::ExecAt.atom();
+                Ok((atoms::error(), step, errno as i32).encode(env))
+            }
+        };
+    }
+
+    if let Some(fd) = options.exec_fd {
+        return match fcntl(fd, FcntlArg::F_GETFD) {
+            Ok(_) => Ok(atoms::ok().encode(env)),
+            Err(errno) => {
+                let step = pre_exec::Step::Fexecve.atom();
+                Ok((atoms::error(), step, errno as i32).encode(env))
+            }
+        };
+    }
+
+    let path = child_env
+        .iter()
+        .rev()
+        .find(|(key, _)| key == "PATH")
+        .map(|(_, value)| value.clone());
+    let program = match options.search(&cmd, &cd)? {
+        Some(program) => Some(PathBuf::from(program)),
+        None => resolve_program(Path::new(&cmd), path, cwd),
+    };
+    match program {
+        None => exec_error(std::io::Error::from_raw_os_error(libc::ENOENT)),
+        Some(program) if !program.exists() => {
+            exec_error(std::io::Error::from_raw_os_error(libc::ENOENT))
+        }
+        Some(program) if !is_executable_file(&program) => match inaccessible(&program) {
+            Some(Inaccessible::Directory) => {
+                Ok((atoms::error(), atoms::is_a_directory()).encode(env))
+            }
+            Some(Inaccessible::NoExecuteBits(mode)) => {
+                Ok((atoms::error(), atoms::not_executable(), mode).encode(env))
+            }
+            None => exec_error(std::io::Error::from_raw_os_error(libc::EACCES)),
+        },
+        Some(program) => match missing_shebang_interpreter(&program, cwd) {
+            Some(interpreter) => {
+                let interpreter = interpreter.to_string_lossy().into_owned();
+                Ok((atoms::error(), atoms::bad_interpreter(), interpreter).encode(env))
+            }
+            None => Ok(atoms::ok().encode(env)),
+        },
+    }
+}
+
+/// Checks that a file stdio config could be opened, without opening output
+/// files (which would create or truncate them): the file must be writable,
+/// or its directory must be if it doesn't exist yet.
+fn validate_stdio_file(
+    config: &StdioConfig,
+    name: &str,
+    input: bool,
+    file_options: Option<&FileOptions>,
+) -> NifResult<()> {
+    let StdioConfig::File(path) = config else {
+        return Ok(());
+    };
+    if input {
+        return File::open(path).map(drop).map_err(|e| {
+            Error::Term(Box::new(format!(
+                "Failed to open {} file {}: {}",
+                name, path, e
+            )))
+        });
+    }
+
+    let exclusive = match file_options {
+        Some(file_options) => {
+            file_options.open_options()?;
+            file_options.flags.contains(&atoms::exclusive())
+        }
+        None => false,
+    };
+    let file = Path::new(path);
+    let result = if file.exists() {
+        if exclusive {
+            return Err(Error::Term(Box::new((atoms::file_exists(), path.clone()))));
+        }
+        access(file, AccessFlags::W_OK)
+    } else {
+        let dir = file
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        access(dir, AccessFlags::W_OK | AccessFlags::X_OK)
+    };
+    result.map_err(|e| {
+        Error::Term(Box::new(format!(
+            "Failed to create {} file {}: {}",
+            name,
+            path,
+            std::io::Error::from(e)
+        )))
+    })
+}
+
+/// Resolves the shell used by `spawn_shell_nif`: an explicit path wins,
+/// then `$SHELL`, then `/bin/sh`.
+fn resolve_shell(shell: &str) -> String {
+    if !shell.is_empty() {
+        return shell.to_string();
+    }
+    match std::env::var("SHELL") {
+        Ok(value) if !value.is_empty() => value,
+        _ => "/bin/sh".to_string(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif]
+fn spawn_shell_nif<'a>(
+    env: Env<'a>,
+    command_line: String,
+    shell: String,
+    stdin_mode: String,
+    stdin_path: String,
+    stdout_mode: String,
+    stdout_path: String,
+    stderr_mode: String,
+    stderr_path: String,
+    env_vars: Vec<(String, String)>,
+    cd: String,
+    options: SpawnOptions,
+) -> NifResult<Term<'a>> {
+    spawn_args(
+        env,
+        resolve_shell(&shell),
+        vec!["-lc".to_string(), command_line],
+        [
+            stdin_mode,
+            stdin_path,
+            stdout_mode,
+            stdout_path,
+            stderr_mode,
+            stderr_path,
+        ],
+        env_vars,
+        cd,
+        options,
+        true,
+    )
+}
+
+#[rustler::nif]
+fn signal_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    signal: i32,
+    verify_identity: bool,
+) -> NifResult<Term<'a>> {
+    // The child lock is never held across a blocking wait, and reaping
+    // happens under it, so holding it here keeps the PID from being
+    // recycled until the signal has been sent.
+    let child_lock = lock(&resource.child)?;
+
+    if released(&resource, &child_lock)? {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+
+    let sig = parse_signal(signal)?;
+
+    // Exits we never saw (a SIGCHLD handler reaping behind our back) leave
+    // the PID free for reuse; a different start time means it was reused.
+    if verify_identity {
+        if let Some(expected) = resource.start_time {
+            if start_time(resource.pid) != Some(expected) {
+                return Ok((atoms::error(), atoms::identity_mismatch()).encode(env));
+            }
+        }
+    }
+
+    match send_signal(&resource, sig) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Sends `signal` to every process in group `pgid`, e.g. children spawned
+/// with `pgid:`. Returns `{:error, :not_found}` once the group is empty.
+#[rustler::nif]
+fn signal_group_nif(env: Env, pgid: i32, signal: i32) -> NifResult<Term> {
+    if pgid <= 0 {
+        return Err(Error::BadArg);
+    }
+    let sig = parse_signal(signal)?;
+    match nix::sys::signal::killpg(Pid::from_raw(pgid), sig) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(nix::errno::Errno::ESRCH) => Ok((atoms::error(), atoms::not_found()).encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// The start time of `pid` in clock ticks since boot: field 22 of
+/// `/proc/<pid>/stat`. The fields are counted from the end of the command
+/// name, which is parenthesised and may itself contain spaces or `)`.
+#[cfg(target_os = "linux")]
+fn start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_time(_pid: i32) -> Option<u64> {
+    None
+}
+
+/// Sends `signal` only if the child is in `expected` state, `:running` or
+/// `:stopped`, checked under the child lock that `signal_nif` and reaping
+/// hold, so no wait can reap it in between. Returns `{:error,
+/// :state_mismatch}` if it is in the other state and `{:error,
+/// :already_exited}` once it has exited, reaped or not.
+#[rustler::nif]
+fn signal_if_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    expected: rustler::Atom,
+    signal: i32,
+) -> NifResult<Term<'a>> {
+    let want_stopped = if expected == atoms::running() {
+        false
+    } else if expected == atoms::stopped() {
+        true
+    } else {
+        return Err(Error::BadArg);
+    };
+    let sig = parse_signal(signal)?;
+
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)?
+        || (!resource.adopted && pending_wait(resource.pid, libc::WEXITED))
+    {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+    if is_stopped(resource.pid) != want_stopped {
+        return Ok((atoms::error(), atoms::state_mismatch()).encode(env));
+    }
+
+    match send_signal(&resource, sig) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Queues `signal` for the child with `value` as its payload, like
+/// `sigqueue(3)`; a handler installed with `SA_SIGINFO` finds it in
+/// `si_value`. Unlike `signal_nif`, realtime signals are accepted. Checked
+/// under the child lock like `signal_nif`. Returns `{:error, errno}` if
+/// `sigqueue` fails, and `{:error, :not_supported}` off Linux or for an
+/// adopted process, whose PID alone could be reused.
+#[rustler::nif]
+fn sigqueue_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    signal: i32,
+    value: i64,
+) -> NifResult<Term<'a>> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+    if resource.adopted {
+        return Ok((atoms::error(), atoms::not_supported()).encode(env));
+    }
+
+    match queue_signal(resource.pid, signal, value)? {
+        Some(Ok(())) => {
+            resource.last_signal.store(signal, Ordering::Release);
+            Ok(atoms::ok().encode(env))
+        }
+        Some(Err(errno)) => Ok((atoms::error(), errno as i32).encode(env)),
+        None => Ok((atoms::error(), atoms::not_supported()).encode(env)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn queue_signal(pid: i32, signal: i32, value: i64) -> NifResult<Option<nix::Result<()>>> {
+    if !(1..=libc::SIGRTMAX()).contains(&signal) {
+        return Err(Error::Term(Box::new("Invalid signal")));
+    }
+    // Not bound by the libc crate; glibc and musl both provide it.
+    extern "C" {
+        fn sigqueue(pid: libc::pid_t, sig: libc::c_int, value: libc::sigval) -> libc::c_int;
+    }
+    let value = libc::sigval {
+        sival_ptr: value as isize as *mut libc::c_void,
+    };
+    let result = unsafe { sigqueue(pid, signal, value) };
+    Ok(Some(nix::errno::Errno::result(result).map(drop)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn queue_signal(_pid: i32, signal: i32, _value: i64) -> NifResult<Option<nix::Result<()>>> {
+    parse_signal(signal)?;
+    Ok(None)
+}
+
+/// Reports whether the child's PID still exists, with `kill(pid, 0)`,
+/// without ever waiting on it, so the exit stays for another caller to
+/// reap. An exited but unreaped child (a zombie) still exists. On Linux a
+/// PID whose start time no longer matches has been reused and counts as
+/// gone.
+#[rustler::nif]
+fn is_pid_alive_nif(resource: ResourceArc<ProcessResource>) -> NifResult<bool> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok(false);
+    }
+
+    if kill(Pid::from_raw(resource.pid), None).is_err() {
+        return Ok(false);
+    }
+    Ok(resource.start_time.is_none() || start_time(resource.pid) == resource.start_time)
+}
+
+/// After the child has been reaped, reports the signal that killed it as
+/// `{:signaled, signal, by_us}`, where `by_us` tells whether it was the last
+/// signal we sent. Returns `:not_signaled` for a normal exit or an exit
+/// reaped elsewhere, and `{:error, :running}` before the child is reaped.
+#[rustler::nif]
+fn termination_signal_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let signal = match *lock(&resource.exit_status)? {
+        None => return Ok((atoms::error(), atoms::running()).encode(env)),
+        Some(Exit::Status(status)) => status.signal(),
+        Some(Exit::ReapedExternally) => None,
+    };
+    match signal {
+        Some(signal) => {
+            let by_us = resource.last_signal.load(Ordering::Acquire) == signal;
+            Ok((atoms::signaled(), signal, by_us).encode(env))
+        }
+        None => Ok(atoms::not_signaled().encode(env)),
+    }
+}
+
+/// Decodes a raw `wait(2)` status with the `WIFEXITED`/`WIFSIGNALED` family
+/// of macros into the same term `communicate_nif` returns. Statuses of
+/// stopped or continued children yield `{:error, :not_terminated}`.
+#[rustler::nif]
+fn decode_exit_status_nif(env: Env, raw_status: i32) -> Term {
+    let status = ExitStatus::from_raw(raw_status);
+    if status.code().is_none() && status.signal().is_none() {
+        return (atoms::error(), atoms::not_terminated()).encode(env);
+    }
+    encode_exit(env, Exit::Status(status))
+}
+
+/// Limits how many spawned children may be live (not yet reaped, resource
+/// not yet collected) at once. Zero removes the limit.
+#[rustler::nif]
+fn set_max_children_nif(max: usize) -> rustler::Atom {
+    registry::set_max_children(max);
+    atoms::ok()
+}
+
+/// Number of spawned children counted against the limit.
+#[rustler::nif]
+fn live_children_nif() -> usize {
+    registry::live_children()
+}
+
+/// PIDs of spawned children that have not been reaped and whose resources
+/// are still alive.
+#[rustler::nif]
+fn live_child_pids_nif() -> Vec<i32> {
+    registry::live_pids()
+}
+
+/// Makes the `nth` call at `site` (`:read`, `:write`, `:wait`, `:alloc` or
+/// `:lock`) from now on fail with `errno`. Only built with the
+/// `fault_injection` feature; see `fault`.
+#[cfg(feature = "fault_injection")]
+#[rustler::nif]
+fn inject_fault_nif(site: rustler::Atom, nth: u64, errno: i32) -> NifResult<rustler::Atom> {
+    let site = if site == atoms::read() {
+        fault::Site::Read
+    } else if site == atoms::write() {
+        fault::Site::Write
+    } else if site == atoms::wait() {
+        fault::Site::Wait
+    } else if site == atoms::alloc() {
+        fault::Site::Alloc
+    } else if site == atoms::lock() {
+        fault::Site::Lock
+    } else {
+        return Err(Error::BadArg);
+    };
+    if nth == 0 {
+        return Err(Error::BadArg);
+    }
+    fault::arm(site, nth, errno);
+    Ok(atoms::ok())
+}
+
+/// Disarms every fault that has not fired yet.
+#[cfg(feature = "fault_injection")]
+#[rustler::nif]
+fn clear_faults_nif() -> rustler::Atom {
+    fault::clear();
+    atoms::ok()
+}
+
+/// Returns the legacy exit code, `:reaped_externally` if the child was
+/// reaped by someone else before we could collect its status, or
+/// `:cancelled` if `cancel_wait_nif` was called first.
+#[rustler::nif(schedule = "DirtyIo")]
+fn wait_nif<'a>(env: Env<'a>, resource: ResourceArc<ProcessResource>) -> NifResult<Term<'a>> {
+    match wait_exit_cancellable(&resource)? {
+        Some(exit) => Ok(encode_exit_code(env, exit)),
+        None => Ok(atoms::cancelled().encode(env)),
+    }
+}
+
+/// Resource usage of a reaped child, from `wait4(2)`.
+#[derive(NifMap)]
+struct Usage {
+    user_time_us: i64,
+    system_time_us: i64,
+    /// Peak resident set size in kilobytes.
+    max_rss_kb: i64,
+    minor_faults: i64,
+    major_faults: i64,
+    voluntary_context_switches: i64,
+    involuntary_context_switches: i64,
+}
+
+impl Usage {
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    fn from_rusage(usage: &libc::rusage) -> Usage {
+        let micros = |tv: libc::timeval| tv.tv_sec as i64 * 1_000_000 + tv.tv_usec as i64;
+        // macOS reports ru_maxrss in bytes, Linux in kilobytes.
+        let max_rss_kb = if cfg!(target_os = "macos") {
+            usage.ru_maxrss as i64 / 1024
+        } else {
+            usage.ru_maxrss as i64
+        };
+        Usage {
+            user_time_us: micros(usage.ru_utime),
+            system_time_us: micros(usage.ru_stime),
+            max_rss_kb,
+            minor_faults: usage.ru_minflt as i64,
+            major_faults: usage.ru_majflt as i64,
+            voluntary_context_switches: usage.ru_nvcsw as i64,
+            involuntary_context_switches: usage.ru_nivcsw as i64,
+        }
+    }
+}
+
+/// Reaps the child with `wait4` if it has exited, without blocking, and
+/// returns `{exit, usage}`: the `communicate_nif` exit term and the child's
+/// resource usage. Usage is `nil` if the child had already been reaped by
+/// another call (or externally), since only the reaping wait reports it.
+/// Returns `:still_running` otherwise.
+#[rustler::nif]
+fn try_wait_usage_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let child_lock = lock(&resource.child)?;
+
+    if let Some(exit) = *lock(&resource.exit_status)? {
+        return Ok((encode_exit(env, exit), None::<Usage>).encode(env));
+    }
+    if resource.adopted && released(&resource, &child_lock)? {
+        return Ok((encode_exit(env, Exit::ReapedExternally), None::<Usage>).encode(env));
+    }
+    if child_lock.is_none() {
+        return Ok(atoms::still_running().encode(env));
+    }
+
+    capture_siginfo(&resource);
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let result = loop {
+        let result = unsafe { libc::wait4(resource.pid, &mut status, libc::WNOHANG, &mut usage) };
+        if result != -1 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+        {
+            break result;
+        }
+    };
+
+    match result {
+        0 => Ok(atoms::still_running().encode(env)),
+        -1 => {
+            let error = std::io::Error::last_os_error();
+            if !is_echild(&error) {
+                return Err(Error::Term(Box::new(format!("Failed to wait: {}", error))));
+            }
+            let exit = record_exit(&resource, Exit::ReapedExternally)?;
+            Ok((encode_exit(env, exit), None::<Usage>).encode(env))
+        }
+        _ => {
+            let exit = record_exit(&resource, Exit::Status(ExitStatus::from_raw(status)))?;
+            Ok((encode_exit(env, exit), Some(Usage::from_rusage(&usage))).encode(env))
+        }
+    }
+}
+
+/// A recorded exit with the exit code and terminating signal kept apart,
+/// unlike the legacy code that folds signals into `128 + signal`.
+#[derive(NifMap)]
+struct ExitInfo {
+    /// `WEXITSTATUS`, or `nil` if the child was killed by a signal.
+    code: Option<i32>,
+    /// `WTERMSIG`, or `nil` if the child exited normally.
+    signal: Option<i32>,
+    core_dumped: bool,
+}
+
+/// Returns `{:ok, info}` for a child whose exit has been recorded by a wait,
+/// `{:error, :running}` if none has, or `{:error, :reaped_externally}` if
+/// the status was lost. Never waits itself.
+#[rustler::nif]
+fn exit_info_nif(env: Env, resource: ResourceArc<ProcessResource>) -> NifResult<Term> {
+    let status = match *lock(&resource.exit_status)? {
+        None => return Ok((atoms::error(), atoms::running()).encode(env)),
+        Some(Exit::ReapedExternally) => {
+            return Ok((atoms::error(), atoms::reaped_externally()).encode(env))
+        }
+        Some(Exit::Status(status)) => status,
+    };
+    let info = ExitInfo {
+        code: status.code(),
+        signal: status.signal(),
+        core_dumped: status.core_dumped(),
+    };
+    Ok((atoms::ok(), info).encode(env))
+}
+
+/// Describes a signal death as `waitid` reported it: `{:ok, %{signal,
+/// code, status, pid, uid}}`, `code` being `:killed` or `:dumped`
+/// (`CLD_KILLED`, `CLD_DUMPED`). `pid` is the child's own, since `waitid`
+/// never says who sent the signal. `uid` is `nil` when the exit was reaped
+/// without a `siginfo_t`, e.g. by a blocking wait racing the exit, and the
+/// rest comes from the wait status. Returns `{:error, :not_signaled}` for a
+/// normal exit, and `:running` and `:reaped_externally` like
+/// `exit_info_nif`.
+#[rustler::nif]
+fn signal_info_nif(env: Env, resource: ResourceArc<ProcessResource>) -> NifResult<Term> {
+    let status = match *lock(&resource.exit_status)? {
+        None => return Ok((atoms::error(), atoms::running()).encode(env)),
+        Some(Exit::ReapedExternally) => {
+            return Ok((atoms::error(), atoms::reaped_externally()).encode(env))
+        }
+        Some(Exit::Status(status)) => status,
+    };
+    let Some(signal) = status.signal() else {
+        return Ok((atoms::error(), atoms::not_signaled()).encode(env));
+    };
+
+    let siginfo = lock(&resource.siginfo)?.filter(|info| info.code != libc::CLD_EXITED);
+    let (code, uid) = match siginfo {
+        Some(info) => (info.code, Some(info.uid)),
+        None if status.core_dumped() => (libc::CLD_DUMPED, None),
+        None => (libc::CLD_KILLED, None),
+    };
+    let code = match code {
+        libc::CLD_DUMPED => atoms::dumped().encode(env),
+        libc::CLD_KILLED => atoms::killed().encode(env),
+        other => other.encode(env),
+    };
+    let info = Term::map_from_pairs(
+        env,
+        &[
+            (atoms::signal().encode(env), signal.encode(env)),
+            (atoms::code().encode(env), code),
+            (
+                atoms::status().encode(env),
+                siginfo.map_or(signal, |info| info.status).encode(env),
+            ),
+            (
+                atoms::pid().encode(env),
+                siginfo.map_or(resource.pid, |info| info.pid).encode(env),
+            ),
+            (atoms::uid().encode(env), uid.encode(env)),
+        ],
+    )?;
+    Ok((atoms::ok(), info).encode(env))
+}
+
+/// Makes every `wait_nif` call blocked on the child return `:cancelled`.
+/// Returns `{:error, :not_waiting}` if there were none.
+#[rustler::nif]
+fn cancel_wait_nif(env: Env, resource: ResourceArc<ProcessResource>) -> Term {
+    match resource.waiters.cancel_all() {
+        0 => (atoms::error(), atoms::not_waiting()).encode(env),
+        _ => atoms::ok().encode(env),
+    }
+}
+
+/// Checks the exited flag first, so polling a child that has already been
+/// reaped takes no lock. Only a child not yet known to have exited costs
+/// the child lock and a `try_wait`.
+#[rustler::nif]
+fn alive_nif(resource: ResourceArc<ProcessResource>) -> NifResult<bool> {
+    if resource.exited.load(Ordering::Acquire) {
+        return Ok(false);
+    }
+
+    let child_lock = lock(&resource.child)?;
+
+    // Another caller may have reaped the child while we waited for the lock.
+    if resource.exited.load(Ordering::Relaxed) {
+        return Ok(false);
+    }
+
+    if child_lock.is_some() {
+        match record_wait(&resource, try_wait_siginfo(&resource)) {
+            Ok(exit) => Ok(exit.is_none()),
+            Err(_) => Ok(false),
+        }
+    } else {
+        Ok(!released(&resource, &child_lock)?)
+    }
+}
+
+#[rustler::nif]
+fn write_stdin_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    data: Binary<'a>,
+) -> NifResult<Term<'a>> {
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+
+    if let Some(stdin) = stdin_lock.as_mut() {
+        Ok(write_to_stdin(env, &resource, stdin, data.as_slice()))
+    } else {
+        Ok((atoms::error(), atoms::not_piped()).encode(env))
+    }
+}
+
+/// Like `write_stdin_nif`, but takes iodata: nested lists of binaries and
+/// bytes. Binaries are written in place with one `writev`, without
+/// flattening the term first; only runs of list bytes are copied.
+#[rustler::nif]
+fn write_stdin_iodata_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    data: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let mut bytes = Vec::new();
+    let pieces = iodata_pieces(data, &mut bytes)?;
+    let slices: Vec<IoSlice> = pieces
+        .iter()
+        .map(|piece| match piece {
+            IodataPiece::Binary(data) => IoSlice::new(data),
+            IodataPiece::Bytes(range) => IoSlice::new(&bytes[range.clone()]),
+        })
+        .collect();
+
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+    let Some(stdin) = stdin_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+    Ok(write_slices_to_stdin(env, &resource, stdin, &slices))
+}
+
+/// A non-empty piece of an iodata term: a binary, or a run of list bytes
+/// copied into a side buffer.
+enum IodataPiece<'a> {
+    Binary(&'a [u8]),
+    Bytes(std::ops::Range<usize>),
+}
+
+/// Walks `term` in order, with an explicit stack so deep nesting cannot
+/// overflow ours. Byte runs go into `bytes`.
+fn iodata_pieces<'a>(term: Term<'a>, bytes: &mut Vec<u8>) -> NifResult<Vec<IodataPiece<'a>>> {
+    if !term.is_binary() && !term.is_list() && !term.is_empty_list() {
+        return Err(Error::BadArg);
+    }
+
+    let mut pieces = Vec::new();
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        if let Ok(binary) = Binary::from_term(term) {
+            if !binary.is_empty() {
+                pieces.push(IodataPiece::Binary(binary.as_slice()));
+            }
+        } else if let Ok((head, tail)) = term.list_get_cell() {
+            stack.push(tail);
+            stack.push(head);
+        } else if !term.is_empty_list() {
+            let byte: u8 = term.decode()?;
+            match pieces.last_mut() {
+                Some(IodataPiece::Bytes(run)) if run.end == bytes.len() => run.end += 1,
+                _ => pieces.push(IodataPiece::Bytes(bytes.len()..bytes.len() + 1)),
+            }
+            bytes.push(byte);
+        }
+    }
+    Ok(pieces)
+}
+
+/// Like `write_stdin_nif`, but appends a newline to `data` unless it already
+/// ends with one, for line-oriented coprocesses.
+#[rustler::nif]
+fn write_line_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    data: Binary<'a>,
+) -> NifResult<Term<'a>> {
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+    let Some(stdin) = stdin_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    if data.ends_with(b"\n") {
+        return Ok(write_to_stdin(env, &resource, stdin, data.as_slice()));
+    }
+    let mut line = Vec::with_capacity(data.len() + 1);
+    line.extend_from_slice(data.as_slice());
+    line.push(b'\n');
+    Ok(write_to_stdin(env, &resource, stdin, &line))
+}
+
+/// Writes `data` to stdin. Whatever the pipe does not accept replaces the
+/// stream's pending tail, so `write_stdin_continue_nif` can resume it; a
+/// complete write or a hard error clears it.
+///
+/// A child whose exit has already been recorded gets
+/// `{:error, :already_exited}` without a write being attempted, since
+/// whether that write would fail with EPIPE depends on whether anything
+/// else still holds the read end. A read end that is already gone is
+/// reported as `{:error, :reader_gone}`, found with a zero-timeout poll
+/// before writing, so the write never runs into EPIPE. A reader that goes
+/// away mid-write still shows up as `:broken_pipe`.
+fn write_to_stdin<'a>(
+    env: Env<'a>,
+    resource: &ProcessResource,
+    stdin: &mut StdioStream,
+    data: &[u8],
+) -> Term<'a> {
+    write_slices_to_stdin(env, resource, stdin, &[IoSlice::new(data)])
+}
+
+/// Most slices passed to one `writev`; Linux and macOS both allow 1024.
+/// Slices past it are left pending as if the pipe had been full.
+const MAX_IOVECS: usize = 1024;
+
+/// `write_to_stdin` for data in several slices, written with one `writev`.
+/// Only an unwritten tail is copied, into the pending buffer.
+fn write_slices_to_stdin<'a>(
+    env: Env<'a>,
+    resource: &ProcessResource,
+    stdin: &mut StdioStream,
+    slices: &[IoSlice],
+) -> Term<'a> {
+    // An empty write never reaches the fd: a zero-length write(2) is
+    // ambiguous on a socket, and there is nothing to deliver anyway. The
+    // pending tail is left for `write_stdin_continue_nif`.
+    if slices.iter().all(|slice| slice.is_empty()) {
+        return atoms::ok().encode(env);
+    }
+    if lock(&resource.exit_status).is_ok_and(|exit| exit.is_some()) {
+        stdin.buffer.clear();
+        return (atoms::error(), atoms::already_exited()).encode(env);
+    }
+    if reader_gone(stdin) {
+        stdin.buffer.clear();
+        return (atoms::error(), atoms::reader_gone()).encode(env);
+    }
+
+    let total: usize = slices.iter().map(|slice| slice.len()).sum();
+    let result = fault::inject(fault::Site::Write).and_then(|()| match slices {
+        [data] => (&stdin.file).write(data),
+        _ => (&stdin.file).write_vectored(&slices[..slices.len().min(MAX_IOVECS)]),
+    });
+    if let Ok(n @ 1..) = result {
+        resource.touch_io();
+        count_io(&resource.stdin_written, n);
+    }
+    match result {
+        Ok(n) if n == total => {
+            stdin.buffer.clear();
+            atoms::ok().encode(env)
+        }
+        Ok(n) => {
+            stdin.buffer = unwritten(slices, n);
+            (atoms::partial(), n as i64).encode(env)
+        }
+        Err(ref e) if is_would_block(e) => {
+            stdin.buffer = unwritten(slices, 0);
+            atoms::would_block().encode(env)
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+            stdin.buffer.clear();
+            (atoms::error(), atoms::broken_pipe()).encode(env)
+        }
+        Err(e) => {
+            stdin.buffer.clear();
+            (atoms::error(), format!("{}", e)).encode(env)
+        }
+    }
+}
+
+/// Whether nothing holds the read end of a stdin pipe or socket any more:
+/// polling the write end reports POLLERR for a pipe and POLLHUP for a
+/// socket once the reader is gone.
+fn reader_gone(stream: &StdioStream) -> bool {
+    let mut fds = [PollFd::new(stream.file.as_fd(), PollFlags::POLLOUT)];
+    match poll(&mut fds, PollTimeout::ZERO) {
+        Ok(_) => fds[0]
+            .revents()
+            .is_some_and(|r| r.intersects(PollFlags::POLLERR | PollFlags::POLLHUP)),
+        Err(_) => false,
+    }
+}
+
+/// The bytes of `slices` after the first `written`.
+fn unwritten(slices: &[IoSlice], mut written: usize) -> Vec<u8> {
+    let mut rest = Vec::new();
+    for slice in slices {
+        let skip = written.min(slice.len());
+        written -= skip;
+        rest.extend_from_slice(&slice[skip..]);
+    }
+    rest
+}
+
+/// Retries the unwritten tail left by the last `:partial` or `:would_block`
+/// write. Returns `:ok` when nothing is pending.
+#[rustler::nif]
+fn write_stdin_continue_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+
+    match stdin_lock.as_mut() {
+        Some(stdin) if stdin.buffer.is_empty() => Ok(atoms::ok().encode(env)),
+        Some(stdin) => {
+            let pending = std::mem::take(&mut stdin.buffer);
+            Ok(write_to_stdin(env, &resource, stdin, &pending))
+        }
+        None => Ok((atoms::error(), atoms::not_piped()).encode(env)),
+    }
+}
+
+/// Number of bytes still waiting for `write_stdin_continue_nif`.
+#[rustler::nif]
+fn pending_write_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    match lock(&resource.stdin_pipe)?.as_ref() {
+        Some(stdin) => Ok((atoms::ok(), stdin.buffer.len()).encode(env)),
+        None => Ok((atoms::error(), atoms::not_piped()).encode(env)),
+    }
+}
+
+/// Writes out the pending stdin tail left by partial or blocked writes,
+/// parking on writability until `timeout` (zero waits forever). Returns
+/// `:ok` once nothing is pending, `:timeout` with the rest still pending, or
+/// `{:error, {:stdin, reason}}`, dropping the rest. Then syncs `{:log, ...}`
+/// files to disk, failing with `{:error, {:stdout | :stderr, reason}}`.
+/// Output streams are otherwise left alone: the drain threads behind tail,
+/// fan-out, active and log outputs are not waited on, and `{:file, ...}`
+/// outputs are the child's to sync.
+#[rustler::nif(schedule = "DirtyIo")]
+fn flush_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    timeout: Timeout,
+) -> NifResult<Term<'a>> {
+    let deadline = timeout.deadline();
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+    if let Some(stdin) = stdin_lock.as_mut() {
+        while !stdin.buffer.is_empty() {
+            match stdin.write_some(&stdin.buffer) {
+                Ok(n) => {
+                    resource.touch_io();
+                    count_io(&resource.stdin_written, n);
+                    stdin.buffer.drain(..n);
+                }
+                Err(ref e) if is_would_block(e) => {
+                    if !poll_ready(stdin, PollFlags::POLLOUT, deadline) {
+                        return Ok(atoms::timeout().encode(env));
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    stdin.buffer.clear();
+                    let reason = if e.kind() == std::io::ErrorKind::BrokenPipe {
+                        atoms::broken_pipe().encode(env)
+                    } else {
+                        format!("{}", e).encode(env)
+                    };
+                    return Ok((atoms::error(), (atoms::stdin(), reason)).encode(env));
+                }
+            }
+        }
+    }
+    drop(stdin_lock);
+
+    let logs = [
+        (atoms::stdout(), &resource.stdout_log),
+        (atoms::stderr(), &resource.stderr_log),
+    ];
+    for (name, log) in logs {
+        if let Err(e) = log.as_ref().map_or(Ok(()), |log| log.flush()) {
+            return Ok((atoms::error(), (name, format!("{}", e))).encode(env));
+        }
+    }
+    Ok(atoms::ok().encode(env))
+}
+
+/// Writes any pending tail and then all of `data` to stdin, parking on
+/// writability until `timeout` (zero waits forever), and closes stdin once
+/// everything is written. On timeout stdin stays open and returns
+/// `{:partial, n}`, `n` counting bytes of `data` only, with the rest left
+/// pending as after a partial `write_stdin_nif`. A write error drops the
+/// pending bytes like `flush_nif`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn write_stdin_close_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    data: Binary<'a>,
+    timeout: Timeout,
+) -> NifResult<Term<'a>> {
+    let deadline = timeout.deadline();
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+    let Some(stdin) = stdin_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+    if lock(&resource.exit_status)?.is_some() {
+        stdin.buffer.clear();
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+
+    let pending = std::mem::take(&mut stdin.buffer);
+    let (flushed, result) = write_some_until(stdin, &pending, deadline, &resource.stdin_written);
+    let (written, result) = match result {
+        Ok(()) => write_some_until(stdin, &data, deadline, &resource.stdin_written),
+        Err(e) => (0, Err(e)),
+    };
+    if flushed + written > 0 {
+        resource.touch_io();
+    }
+
+    match result {
+        Ok(()) => {
+            *stdin_lock = None;
+            Ok(atoms::ok().encode(env))
+        }
+        Err(RequestError::Timeout) => {
+            let mut rest = pending[flushed..].to_vec();
+            rest.extend_from_slice(&data[written..]);
+            stdin.buffer = rest;
+            Ok((atoms::partial(), written).encode(env))
+        }
+        Err(RequestError::BrokenPipe) => Ok((atoms::error(), atoms::broken_pipe()).encode(env)),
+        Err(RequestError::Io(e)) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Detaches the parent's end of a piped `stream` from the resource and
+/// returns it as `{:ok, fd}`, a raw descriptor the caller now owns: Px no
+/// longer reads, writes or closes it, and the caller must close it (with
+/// `close_fd_nif` or whatever it hands it to). Refuses with
+/// `{:error, :pending_data}` while stdin has a pending write tail or an
+/// output stream has bytes buffered past the last line or frame returned,
+/// since they would be lost.
+#[rustler::nif]
+fn take_fd_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    stream: rustler::Atom,
+) -> NifResult<Term<'a>> {
+    let pipe = if stream == atoms::stdin() {
+        &resource.stdin_pipe
+    } else if stream == atoms::stdout() {
+        &resource.stdout_pipe
+    } else if stream == atoms::stderr() {
+        &resource.stderr_pipe
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    let mut pipe_lock = lock(pipe)?;
+    match pipe_lock.take_if(|stream| stream.buffer.is_empty()) {
+        Some(stream) => Ok((atoms::ok(), stream.file.into_raw_fd()).encode(env)),
+        None if pipe_lock.is_some() => Ok((atoms::error(), atoms::pending_data()).encode(env)),
+        None => Ok((atoms::error(), atoms::not_piped()).encode(env)),
+    }
+}
+
+/// A duplicate of an output pipe from `dup_fd_nif`. The descriptor is
+/// closed when the handle is garbage collected, unless `dup_fd_into_nif`
+/// has handed it over first.
+pub struct DupFd {
+    fd: Mutex<Option<OwnedFd>>,
+}
+
+/// Duplicates the parent's end of piped `stdout` or `stderr` and returns
+/// the copy as `{:ok, handle}`, close-on-exec, while the resource keeps
+/// reading the original. Both descriptors refer to the same open pipe, so
+/// each byte goes to whichever reads it first; for every reader to get
+/// every byte, spawn with `stdout: :fanout` instead.
+#[rustler::nif]
+fn dup_fd_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    stream: rustler::Atom,
+) -> NifResult<Term<'a>> {
+    let pipe = if stream == atoms::stdout() {
+        &resource.stdout_pipe
+    } else if stream == atoms::stderr() {
+        &resource.stderr_pipe
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    let pipe_lock = lock(pipe)?;
+    let Some(stream) = pipe_lock.as_ref() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+    match stream.file.as_fd().try_clone_to_owned() {
+        Ok(fd) => {
+            let handle = ResourceArc::new(DupFd {
+                fd: Mutex::new(Some(fd)),
+            });
+            Ok((atoms::ok(), handle).encode(env))
+        }
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Reads up to `max_bytes` from a `dup_fd_nif` handle: `{:ok, data}`,
+/// `:eof`, `:would_block` if the pipe is non-blocking and empty, or
+/// `{:error, :closed}` once the descriptor has been handed over. The binary
+/// is allocated before the read, so a failed allocation consumes nothing.
+#[rustler::nif(schedule = "DirtyIo")]
+fn dup_fd_read_nif<'a>(
+    env: Env<'a>,
+    handle: ResourceArc<DupFd>,
+    max_bytes: usize,
+) -> NifResult<Term<'a>> {
+    if max_bytes == 0 {
+        return Err(Error::BadArg);
+    }
+    let fd_lock = lock(&handle.fd)?;
+    let Some(fd) = fd_lock.as_ref() else {
+        return Ok((atoms::error(), atoms::closed()).encode(env));
+    };
+
+    let mut binary = alloc_binary(max_bytes)?;
+    loop {
+        let result = fault::inject(fault::Site::Read).and_then(|()| {
+            nix::unistd::read(fd.as_raw_fd(), binary.as_mut_slice()).map_err(Into::into)
+        });
+        match result {
+            Ok(0) => return Ok(atoms::eof().encode(env)),
+            Ok(n) => return Ok((atoms::ok(), shrink_binary(env, binary, n)?).encode(env)),
+            Err(ref e) if is_would_block(e) => return Ok(atoms::would_block().encode(env)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+        }
+    }
+}
+
+/// Hands a `dup_fd_nif` descriptor over as a raw `{:ok, fd}`, which the
+/// caller then owns and closes with `close_fd_nif`. The handle is left
+/// closed, so this works once.
+#[rustler::nif]
+fn dup_fd_into_nif(env: Env, handle: ResourceArc<DupFd>) -> NifResult<Term> {
+    match lock(&handle.fd)?.take() {
+        Some(fd) => Ok((atoms::ok(), fd.into_raw_fd()).encode(env)),
+        None => Ok((atoms::error(), atoms::closed()).encode(env)),
+    }
+}
+
+/// Opens `path` read-only and close-on-exec for `exec_fd`, so the caller
+/// can verify the file and then exec exactly what it verified. The caller
+/// owns the descriptor and closes it with `close_fd_nif`.
+#[rustler::nif]
+fn open_exec_nif(env: Env, path: String) -> Term {
+    match File::open(&path) {
+        Ok(file) => (atoms::ok(), file.into_raw_fd()).encode(env),
+        Err(e) => (atoms::error(), format!("{}", e)).encode(env),
+    }
+}
+
+/// Closes a descriptor obtained from `take_fd_nif`, `dup_fd_into_nif` or
+/// `open_exec_nif`.
+#[rustler::nif]
+fn close_fd_nif(env: Env, fd: i32) -> Term {
+    match nix::unistd::close(fd) {
+        Ok(()) => atoms::ok().encode(env),
+        Err(errno) => (atoms::error(), format!("{}", errno)).encode(env),
+    }
+}
+
+#[rustler::nif]
+fn close_stdin_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+
+    if stdin_lock.is_some() {
+        *stdin_lock = None;
+        Ok(atoms::ok().encode(env))
+    } else {
+        Ok((atoms::error(), atoms::not_piped()).encode(env))
+    }
+}
+
+/// Closes the read end of stdout, so the child's next write to it fails
+/// with EPIPE, or kills it with SIGPIPE unless it ignores that: a way to
+/// tell a producer to stop, as `head` does once it has read enough. The
+/// descriptor is closed here rather than dropped, so a failed close is
+/// reported. Anything buffered by line mode is discarded.
+#[rustler::nif]
+fn close_stdout_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    close_output(env, &resource.stdout_pipe)
+}
+
+/// Closes the read end of stderr, like `close_stdout_nif`.
+#[rustler::nif]
+fn close_stderr_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    close_output(env, &resource.stderr_pipe)
+}
+
+/// A pty's output is not refused this way: the master stays open for
+/// stdin and terminal control, so the child would never see EPIPE, and the
+/// call returns `{:error, :not_supported}` with the stream left as it is.
+fn close_output<'a>(env: Env<'a>, pipe: &Mutex<Option<StdioStream>>) -> NifResult<Term<'a>> {
+    let mut stream_lock = lock(pipe)?;
+    let Some(stream) = stream_lock.take_if(|stream| !stream.pty) else {
+        return Ok(match stream_lock.is_some() {
+            true => (atoms::error(), atoms::not_supported()).encode(env),
+            false => (atoms::error(), atoms::not_piped()).encode(env),
+        });
+    };
+    match nix::unistd::close(stream.file.into_raw_fd()) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(errno) => Ok((atoms::error(), format!("{}", errno)).encode(env)),
+    }
+}
+
+/// Whether the peer of a socket-backed stream has shut down its write side.
+///
+/// On a socket, a zero-length read is only trusted as end of stream once
+/// `POLLHUP`/`POLLRDHUP` confirms the peer really hung up.
+fn socket_hung_up(stream: &StdioStream) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let rdhup = PollFlags::from_bits_retain(libc::POLLRDHUP);
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let rdhup = PollFlags::empty();
+
+    let mut fds = [PollFd::new(stream.file.as_fd(), PollFlags::POLLIN | rdhup)];
+    match poll(&mut fds, PollTimeout::ZERO) {
+        Ok(_) => fds[0]
+            .revents()
+            .is_some_and(|r| r.intersects(PollFlags::POLLHUP | rdhup)),
+        Err(_) => true,
+    }
+}
+
+/// Size of the binary each raw read fills.
+const READ_CHUNK: usize = 4096;
+
+/// Reads straight into a binary allocated before the read, so a failed
+/// allocation leaves the data in the pipe for a retry instead of dropping
+/// it. The binary is reused while reads return nothing, so polling an idle
+/// stream does not churn the allocator. Reading into a caller-supplied binary
+/// is deliberately not offered: BEAM binaries are immutable and may be
+/// shared, so a NIF must not write into one it did not just allocate.
+///
+/// Reaping the child leaves its pipes open, so data it wrote before exiting
+/// is still returned afterwards; `:eof` only comes once the pipe is empty
+/// and has no writers left.
+fn read_stream<'a>(
+    env: Env<'a>,
+    resource: &ProcessResource,
+    pipe: &Mutex<Option<StdioStream>>,
+    counter: &AtomicU64,
+) -> NifResult<Term<'a>> {
+    let mut stream_lock = lock(pipe)?;
+
+    if let Some(stream) = stream_lock.as_mut() {
+        if stream.lines {
+            return read_line(env, resource, stream, counter);
+        }
+
+        if !stream.buffer.is_empty() {
+            let len = stream.buffer.len();
+            return Ok((atoms::ok(), drain_binary(env, &mut stream.buffer, len)?).encode(env));
+        }
+
+        let mut binary = match stream.scratch.take() {
+            Some(binary) => binary,
+            None => alloc_binary(READ_CHUNK)?,
+        };
+
+        let result = stream.read_some(binary.as_mut_slice());
+        if let Ok(n) = result {
+            if n > 0 {
+                resource.touch_io();
+                count_io(counter, n);
+                return Ok((atoms::ok(), shrink_binary(env, binary, n)?).encode(env));
+            }
+        }
+        stream.scratch = Some(binary);
+
+        match result {
+            Ok(_) if stream.socket && !socket_hung_up(stream) => {
+                Ok(atoms::would_block().encode(env))
+            }
+            Ok(_) => Ok(atoms::eof().encode(env)),
+            Err(ref e) if is_would_block(e) => Ok(atoms::would_block().encode(env)),
+            Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+        }
+    } else {
+        Ok((atoms::error(), atoms::not_piped()).encode(env))
+    }
+}
+
+/// Trims a read binary to the `len` bytes actually filled. Falls back to a
+/// sub-binary if the shrinking realloc fails, so no data is lost.
+fn shrink_binary(env: Env, mut binary: OwnedBinary, len: usize) -> NifResult<Binary> {
+    if binary.realloc(len) {
+        Ok(binary.release(env))
+    } else {
+        binary.release(env).make_subbinary(0, len)
+    }
+}
+
+/// The length of the first complete line (including its `\n`) at the front
+/// of `buffer`.
+fn line_len(buffer: &[u8]) -> Option<usize> {
+    buffer.iter().position(|&b| b == b'\n').map(|end| end + 1)
+}
+
+/// Copies the first `len` bytes of `buffer` into a binary, removing them
+/// only once the binary is allocated, so a failed allocation loses nothing.
+fn drain_binary<'a>(env: Env<'a>, buffer: &mut Vec<u8>, len: usize) -> NifResult<Binary<'a>> {
+    let binary = binary_from(env, &buffer[..len])?;
+    buffer.drain(..len);
+    Ok(binary)
+}
+
+/// Line-mode read: returns the next complete line, reading as much as is
+/// available to find one, or `:would_block` while only a partial line is
+/// buffered. At EOF a final unterminated line is returned before `:eof`.
+fn read_line<'a>(
+    env: Env<'a>,
+    resource: &ProcessResource,
+    stream: &mut StdioStream,
+    counter: &AtomicU64,
+) -> NifResult<Term<'a>> {
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(len) = line_len(&stream.buffer) {
+            return Ok((atoms::ok(), drain_binary(env, &mut stream.buffer, len)?).encode(env));
+        }
+
+        match stream.read_some(&mut buf) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => {
+                return Ok(atoms::would_block().encode(env))
+            }
+            Ok(0) if stream.buffer.is_empty() => return Ok(atoms::eof().encode(env)),
+            Ok(0) => {
+                let len = stream.buffer.len();
+                return Ok((atoms::ok(), drain_binary(env, &mut stream.buffer, len)?).encode(env));
+            }
+            Ok(n) => {
+                resource.touch_io();
+                count_io(counter, n);
+                stream.buffer.extend_from_slice(&buf[..n]);
+            }
+            Err(ref e) if is_would_block(e) => return Ok(atoms::would_block().encode(env)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+        }
+    }
+}
+
+/// With `eof_reason`, `:eof` becomes `{:eof, :exited}` if the child has
+/// exited and `{:eof, :closed}` if it closed the stream while still running.
+#[rustler::nif]
+fn read_stdout_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    eof_reason: bool,
+) -> NifResult<Term<'a>> {
+    let result = read_stream(env, &resource, &resource.stdout_pipe, &resource.stdout_read)?;
+    explain_eof(env, &resource, result, eof_reason)
+}
+
+#[rustler::nif]
+fn read_stderr_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    eof_reason: bool,
+) -> NifResult<Term<'a>> {
+    let result = read_stream(env, &resource, &resource.stderr_pipe, &resource.stderr_read)?;
+    explain_eof(env, &resource, result, eof_reason)
+}
+
+/// Returns what is in stdout's pipe buffer right now with one `FIONREAD`
+/// and one read into a binary of exactly that size, instead of reading in
+/// `READ_CHUNK`s. With nothing buffered the read is a plain one, to tell
+/// `:would_block` from `:eof`. Anything a line-mode read left buffered comes
+/// back first, without a syscall; line boundaries are otherwise ignored.
+#[rustler::nif]
+fn read_stdout_buffered_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let mut stream_lock = lock(&resource.stdout_pipe)?;
+    let Some(stream) = stream_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    if !stream.buffer.is_empty() {
+        let len = stream.buffer.len();
+        return Ok((atoms::ok(), drain_binary(env, &mut stream.buffer, len)?).encode(env));
+    }
+
+    let buffered = match buffered_len(stream) {
+        Ok(buffered) => buffered,
+        Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+    };
+    let size = if buffered == 0 { READ_CHUNK } else { buffered };
+    let mut binary = alloc_binary(size)?;
+    loop {
+        match stream.read_some(binary.as_mut_slice()) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => {
+                return Ok(atoms::would_block().encode(env))
+            }
+            Ok(0) => return Ok(atoms::eof().encode(env)),
+            Ok(n) => {
+                resource.touch_io();
+                count_io(&resource.stdout_read, n);
+                return Ok((atoms::ok(), shrink_binary(env, binary, n)?).encode(env));
+            }
+            Err(ref e) if is_would_block(e) => return Ok(atoms::would_block().encode(env)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+        }
+    }
+}
+
+/// Scatter read of stdout: allocates one binary per entry of `sizes` and
+/// fills them in order with a single `readv`, so a fixed-size header and
+/// the body after it arrive in separate binaries without a copy. A short
+/// read fills the first binaries and leaves the rest short or empty; every
+/// binary is returned, trimmed to what it got, so the list always has one
+/// entry per size. Bytes a line-mode read left buffered are copied in first
+/// and the `readv` only fills what they leave; if they fill everything,
+/// nothing is read.
+///
+/// The binaries are allocated before anything is read, as in `read_stream`.
+/// Returns `{:ok, binaries}`, `:would_block`, `:eof` or `{:error, reason}`.
+#[rustler::nif]
+fn read_stdout_vectored_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    sizes: Vec<usize>,
+) -> NifResult<Term<'a>> {
+    if sizes.is_empty() || sizes.len() > MAX_IOVECS || sizes.contains(&0) {
+        return Err(Error::BadArg);
+    }
+    let mut stream_lock = lock(&resource.stdout_pipe)?;
+    let Some(stream) = stream_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    let mut binaries = sizes
+        .iter()
+        .map(|&size| alloc_binary(size))
+        .collect::<NifResult<Vec<_>>>()?;
+    let mut filled = vec![0; sizes.len()];
+
+    // What line mode left buffered goes first, in order.
+    let mut buffered = 0;
+    for (binary, filled) in binaries.iter_mut().zip(filled.iter_mut()) {
+        let n = binary.len().min(stream.buffer.len() - buffered);
+        binary.as_mut_slice()[..n].copy_from_slice(&stream.buffer[buffered..buffered + n]);
+        *filled = n;
+        buffered += n;
+    }
+    stream.buffer.drain(..buffered);
+
+    // The first binary with room left takes the read from where the
+    // buffered bytes stopped.
+    let first = filled
+        .iter()
+        .zip(&sizes)
+        .position(|(filled, size)| filled < size);
+    let result = match first {
+        None => Ok(0),
+        Some(first) => {
+            let mut bufs: Vec<IoSliceMut> = binaries[first..]
+                .iter_mut()
+                .zip(&filled[first..])
+                .map(|(binary, &filled)| IoSliceMut::new(&mut binary.as_mut_slice()[filled..]))
+                .collect();
+            loop {
+                match stream.read_vectored_some(&mut bufs) {
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    result => break result,
+                }
+            }
+        }
+    };
+
+    let read = match result {
+        Ok(n) => n,
+        Err(_) if buffered > 0 => 0,
+        Err(ref e) if is_would_block(e) => return Ok(atoms::would_block().encode(env)),
+        Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+    };
+    if read == 0 && buffered == 0 {
+        if stream.socket && !socket_hung_up(stream) {
+            return Ok(atoms::would_block().encode(env));
+        }
+        return Ok(atoms::eof().encode(env));
+    }
+    if read > 0 {
+        resource.touch_io();
+        count_io(&resource.stdout_read, read);
+    }
+
+    // Spread the `read` bytes over the binaries the `readv` was given.
+    let mut rest = read;
+    for (filled, &size) in filled.iter_mut().zip(&sizes) {
+        let n = (size - *filled).min(rest);
+        *filled += n;
+        rest -= n;
+    }
+    let binaries = binaries
+        .into_iter()
+        .zip(filled)
+        .map(|(binary, filled)| shrink_binary(env, binary, filled))
+        .collect::<NifResult<Vec<_>>>()?;
+    Ok((atoms::ok(), binaries).encode(env))
+}
+
+/// Bytes ready to be read from `stream` (`FIONREAD`).
+fn buffered_len(stream: &StdioStream) -> std::io::Result<usize> {
+    let mut buffered: libc::c_int = 0;
+    if unsafe { libc::ioctl(stream.as_raw_fd(), libc::FIONREAD, &mut buffered) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(buffered as usize)
+}
+
+/// Text read: like `read_stream`, but returns only whole UTF-8 sequences,
+/// as a string with invalid bytes replaced by U+FFFD. A multibyte sequence
+/// cut off at the end of a read stays in the stream's buffer until the rest
+/// arrives; at EOF whatever is left is returned, replaced.
+fn read_text<'a>(
+    env: Env<'a>,
+    resource: &ProcessResource,
+    pipe: &Mutex<Option<StdioStream>>,
+    counter: &AtomicU64,
+) -> NifResult<Term<'a>> {
+    let mut stream_lock = lock(pipe)?;
+    let Some(stream) = stream_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    let text = |buffer: &mut Vec<u8>, len: usize| -> NifResult<Term<'a>> {
+        let binary = binary_from(env, String::from_utf8_lossy(&buffer[..len]).as_bytes())?;
+        buffer.drain(..len);
+        Ok((atoms::ok(), binary).encode(env))
+    };
+    let mut buf = [0u8; READ_CHUNK];
+    loop {
+        let complete = stream.buffer.len() - incomplete_utf8_tail(&stream.buffer);
+        if complete > 0 {
+            return text(&mut stream.buffer, complete);
+        }
+
+        match stream.read_some(&mut buf) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => {
+                return Ok(atoms::would_block().encode(env))
+            }
+            Ok(0) if stream.buffer.is_empty() => return Ok(atoms::eof().encode(env)),
+            Ok(0) => {
+                let len = stream.buffer.len();
+                return text(&mut stream.buffer, len);
+            }
+            Ok(n) => {
+                resource.touch_io();
+                count_io(counter, n);
+                stream.buffer.extend_from_slice(&buf[..n]);
+            }
+            Err(ref e) if is_would_block(e) => return Ok(atoms::would_block().encode(env)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+        }
+    }
+}
+
+/// Length of a UTF-8 sequence cut off at the end of `data`: a lead byte
+/// followed by fewer continuation bytes than it announces. Zero if `data`
+/// ends on a sequence boundary, or in bytes no later input can make valid.
+fn incomplete_utf8_tail(data: &[u8]) -> usize {
+    for back in 1..=data.len().min(3) {
+        let byte = data[data.len() - back];
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let needed = match byte {
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return 0,
+        };
+        return if back < needed { back } else { 0 };
+    }
+    0
+}
+
+#[rustler::nif]
+fn read_stdout_text_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    read_text(env, &resource, &resource.stdout_pipe, &resource.stdout_read)
+}
+
+#[rustler::nif]
+fn read_stderr_text_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    read_text(env, &resource, &resource.stderr_pipe, &resource.stderr_read)
+}
+
+fn explain_eof<'a>(
+    env: Env<'a>,
+    resource: &ProcessResource,
+    result: Term<'a>,
+    eof_reason: bool,
+) -> NifResult<Term<'a>> {
+    if !eof_reason || result != atoms::eof().encode(env) {
+        return Ok(result);
+    }
+    let reason = if has_exited(resource)? {
+        atoms::exited()
+    } else {
+        atoms::closed()
+    };
+    Ok((atoms::eof(), reason).encode(env))
+}
+
+/// Whether the child has exited, reaped or not. Unlike `alive_nif`, never
+/// reaps it.
+fn has_exited(resource: &ProcessResource) -> NifResult<bool> {
+    let child_lock = lock(&resource.child)?;
+    if released(resource, &child_lock)? {
+        return Ok(true);
+    }
+    if resource.adopted {
+        return Ok(false);
+    }
+
+    Ok(pending_wait(resource.pid, libc::WEXITED))
+}
+
+/// The child's state, without reaping it: `:running`, `:stopped`, `:zombie`
+/// (exited but not reaped yet) or `:reaped`. A child reaped outside Px is
+/// `:reaped` too, though its exit is only recorded by the next wait.
+/// Checked under the child lock, so no wait reaps it halfway through.
+#[rustler::nif]
+fn state_nif(resource: ResourceArc<ProcessResource>) -> NifResult<rustler::Atom> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok(atoms::reaped());
+    }
+    if resource.adopted {
+        return Ok(match is_stopped(resource.pid) {
+            true => atoms::stopped(),
+            false => atoms::running(),
+        });
+    }
+
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    let result = unsafe { libc::waitid(libc::P_PID, resource.pid as libc::id_t, &mut info, flags) };
+    if result == -1 {
+        let error = std::io::Error::last_os_error();
+        if is_echild(&error) {
+            return Ok(atoms::reaped());
+        }
+        return Err(Error::Term(Box::new(format!("Failed to wait: {}", error))));
+    }
+    if unsafe { info.si_pid() } != 0 {
+        Ok(atoms::zombie())
+    } else if is_stopped(resource.pid) {
+        Ok(atoms::stopped())
+    } else {
+        Ok(atoms::running())
+    }
+}
+
+/// Whether `waitid` has a state change of the kinds in `which` waiting for
+/// `pid`, without consuming it. ECHILD (reaped outside Px) counts as one.
+fn pending_wait(pid: i32, which: libc::c_int) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = which | libc::WNOHANG | libc::WNOWAIT;
+    let result = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) };
+    if result == -1 {
+        return is_echild(&std::io::Error::last_os_error());
+    }
+    unsafe { info.si_pid() != 0 }
+}
+
+/// Whether the (unreaped) child is stopped. On Linux this is the state in
+/// `/proc/<pid>/stat`; elsewhere it relies on the stop not having been
+/// consumed by another wait yet.
+#[cfg(target_os = "linux")]
+fn is_stopped(pid: i32) -> bool {
+    let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+        return false;
+    };
+    let state = stat
+        .rfind(')')
+        .and_then(|end| stat[end + 1..].split_whitespace().next());
+    matches!(state, Some("T" | "t"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_stopped(pid: i32) -> bool {
+    pending_wait(pid, libc::WSTOPPED)
+}
+
+/// Share of a scheduler timeslice charged for each chunk read.
+const TIMESLICE_PERCENT_PER_CHUNK: i32 = 1;
+
+/// Reads stdout and stderr alternately, one chunk from each per turn, until
+/// `max_bytes` have been read in total or neither has data ready. Streams
+/// that are not piped, would block or are at EOF contribute nothing more.
+/// At most `max_reads` reads that return data are made (at least one, so
+/// every call gets somewhere), so a child making many tiny writes cannot
+/// keep the NIF going for longer than a byte limit allows. A read that finds
+/// nothing ends that stream's turn and does not count.
+///
+/// Returns `{stdout, stderr}`, or `{:more, stdout, stderr}` if the NIF used up
+/// its timeslice or `max_reads` first, so the caller can yield before asking
+/// for the rest. A read error returns `{:error, reason}`, leaving what was
+/// read so far buffered.
+#[rustler::nif]
+fn read_balanced_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    max_bytes: usize,
+    max_reads: usize,
+) -> NifResult<Term<'a>> {
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let mut stderr_lock = lock(&resource.stderr_pipe)?;
+    let mut streams = [stdout_lock.as_mut(), stderr_lock.as_mut()];
+    let counters = [&resource.stdout_read, &resource.stderr_read];
+
+    let mut taken = [0, 0];
+    let mut active = [streams[0].is_some(), streams[1].is_some()];
+    let mut buf = [0u8; 4096];
+    let mut total = 0;
+    let max_reads = max_reads.max(1);
+    let mut reads = 0;
+    let mut yielded = false;
+
+    while total < max_bytes && active.contains(&true) && !yielded {
+        for i in 0..2 {
+            let Some(stream) = streams[i].as_deref_mut() else {
+                continue;
+            };
+            if !active[i] || total >= max_bytes {
+                continue;
+            }
+
+            let want = (max_bytes - total).min(buf.len());
+            if stream.buffer.len() > taken[i] {
+                let n = want.min(stream.buffer.len() - taken[i]);
+                taken[i] += n;
+                total += n;
+                continue;
+            }
+
+            if reads == max_reads {
+                yielded = true;
+                break;
+            }
+            match stream.read_some(&mut buf[..want]) {
+                Ok(n) if n > 0 => {
+                    reads += 1;
+                    stream.buffer.extend_from_slice(&buf[..n]);
+                    taken[i] += n;
+                    count_io(counters[i], n);
+                    total += n;
+                    yielded =
+                        rustler::schedule::consume_timeslice(env, TIMESLICE_PERCENT_PER_CHUNK);
+                }
+                Ok(_) => active[i] = false,
+                Err(ref e) if is_would_block(e) => active[i] = false,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+            }
+        }
+    }
+
+    if total > 0 {
+        resource.touch_io();
+    }
+
+    let (stdout, stderr) = drain_pair(env, streams, taken)?;
+    if yielded && total < max_bytes {
+        Ok((atoms::more(), stdout, stderr).encode(env))
+    } else {
+        Ok((stdout, stderr).encode(env))
+    }
+}
+
+/// `drain_binary` for stdout and stderr together: takes the first `taken`
+/// bytes of each stream's buffer, allocating both binaries before draining
+/// either, so a failed allocation leaves everything buffered.
+fn drain_pair<'a>(
+    env: Env<'a>,
+    mut streams: [Option<&mut StdioStream>; 2],
+    taken: [usize; 2],
+) -> NifResult<(Binary<'a>, Binary<'a>)> {
+    let data = |i: usize| {
+        streams[i]
+            .as_deref()
+            .map_or(&[][..], |s| &s.buffer[..taken[i]])
+    };
+    let binaries = (binary_from(env, data(0))?, binary_from(env, data(1))?);
+    for (stream, n) in streams.iter_mut().zip(taken) {
+        if let Some(stream) = stream.as_deref_mut() {
+            stream.buffer.drain(..n);
+        }
+    }
+    Ok(binaries)
+}
+
+/// Reads the `primary` stream (`:stdout` or `:stderr`) until it has nothing
+/// ready or `primary_max_bytes` have been read from it, and only then the
+/// other one, until `max_bytes` have been read in total.
+///
+/// Returns `{stdout, stderr}`, or `{:more, stdout, stderr}` if the NIF used up
+/// its timeslice or `max_reads` first, and `{:error, reason}` on a read
+/// error, like `read_balanced_nif`.
+#[rustler::nif]
+fn read_prioritized_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    primary: rustler::Atom,
+    max_bytes: usize,
+    primary_max_bytes: usize,
+    max_reads: usize,
+) -> NifResult<Term<'a>> {
+    let first = if primary == atoms::stdout() {
+        0
+    } else if primary == atoms::stderr() {
+        1
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let mut stderr_lock = lock(&resource.stderr_pipe)?;
+    let mut streams = [stdout_lock.as_mut(), stderr_lock.as_mut()];
+    let counters = [&resource.stdout_read, &resource.stderr_read];
+
+    let mut taken = [0, 0];
+    let mut buf = [0u8; 4096];
+    let mut total = 0;
+    let max_reads = max_reads.max(1);
+    let mut reads = 0;
+    let mut yielded = false;
+
+    let limits = [primary_max_bytes.min(max_bytes), max_bytes];
+    for (i, limit) in [first, 1 - first].into_iter().zip(limits) {
+        let Some(stream) = streams[i].as_deref_mut() else {
+            continue;
+        };
+        while total < limit && !yielded {
+            let want = (limit - total).min(buf.len());
+            if stream.buffer.len() > taken[i] {
+                let n = want.min(stream.buffer.len() - taken[i]);
+                taken[i] += n;
+                total += n;
+                continue;
+            }
+
+            if reads == max_reads {
+                yielded = true;
+                break;
+            }
+            match stream.read_some(&mut buf[..want]) {
+                Ok(n) if n > 0 => {
+                    reads += 1;
+                    stream.buffer.extend_from_slice(&buf[..n]);
+                    taken[i] += n;
+                    count_io(counters[i], n);
+                    total += n;
+                    yielded =
+                        rustler::schedule::consume_timeslice(env, TIMESLICE_PERCENT_PER_CHUNK);
+                }
+                Ok(_) => break,
+                Err(ref e) if is_would_block(e) => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+            }
+        }
+    }
+
+    if total > 0 {
+        resource.touch_io();
+    }
+
+    let (stdout, stderr) = drain_pair(env, streams, taken)?;
+    if yielded && total < max_bytes {
+        Ok((atoms::more(), stdout, stderr).encode(env))
+    } else {
+        Ok((stdout, stderr).encode(env))
+    }
+}
+
+/// Performs up to `max_chunks` reads of stdout, stopping early once
+/// `max_total_bytes` have been read, and returns `{chunks, status}`: the
+/// chunks as read, uncombined, and `:more` if a limit or the timeslice ended
+/// the batch, `:would_block`, `:eof` or `{:error, reason}` otherwise.
+#[rustler::nif]
+fn read_stdout_chunks_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    max_chunks: usize,
+    max_total_bytes: usize,
+) -> NifResult<Term<'a>> {
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let Some(stream) = stdout_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    let mut lens = Vec::new();
+    let mut total = 0;
+    if !stream.buffer.is_empty() && max_chunks > 0 && max_total_bytes > 0 {
+        let n = stream.buffer.len().min(max_total_bytes);
+        total += n;
+        lens.push(n);
+    }
+
+    let mut buf = [0u8; READ_CHUNK];
+    let status = loop {
+        if lens.len() >= max_chunks || total >= max_total_bytes {
+            break atoms::more().encode(env);
+        }
+        let want = (max_total_bytes - total).min(buf.len());
+        match stream.read_some(&mut buf[..want]) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => {
+                break atoms::would_block().encode(env)
+            }
+            Ok(0) => break atoms::eof().encode(env),
+            Ok(n) => {
+                total += n;
+                count_io(&resource.stdout_read, n);
+                stream.buffer.extend_from_slice(&buf[..n]);
+                lens.push(n);
+                if rustler::schedule::consume_timeslice(env, TIMESLICE_PERCENT_PER_CHUNK) {
+                    break atoms::more().encode(env);
+                }
+            }
+            Err(ref e) if is_would_block(e) => break atoms::would_block().encode(env),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => break (atoms::error(), format!("{}", e)).encode(env),
+        }
+    };
+
+    if total > 0 {
+        resource.touch_io();
+    }
+
+    // The chunks stay buffered until every binary exists, so a failed
+    // allocation leaves them for the next read.
+    let mut start = 0;
+    let mut chunks = Vec::with_capacity(lens.len());
+    for n in lens {
+        chunks.push(binary_from(env, &stream.buffer[start..start + n])?);
+        start += n;
+    }
+    stream.buffer.drain(..total);
+    Ok((chunks, status).encode(env))
+}
+
+/// Buffer usage of a pipe, as returned by `pipe_stats_nif`.
+#[derive(NifMap)]
+struct PipeStats {
+    /// Pipe buffer size in bytes (`F_GETPIPE_SZ`).
+    capacity: i32,
+    /// Bytes currently buffered in the pipe (`FIONREAD`).
+    buffered: i32,
+}
+
+/// Largest chunk moved per call when `splice(2)` can't be used.
+const COPY_CHUNK: usize = 65_536;
+
+/// Moves up to `max_bytes` from `src`'s stdout to `dst`'s stdin without
+/// passing them through the BEAM: with `splice(2)` on Linux when one side is
+/// a pipe, otherwise with a read and a write. Bytes `dst` does not accept
+/// become its pending write and go out first on the next call, as does
+/// anything line mode left buffered on `src`.
+///
+/// Returns `{:ok, moved}`, `:would_block` if either side is not ready, or
+/// `:eof` once `src`'s stdout is exhausted.
+#[rustler::nif]
+fn splice_nif<'a>(
+    env: Env<'a>,
+    src: ResourceArc<ProcessResource>,
+    dst: ResourceArc<ProcessResource>,
+    max_bytes: usize,
+) -> NifResult<Term<'a>> {
+    let mut src_lock = lock(&src.stdout_pipe)?;
+    let mut dst_lock = lock(&dst.stdin_pipe)?;
+    let (Some(from), Some(to)) = (src_lock.as_mut(), dst_lock.as_mut()) else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+    let error = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::BrokenPipe => (atoms::error(), atoms::broken_pipe()).encode(env),
+        _ => (atoms::error(), format!("{}", e)).encode(env),
+    };
+
+    if !to.buffer.is_empty() {
+        let pending = std::mem::take(&mut to.buffer);
+        if let Err(e) = push_stdin(&dst, to, &pending) {
+            return Ok(error(e));
+        }
+        if !to.buffer.is_empty() {
+            return Ok(atoms::would_block().encode(env));
+        }
+    }
+
+    if !from.buffer.is_empty() {
+        let len = from.buffer.len().min(max_bytes);
+        return match push_stdin(&dst, to, &from.buffer[..len]) {
+            Ok(()) => {
+                from.buffer.drain(..len);
+                Ok((atoms::ok(), len).encode(env))
+            }
+            Err(e) => Ok(error(e)),
+        };
+    }
+
+    let spliced = if from.pty || to.pty {
+        None
+    } else {
+        splice_fds(&from.file, &to.file, max_bytes)
+    };
+    let result = match spliced {
+        Some(Ok(n)) => {
+            if n > 0 {
+                dst.touch_io();
+                count_io(&dst.stdin_written, n);
+            }
+            Ok(n)
+        }
+        Some(Err(e)) => Err(e),
+        None => {
+            let mut buf = vec![0u8; max_bytes.min(COPY_CHUNK)];
+            from.read_some(&mut buf).and_then(|n| {
+                // Bytes stdin refused outright go back on `src` for the
+                // next call rather than being dropped.
+                push_stdin(&dst, to, &buf[..n])
+                    .map(|()| n)
+                    .inspect_err(|_| from.buffer.extend_from_slice(&buf[..n]))
+            })
+        }
+    };
+
+    match result {
+        Ok(0) if from.socket && !socket_hung_up(from) => Ok(atoms::would_block().encode(env)),
+        Ok(0) => Ok(atoms::eof().encode(env)),
+        Ok(n) => {
+            src.touch_io();
+            count_io(&src.stdout_read, n);
+            Ok((atoms::ok(), n).encode(env))
+        }
+        Err(ref e) if is_would_block(e) => Ok(atoms::would_block().encode(env)),
+        Err(e) => Ok(error(e)),
+    }
+}
+
+/// Writes `data` to stdin, appending whatever the pipe does not accept to
+/// the stream's pending tail. Only hard errors are returned.
+fn push_stdin(
+    resource: &ProcessResource,
+    stdin: &mut StdioStream,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let written = match stdin.write_some(data) {
+        Ok(n) => n,
+        Err(ref e) if is_would_block(e) => 0,
+        Err(e) => return Err(e),
+    };
+    if written > 0 {
+        resource.touch_io();
+        count_io(&resource.stdin_written, written);
+    }
+    stdin.buffer.extend_from_slice(&data[written..]);
+    Ok(())
+}
+
+/// Moves bytes between two fds in the kernel. Returns `None` when `splice`
+/// can't be used: off Linux, or when neither fd is a pipe.
+#[cfg(target_os = "linux")]
+fn splice_fds(from: &File, to: &File, len: usize) -> Option<std::io::Result<usize>> {
+    if let Err(e) = fault::inject(fault::Site::Write) {
+        return Some(Err(e));
+    }
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let null = std::ptr::null_mut();
+    let n = unsafe { libc::splice(from.as_raw_fd(), null, to.as_raw_fd(), null, len, flags) };
+    if n >= 0 {
+        return Some(Ok(n as usize));
+    }
+    let e = std::io::Error::last_os_error();
+    (e.raw_os_error() != Some(libc::EINVAL)).then_some(Err(e))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn splice_fds(_from: &File, _to: &File, _len: usize) -> Option<std::io::Result<usize>> {
+    None
+}
+
+/// Counts the child's open file descriptors from `/proc/<pid>/fd` (Linux
+/// only). Like `signal_nif`, holds the child lock so a reaped child's PID is
+/// never looked at.
+#[rustler::nif]
+fn fd_count_nif<'a>(env: Env<'a>, resource: ResourceArc<ProcessResource>) -> NifResult<Term<'a>> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+
+    match fd_count(resource.pid) {
+        Ok(count) => Ok((atoms::ok(), count).encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn fd_count(pid: i32) -> std::io::Result<usize> {
+    Ok(std::fs::read_dir(format!("/proc/{}/fd", pid))?.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fd_count(_pid: i32) -> std::io::Result<usize> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// The child's controlling terminal (Linux only): `{:ok, path}` for a
+/// device found in `/dev/pts` or `/dev`, `{:ok, {major, minor}}` for one
+/// that is not, or `{:ok, :none}`. Holds the child lock like `fd_count_nif`.
+#[rustler::nif]
+fn controlling_tty_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+
+    match controlling_tty(resource.pid) {
+        Ok(None) => Ok((atoms::ok(), atoms::none()).encode(env)),
+        Ok(Some((major, minor))) => match tty_path(major, minor) {
+            Some(path) => Ok((atoms::ok(), path).encode(env)),
+            None => Ok((atoms::ok(), (major, minor)).encode(env)),
+        },
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Major and minor number of the controlling terminal from field 7
+/// (`tty_nr`) of `/proc/<pid>/stat`, counted like `start_time`. Zero means
+/// none.
+#[cfg(target_os = "linux")]
+fn controlling_tty(pid: i32) -> std::io::Result<Option<(u32, u32)>> {
+    let malformed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed stat");
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let fields = &stat[stat.rfind(')').ok_or_else(malformed)? + 1..];
+    let tty_nr: u32 = fields
+        .split_whitespace()
+        .nth(4)
+        .and_then(|field| field.parse::<i32>().ok())
+        .ok_or_else(malformed)? as u32;
+    if tty_nr == 0 {
+        return Ok(None);
+    }
+    let major = (tty_nr >> 8) & 0xfff;
+    let minor = (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00);
+    Ok(Some((major, minor)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn controlling_tty(_pid: i32) -> std::io::Result<Option<(u32, u32)>> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// Finds the character device with this number among the pseudo-terminals
+/// in `/dev/pts` and the `tty*` and `console` devices in `/dev`.
+fn tty_path(major: u32, minor: u32) -> Option<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let pts = std::fs::read_dir("/dev/pts").into_iter().flatten();
+    let dev = std::fs::read_dir("/dev")
+        .into_iter()
+        .flatten()
+        .filter(|entry| {
+            entry.as_ref().is_ok_and(|entry| {
+                let name = entry.file_name();
+                name.as_bytes().starts_with(b"tty") || name == "console"
+            })
+        });
+    pts.chain(dev).flatten().find_map(|entry| {
+        let metadata = std::fs::metadata(entry.path()).ok()?;
+        let rdev = metadata.rdev() as libc::dev_t;
+        let matches = metadata.file_type().is_char_device()
+            && libc::major(rdev) as u32 == major
+            && libc::minor(rdev) as u32 == minor;
+        matches.then(|| entry.path().to_string_lossy().into_owned())
+    })
+}
+
+/// Reads back the child's nice value, scheduling policy and CPU affinity
+/// (Linux only), to check settings that need privileges and may have been
+/// refused or clamped. Holds the child lock like `fd_count_nif`.
+///
+/// Returns `{:ok, %{nice: nice, policy: policy, cpus: cpus}}`.
+#[rustler::nif]
+fn sched_info_nif<'a>(env: Env<'a>, resource: ResourceArc<ProcessResource>) -> NifResult<Term<'a>> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+
+    match sched_info(resource.pid) {
+        Ok((nice, policy, cpus)) => {
+            let policy = match policy {
+                libc::SCHED_OTHER => atoms::other().encode(env),
+                libc::SCHED_BATCH => atoms::batch().encode(env),
+                libc::SCHED_IDLE => atoms::idle().encode(env),
+                libc::SCHED_FIFO => atoms::fifo().encode(env),
+                libc::SCHED_RR => atoms::rr().encode(env),
+                SCHED_DEADLINE => atoms::deadline().encode(env),
+                other => other.encode(env),
+            };
+            let info = Term::map_from_pairs(
+                env,
+                &[
+                    (atoms::nice().encode(env), nice.encode(env)),
+                    (atoms::policy().encode(env), policy),
+                    (atoms::cpus().encode(env), cpus.encode(env)),
+                ],
+            )?;
+            Ok((atoms::ok(), info).encode(env))
+        }
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// `SCHED_DEADLINE`, which the libc crate does not define.
+const SCHED_DEADLINE: libc::c_int = 6;
+
+/// Nice value, scheduling policy and allowed CPUs of `pid`.
+#[cfg(target_os = "linux")]
+fn sched_info(pid: i32) -> std::io::Result<(i32, libc::c_int, Vec<usize>)> {
+    // -1 is a valid nice value, so only errno tells a failure apart.
+    nix::errno::Errno::clear();
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t) };
+    if nice == -1 && nix::errno::Errno::last_raw() != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let policy = unsafe { libc::sched_getscheduler(pid) };
+    if policy == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_getaffinity(pid, size, &mut set) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let cpus = (0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect();
+
+    // The policy may carry SCHED_RESET_ON_FORK.
+    Ok((nice, policy & !libc::SCHED_RESET_ON_FORK, cpus))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sched_info(_pid: i32) -> std::io::Result<(i32, libc::c_int, Vec<usize>)> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// Subscribes `pid` to stdout of a `stdout: :fanout` child, with a window of
+/// `window` chunks it may be sent before acknowledging them.
+#[rustler::nif]
+fn subscribe_nif(
+    env: Env,
+    resource: ResourceArc<ProcessResource>,
+    pid: rustler::LocalPid,
+    window: usize,
+) -> Term {
+    match &resource.stdout_fanout {
+        None => (atoms::error(), atoms::not_fanout()).encode(env),
+        Some(fanout) if fanout.subscribe(env, pid, window) => atoms::ok().encode(env),
+        Some(_) => (atoms::error(), atoms::already_subscribed()).encode(env),
+    }
+}
+
+/// Acknowledges `count` chunks on behalf of the calling process.
+#[rustler::nif]
+fn ack_nif(env: Env, resource: ResourceArc<ProcessResource>, count: usize) -> Term {
+    match &resource.stdout_fanout {
+        None => (atoms::error(), atoms::not_fanout()).encode(env),
+        Some(fanout) if fanout.ack(env.pid(), count) => atoms::ok().encode(env),
+        Some(_) => (atoms::error(), atoms::not_subscribed()).encode(env),
+    }
+}
+
+/// Unsubscribes the calling process.
+#[rustler::nif]
+fn unsubscribe_nif(env: Env, resource: ResourceArc<ProcessResource>) -> Term {
+    match &resource.stdout_fanout {
+        None => (atoms::error(), atoms::not_fanout()).encode(env),
+        Some(fanout) if fanout.unsubscribe(env, env.pid()) => atoms::ok().encode(env),
+        Some(_) => (atoms::error(), atoms::not_subscribed()).encode(env),
+    }
+}
+
+/// Returns what the tail ring of stdout or stderr currently holds.
+#[rustler::nif]
+fn tail_output_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    stream: rustler::Atom,
+) -> NifResult<Term<'a>> {
+    let tail = if stream == atoms::stdout() {
+        &resource.stdout_tail
+    } else if stream == atoms::stderr() {
+        &resource.stderr_tail
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    match tail {
+        Some(tail) => Ok((atoms::ok(), binary_from(env, &tail.contents())?).encode(env)),
+        None => Ok((atoms::error(), atoms::not_tailed()).encode(env)),
+    }
+}
+
+/// Returns the chunks of the tailed streams recorded with
+/// `interleaved_log`, oldest first, as `{stream, monotonic_ns, data}`.
+#[rustler::nif]
+fn interleaved_log_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let Some(interleaved) = &resource.interleaved else {
+        return Ok((atoms::error(), atoms::not_interleaved()).encode(env));
+    };
+    let chunks = interleaved
+        .chunks()
+        .into_iter()
+        .map(|chunk| {
+            let stream = match chunk.stream {
+                interleave::Stream::Stdout => atoms::stdout(),
+                interleave::Stream::Stderr => atoms::stderr(),
+            };
+            Ok((stream, chunk.monotonic_ns, binary_from(env, &chunk.data)?).encode(env))
+        })
+        .collect::<NifResult<Vec<Term>>>()?;
+    Ok((atoms::ok(), chunks).encode(env))
+}
+
+/// Points a `{:log, path}` stream at a newly opened `path`, appending. The
+/// child is unaffected; output it writes after the swap lands in the new
+/// file. Other modes give the file to the child, so they return
+/// `{:error, :not_supported}`.
+#[rustler::nif]
+fn reopen_output_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    stream: rustler::Atom,
+    path: String,
+) -> NifResult<Term<'a>> {
+    let log = if stream == atoms::stdout() {
+        &resource.stdout_log
+    } else if stream == atoms::stderr() {
+        &resource.stderr_log
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    match log.as_ref().map(|log| log.reopen(&path)) {
+        Some(Ok(())) => Ok(atoms::ok().encode(env)),
+        Some(Err(e)) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+        None => Ok((atoms::error(), atoms::not_supported()).encode(env)),
+    }
+}
+
+/// Hands piped stdin to a thread that feeds it the contents of `path`, after
+/// any bytes a partial write left pending, and closes it at EOF. Later
+/// writes from the BEAM get `{:error, :not_piped}`. A pty's input is shared
+/// with its output, so it returns `{:error, :not_supported}`.
+#[rustler::nif]
+fn stdin_from_file_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    path: String,
+) -> NifResult<Term<'a>> {
+    let source = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+    };
+    let mut stdin_lock = lock(&resource.stdin_pipe)?;
+    let Some(stream) = stdin_lock.take_if(|stream| !stream.pty) else {
+        let reason = if stdin_lock.is_some() {
+            atoms::not_supported()
+        } else {
+            atoms::not_piped()
+        };
+        return Ok((atoms::error(), reason).encode(env));
+    };
+    if let Err(e) = set_blocking(&stream.file) {
+        *stdin_lock = Some(stream);
+        return Ok((atoms::error(), format!("{}", e)).encode(env));
+    }
+    match stdin_file::feed(stream.buffer, source, stream.file) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Reads up to `length` bytes at `offset` from a `:tmpfile` stream, while
+/// the child runs or after. Returns `:eof` at or past the end of what the
+/// child has written, and `{:error, :not_supported}` for other modes.
+#[rustler::nif(schedule = "DirtyIo")]
+fn read_output_file_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    stream: rustler::Atom,
+    offset: u64,
+    length: usize,
+) -> NifResult<Term<'a>> {
+    let tmpfile = if stream == atoms::stdout() {
+        &resource.stdout_tmpfile
+    } else if stream == atoms::stderr() {
+        &resource.stderr_tmpfile
+    } else {
+        return Err(Error::BadArg);
+    };
+    if length == 0 {
+        return Err(Error::BadArg);
+    }
+    let Some(file) = tmpfile else {
+        return Ok((atoms::error(), atoms::not_supported()).encode(env));
+    };
+
+    let size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+    };
+    if offset >= size {
+        return Ok(atoms::eof().encode(env));
+    }
+    let length = length.min((size - offset).try_into().unwrap_or(usize::MAX));
+
+    let mut binary = alloc_binary(length)?;
+    match std::os::unix::fs::FileExt::read_exact_at(file, binary.as_mut_slice(), offset) {
+        Ok(()) => Ok((atoms::ok(), Binary::from_owned(binary, env)).encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Reports the capacity and fill level of a piped stream (Linux only). For
+/// stdin, `capacity - buffered` is the room left for writes; for output
+/// streams, `buffered` is what the next reads will return.
+#[rustler::nif]
+fn pipe_stats_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    stream: rustler::Atom,
+) -> NifResult<Term<'a>> {
+    let pipe = if stream == atoms::stdin() {
+        &resource.stdin_pipe
+    } else if stream == atoms::stdout() {
+        &resource.stdout_pipe
+    } else if stream == atoms::stderr() {
+        &resource.stderr_pipe
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    let stream_lock = lock(pipe)?;
+    let Some(stream) = stream_lock.as_ref().filter(|s| !s.socket && !s.pty) else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    match pipe_stats(stream) {
+        Ok(stats) => Ok((atoms::ok(), stats).encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pipe_stats(stream: &StdioStream) -> Result<PipeStats, nix::Error> {
+    let capacity = fcntl(stream.as_raw_fd(), FcntlArg::F_GETPIPE_SZ)?;
+    let mut buffered: libc::c_int = 0;
+    if unsafe { libc::ioctl(stream.as_raw_fd(), libc::FIONREAD, &mut buffered) } == -1 {
+        return Err(nix::Error::last());
+    }
+    Ok(PipeStats { capacity, buffered })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pipe_stats(_stream: &StdioStream) -> Result<PipeStats, nix::Error> {
+    Err(nix::Error::ENOTSUP)
+}
+
+/// Restores O_NONBLOCK on every piped stream and returns the streams that
+/// had lost it. Streams that are not piped are skipped.
+#[rustler::nif]
+fn reassert_nonblocking_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+) -> NifResult<Term<'a>> {
+    let streams = [
+        (atoms::stdin(), &resource.stdin_pipe),
+        (atoms::stdout(), &resource.stdout_pipe),
+        (atoms::stderr(), &resource.stderr_pipe),
+    ];
+
+    let mut fixed = Vec::new();
+    for (i, (name, pipe)) in streams.into_iter().enumerate() {
+        if let Some(stream) = lock(pipe)?.as_ref() {
+            match reassert_nonblocking(stream) {
+                Ok(true) => fixed.push(name),
+                Ok(false) => {}
+                Err(e) => return Ok((atoms::error(), name, format!("{}", e)).encode(env)),
+            }
+            resource.nonblocking[i].store(true, Ordering::Relaxed);
+        }
+    }
+
+    Ok((atoms::ok(), fixed).encode(env))
+}
+
+/// Milliseconds since spawn while the child runs, or its total runtime once
+/// it has been reaped. The end time is taken when the exit is observed.
+#[rustler::nif]
+fn runtime_ms_nif(resource: ResourceArc<ProcessResource>) -> NifResult<u64> {
+    let end = lock(&resource.exited_at)?.unwrap_or_else(Instant::now);
+    Ok(end.duration_since(resource.spawned_at).as_millis() as u64)
+}
+
+/// Returns `:posix_spawn` or `:fork_exec` depending on how the child was
+/// started, or `:adopted` for a process taken over with `adopt_nif`.
+#[rustler::nif]
+fn spawn_method_nif(resource: ResourceArc<ProcessResource>) -> rustler::Atom {
+    resource.method.atom()
+}
+
+/// Milliseconds since the last read from stdout/stderr that returned data or
+/// the last write to stdin that accepted bytes. Counts from spawn if there
+/// has been no I/O yet.
+#[rustler::nif]
+fn idle_ms_nif(resource: ResourceArc<ProcessResource>) -> u64 {
+    let last_io = Duration::from_nanos(resource.last_io_ns.load(Ordering::Relaxed));
+    resource
+        .spawned_at
+        .elapsed()
+        .saturating_sub(last_io)
+        .as_millis() as u64
+}
+
+/// The stdio modes recorded at spawn time, and the streams known to be
+/// non-blocking, as `{stdin, stdout, stderr, nonblocking}`. The modes do not
+/// change when a stream is later closed or taken.
+#[rustler::nif]
+fn stdio_modes_nif(
+    resource: ResourceArc<ProcessResource>,
+) -> (StdioMode, StdioMode, StdioMode, Vec<rustler::Atom>) {
+    let [stdin, stdout, stderr] = resource.stdio_modes;
+    let nonblocking = [atoms::stdin(), atoms::stdout(), atoms::stderr()]
+        .into_iter()
+        .zip(&resource.nonblocking)
+        .filter(|(_, flag)| flag.load(Ordering::Relaxed))
+        .map(|(name, _)| name)
+        .collect();
+    (stdin, stdout, stderr, nonblocking)
+}
+
+/// Bytes moved through the child's stdio by the read and write NIFs, as
+/// `{stdin_written, stdout_read, stderr_read}`. Bytes buffered for a line or
+/// frame count when read from the pipe, not when returned.
+#[rustler::nif]
+fn io_counters_nif(resource: ResourceArc<ProcessResource>) -> (u64, u64, u64) {
+    (
+        resource.stdin_written.load(Ordering::Relaxed),
+        resource.stdout_read.load(Ordering::Relaxed),
+        resource.stderr_read.load(Ordering::Relaxed),
+    )
+}
+
+/// Disarms the `timeout:` watchdog. Returns `{:error, :no_timeout}` if it
+/// was never armed, was already cancelled, or has fired.
+#[rustler::nif]
+fn cancel_timeout_nif(env: Env, resource: ResourceArc<ProcessResource>) -> Term {
+    if resource.watchdog.cancel() {
+        atoms::ok().encode(env)
+    } else {
+        (atoms::error(), atoms::no_timeout()).encode(env)
+    }
+}
+
+/// Moves the `timeout:` deadline to `timeout_ms` from now, earlier or
+/// later. Returns `{:error, :no_timeout}` like `cancel_timeout_nif`.
+#[rustler::nif]
+fn extend_timeout_nif(
+    env: Env,
+    resource: ResourceArc<ProcessResource>,
+    timeout_ms: u64,
+) -> NifResult<Term> {
+    let deadline = deadline_after(timeout_ms).ok_or(Error::BadArg)?;
+    if resource.watchdog.extend(deadline) {
+        Ok(atoms::ok().encode(env))
+    } else {
+        Ok((atoms::error(), atoms::no_timeout()).encode(env))
+    }
+}
+
+/// Arms, re-arms or (with `nil`) disarms a watchdog that SIGTERMs the child
+/// once it has run `lifetime_ms` since spawn, and SIGKILLs it `grace_ms`
+/// later if it has not exited by then. Independent of the `timeout:`
+/// watchdog. Checked under the child lock, so a reaped child is never armed
+/// for. A lifetime already used up fires at once.
+#[rustler::nif]
+fn set_max_lifetime_nif(
+    env: Env,
+    resource: ResourceArc<ProcessResource>,
+    lifetime_ms: Option<u64>,
+    grace_ms: u64,
+) -> NifResult<Term> {
+    let child_lock = lock(&resource.child)?;
+    if released(&resource, &child_lock)? {
+        return Ok((atoms::error(), atoms::already_exited()).encode(env));
+    }
+
+    let Some(lifetime_ms) = lifetime_ms else {
+        resource.lifetime.cancel();
+        return Ok(atoms::ok().encode(env));
+    };
+    let deadline = resource
+        .spawned_at
+        .checked_add(Duration::from_millis(lifetime_ms))
+        .ok_or(Error::BadArg)?;
+    resource
+        .lifetime_grace_ms
+        .store(grace_ms, Ordering::Relaxed);
+    match start_lifetime(resource.clone(), deadline) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(e) => Err(Error::Term(Box::new(format!(
+            "Failed to start lifetime watchdog: {}",
+            e
+        )))),
+    }
+}
+
+/// Closes every pipe, SIGKILLs the child if it is still running and reaps
+/// it. Safe to call repeatedly; later calls return the same exit.
+#[rustler::nif(schedule = "DirtyIo")]
+fn destroy_nif<'a>(env: Env<'a>, resource: ResourceArc<ProcessResource>) -> NifResult<Term<'a>> {
+    for pipe in [
+        &resource.stdin_pipe,
+        &resource.stdout_pipe,
+        &resource.stderr_pipe,
+    ] {
+        lock(pipe)?.take();
+    }
+
+    kill_if_running(&resource, Signal::SIGKILL)?;
+    let exit = wait_exit(&resource)?;
+    Ok((atoms::ok(), encode_exit(env, exit)).encode(env))
+}
+
+/// The steps `shutdown_nif` takes, passed from Elixir as a map.
+#[derive(NifMap)]
+struct ShutdownOptions {
+    /// Close stdin first, so a child reading it sees EOF.
+    close_stdin: bool,
+    /// Read stdout and stderr to EOF and return what they held.
+    drain: bool,
+    /// Signal sent once stdin is closed; SIGKILL follows if the child is
+    /// still running `grace_ms` later.
+    signal: Option<i32>,
+    grace_ms: u64,
+}
+
+/// Tears the child down in an order that neither loses output nor hangs:
+/// closes stdin, signals the child, reads stdout and stderr to EOF, closes
+/// them and only then reaps the child. Draining before reaping keeps what
+/// the child wrote while exiting, and without `drain` the output pipes are
+/// closed before the wait, so a child blocked writing to a full one gets
+/// EPIPE instead of hanging it.
+///
+/// With a signal, the drain and the wait share the grace period, after
+/// which the child is SIGKILLed and its remaining output collected, for up
+/// to another grace period in case a grandchild holds the pipes open.
+/// Without one, both wait for as long as the child takes. Returns
+/// `{:ok, exit, stdout, stderr}`, with empty output without `drain`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn shutdown_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    options: ShutdownOptions,
+) -> NifResult<Term<'a>> {
+    let signal = options.signal.map(parse_signal).transpose()?;
+    let grace = Duration::from_millis(options.grace_ms);
+
+    if options.close_stdin {
+        lock(&resource.stdin_pipe)?.take();
+    }
+    if let Some(signal) = signal {
+        kill_if_running(&resource, signal)?;
+    }
+    let deadline = signal.map(|_| Instant::now() + grace);
+
+    let mut streams = [
+        lock(&resource.stdout_pipe)?.take(),
+        lock(&resource.stderr_pipe)?.take(),
+    ];
+    let counters = [&resource.stdout_read, &resource.stderr_read];
+    let buf_size = resource.drain_buf;
+    let output = if options.drain {
+        let open = [streams[0].as_ref(), streams[1].as_ref()];
+        // Unbounded, so never over the limit.
+        match drain_streams(open, counters, deadline, 0, Overflow::Fail, buf_size) {
+            DrainOutcome::Complete(output) => output,
+            DrainOutcome::LimitExceeded => Default::default(),
+            DrainOutcome::TimedOut(mut output) => {
+                kill_if_running(&resource, Signal::SIGKILL)?;
+                // Buffered bytes are already in `output`.
+                for stream in streams.iter_mut().flatten() {
+                    stream.buffer.clear();
+                }
+                let open = [streams[0].as_ref(), streams[1].as_ref()];
+                let rest = match drain_streams(
+                    open,
+                    counters,
+                    Some(Instant::now() + grace),
+                    0,
+                    Overflow::Fail,
+                    buf_size,
+                ) {
+                    DrainOutcome::Complete(rest) | DrainOutcome::TimedOut(rest) => rest,
+                    DrainOutcome::LimitExceeded => Default::default(),
+                };
+                for (output, rest) in output.iter_mut().zip(rest) {
+                    output.extend(rest);
+                }
+                output
+            }
+        }
+    } else {
+        Default::default()
+    };
+    drop(streams);
+
+    let exit = match wait_exit_until(&resource, deadline)? {
+        Some(exit) => exit,
+        None => {
+            kill_if_running(&resource, Signal::SIGKILL)?;
+            wait_exit(&resource)?
+        }
+    };
+    let [stdout, stderr] = output;
+    Ok((
+        atoms::ok(),
+        encode_exit(env, exit),
+        binary_from(env, &stdout)?,
+        binary_from(env, &stderr)?,
+    )
+        .encode(env))
+}
+
+/// Kills the child and everything it started by freezing its cgroup,
+/// SIGKILLing every process in it and thawing it, then reaps the child.
+/// Unlike a signal to a process group, nothing can fork or slip out in
+/// between. Returns `{:error, :not_in_cgroup}` if the child was not spawned
+/// with `cgroup`, and `{:error, :own_cgroup}`, without killing anything, if
+/// the BEAM itself is in that cgroup.
+#[rustler::nif(schedule = "DirtyIo")]
+fn kill_tree_nif<'a>(env: Env<'a>, resource: ResourceArc<ProcessResource>) -> NifResult<Term<'a>> {
+    let Some(dir) = &resource.cgroup else {
+        return Ok((atoms::error(), atoms::not_in_cgroup()).encode(env));
+    };
+    #[cfg(target_os = "linux")]
+    {
+        let failed = |e: std::io::Error| {
+            Error::Term(Box::new(format!(
+                "Failed to kill cgroup {}: {}",
+                dir.display(),
+                e
+            )))
+        };
+        if cgroup::contains_self(dir).map_err(failed)? {
+            return Ok((atoms::error(), atoms::own_cgroup()).encode(env));
+        }
+        cgroup::kill(dir).map_err(failed)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = dir;
+
+    // The child may have moved itself out of the cgroup since.
+    kill_if_running(&resource, Signal::SIGKILL)?;
+    let exit = wait_exit(&resource)?;
+    Ok((atoms::ok(), encode_exit(env, exit)).encode(env))
+}
+
+/// Writes the terminal's interrupt (`:intr`), end-of-file (`:eof`) or
+/// suspend (`:susp`) character to the pty, as if typed at the keyboard.
+#[rustler::nif]
+fn send_control_char_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    control: rustler::Atom,
+) -> NifResult<Term<'a>> {
+    let Some(master) = resource.pty_master.as_ref() else {
+        return Ok((atoms::error(), atoms::not_pty()).encode(env));
+    };
+
+    let control = if control == atoms::intr() {
+        pty::ControlChar::Interrupt
+    } else if control == atoms::eof() {
+        pty::ControlChar::Eof
+    } else if control == atoms::susp() {
+        pty::ControlChar::Suspend
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    match pty::send_control_char(master, control) {
+        Ok(()) => {
+            resource.touch_io();
+            Ok(atoms::ok().encode(env))
+        }
+        Err(ref e) if is_would_block(e) => Ok(atoms::would_block().encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Resizes the pty to `rows` x `cols` and signals SIGWINCH to its
+/// foreground process group.
+#[rustler::nif]
+fn notify_resize_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    rows: u16,
+    cols: u16,
+) -> NifResult<Term<'a>> {
+    let Some(master) = resource.pty_master.as_ref() else {
+        return Ok((atoms::error(), atoms::not_pty()).encode(env));
+    };
+
+    match pty::resize(master, rows, cols) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Switches the pty between `:raw`, `:cooked` and `:no_echo` modes.
+#[rustler::nif]
+fn set_term_mode_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    mode: rustler::Atom,
 ) -> NifResult<Term<'a>> {
-    let mut stderr_lock = resource
-        .stderr_pipe
-        .lock()
-        .map_err(|e| Error::Term(Box::new(format!("Lock failed: {}", e))))?;
+    let Some(master) = resource.pty_master.as_ref() else {
+        return Ok((atoms::error(), atoms::not_pty()).encode(env));
+    };
+
+    let mode = if mode == atoms::raw() {
+        pty::TermMode::Raw
+    } else if mode == atoms::cooked() {
+        pty::TermMode::Cooked
+    } else if mode == atoms::no_echo() {
+        pty::TermMode::NoEcho
+    } else {
+        return Err(Error::BadArg);
+    };
+
+    match pty::set_term_mode(master, mode) {
+        Ok(()) => Ok(atoms::ok().encode(env)),
+        Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Converts an optional deadline into a poll timeout. `None` waits forever.
+fn poll_timeout(deadline: Option<Instant>) -> PollTimeout {
+    match deadline {
+        None => PollTimeout::NONE,
+        Some(deadline) => {
+            // Round up so a wait never ends just short of the deadline.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let remaining_ms = remaining.as_nanos().div_ceil(1_000_000);
+            PollTimeout::try_from(remaining_ms.min(i32::MAX as u128) as i32)
+                .unwrap_or(PollTimeout::MAX)
+        }
+    }
+}
+
+/// A timeout passed from Elixir: milliseconds from now (zero waits forever),
+/// or `{:deadline, ns}`, an absolute `CLOCK_MONOTONIC` time that a sequence
+/// of calls can share without recomputing what is left of it.
+enum Timeout {
+    Relative(u64),
+    Deadline(i64),
+}
+
+impl<'a> rustler::Decoder<'a> for Timeout {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if let Ok(timeout_ms) = term.decode::<u64>() {
+            return Ok(Timeout::Relative(timeout_ms));
+        }
+        match term.decode::<(rustler::Atom, i64)>()? {
+            (tag, ns) if tag == atoms::deadline() => Ok(Timeout::Deadline(ns)),
+            _ => Err(Error::BadArg),
+        }
+    }
+}
+
+impl Timeout {
+    fn deadline(self) -> Option<Instant> {
+        match self {
+            Timeout::Relative(timeout_ms) => deadline_after(timeout_ms),
+            Timeout::Deadline(ns) => {
+                let remaining = ns.saturating_sub(monotonic_ns()).max(0) as u64;
+                Some(Instant::now() + Duration::from_nanos(remaining))
+            }
+        }
+    }
+}
+
+/// `CLOCK_MONOTONIC` in nanoseconds, the clock `{:deadline, ns}` refers to.
+fn monotonic_ns() -> i64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32).as_nanos() as i64
+}
+
+#[rustler::nif]
+fn monotonic_ns_nif() -> i64 {
+    monotonic_ns()
+}
+
+fn deadline_after(timeout_ms: u64) -> Option<Instant> {
+    (timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(timeout_ms))
+}
+
+fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Writes all of `data` to a non-blocking stdin stream, parking on writability,
+/// then closes it by dropping the stream. Gives up early if `stop` is raised
+/// or the child stops reading. Returns the number of bytes written.
+fn feed_stdin(stream: StdioStream, data: &[u8], stop: &AtomicBool) -> usize {
+    let mut offset = 0;
+    while offset < data.len() && !stop.load(Ordering::Relaxed) {
+        match stream.write_some(&data[offset..]) {
+            Ok(n) => offset += n,
+            Err(ref e) if is_would_block(e) => {
+                let mut fds = [PollFd::new(stream.file.as_fd(), PollFlags::POLLOUT)];
+                let _ = poll(&mut fds, PollTimeout::from(50u8));
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    offset
+}
+
+/// What `drain_streams` does when a stream's output grows past `max_bytes`.
+#[derive(Clone, Copy, PartialEq)]
+enum Overflow {
+    /// Stop with `LimitExceeded`.
+    Fail,
+    /// Keep reading, holding on to only the last `max_bytes`.
+    KeepLast,
+}
+
+enum DrainOutcome {
+    Complete([Vec<u8>; 2]),
+    /// The deadline passed, with what had been read by then.
+    TimedOut([Vec<u8>; 2]),
+    LimitExceeded,
+}
+
+/// Reads the given streams until every one of them reaches EOF, keeping the
+/// data read from each. Stops early when `deadline` passes or, with
+/// `Overflow::Fail`, when any single stream exceeds `max_bytes` (zero means
+/// unbounded). Reads go through a `buf_size` buffer, and bytes read are added
+/// to the matching `counters`.
+fn drain_streams(
+    streams: [Option<&StdioStream>; 2],
+    counters: [&AtomicU64; 2],
+    deadline: Option<Instant>,
+    max_bytes: usize,
+    overflow: Overflow,
+    buf_size: usize,
+) -> DrainOutcome {
+    let keep_last = |mut output: [Vec<u8>; 2]| {
+        if max_bytes > 0 {
+            for data in output.iter_mut() {
+                data.drain(..data.len().saturating_sub(max_bytes));
+            }
+        }
+        output
+    };
+    let mut output = streams.map(|stream| stream.map(|s| s.buffer.clone()).unwrap_or_default());
+    let mut open = [streams[0].is_some(), streams[1].is_some()];
+    let mut buf = vec![0u8; buf_size];
+
+    loop {
+        let ready: Vec<usize> = {
+            let indices: Vec<usize> = (0..2).filter(|&i| open[i]).collect();
+            if indices.is_empty() {
+                return DrainOutcome::Complete(keep_last(output));
+            }
+            if deadline_passed(deadline) {
+                return DrainOutcome::TimedOut(keep_last(output));
+            }
+
+            let mut fds: Vec<PollFd> = indices
+                .iter()
+                .filter_map(|&i| streams[i])
+                .map(|stream| PollFd::new(stream.file.as_fd(), PollFlags::POLLIN))
+                .collect();
+
+            match poll(&mut fds, poll_timeout(deadline)) {
+                Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+                Err(_) => return DrainOutcome::Complete(keep_last(output)),
+            }
 
-    if let Some(stderr) = stderr_lock.as_mut() {
-        let mut buf = [0u8; 4096];
-        match stderr.read(&mut buf) {
-            Ok(0) => Ok(atoms::eof().encode(env)),
+            indices
+                .iter()
+                .zip(fds.iter())
+                .filter(|(_, fd)| fd.revents().is_some_and(|r| !r.is_empty()))
+                .map(|(&i, _)| i)
+                .collect()
+        };
+
+        for i in ready {
+            let Some(stream) = streams[i] else { continue };
+            loop {
+                match stream.read_some(&mut buf) {
+                    Ok(0) if stream.socket && !socket_hung_up(stream) => break,
+                    Ok(0) => {
+                        open[i] = false;
+                        break;
+                    }
+                    Ok(n) => {
+                        output[i].extend_from_slice(&buf[..n]);
+                        count_io(counters[i], n);
+                        if max_bytes > 0 && output[i].len() > max_bytes {
+                            if overflow == Overflow::Fail {
+                                return DrainOutcome::LimitExceeded;
+                            }
+                            // Trimmed only once twice over the limit, so each
+                            // byte is moved at most once on average.
+                            if output[i].len() > 2 * max_bytes {
+                                let excess = output[i].len() - max_bytes;
+                                output[i].drain(..excess);
+                            }
+                        }
+                    }
+                    Err(ref e) if is_would_block(e) => break,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => {
+                        open[i] = false;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Polls for the child's exit until `deadline`, reaping it when it exits.
+fn wait_exit_until(
+    resource: &ProcessResource,
+    deadline: Option<Instant>,
+) -> NifResult<Option<Exit>> {
+    if deadline.is_none() {
+        return wait_exit(resource).map(Some);
+    }
+    loop {
+        if let Some(exit) = try_reap(resource)? {
+            return Ok(Some(exit));
+        }
+        if deadline_passed(deadline) {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn binary_from<'a>(env: Env<'a>, data: &[u8]) -> NifResult<Binary<'a>> {
+    let mut binary = alloc_binary(data.len())?;
+    binary.as_mut_slice().copy_from_slice(data);
+    Ok(binary.release(env))
+}
+
+/// `OwnedBinary::new`, failing with an error term rather than `None`.
+fn alloc_binary(size: usize) -> NifResult<OwnedBinary> {
+    fault::inject(fault::Site::Alloc)
+        .ok()
+        .and_then(|()| OwnedBinary::new(size))
+        .ok_or_else(|| Error::Term(Box::new("Failed to allocate binary")))
+}
+
+/// Sends `signal` and allows `grace` for the child to exit before
+/// SIGKILLing it, then reaps it. With no signal, SIGKILLs right away.
+fn terminate(resource: &ProcessResource, signal: Option<Signal>, grace: Duration) -> NifResult<()> {
+    if let Some(signal) = signal.filter(|&signal| signal != Signal::SIGKILL) {
+        kill_if_running(resource, signal)?;
+        if wait_exit_until(resource, Some(Instant::now() + grace))?.is_some() {
+            return Ok(());
+        }
+    }
+    kill_if_running(resource, Signal::SIGKILL)?;
+    wait_exit(resource)?;
+    Ok(())
+}
+
+/// How often `race_nif` re-checks racers that have no pidfd to poll.
+const RACE_POLL_MS: u64 = 10;
+
+/// Races already spawned children: collects each one's stdout until the
+/// first exits with status 0, then SIGKILLs and reaps the rest. Returns
+/// `{:ok, index, stdout}` for the winner, `{:error, :all_failed, exits}`
+/// if every child exited unsuccessfully, or `{:error, :timeout}` once
+/// `timeout` passes (zero waits forever), with every child killed.
+///
+/// Output is read as it arrives, so no racer stalls on a full pipe, and the
+/// winner's pipe is drained after its exit, so nothing it wrote is lost.
+#[rustler::nif(schedule = "DirtyIo")]
+fn race_nif<'a>(
+    env: Env<'a>,
+    resources: Vec<ResourceArc<ProcessResource>>,
+    timeout: Timeout,
+) -> NifResult<Term<'a>> {
+    if resources.is_empty() {
+        return Err(Error::BadArg);
+    }
+    let deadline = timeout.deadline();
+    let count = resources.len();
+
+    let mut stdout_locks = resources
+        .iter()
+        .map(|resource| lock(&resource.stdout_pipe))
+        .collect::<NifResult<Vec<_>>>()?;
+    let mut output: Vec<Vec<u8>> = stdout_locks
+        .iter_mut()
+        .map(|stream| {
+            stream
+                .as_mut()
+                .map(|s| std::mem::take(&mut s.buffer))
+                .unwrap_or_default()
+        })
+        .collect();
+    let mut open: Vec<bool> = stdout_locks.iter().map(|stream| stream.is_some()).collect();
+    let mut exits: Vec<Option<Exit>> = vec![None; count];
+    let mut buf = [0u8; READ_CHUNK];
+
+    let winner = loop {
+        for (i, resource) in resources.iter().enumerate() {
+            if exits[i].is_none() {
+                exits[i] = try_reap(resource)?;
+            }
+        }
+        let winner = exits.iter().position(
+            |exit| matches!(exit, Some(Exit::Status(status)) if status.code() == Some(0)),
+        );
+        if winner.is_some() || exits.iter().all(Option::is_some) || deadline_passed(deadline) {
+            break winner;
+        }
+
+        let mut fds = Vec::new();
+        let mut readers = Vec::new();
+        for (i, stream) in stdout_locks.iter().enumerate() {
+            if let Some(stream) = stream.as_ref().filter(|_| open[i]) {
+                fds.push(PollFd::new(stream.file.as_fd(), PollFlags::POLLIN));
+                readers.push(i);
+            }
+        }
+        let mut unwatched = false;
+        for (i, resource) in resources.iter().enumerate() {
+            match &resource.pidfd {
+                Some(pidfd) if exits[i].is_none() => {
+                    fds.push(PollFd::new(pidfd.as_fd(), PollFlags::POLLIN))
+                }
+                None if exits[i].is_none() => unwatched = true,
+                _ => {}
+            }
+        }
+        let fallback = Instant::now() + Duration::from_millis(RACE_POLL_MS);
+        let wake = match deadline {
+            Some(deadline) if !unwatched || deadline < fallback => Some(deadline),
+            _ if unwatched => Some(fallback),
+            _ => None,
+        };
+        match poll(&mut fds, poll_timeout(wake)) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(e) => return Err(Error::Term(Box::new(format!("Failed to poll: {}", e)))),
+        }
+
+        let ready: Vec<usize> = readers
+            .iter()
+            .zip(fds.iter())
+            .filter(|(_, fd)| fd.revents().is_some_and(|r| !r.is_empty()))
+            .map(|(&i, _)| i)
+            .collect();
+        for i in ready {
+            if let Some(stream) = stdout_locks[i].as_ref() {
+                let counter = &resources[i].stdout_read;
+                open[i] = read_available(stream, &mut buf, &mut output[i], counter);
+            }
+        }
+    };
+
+    // Everything the winner wrote before exiting is in the pipe by now.
+    if let Some(i) = winner {
+        if let Some(stream) = stdout_locks[i].as_ref().filter(|_| open[i]) {
+            read_available(stream, &mut buf, &mut output[i], &resources[i].stdout_read);
+        }
+    }
+    drop(stdout_locks);
+
+    for (resource, exit) in resources.iter().zip(&exits) {
+        if exit.is_none() {
+            terminate(resource, None, Duration::ZERO)?;
+        }
+    }
+
+    match winner {
+        Some(i) => Ok((atoms::ok(), i, binary_from(env, &output[i])?).encode(env)),
+        None if exits.iter().all(Option::is_some) => {
+            let exits: Vec<Term> = exits
+                .into_iter()
+                .flatten()
+                .map(|exit| encode_exit(env, exit))
+                .collect();
+            Ok((atoms::error(), atoms::all_failed(), exits).encode(env))
+        }
+        None => Ok((atoms::error(), atoms::timeout()).encode(env)),
+    }
+}
+
+/// Reads what `stream` has ready into `output`. Returns false at EOF or on
+/// an error, true when it would block.
+fn read_available(
+    stream: &StdioStream,
+    buf: &mut [u8],
+    output: &mut Vec<u8>,
+    counter: &AtomicU64,
+) -> bool {
+    loop {
+        match stream.read_some(buf) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => return true,
+            Ok(0) => return false,
             Ok(n) => {
-                let mut binary = OwnedBinary::new(n)
-                    .ok_or_else(|| Error::Term(Box::new("Failed to allocate binary")))?;
-                binary.as_mut_slice().copy_from_slice(&buf[..n]);
-                Ok((atoms::ok(), binary.release(env)).encode(env))
+                output.extend_from_slice(&buf[..n]);
+                count_io(counter, n);
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                Ok(atoms::would_block().encode(env))
+            Err(ref e) if is_would_block(e) => return true,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Feeds `input` to stdin (then closes it), drains stdout and stderr, and
+/// reaps the child. On timeout or when an output stream exceeds
+/// `max_output_bytes`, the child is terminated with `terminate` (SIGKILL
+/// unless `kill_signal` is given) and reaped before returning.
+#[rustler::nif(schedule = "DirtyIo")]
+fn communicate_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    input: Binary<'a>,
+    timeout: Timeout,
+    max_output_bytes: u64,
+    kill_signal: Option<i32>,
+    kill_timeout_ms: u64,
+) -> NifResult<Term<'a>> {
+    let deadline = timeout.deadline();
+    let kill_signal = kill_signal.map(parse_signal).transpose()?;
+
+    let stdin = lock(&resource.stdin_pipe)?.take();
+    if stdin.is_none() && !input.is_empty() {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = stdin.map(|stream| {
+        let data = input.as_slice().to_vec();
+        let stop = stop.clone();
+        thread::spawn(move || feed_stdin(stream, &data, &stop))
+    });
+
+    let outcome = {
+        let stdout_lock = lock(&resource.stdout_pipe)?;
+        let stderr_lock = lock(&resource.stderr_pipe)?;
+        let streams = [stdout_lock.as_ref(), stderr_lock.as_ref()];
+        let counters = [&resource.stdout_read, &resource.stderr_read];
+        let max_bytes = max_output_bytes as usize;
+        let buf_size = resource.drain_buf;
+        match drain_streams(
+            streams,
+            counters,
+            deadline,
+            max_bytes,
+            Overflow::Fail,
+            buf_size,
+        ) {
+            DrainOutcome::Complete(output) => match wait_exit_until(&resource, deadline)? {
+                Some(exit) => Ok((exit, output)),
+                None => Err(atoms::timeout()),
+            },
+            DrainOutcome::TimedOut(_) => Err(atoms::timeout()),
+            DrainOutcome::LimitExceeded => Err(atoms::output_limit_exceeded()),
+        }
+    };
+
+    if outcome.is_err() {
+        terminate(
+            &resource,
+            kill_signal,
+            Duration::from_millis(kill_timeout_ms),
+        )?;
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    if let Some(writer) = writer {
+        if let Ok(written) = writer.join() {
+            count_io(&resource.stdin_written, written);
+        }
+    }
+
+    match outcome {
+        Ok((exit, [stdout, stderr])) => Ok((
+            atoms::ok(),
+            encode_exit(env, exit),
+            binary_from(env, &stdout)?,
+            binary_from(env, &stderr)?,
+        )
+            .encode(env)),
+        Err(reason) => Ok(reason.encode(env)),
+    }
+}
+
+/// Drains piped stdout and stderr to EOF while the child runs, then reaps
+/// it. Reading first means a child blocked on a full pipe can always finish.
+/// Only the last `max_output_bytes` of each stream are kept (zero keeps
+/// everything); streams that aren't piped come back empty.
+#[rustler::nif(schedule = "DirtyIo")]
+fn wait_full_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    max_output_bytes: u64,
+) -> NifResult<Term<'a>> {
+    let output = {
+        let stdout_lock = lock(&resource.stdout_pipe)?;
+        let stderr_lock = lock(&resource.stderr_pipe)?;
+        let streams = [stdout_lock.as_ref(), stderr_lock.as_ref()];
+        let counters = [&resource.stdout_read, &resource.stderr_read];
+        let max_bytes = max_output_bytes as usize;
+        let buf_size = resource.drain_buf;
+        match drain_streams(
+            streams,
+            counters,
+            None,
+            max_bytes,
+            Overflow::KeepLast,
+            buf_size,
+        ) {
+            DrainOutcome::Complete(output) | DrainOutcome::TimedOut(output) => output,
+            DrainOutcome::LimitExceeded => Default::default(),
+        }
+    };
+    let exit = wait_exit(&resource)?;
+
+    let [stdout, stderr] = output;
+    Ok((
+        encode_exit(env, exit),
+        binary_from(env, &stdout)?,
+        binary_from(env, &stderr)?,
+    )
+        .encode(env))
+}
+
+/// Waits until `stream` is ready for `events` or `deadline` passes. Returns
+/// false on timeout.
+fn poll_ready(stream: &StdioStream, events: PollFlags, deadline: Option<Instant>) -> bool {
+    let mut fds = [PollFd::new(stream.file.as_fd(), events)];
+    match poll(&mut fds, poll_timeout(deadline)) {
+        Ok(0) => false,
+        Err(nix::errno::Errno::EINTR) => !deadline_passed(deadline),
+        Ok(_) | Err(_) => true,
+    }
+}
+
+enum RequestError {
+    Timeout,
+    BrokenPipe,
+    Io(std::io::Error),
+}
+
+/// Writes all of `data` to stdin, parking on writability until `deadline`.
+fn write_all_until(
+    stream: &StdioStream,
+    data: &[u8],
+    deadline: Option<Instant>,
+    written: &AtomicU64,
+) -> Result<(), RequestError> {
+    write_some_until(stream, data, deadline, written).1
+}
+
+/// `write_all_until`, also returning how much of `data` was written, which
+/// on error is less than all of it.
+fn write_some_until(
+    stream: &StdioStream,
+    data: &[u8],
+    deadline: Option<Instant>,
+    written: &AtomicU64,
+) -> (usize, Result<(), RequestError>) {
+    let mut offset = 0;
+    while offset < data.len() {
+        match stream.write_some(&data[offset..]) {
+            Ok(n) => {
+                count_io(written, n);
+                offset += n;
             }
-            Err(e) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+            Err(ref e) if is_would_block(e) => {
+                if !poll_ready(stream, PollFlags::POLLOUT, deadline) {
+                    return (offset, Err(RequestError::Timeout));
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                return (offset, Err(RequestError::BrokenPipe))
+            }
+            Err(e) => return (offset, Err(RequestError::Io(e))),
+        }
+    }
+    (offset, Ok(()))
+}
+
+/// Reads one response from stdout: in line mode the next complete line,
+/// otherwise whatever is ready once data arrives, up to `max_bytes`. Bytes
+/// past the response stay in the stream's buffer. Returns `None` at EOF with
+/// nothing buffered.
+fn read_response_until(
+    stream: &mut StdioStream,
+    line: bool,
+    max_bytes: usize,
+    deadline: Option<Instant>,
+    read: &AtomicU64,
+) -> Result<Option<usize>, RequestError> {
+    if !line {
+        return read_chunk_until(stream, max_bytes, deadline, read);
+    }
+
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(len) = line_len(&stream.buffer) {
+            return Ok(Some(len));
+        }
+
+        match stream.read_some(&mut buf) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => {
+                if !poll_ready(stream, PollFlags::POLLIN, deadline) {
+                    return Err(RequestError::Timeout);
+                }
+            }
+            Ok(0) if stream.buffer.is_empty() => return Ok(None),
+            Ok(0) => return Ok(Some(stream.buffer.len())),
+            Ok(n) => {
+                count_io(read, n);
+                stream.buffer.extend_from_slice(&buf[..n]);
+            }
+            Err(ref e) if is_would_block(e) => {
+                if !poll_ready(stream, PollFlags::POLLIN, deadline) {
+                    return Err(RequestError::Timeout);
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(RequestError::Io(e)),
+        }
+    }
+}
+
+/// Waits until `deadline` for data, then reads whatever is immediately
+/// available into the stream's buffer, up to `max_bytes` in all. Returns the
+/// length of the chunk at the front of the buffer, left for the caller to
+/// take, or `None` at EOF with nothing buffered.
+fn read_chunk_until(
+    stream: &mut StdioStream,
+    max_bytes: usize,
+    deadline: Option<Instant>,
+    read: &AtomicU64,
+) -> Result<Option<usize>, RequestError> {
+    let mut buf = [0u8; READ_CHUNK];
+    while stream.buffer.len() < max_bytes {
+        let want = (max_bytes - stream.buffer.len()).min(buf.len());
+        let would_block = match stream.read_some(&mut buf[..want]) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => true,
+            Ok(0) if stream.buffer.is_empty() => return Ok(None),
+            Ok(0) => break,
+            Ok(n) => {
+                count_io(read, n);
+                stream.buffer.extend_from_slice(&buf[..n]);
+                false
+            }
+            Err(ref e) if is_would_block(e) => true,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => false,
+            Err(e) => return Err(RequestError::Io(e)),
+        };
+
+        if would_block {
+            // Once something has been read, only take what is ready.
+            if !stream.buffer.is_empty() {
+                break;
+            }
+            if !poll_ready(stream, PollFlags::POLLIN, deadline) {
+                return Err(RequestError::Timeout);
+            }
+        }
+    }
+    Ok(Some(stream.buffer.len().min(max_bytes)))
+}
+
+/// Coprocess round trip in one call: writes all of `input` to stdin, then
+/// waits up to `timeout` (zero waits forever) for one response on stdout,
+/// either what is ready once data arrives, up to `max_bytes`, or with `line`
+/// the next complete line.
+#[rustler::nif(schedule = "DirtyIo")]
+fn request_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    input: Binary<'a>,
+    timeout: Timeout,
+    line: bool,
+    max_bytes: usize,
+) -> NifResult<Term<'a>> {
+    if max_bytes == 0 {
+        return Err(Error::BadArg);
+    }
+    let deadline = timeout.deadline();
+
+    let stdin_lock = lock(&resource.stdin_pipe)?;
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let (Some(stdin), Some(stdout)) = (stdin_lock.as_ref(), stdout_lock.as_mut()) else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+    let result = write_all_until(stdin, input.as_slice(), deadline, &resource.stdin_written)
+        .and_then(|()| {
+            read_response_until(stdout, line, max_bytes, deadline, &resource.stdout_read)
+        });
+
+    match result {
+        Ok(Some(len)) => {
+            resource.touch_io();
+            Ok((atoms::ok(), drain_binary(env, &mut stdout.buffer, len)?).encode(env))
         }
+        Ok(None) => Ok(atoms::eof().encode(env)),
+        Err(RequestError::Timeout) => Ok(atoms::timeout().encode(env)),
+        Err(RequestError::BrokenPipe) => Ok((atoms::error(), atoms::broken_pipe()).encode(env)),
+        Err(RequestError::Io(e)) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// The length, prefix included, of the `[length][payload]` frame at the
+/// front of `buffer`, if it is complete.
+fn frame_len(buffer: &[u8], length_bytes: usize, big_endian: bool) -> Option<usize> {
+    let prefix = buffer.get(..length_bytes)?;
+    let mut length = [0u8; 8];
+    let length = if big_endian {
+        length[8 - length_bytes..].copy_from_slice(prefix);
+        u64::from_be_bytes(length)
     } else {
-        Ok((atoms::error(), atoms::not_piped()).encode(env))
+        length[..length_bytes].copy_from_slice(prefix);
+        u64::from_le_bytes(length)
+    };
+    let end = usize::try_from(length).ok()?.checked_add(length_bytes)?;
+    (buffer.len() >= end).then_some(end)
+}
+
+/// Reads the next length-prefixed frame from stdout: a `length_bytes`-byte
+/// (1, 2, 4 or 8) unsigned length in `endianness` (`:big` or `:little`),
+/// then that many payload bytes. Bytes of an incomplete frame are kept in
+/// the stream's buffer for the next call. With no `timeout`, returns
+/// `:would_block` instead of waiting for the rest of a frame; when it
+/// passes, `{:timeout, buffered}` with the number of bytes of the frame,
+/// prefix included, read so far. A partial frame at EOF is
+/// `{:error, :truncated_frame}`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn read_frame_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    length_bytes: usize,
+    endianness: rustler::Atom,
+    timeout: Option<Timeout>,
+) -> NifResult<Term<'a>> {
+    if ![1, 2, 4, 8].contains(&length_bytes) {
+        return Err(Error::BadArg);
+    }
+    let big_endian = if endianness == atoms::big() {
+        true
+    } else if endianness == atoms::little() {
+        false
+    } else {
+        return Err(Error::BadArg);
+    };
+    let deadline = timeout.map(Timeout::deadline);
+
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let Some(stream) = stdout_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    let mut buf = [0u8; READ_CHUNK];
+    loop {
+        if let Some(end) = frame_len(&stream.buffer, length_bytes, big_endian) {
+            let frame = binary_from(env, &stream.buffer[length_bytes..end])?;
+            stream.buffer.drain(..end);
+            return Ok((atoms::ok(), frame).encode(env));
+        }
+
+        let would_block = match stream.read_some(&mut buf) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => true,
+            Ok(0) if stream.buffer.is_empty() => return Ok(atoms::eof().encode(env)),
+            Ok(0) => return Ok((atoms::error(), atoms::truncated_frame()).encode(env)),
+            Ok(n) => {
+                resource.touch_io();
+                count_io(&resource.stdout_read, n);
+                stream.buffer.extend_from_slice(&buf[..n]);
+                false
+            }
+            Err(ref e) if is_would_block(e) => true,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => false,
+            Err(e) => return Ok((atoms::error(), format!("{}", e)).encode(env)),
+        };
+
+        if would_block {
+            match deadline {
+                None => return Ok(atoms::would_block().encode(env)),
+                Some(deadline) => {
+                    if !poll_ready(stream, PollFlags::POLLIN, deadline) {
+                        let buffered = stream.buffer.len();
+                        return Ok((atoms::timeout(), buffered).encode(env));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Waits up to `timeout` (zero waits forever) for stdout to have data, then
+/// reads whatever is immediately available, up to `max_bytes`, as one chunk.
+/// Returns `{:ok, data}`, `:timeout` if nothing arrived in time, or `:eof`
+/// once stdout is closed with nothing left to read.
+#[rustler::nif(schedule = "DirtyIo")]
+fn read_chunk_timeout_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    timeout: Timeout,
+    max_bytes: usize,
+) -> NifResult<Term<'a>> {
+    if max_bytes == 0 {
+        return Err(Error::BadArg);
     }
+    let deadline = timeout.deadline();
+
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let Some(stream) = stdout_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    match read_chunk_until(stream, max_bytes, deadline, &resource.stdout_read) {
+        Ok(Some(len)) => {
+            resource.touch_io();
+            Ok((atoms::ok(), drain_binary(env, &mut stream.buffer, len)?).encode(env))
+        }
+        Ok(None) => Ok(atoms::eof().encode(env)),
+        Err(RequestError::Timeout) => Ok(atoms::timeout().encode(env)),
+        Err(RequestError::BrokenPipe) => Ok((atoms::error(), atoms::broken_pipe()).encode(env)),
+        Err(RequestError::Io(e)) => Ok((atoms::error(), format!("{}", e)).encode(env)),
+    }
+}
+
+/// Reads stdout until EOF, parking between reads, and returns everything
+/// read: `{:eof, data}`, or `{:timeout, data}` if the deadline passed first.
+/// Stdout can close well before the child exits, or stay open after it
+/// exits if a grandchild inherited it.
+#[rustler::nif(schedule = "DirtyIo")]
+fn wait_stdout_eof_nif<'a>(
+    env: Env<'a>,
+    resource: ResourceArc<ProcessResource>,
+    timeout: Timeout,
+) -> NifResult<Term<'a>> {
+    let deadline = timeout.deadline();
+
+    let mut stdout_lock = lock(&resource.stdout_pipe)?;
+    let Some(stream) = stdout_lock.as_mut() else {
+        return Ok((atoms::error(), atoms::not_piped()).encode(env));
+    };
+
+    let mut data = std::mem::take(&mut stream.buffer);
+    let mut buf = [0u8; READ_CHUNK];
+    let outcome = loop {
+        match stream.read_some(&mut buf) {
+            Ok(0) if stream.socket && !socket_hung_up(stream) => {
+                if !poll_ready(stream, PollFlags::POLLIN, deadline) {
+                    break atoms::timeout();
+                }
+            }
+            Ok(0) => break atoms::eof(),
+            Ok(n) => {
+                resource.touch_io();
+                count_io(&resource.stdout_read, n);
+                data.extend_from_slice(&buf[..n]);
+            }
+            Err(ref e) if is_would_block(e) => {
+                if !poll_ready(stream, PollFlags::POLLIN, deadline) {
+                    break atoms::timeout();
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                stream.buffer = data;
+                return Ok((atoms::error(), format!("{}", e)).encode(env));
+            }
+        }
+    };
+
+    Ok((outcome, binary_from(env, &data)?).encode(env))
 }
 
 rustler::init!("Elixir.Px", load = load);
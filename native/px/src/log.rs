@@ -0,0 +1,69 @@
+//! Parent-held output files, for `{:log, path}` stdio.
+//!
+//! With `{:file, path}` the child writes to the file itself, so only the
+//! child can switch to a new one. Here the child writes to a pipe instead,
+//! and a detached thread copies the pipe into a file the BEAM holds open.
+//! Owning the descriptor lets `reopen` swap in a new file between two
+//! writes, which is what log rotation needs: rename the old file, reopen the
+//! path, and the child never notices. The thread exits at EOF.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub struct Log {
+    file: Mutex<File>,
+}
+
+/// Opens `path` for appending, creating it if needed.
+pub fn open(path: &str) -> std::io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+impl Log {
+    /// Starts copying `pipe` into `file`, up to `buf_size` bytes at a time.
+    pub fn drain(mut pipe: File, file: File, buf_size: usize) -> std::io::Result<Arc<Log>> {
+        let log = Arc::new(Log {
+            file: Mutex::new(file),
+        });
+
+        let target = log.clone();
+        thread::Builder::new()
+            .name("px-log".to_string())
+            .spawn(move || {
+                let mut buf = vec![0u8; buf_size];
+                loop {
+                    match pipe.read(&mut buf) {
+                        Ok(0) => break,
+                        // A failed write drops the data rather than stalling
+                        // the child on a full pipe.
+                        Ok(n) => {
+                            let _ = target.lock().write_all(&buf[..n]);
+                        }
+                        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+            })?;
+        Ok(log)
+    }
+
+    /// Opens `path` and makes it the target of every later write. On error
+    /// the current file stays in use.
+    pub fn reopen(&self, path: &str) -> std::io::Result<()> {
+        let file = open(path)?;
+        *self.lock() = file;
+        Ok(())
+    }
+
+    /// Syncs what has been written so far to disk. Taking the lock first
+    /// lets a write in progress finish.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.lock().sync_data()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, File> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
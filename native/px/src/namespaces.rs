@@ -0,0 +1,122 @@
+//! Running a child in new namespaces, for `namespaces: [...]`.
+//!
+//! The child calls `unshare(2)` between fork and exec. With a user
+//! namespace it then maps itself to root inside it: uid and gid 0 map to
+//! the BEAM's own ids, which an unprivileged process may do for itself, and
+//! `setgroups` is denied first as the kernel requires before a gid map is
+//! written. The other namespaces are then owned by the new user namespace,
+//! so creating them needs no privileges outside it.
+//!
+//! A pid namespace is not offered: `unshare(CLONE_NEWPID)` only places the
+//! caller's later children in it, so the exec'd child itself would stay in
+//! the BEAM's namespace.
+
+use crate::pre_exec::{Reporter, Step};
+use rustler::{Atom, Error, NifResult};
+use std::ffi::CStr;
+
+mod atoms {
+    rustler::atoms! {
+        user,
+        mount,
+        uts,
+        ipc,
+        net,
+        pid,
+        user_namespaces_disabled,
+    }
+}
+
+/// What the child does before exec: the `unshare` flags, and with a user
+/// namespace the id maps to write, formatted in the parent.
+pub struct Unshare {
+    flags: libc::c_int,
+    id_maps: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Unshare {
+    /// Parses the namespace atoms. With `:user`, fails with
+    /// `{:error, :user_namespaces_disabled}` when the kernel is configured
+    /// to refuse unprivileged user namespaces.
+    pub fn new(namespaces: &[Atom]) -> NifResult<Unshare> {
+        let mut flags = 0;
+        for &namespace in namespaces {
+            flags |= if namespace == atoms::user() {
+                libc::CLONE_NEWUSER
+            } else if namespace == atoms::mount() {
+                libc::CLONE_NEWNS
+            } else if namespace == atoms::uts() {
+                libc::CLONE_NEWUTS
+            } else if namespace == atoms::ipc() {
+                libc::CLONE_NEWIPC
+            } else if namespace == atoms::net() {
+                libc::CLONE_NEWNET
+            } else if namespace == atoms::pid() {
+                return Err(Error::Term(Box::new(
+                    "pid namespaces are not supported: unshare leaves the child outside them",
+                )));
+            } else {
+                return Err(Error::Term(Box::new(
+                    "invalid namespace, expected user, mount, uts, ipc or net",
+                )));
+            };
+        }
+
+        let id_maps = if flags & libc::CLONE_NEWUSER != 0 {
+            if user_namespaces_disabled() {
+                return Err(Error::Term(Box::new(atoms::user_namespaces_disabled())));
+            }
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            Some((
+                format!("0 {} 1\n", uid).into_bytes(),
+                format!("0 {} 1\n", gid).into_bytes(),
+            ))
+        } else {
+            None
+        };
+        Ok(Unshare { flags, id_maps })
+    }
+
+    /// Runs in the child. Async-signal-safe: only `unshare`, `open`, `write`
+    /// and `close`.
+    pub fn enter(&self, reporter: Reporter) -> std::io::Result<()> {
+        if unsafe { libc::unshare(self.flags) } == -1 {
+            return Err(reporter.fail(Step::Unshare));
+        }
+        if let Some((uid_map, gid_map)) = &self.id_maps {
+            let written = write_file(c"/proc/self/uid_map", uid_map)
+                && write_file(c"/proc/self/setgroups", b"deny")
+                && write_file(c"/proc/self/gid_map", gid_map);
+            if !written {
+                return Err(reporter.fail(Step::IdMap));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the kernel refuses user namespaces to unprivileged processes:
+/// `user.max_user_namespaces` is 0, or on Debian-derived kernels
+/// `kernel.unprivileged_userns_clone` is 0.
+fn user_namespaces_disabled() -> bool {
+    let setting = |path| {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    let unprivileged = unsafe { libc::geteuid() } != 0;
+    setting("/proc/sys/user/max_user_namespaces") == Some(0)
+        || (unprivileged && setting("/proc/sys/kernel/unprivileged_userns_clone") == Some(0))
+}
+
+fn write_file(path: &CStr, data: &[u8]) -> bool {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return false;
+        }
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        libc::close(fd);
+        written == data.len() as isize
+    }
+}
@@ -0,0 +1,90 @@
+//! Parent-death cleanup for macOS, which has no `PR_SET_PDEATHSIG`.
+//!
+//! After a spawn, a detached watchdog process waits on kqueue for either the
+//! VM or the child to exit. If the VM goes first, the watchdog SIGKILLs the
+//! child. The watchdog is double-forked so launchd, not the VM, reaps it,
+//! and it exits as soon as the child does, so it never signals a recycled
+//! PID.
+
+use std::io;
+
+/// Starts a watchdog that SIGKILLs `child` when this process exits.
+pub fn watch(child: i32) -> io::Result<()> {
+    let parent = unsafe { libc::getpid() };
+    let max_fd = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        n if n > 0 => n as i32,
+        _ => 1024,
+    };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe {
+            // Intermediate process: start the watchdog and exit right away
+            // so it is reparented to launchd.
+            if libc::fork() == 0 {
+                watchdog(parent, child, max_fd);
+            }
+            libc::_exit(0)
+        },
+        intermediate => {
+            let mut status = 0;
+            loop {
+                let result = unsafe { libc::waitpid(intermediate, &mut status, 0) };
+                if result != -1 || io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Body of the watchdog. The VM was multithreaded when it forked, so only
+/// async-signal-safe calls are allowed here.
+unsafe fn watchdog(parent: i32, child: i32, max_fd: i32) -> ! {
+    // Leave the VM's session so terminal signals aren't delivered here, and
+    // drop inherited descriptors so no pipe of any child is held open.
+    libc::setsid();
+    for fd in 3..max_fd {
+        libc::close(fd);
+    }
+
+    let kq = libc::kqueue();
+    if kq == -1 {
+        libc::_exit(1);
+    }
+
+    // The child is registered first: if it is already gone there is nothing
+    // to guard. If the VM is already gone, registering it fails with ESRCH.
+    if !watch_exit(kq, child) {
+        libc::_exit(0);
+    }
+    if !watch_exit(kq, parent) {
+        libc::kill(child, libc::SIGKILL);
+        libc::_exit(0);
+    }
+
+    let mut event: libc::kevent = std::mem::zeroed();
+    loop {
+        let n = libc::kevent(kq, std::ptr::null(), 0, &mut event, 1, std::ptr::null());
+        if n == 1 {
+            if event.ident == parent as libc::uintptr_t {
+                libc::kill(child, libc::SIGKILL);
+            }
+            libc::_exit(0);
+        }
+        if n == -1 && *libc::__error() != libc::EINTR {
+            libc::_exit(1);
+        }
+    }
+}
+
+/// Registers interest in `pid` exiting. Returns false if it no longer exists.
+unsafe fn watch_exit(kq: i32, pid: i32) -> bool {
+    let mut change: libc::kevent = std::mem::zeroed();
+    change.ident = pid as libc::uintptr_t;
+    change.filter = libc::EVFILT_PROC;
+    change.flags = libc::EV_ADD | libc::EV_ONESHOT;
+    change.fflags = libc::NOTE_EXIT;
+
+    libc::kevent(kq, &change, 1, std::ptr::null_mut(), 0, std::ptr::null()) != -1
+}
@@ -0,0 +1,163 @@
+//! Error reporting for `pre_exec` steps.
+//!
+//! `Command::spawn` collapses any `pre_exec` failure into one generic
+//! `io::Error`, losing which step failed. Each step instead reports through
+//! a close-on-exec pipe: on failure it writes a one-byte step code and the
+//! errno before returning, and the parent reads that record back when the
+//! spawn fails. A successful exec closes the pipe with nothing written.
+
+use rustler::Atom;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+
+mod atoms {
+    rustler::atoms! {
+        pdeathsig_failed,
+        setsid_failed,
+        controlling_terminal_failed,
+        tcsetpgrp_failed,
+        execveat_failed,
+        ptrace_failed,
+        file_action_failed,
+        unshare_failed,
+        id_map_failed,
+        setpgid_failed,
+        fexecve_failed,
+        cgroup_failed,
+    }
+}
+
+/// A `pre_exec` operation that can fail.
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum Step {
+    Pdeathsig = 1,
+    Setsid = 2,
+    ControllingTerminal = 3,
+    ForegroundGroup = 4,
+    ExecAt = 5,
+    TraceMe = 6,
+    FileAction = 7,
+    Unshare = 8,
+    IdMap = 9,
+    ProcessGroup = 10,
+    Fexecve = 11,
+    Cgroup = 12,
+}
+
+impl Step {
+    fn from_code(code: u8) -> Option<Step> {
+        match code {
+            1 => Some(Step::Pdeathsig),
+            2 => Some(Step::Setsid),
+            3 => Some(Step::ControllingTerminal),
+            4 => Some(Step::ForegroundGroup),
+            5 => Some(Step::ExecAt),
+            6 => Some(Step::TraceMe),
+            7 => Some(Step::FileAction),
+            8 => Some(Step::Unshare),
+            9 => Some(Step::IdMap),
+            10 => Some(Step::ProcessGroup),
+            11 => Some(Step::Fexecve),
+            12 => Some(Step::Cgroup),
+            _ => None,
+        }
+    }
+
+    /// The error atom reported to Elixir, e.g. `:pdeathsig_failed`.
+    pub fn atom(self) -> Atom {
+        match self {
+            Step::Pdeathsig => atoms::pdeathsig_failed(),
+            Step::Setsid => atoms::setsid_failed(),
+            Step::ControllingTerminal => atoms::controlling_terminal_failed(),
+            Step::ForegroundGroup => atoms::tcsetpgrp_failed(),
+            Step::ExecAt => atoms::execveat_failed(),
+            Step::TraceMe => atoms::ptrace_failed(),
+            Step::FileAction => atoms::file_action_failed(),
+            Step::Unshare => atoms::unshare_failed(),
+            Step::IdMap => atoms::id_map_failed(),
+            Step::ProcessGroup => atoms::setpgid_failed(),
+            Step::Fexecve => atoms::fexecve_failed(),
+            Step::Cgroup => atoms::cgroup_failed(),
+        }
+    }
+}
+
+const RECORD_LEN: usize = 5;
+
+pub struct ErrorPipe {
+    read: File,
+    write: OwnedFd,
+}
+
+impl ErrorPipe {
+    pub fn new() -> std::io::Result<ErrorPipe> {
+        let (read, write) = cloexec_pipe()?;
+        Ok(ErrorPipe {
+            read: File::from(read),
+            write,
+        })
+    }
+
+    /// A handle the child can use to report a failed step.
+    pub fn reporter(&self) -> Reporter {
+        Reporter(self.write.as_raw_fd())
+    }
+
+    /// Closes the parent's write end and returns the failure recorded by the
+    /// child, if any. Only meaningful after the spawn has failed, at which
+    /// point the child has exited and the record (if any) is in the pipe.
+    pub fn into_failure(self) -> Option<(Step, i32)> {
+        let ErrorPipe { mut read, write } = self;
+        drop(write);
+
+        let mut record = [0u8; RECORD_LEN];
+        read.read_exact(&mut record).ok()?;
+        let step = Step::from_code(record[0])?;
+        let errno = i32::from_ne_bytes([record[1], record[2], record[3], record[4]]);
+        Some((step, errno))
+    }
+}
+
+/// Child-side handle for the error pipe. Copyable so each `pre_exec` closure
+/// can capture its own.
+#[derive(Clone, Copy)]
+pub struct Reporter(RawFd);
+
+impl Reporter {
+    /// Records that `step` failed with the current errno and returns the
+    /// error for the `pre_exec` closure to propagate. Async-signal-safe: it
+    /// only reads errno and calls `write(2)`.
+    pub fn fail(self, step: Step) -> std::io::Error {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        self.fail_with(step, errno)
+    }
+
+    /// Like `fail`, for a step that failed a check rather than a call, so
+    /// there is no errno to read.
+    pub fn fail_with(self, step: Step, errno: i32) -> std::io::Error {
+        let code = errno.to_ne_bytes();
+        let record = [step as u8, code[0], code[1], code[2], code[3]];
+        unsafe {
+            libc::write(self.0, record.as_ptr().cast(), RECORD_LEN);
+        }
+        std::io::Error::from_raw_os_error(errno)
+    }
+}
+
+/// A pipe with both ends close-on-exec, so children never inherit it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn cloexec_pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    Ok(nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn cloexec_pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    let (read, write) = nix::unistd::pipe()?;
+    for fd in [&read, &write] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    Ok((read, write))
+}
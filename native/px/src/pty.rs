@@ -0,0 +1,125 @@
+//! Pseudo-terminal support for `pty: true` spawns.
+//!
+//! The child gets the slave side as stdin, stdout and stderr and becomes the
+//! leader of a new session with the slave as its controlling terminal, so
+//! job-control characters written to the master reach its foreground
+//! process group like they would from a real terminal.
+
+use crate::pre_exec::{Reporter, Step};
+use nix::pty::{openpty, OpenptyResult};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::termios::{
+    cfmakeraw, tcgetattr, tcsetattr, InputFlags, LocalFlags, OutputFlags, SetArg,
+    SpecialCharacterIndices,
+};
+use nix::unistd::tcgetpgrp;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+
+pub struct Pty {
+    pub master: File,
+    pub slave: File,
+}
+
+pub fn open() -> std::io::Result<Pty> {
+    let OpenptyResult { master, slave } = openpty(None, None)?;
+    Ok(Pty {
+        master: File::from(master),
+        slave: File::from(slave),
+    })
+}
+
+/// Runs in the child after stdio has been set up: starts a new session,
+/// makes the slave (now fd 0) its controlling terminal and puts the child's
+/// process group in the foreground. Async-signal-safe.
+pub fn attach_controlling_terminal(reporter: Reporter) -> std::io::Result<()> {
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(reporter.fail(Step::Setsid));
+        }
+        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(reporter.fail(Step::ControllingTerminal));
+        }
+        if libc::tcsetpgrp(0, libc::getpid()) == -1 {
+            return Err(reporter.fail(Step::ForegroundGroup));
+        }
+    }
+    Ok(())
+}
+
+/// A terminal control character, looked up in the terminal's current
+/// settings rather than assumed to be the usual ^C, ^D or ^Z.
+#[derive(Clone, Copy)]
+pub enum ControlChar {
+    Interrupt,
+    Eof,
+    Suspend,
+}
+
+impl ControlChar {
+    fn index(self) -> SpecialCharacterIndices {
+        match self {
+            ControlChar::Interrupt => SpecialCharacterIndices::VINTR,
+            ControlChar::Eof => SpecialCharacterIndices::VEOF,
+            ControlChar::Suspend => SpecialCharacterIndices::VSUSP,
+        }
+    }
+}
+
+/// Writes `control` to the master, as if typed at the terminal.
+pub fn send_control_char(master: &File, control: ControlChar) -> std::io::Result<()> {
+    let termios = tcgetattr(master)?;
+    let byte = termios.control_chars[control.index() as usize];
+    (&*master).write_all(&[byte])
+}
+
+/// Line discipline settings selectable from Elixir.
+#[derive(Clone, Copy)]
+pub enum TermMode {
+    /// No line editing, echo, signal characters or output processing.
+    Raw,
+    /// Canonical line editing with echo, like a freshly opened terminal.
+    Cooked,
+    /// Cooked, but without echo (password prompts).
+    NoEcho,
+}
+
+/// Applies `mode` to the terminal. Settings made through the master apply to
+/// the slave the child sees.
+pub fn set_term_mode(master: &File, mode: TermMode) -> nix::Result<()> {
+    let mut termios = tcgetattr(master)?;
+    let echo = LocalFlags::ECHO | LocalFlags::ECHOE | LocalFlags::ECHOK;
+    match mode {
+        TermMode::Raw => cfmakeraw(&mut termios),
+        TermMode::Cooked | TermMode::NoEcho => {
+            // Undo anything a previous switch to raw cleared.
+            termios.input_flags |= InputFlags::ICRNL | InputFlags::IXON | InputFlags::BRKINT;
+            termios.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+            termios.local_flags |=
+                LocalFlags::ICANON | LocalFlags::ISIG | LocalFlags::IEXTEN | echo;
+            if let TermMode::NoEcho = mode {
+                termios.local_flags.remove(echo | LocalFlags::ECHONL);
+            }
+        }
+    }
+    tcsetattr(master, SetArg::TCSANOW, &termios)
+}
+
+/// Sets the terminal size, then sends SIGWINCH to the terminal's foreground
+/// process group. The size is in place before the signal is sent, so a
+/// handler that asks for it sees the new one. The kernel only signals on an
+/// actual change; sending it ourselves makes every call a repaint.
+pub fn resize(master: &File, rows: u16, cols: u16) -> nix::Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } == -1 {
+        return Err(nix::errno::Errno::last());
+    }
+    let group = tcgetpgrp(master)?;
+    killpg(group, Signal::SIGWINCH)
+}
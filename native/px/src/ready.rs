@@ -0,0 +1,244 @@
+//! Startup readiness probes, for `ready:`.
+//!
+//! A thread watches for the child to become ready and reports once, either
+//! way: when the probe succeeds, or when the deadline passes, the child
+//! closes the probed stream or, for a connect probe, exits first.
+//!
+//! An output probe scans piped stdout or stderr for a pattern without
+//! taking the output away from `read`: a second pipe is spliced in between,
+//! and the thread copies every chunk from the child's pipe into it before
+//! looking at it, so the output is there to read by the time readiness is
+//! reported. The thread keeps copying after the report, until EOF, and the
+//! child still blocks on a full pipe when nobody reads. A connect probe
+//! tries to connect to a Unix socket or a TCP port every `RETRY` until it
+//! succeeds.
+
+use nix::poll::{poll, PollFd, PollFlags};
+use rustler::{Atom, Binary, Encoder, Env, Error, NewBinary, NifResult, Term};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a connect probe waits between attempts, and the most a single
+/// TCP connect may take.
+const RETRY: Duration = Duration::from_millis(20);
+
+mod atoms {
+    rustler::atoms! {
+        output,
+        unix,
+        tcp,
+        stdout,
+        stderr,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Probe {
+    /// `{:output, stream, pattern}`: `pattern` appears on stdout (0) or
+    /// stderr (1).
+    Output { stream: usize, pattern: Vec<u8> },
+    /// `{:unix, path}`: a connect to the Unix socket at `path` succeeds.
+    Unix(PathBuf),
+    /// `{:tcp, host, port}`: a TCP connect to `host:port` succeeds.
+    Tcp(String, u16),
+}
+
+fn invalid(message: &str) -> Error {
+    Error::Term(Box::new(format!("invalid ready probe: {}", message)))
+}
+
+impl<'a> rustler::Decoder<'a> for Probe {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if let Ok((tag, stream, pattern)) = term.decode::<(Atom, Atom, Binary)>() {
+            if tag == atoms::output() {
+                let stream = if stream == atoms::stdout() {
+                    0
+                } else if stream == atoms::stderr() {
+                    1
+                } else {
+                    return Err(invalid("expected stream stdout or stderr"));
+                };
+                if pattern.is_empty() {
+                    return Err(invalid("pattern must not be empty"));
+                }
+                let pattern = pattern.as_slice().to_vec();
+                return Ok(Probe::Output { stream, pattern });
+            }
+        }
+        if let Ok((tag, path)) = term.decode::<(Atom, String)>() {
+            if tag == atoms::unix() {
+                return Ok(Probe::Unix(PathBuf::from(path)));
+            }
+        }
+        if let Ok((tag, host, port)) = term.decode::<(Atom, String, u16)>() {
+            if tag == atoms::tcp() {
+                return Ok(Probe::Tcp(host, port));
+            }
+        }
+        Err(invalid(
+            "expected {:output, stream, pattern}, {:unix, path} or {:tcp, host, port}",
+        ))
+    }
+}
+
+impl Encoder for Probe {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Probe::Output { stream, pattern } => {
+                let stream = [atoms::stdout(), atoms::stderr()][*stream];
+                let mut binary = NewBinary::new(env, pattern.len());
+                binary.as_mut_slice().copy_from_slice(pattern);
+                (atoms::output(), stream, Binary::from(binary)).encode(env)
+            }
+            Probe::Unix(path) => (atoms::unix(), path.to_string_lossy().into_owned()).encode(env),
+            Probe::Tcp(host, port) => (atoms::tcp(), host, port).encode(env),
+        }
+    }
+}
+
+impl Probe {
+    /// The index of the stream an output probe scans.
+    pub fn stream(&self) -> Option<usize> {
+        match self {
+            Probe::Output { stream, .. } => Some(*stream),
+            _ => None,
+        }
+    }
+
+    /// Starts the probe thread. An output probe needs the `Splice` made
+    /// for its stream. `exited` tells a connect probe that the child is
+    /// gone. `report` is called once, with whether the child became ready.
+    pub fn start(
+        self,
+        splice: Option<Splice>,
+        deadline: Option<Instant>,
+        buf_size: usize,
+        exited: impl Fn() -> bool + Send + 'static,
+        report: impl FnOnce(bool) + Send + 'static,
+    ) -> std::io::Result<()> {
+        let watch: Box<dyn FnOnce() + Send> = match (self, splice) {
+            (Probe::Output { pattern, .. }, Some(splice)) => {
+                Box::new(move || scan(splice, &pattern, deadline, buf_size, report))
+            }
+            (Probe::Output { .. }, None) => {
+                return Err(std::io::Error::other("ready output probe needs a pipe"))
+            }
+            (probe, _) => Box::new(move || report(connect(&probe, deadline, exited))),
+        };
+        thread::Builder::new()
+            .name("px-ready".to_string())
+            .spawn(watch)?;
+        Ok(())
+    }
+}
+
+/// The child's pipe and the write end of the one spliced in after it.
+pub struct Splice {
+    source: File,
+    sink: File,
+}
+
+/// Splices a pipe in after `source`, the parent's end of the child's pipe.
+/// Returns the new pipe's read end, for the parent to read instead.
+pub fn splice(source: File) -> std::io::Result<(File, Splice)> {
+    let (read_end, write_end) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+    let sink = File::from(write_end);
+    Ok((File::from(read_end), Splice { source, sink }))
+}
+
+/// Copies the child's pipe into the spliced one until EOF, reporting once `pattern` has gone
+/// through, or not once `deadline` passes or the stream ends first.
+fn scan(
+    splice: Splice,
+    pattern: &[u8],
+    deadline: Option<Instant>,
+    buf_size: usize,
+    report: impl FnOnce(bool),
+) {
+    let Splice {
+        mut source,
+        mut sink,
+    } = splice;
+    let mut report = Some(report);
+    // The end of the output so far, short of a whole pattern, so a match
+    // split across reads is still found.
+    let mut carry: Vec<u8> = Vec::new();
+    let mut buf = vec![0u8; buf_size];
+
+    loop {
+        if report.is_some() && !readable_before(&source, deadline) {
+            if let Some(report) = report.take() {
+                report(false);
+            }
+            continue;
+        }
+        let n = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        // The parent closed its end; stop, so the child sees EPIPE as it
+        // would have.
+        if sink.write_all(&buf[..n]).is_err() {
+            break;
+        }
+        if report.is_some() {
+            carry.extend_from_slice(&buf[..n]);
+            if carry.windows(pattern.len()).any(|window| window == pattern) {
+                if let Some(report) = report.take() {
+                    report(true);
+                }
+                carry = Vec::new();
+            } else {
+                carry.drain(..carry.len().saturating_sub(pattern.len() - 1));
+            }
+        }
+    }
+    if let Some(report) = report {
+        report(false);
+    }
+}
+
+/// Waits for `source` to become readable, or hang up, until `deadline`.
+fn readable_before(source: &File, deadline: Option<Instant>) -> bool {
+    loop {
+        let mut fds = [PollFd::new(source.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, crate::poll_timeout(deadline)) {
+            Ok(0) => return false,
+            Err(nix::errno::Errno::EINTR) => {}
+            Ok(_) | Err(_) => return true,
+        }
+    }
+}
+
+/// Tries to connect until it works, returning false once `deadline` passes
+/// or the child exits.
+fn connect(probe: &Probe, deadline: Option<Instant>, exited: impl Fn() -> bool) -> bool {
+    loop {
+        let connected = match probe {
+            Probe::Unix(path) => UnixStream::connect(path).is_ok(),
+            Probe::Tcp(host, port) => {
+                (host.as_str(), *port)
+                    .to_socket_addrs()
+                    .is_ok_and(|mut addrs| {
+                        addrs.any(|addr| TcpStream::connect_timeout(&addr, RETRY).is_ok())
+                    })
+            }
+            Probe::Output { .. } => false,
+        };
+        if connected {
+            return true;
+        }
+        if exited() || crate::deadline_passed(deadline) {
+            return false;
+        }
+        thread::sleep(RETRY);
+    }
+}
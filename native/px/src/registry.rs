@@ -0,0 +1,138 @@
+//! Crate-wide registry of spawned children that have not been reaped yet.
+//!
+//! Resources hold a strong `Arc<Registration>`; the registry only keeps weak
+//! references, so dropping a resource removes it without any bookkeeping.
+//!
+//! The registry also enforces the optional limit on live children: a spawn
+//! first takes a `Slot`, which is given back when the child is reaped or its
+//! resource is dropped, whichever happens first.
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, Weak};
+
+pub struct Registration {
+    pid: i32,
+    cleanup_signal: Option<Signal>,
+    reaped: AtomicBool,
+    slot: Mutex<Option<Slot>>,
+}
+
+impl Registration {
+    /// Marks the child as reaped and frees its slot. Must be called right
+    /// after reaping, while the resource's child lock is still held.
+    pub fn mark_reaped(&self) {
+        self.reaped.store(true, Ordering::Release);
+        self.slot.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    fn is_live(&self) -> bool {
+        !self.reaped.load(Ordering::Acquire)
+    }
+}
+
+static LIVE: Mutex<Vec<Weak<Registration>>> = Mutex::new(Vec::new());
+static CLEANUP_HOOK: Once = Once::new();
+
+/// Children holding a slot.
+static LIVE_CHILDREN: AtomicUsize = AtomicUsize::new(0);
+/// Maximum number of slots; zero means unlimited.
+static MAX_CHILDREN: AtomicUsize = AtomicUsize::new(0);
+
+/// Permission to have one more live child. Dropping it frees the slot.
+pub struct Slot(());
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        LIVE_CHILDREN.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Takes a slot, or returns `None` if the limit has been reached.
+pub fn acquire_slot() -> Option<Slot> {
+    LIVE_CHILDREN
+        .fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |live| match MAX_CHILDREN.load(Ordering::Acquire) {
+                0 => Some(live + 1),
+                max if live < max => Some(live + 1),
+                _ => None,
+            },
+        )
+        .ok()
+        .map(|_| Slot(()))
+}
+
+/// Sets the limit on live children; zero removes it. Children already
+/// running beyond a lowered limit are left alone.
+pub fn set_max_children(max: usize) {
+    MAX_CHILDREN.store(max, Ordering::Release);
+}
+
+pub fn live_children() -> usize {
+    LIVE_CHILDREN.load(Ordering::Acquire)
+}
+
+/// A registration for a process Px did not spawn, taken over with
+/// `adopt_nif`: it holds no slot, gets no cleanup signal and is not one of
+/// `live_pids`.
+pub fn adopted(pid: i32) -> Arc<Registration> {
+    Arc::new(Registration {
+        pid,
+        cleanup_signal: None,
+        reaped: AtomicBool::new(false),
+        slot: Mutex::new(None),
+    })
+}
+
+/// PIDs of children that have been neither reaped nor dropped, pruning
+/// the entries of those that have.
+pub fn live_pids() -> Vec<i32> {
+    let mut live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+    live.retain(|entry| entry.upgrade().is_some_and(|r| r.is_live()));
+    live.iter()
+        .filter_map(Weak::upgrade)
+        .map(|registration| registration.pid)
+        .collect()
+}
+
+pub fn register(pid: i32, cleanup_signal: Option<Signal>, slot: Slot) -> Arc<Registration> {
+    let registration = Arc::new(Registration {
+        pid,
+        cleanup_signal,
+        reaped: AtomicBool::new(false),
+        slot: Mutex::new(Some(slot)),
+    });
+
+    if cleanup_signal.is_some() {
+        install_cleanup_hook();
+    }
+
+    let mut live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+    live.retain(|entry| entry.upgrade().is_some_and(|r| r.is_live()));
+    live.push(Arc::downgrade(&registration));
+    registration
+}
+
+fn install_cleanup_hook() {
+    CLEANUP_HOOK.call_once(|| unsafe {
+        libc::atexit(cleanup_at_exit);
+    });
+}
+
+/// Sends each live child its cleanup signal when the VM exits normally.
+/// Children that asked for no cleanup signal are left alone (they still get
+/// PDEATHSIG if it was enabled).
+extern "C" fn cleanup_at_exit() {
+    let live = match LIVE.try_lock() {
+        Ok(live) => live,
+        Err(_) => return,
+    };
+    for registration in live.iter().filter_map(Weak::upgrade) {
+        if let (true, Some(signal)) = (registration.is_live(), registration.cleanup_signal) {
+            let _ = kill(Pid::from_raw(registration.pid), signal);
+        }
+    }
+}
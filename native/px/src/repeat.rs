@@ -0,0 +1,62 @@
+//! Synthetic stdin for `stdin: {:repeat, pattern}`.
+//!
+//! A detached thread writes the pattern into the stdin pipe over and over
+//! with blocking writes, so the child sees an endless (or, with a limit,
+//! bounded) stream without the BEAM feeding it. The thread owns the pipe:
+//! it exits and closes it once `limit` bytes are written, or when the write
+//! fails because the child closed its end or exited.
+
+use rustler::{Error, NifMap, NifResult};
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+
+/// Writes are batched into a buffer of the pattern repeated up to this size.
+const BATCH: usize = 64 * 1024;
+
+#[derive(Clone, NifMap)]
+pub struct Repeat {
+    /// The bytes to repeat; never empty.
+    pattern: Vec<u8>,
+    /// Total bytes to write before closing stdin; `None` repeats forever.
+    limit: Option<u64>,
+}
+
+impl Repeat {
+    pub fn validate(&self) -> NifResult<()> {
+        if self.pattern.is_empty() {
+            return Err(Error::Term(Box::new(
+                "stdin repeat pattern must not be empty",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Starts feeding `file`, the parent's end of the stdin pipe, which must
+    /// still be blocking.
+    pub fn feed(&self, mut file: File) -> std::io::Result<()> {
+        let copies = (BATCH / self.pattern.len()).max(1);
+        let mut batch = self.pattern.repeat(copies);
+        let size = batch.len();
+        let mut remaining = self.limit.unwrap_or(u64::MAX);
+
+        thread::Builder::new()
+            .name("px-repeat".to_string())
+            .spawn(move || {
+                while remaining > 0 {
+                    let len = size.min(remaining.try_into().unwrap_or(usize::MAX));
+                    match file.write(&batch[..len]) {
+                        // Rotate so the next write picks the pattern up
+                        // where a short write left it.
+                        Ok(n) => {
+                            remaining -= n as u64;
+                            batch.rotate_left(n % size);
+                        }
+                        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
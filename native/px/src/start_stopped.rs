@@ -0,0 +1,43 @@
+//! Starting a child stopped right after its exec, for `start_stopped: true`.
+//!
+//! Stopping in `pre_exec` would hang `Command::spawn`, which waits for the
+//! exec to happen. Instead the child asks to be traced, which makes the
+//! kernel stop it with SIGTRAP once the exec has loaded the new program.
+//! The spawning thread (the tracer) then detaches, leaving a plain SIGSTOP
+//! in its place: the child stays stopped with no tracer attached, so a
+//! debugger can attach to it, and SIGCONT lets it run.
+
+use crate::pre_exec::{Reporter, Step};
+use std::io;
+
+/// Runs in the child, as the last step before exec. Async-signal-safe.
+pub fn trace_me(reporter: Reporter) -> io::Result<()> {
+    if unsafe { libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) } == -1 {
+        return Err(reporter.fail(Step::TraceMe));
+    }
+    Ok(())
+}
+
+/// Waits for the post-exec trap of `pid` and detaches, leaving the child
+/// stopped. Must run on the thread that spawned it. A child that died
+/// instead of stopping is left unreaped for the usual wait.
+pub fn detach_stopped(pid: i32) -> io::Result<()> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WSTOPPED | libc::WEXITED | libc::WNOWAIT;
+    while unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) } == -1 {
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+    if info.si_code != libc::CLD_TRAPPED {
+        return Ok(());
+    }
+
+    // Consume the trap so it is not reported to later waits.
+    unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WSTOPPED) };
+    if unsafe { libc::ptrace(libc::PTRACE_DETACH, pid, 0, libc::SIGSTOP) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
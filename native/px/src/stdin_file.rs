@@ -0,0 +1,25 @@
+//! Switching piped stdin over to a file, for `stdin_from_file_nif`.
+//!
+//! The child keeps reading the same pipe, so it can't tell that the BEAM
+//! stopped writing to it. A detached thread takes over the parent's end:
+//! it writes whatever a partial `write` left pending, then copies the file
+//! in, which on Linux `std::io::copy` does with `splice`/`sendfile` and no
+//! pass through userspace, and closes the pipe at EOF. It also stops when
+//! the write fails because the child closed its end or exited.
+
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+
+/// Starts feeding `pending` and then `source` into `pipe`, the parent's end
+/// of the stdin pipe, which must already be blocking.
+pub fn feed(pending: Vec<u8>, mut source: File, mut pipe: File) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("px-stdin-file".to_string())
+        .spawn(move || {
+            if pipe.write_all(&pending).is_ok() {
+                let _ = std::io::copy(&mut source, &mut pipe);
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Tail-only capture of an output stream, for `{:tail, bytes}` stdio.
+//!
+//! A detached thread drains the pipe with blocking reads into a fixed-size
+//! ring, dropping the oldest bytes as new ones arrive, so a chatty child
+//! never blocks on a full pipe and memory stays bounded. The thread only
+//! holds the ring, and the interleaved log if there is one, and exits at
+//! EOF.
+
+use crate::interleave::{Interleaved, Stream};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub struct Tail {
+    capacity: usize,
+    ring: Mutex<VecDeque<u8>>,
+}
+
+impl Tail {
+    /// Starts draining `file` into a new ring holding `capacity` bytes,
+    /// reading up to `buf_size` bytes at a time. With `interleaved`, each
+    /// chunk is also recorded there as coming from that stream.
+    pub fn drain(
+        mut file: File,
+        capacity: usize,
+        buf_size: usize,
+        interleaved: Option<(Arc<Interleaved>, Stream)>,
+    ) -> std::io::Result<Arc<Tail>> {
+        let tail = Arc::new(Tail {
+            capacity,
+            ring: Mutex::new(VecDeque::with_capacity(capacity)),
+        });
+
+        let ring = tail.clone();
+        thread::Builder::new()
+            .name("px-tail".to_string())
+            .spawn(move || {
+                let mut buf = vec![0u8; buf_size];
+                loop {
+                    match file.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            ring.push(&buf[..n]);
+                            if let Some((log, stream)) = &interleaved {
+                                log.push(*stream, &buf[..n]);
+                            }
+                        }
+                        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+            })?;
+        Ok(tail)
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (ring.len() + data.len()).saturating_sub(self.capacity);
+        ring.drain(..overflow);
+        ring.extend(data);
+    }
+
+    /// The last `capacity` bytes written so far, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        let ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+        ring.iter().copied().collect()
+    }
+}
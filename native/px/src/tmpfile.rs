@@ -0,0 +1,51 @@
+//! Anonymous output files, for `stdout: :tmpfile` and `stderr: :tmpfile`.
+//!
+//! The child writes to a file that has no name, and the parent keeps a
+//! descriptor for the same open file to read ranges back with `pread`,
+//! which leaves the child's write offset alone. Closing the last descriptor
+//! frees the space, so nothing is left behind when the resource is dropped.
+//!
+//! On Linux the file is created with `O_TMPFILE` and never has a name.
+//! Filesystems without it, and other platforms, get a uniquely named file
+//! that is unlinked straight away, as `tmpfile(3)` does.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Creates an empty anonymous file in the system temp directory, open for
+/// reading and writing.
+pub fn create() -> std::io::Result<File> {
+    let dir = std::env::temp_dir();
+
+    #[cfg(target_os = "linux")]
+    if let Ok(file) = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_TMPFILE)
+        .open(&dir)
+    {
+        return Ok(file);
+    }
+
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("px-{}-{}", std::process::id(), n));
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => {
+                std::fs::remove_file(&path)?;
+                return Ok(file);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Cancellation of blocked `wait_nif` calls.
+//!
+//! A plain `waitid` can only be woken by the child exiting. A cancellable
+//! wait instead polls its own self-pipe alongside the child: a pidfd on
+//! Linux, or a short timeout between non-blocking `waitid` checks where
+//! pidfds are unavailable. `cancel_all` writes to every registered pipe.
+//!
+//! The pidfd is opened when the child is spawned, not when a wait starts.
+//! It then names the child for good: it becomes readable when the child
+//! exits even if something else reaps it, and a later process reusing the
+//! PID cannot wake the wait.
+
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// How often to re-check the child when there is no pidfd to poll.
+const FALLBACK_POLL_MS: u8 = 10;
+
+/// The write ends of the self-pipes of a child's blocked waiters.
+#[derive(Default)]
+pub struct Waiters {
+    next_id: AtomicU64,
+    wakers: Mutex<Vec<(u64, File)>>,
+}
+
+impl Waiters {
+    fn register(&self) -> std::io::Result<Waiter<'_>> {
+        let (read, write) = crate::pre_exec::cloexec_pipe()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().push((id, File::from(write)));
+        Ok(Waiter {
+            waiters: self,
+            id,
+            read,
+        })
+    }
+
+    /// Wakes every blocked waiter. Returns how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let wakers = std::mem::take(&mut *self.lock());
+        for (_, waker) in &wakers {
+            let _ = (&*waker).write_all(&[0]);
+        }
+        wakers.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(u64, File)>> {
+        self.wakers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Waiter<'a> {
+    waiters: &'a Waiters,
+    id: u64,
+    read: OwnedFd,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.waiters.lock().retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Blocks until `pid` has exited, without reaping it, or until a waiter is
+/// cancelled. Returns false if cancelled. `pidfd` is the child's, from
+/// `pidfd_open` at spawn. A `pid` of `None` is an adopted process, which is
+/// not our child and can only be watched through its pidfd.
+pub fn wait_exited_or_cancelled(
+    pid: Option<i32>,
+    pidfd: Option<BorrowedFd>,
+    waiters: &Waiters,
+) -> std::io::Result<bool> {
+    if pid.is_none() && pidfd.is_none() {
+        return Err(std::io::Error::other("no pidfd to wait on"));
+    }
+    let waiter = waiters.register()?;
+
+    loop {
+        if pid.is_some_and(exited_no_reap) {
+            return Ok(true);
+        }
+
+        let cancelled = match &pidfd {
+            Some(pidfd) => {
+                let mut fds = [
+                    PollFd::new(waiter.read.as_fd(), PollFlags::POLLIN),
+                    PollFd::new(*pidfd, PollFlags::POLLIN),
+                ];
+                let cancelled = poll_fds(&mut fds, PollTimeout::NONE)?;
+                if pid.is_none() && fds[1].any().unwrap_or(false) {
+                    return Ok(true);
+                }
+                cancelled
+            }
+            None => {
+                let mut fds = [PollFd::new(waiter.read.as_fd(), PollFlags::POLLIN)];
+                poll_fds(&mut fds, PollTimeout::from(FALLBACK_POLL_MS))?
+            }
+        };
+        if cancelled {
+            return Ok(false);
+        }
+    }
+}
+
+/// Polls `fds`, returning whether the first one (the waiter's pipe) is
+/// readable.
+fn poll_fds(fds: &mut [PollFd], timeout: PollTimeout) -> std::io::Result<bool> {
+    match poll(fds, timeout) {
+        Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(fds[0]
+        .revents()
+        .is_some_and(|events| events.intersects(PollFlags::POLLIN | PollFlags::POLLHUP)))
+}
+
+/// Whether `pid` has exited, leaving it unreaped. A child that can no longer
+/// be waited for (reaped elsewhere) counts as exited, so the caller goes on
+/// to find that out.
+fn exited_no_reap(pid: i32) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+        )
+    };
+    result != 0 || unsafe { info.si_pid() } != 0
+}
+
+/// A pidfd for `pid`, or `None` before Linux 5.3 and on other platforms.
+/// Call it before the child can have been reaped, i.e. right after spawning,
+/// or for a process that is not our child, before the PID can be reused.
+#[cfg(target_os = "linux")]
+pub fn pidfd_open(pid: i32) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pidfd_open(_pid: i32) -> Option<OwnedFd> {
+    None
+}
+
+/// Whether the process behind `pidfd` has exited, without blocking.
+pub fn pidfd_exited(pidfd: BorrowedFd) -> bool {
+    let mut fds = [PollFd::new(pidfd, PollFlags::POLLIN)];
+    matches!(poll(&mut fds, PollTimeout::ZERO), Ok(1..))
+}
+
+/// Blocks until the process behind `pidfd` has exited.
+pub fn wait_pidfd(pidfd: BorrowedFd) {
+    loop {
+        let mut fds = [PollFd::new(pidfd, PollFlags::POLLIN)];
+        match poll(&mut fds, PollTimeout::NONE) {
+            Err(nix::errno::Errno::EINTR) => {}
+            _ => return,
+        }
+    }
+}
+
+/// Sends `signal` to the process behind `pidfd`, which can never reach a
+/// later process that reused its PID.
+#[cfg(target_os = "linux")]
+pub fn pidfd_send_signal(pidfd: BorrowedFd, signal: i32) -> nix::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            std::os::fd::AsRawFd::as_raw_fd(&pidfd),
+            signal,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    nix::errno::Errno::result(result).map(drop)
+}
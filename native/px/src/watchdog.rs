@@ -0,0 +1,92 @@
+//! Killing a child that outlives its spawn `timeout:` or the lifetime set
+//! with `set_max_lifetime`.
+//!
+//! A detached thread sleeps on a condvar until the deadline. The deadline
+//! lives under the condvar's mutex, so `cancel` and `extend` change it and
+//! wake the thread in one step, and the thread re-reads it after every
+//! wakeup before deciding to kill: an extension can never race a kill that
+//! was decided against the old deadline. Reaping the child cancels the
+//! watchdog, so the thread never outlives the child by much.
+//!
+//! The mutex also records whether a thread is watching, so re-arming a
+//! watchdog hands the new deadline to the thread already waiting on it
+//! instead of starting a second one.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+#[derive(Default)]
+struct State {
+    /// `None` when there is no deadline, it was cancelled, or it has fired.
+    deadline: Option<Instant>,
+    /// Whether a thread is in `expired`, or about to be.
+    watching: bool,
+}
+
+/// The deadline of a child's watchdog.
+#[derive(Default)]
+pub struct Watchdog {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Watchdog {
+    /// Arms the watchdog, or moves its deadline if it is armed. Returns true
+    /// if no thread is watching it, in which case the caller must start one
+    /// that calls `expired`.
+    pub fn arm(&self, deadline: Instant) -> bool {
+        let mut state = self.lock();
+        state.deadline = Some(deadline);
+        self.changed.notify_all();
+        !std::mem::replace(&mut state.watching, true)
+    }
+
+    /// Disarms the watchdog. Returns false if it was not armed.
+    pub fn cancel(&self) -> bool {
+        let armed = self.lock().deadline.take().is_some();
+        self.changed.notify_all();
+        armed
+    }
+
+    /// Moves an armed watchdog's deadline. Returns false if it was not
+    /// armed, in which case it stays disarmed.
+    pub fn extend(&self, deadline: Instant) -> bool {
+        let mut state = self.lock();
+        if state.deadline.is_none() {
+            return false;
+        }
+        state.deadline = Some(deadline);
+        self.changed.notify_all();
+        true
+    }
+
+    /// Blocks until the deadline passes, returning true, or the watchdog is
+    /// disarmed, returning false. Once it returns true the watchdog is
+    /// disarmed, so later `cancel` and `extend` calls report that. Either
+    /// way the calling thread stops watching, and the next `arm` asks for a
+    /// new one.
+    pub fn expired(&self) -> bool {
+        let mut state = self.lock();
+        loop {
+            let Some(at) = state.deadline else {
+                state.watching = false;
+                return false;
+            };
+            let now = Instant::now();
+            if now >= at {
+                state.deadline = None;
+                state.watching = false;
+                return true;
+            }
+            state = self
+                .changed
+                .wait_timeout(state, at - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
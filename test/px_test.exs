@@ -196,6 +196,40 @@ defmodule PxTest do
     end
   end
 
+  describe "socket stdio" do
+    test "reads and writes through socket pairs" do
+      p = Px.spawn!("cat", [], stdin: :socket, stdout: :socket)
+      assert Px.write(p, "hello") == :ok
+      assert Px.close(p, :stdin) == :ok
+      p = Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "hello"}
+      assert Px.read(p, :stdout) == :eof
+    end
+
+    test "child closing its stdin does not report stdout EOF" do
+      p =
+        Px.spawn!("sh", ["-c", "exec 0<&-; sleep 0.2; echo done"],
+          stdin: :socket,
+          stdout: :socket
+        )
+
+      Process.sleep(50)
+      assert Px.read(p, :stdout) == :would_block
+      p = Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "done\n"}
+      assert Px.read(p, :stdout) == :eof
+    end
+
+    test "reports EOF once the child closes its stdout" do
+      p = Px.spawn!("sh", ["-c", "exec 1>&-; sleep 10"], stdout: :socket)
+      Process.sleep(50)
+      assert Px.read(p, :stdout) == :eof
+      assert Px.alive?(p)
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+  end
+
   describe "stderr piping" do
     test "captures stderr when piped" do
       p = Px.spawn!("sh", ["-c", "echo error message >&2"], stderr: :pipe)
@@ -254,12 +288,50 @@ defmodule PxTest do
       assert p.status == {:exited, 0}
     end
 
-    test "write returns broken_pipe after child exits" do
+    test "write returns already_exited after the child has been waited on" do
+      p = Px.spawn!("true", [], stdin: :pipe)
+      Process.sleep(50)
+      Px.wait(p)
+      assert Px.write(p, "data") == {:error, :already_exited}
+      assert Px.write_line(p, "data") == {:error, :already_exited}
+    end
+
+    test "write returns reader_gone when a live child has closed stdin" do
+      p = Px.spawn!("sh", ["-c", "exec 0<&-; sleep 10"], stdin: :pipe)
+      Process.sleep(50)
+      assert Px.write(p, "data") == {:error, :reader_gone}
+      assert Px.write(p, ["da", ?t, "a"]) == {:error, :reader_gone}
+      assert Px.write_line(p, "data") == {:error, :reader_gone}
+      assert Px.io_counters(p).stdin_written == 0
+      Px.destroy(p)
+    end
+
+    test "empty writes return ok without touching stdin" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      {:partial, n} = Px.write(p, :binary.copy("x", 1_000_000))
+
+      assert Px.write(p, "") == :ok
+      assert Px.write(p, [[], [""]]) == :ok
+      assert Px.pending_write(p) == {:ok, 1_000_000 - n}
+      assert Px.io_counters(p).stdin_written == n
+
+      Px.destroy(p)
+      assert Px.write(p, "") == :ok
+      assert Px.write(p, "data") == {:error, :already_exited}
+    end
+
+    test "write returns reader_gone on a socket whose child closed stdin" do
+      p = Px.spawn!("sh", ["-c", "exec 0<&-; sleep 10"], stdin: :socket)
+      Process.sleep(50)
+      assert Px.write(p, "data") == {:error, :reader_gone}
+      Px.destroy(p)
+    end
+
+    test "write returns reader_gone for an exited child not yet waited on" do
       p = Px.spawn!("true", [], stdin: :pipe)
       Process.sleep(50)
+      assert Px.write(p, "data") == {:error, :reader_gone}
       Px.wait(p)
-      # Process has exited, write should fail
-      assert Px.write(p, "data") == {:error, :broken_pipe}
     end
 
     test "close stdout causes SIGPIPE on child write" do
@@ -272,6 +344,41 @@ defmodule PxTest do
       p = Px.wait(p)
       assert p.status == {:exited, 141}
     end
+
+    test "close stderr causes SIGPIPE on child write" do
+      p = Px.spawn!("sh", ["-c", "yes >&2"], stderr: :pipe)
+      Process.sleep(50)
+      assert Px.close(p, :stderr) == :ok
+      p = Px.wait(p)
+      assert p.status == {:exited, 141}
+    end
+
+    test "close stdout gives EPIPE to a child ignoring SIGPIPE" do
+      script = "trap '' PIPE; yes; echo \"yes exited $?\" >&2"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Process.sleep(50)
+      assert Px.close(p, :stdout) == :ok
+
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      {:ok, stderr} = Px.read(p, :stderr)
+      assert stderr =~ "Broken pipe"
+      assert stderr =~ ~r/yes exited [1-9]/
+    end
+
+    test "reading after close stdout reports not_piped" do
+      p = Px.spawn!("yes", [], stdout: :pipe)
+      assert Px.close(p, :stdout) == :ok
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+      assert Px.close(p, :stdout) == {:error, :not_piped}
+      Px.wait(p)
+    end
+
+    test "close stdout is not supported for a pty" do
+      p = Px.spawn!("sh", ["-c", "read x"], pty: true)
+      assert Px.close(p, :stdout) == {:error, :not_supported}
+      Px.destroy(p)
+    end
   end
 
   describe "file redirection" do
@@ -353,6 +460,20 @@ defmodule PxTest do
       assert p.status == {:exited, 0}
     end
 
+    test "concurrent alive? polls stay false once the child is reaped" do
+      p = Px.spawn!("sh", ["-c", "sleep 0.05; exit 3"])
+
+      polls =
+        for _ <- 1..8 do
+          Task.async(fn ->
+            Stream.repeatedly(fn -> Px.alive?(p) end) |> Stream.drop_while(& &1) |> Enum.take(3)
+          end)
+        end
+
+      assert Enum.map(polls, &Task.await/1) == List.duplicate([false, false, false], 8)
+      assert Px.exit_info(p) == {:ok, %{code: 3, signal: nil, core_dumped: false}}
+    end
+
     test "wait works correctly when process killed by signal after alive? check" do
       p = Px.spawn!("sleep", ["10"])
       assert Px.alive?(p) == true
@@ -514,6 +635,28 @@ defmodule PxTest do
       Px.wait(p)
     end
 
+    @tag :tmp_dir
+    test "rejects a missing or non-directory cd", %{tmp_dir: dir} do
+      missing = Path.join(dir, "nope")
+      assert Px.spawn("true", [], cd: missing) == {:error, :bad_cwd, missing}
+
+      file = Path.join(dir, "file")
+      File.write!(file, "")
+      assert Px.spawn("true", [], cd: file) == {:error, :bad_cwd, file}
+    end
+
+    @tag :tmp_dir
+    test "rejects a bad cd before truncating a file output", %{tmp_dir: dir} do
+      out = Path.join(dir, "out")
+      File.write!(out, "kept")
+      missing = Path.join(dir, "nope")
+
+      assert Px.spawn("true", [], cd: missing, stdout: {:file, out}) ==
+               {:error, :bad_cwd, missing}
+
+      assert File.read!(out) == "kept"
+    end
+
     test "nil cd uses current directory" do
       current = File.cwd!()
       p = Px.spawn!("pwd", [], cd: nil, stdout: :pipe)
@@ -524,6 +667,3409 @@ defmodule PxTest do
     end
   end
 
+  describe "login shell" do
+    test "runs the command through the shell" do
+      p = Px.shell!("echo one two | wc -w", stdout: :pipe)
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      {:ok, output} = Px.read(p, :stdout)
+      assert String.trim(output) == "2"
+    end
+
+    test "uses the configured shell" do
+      p = Px.shell!("echo $0", shell: "/bin/sh", stdout: :pipe)
+      Px.wait(p)
+      assert {:ok, output} = Px.read(p, :stdout)
+      assert String.trim(output) =~ "sh"
+    end
+
+    test "applies env and cd" do
+      p = Px.shell!("echo $MY_VAR $(pwd)", env: %{"MY_VAR" => "hi"}, cd: "/tmp", stdout: :pipe)
+      Px.wait(p)
+      {:ok, output} = Px.read(p, :stdout)
+      assert output in ["hi /tmp\n", "hi /private/tmp\n"]
+    end
+
+    test "reports the shell's exit code" do
+      p = Px.shell!("exit 3", shell: "/bin/sh")
+      p = Px.wait(p)
+      assert p.status == {:exited, 3}
+    end
+
+    test "returns an error for a missing shell" do
+      assert {:error, :not_found, _} = Px.shell("true", shell: "/nonexistent/shell")
+    end
+  end
+
+  describe "spawn_parse" do
+    test "splits on blanks and honours quotes and escapes" do
+      line = ~S(printf '[%s]' plain 'single quoted' "double \"quoted\"" back\ slash "a\b")
+      p = Px.spawn_parse!(line, stdout: :pipe) |> Px.wait()
+
+      assert Px.read(p, :stdout) ==
+               {:ok, ~S([plain][single quoted][double "quoted"][back slash][a\b])}
+    end
+
+    test "does not expand anything" do
+      p = Px.spawn_parse!("echo $HOME * `id` ; true", stdout: :pipe) |> Px.wait()
+      assert Px.read(p, :stdout) == {:ok, "$HOME * `id` ; true\n"}
+    end
+
+    test "keeps empty quoted words" do
+      p = Px.spawn_parse!(~S(printf '[%s]' '' ""), stdout: :pipe) |> Px.wait()
+      assert Px.read(p, :stdout) == {:ok, "[][]"}
+    end
+
+    test "reports where an unbalanced quote starts" do
+      assert Px.spawn_parse(~S(grep -i 'foo /var/log)) == {:error, :parse_error, 8}
+      assert Px.spawn_parse("echo trailing\\") == {:error, :parse_error, 13}
+    end
+
+    test "rejects an empty command line" do
+      assert {:error, "empty command line"} = Px.spawn_parse("  ")
+    end
+  end
+
+  describe "communicate" do
+    test "feeds input and collects stdout and stderr" do
+      p =
+        Px.spawn!("sh", ["-c", "cat; echo err >&2; exit 3"],
+          stdin: :pipe,
+          stdout: :pipe,
+          stderr: :pipe
+        )
+
+      assert {:ok, p, "input", "err\n"} = Px.communicate(p, "input")
+      assert p.status == {:exited, 3}
+    end
+
+    test "does not deadlock on output larger than the pipe buffer" do
+      p =
+        Px.spawn!("sh", ["-c", "head -c 200000 /dev/zero; head -c 200000 /dev/zero >&2"],
+          stdout: :pipe,
+          stderr: :pipe
+        )
+
+      assert {:ok, _p, stdout, stderr} = Px.communicate(p)
+      assert byte_size(stdout) == 200_000
+      assert byte_size(stderr) == 200_000
+    end
+
+    test "writes input larger than the pipe buffer" do
+      input = :binary.copy("x", 500_000)
+      p = Px.spawn!("wc", ["-c"], stdin: :pipe, stdout: :pipe)
+      assert {:ok, _p, stdout, ""} = Px.communicate(p, input)
+      assert String.trim(stdout) == "500000"
+    end
+
+    test "kills the child on timeout" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      assert Px.communicate(p, "", timeout: 50) == :timeout
+      assert Px.alive?(p) == false
+      assert Px.signal(p, :sigterm) == {:error, :already_exited}
+    end
+
+    test "kills the child when output exceeds the limit" do
+      p = Px.spawn!("yes", [], stdout: :pipe)
+      assert Px.communicate(p, "", max_output: 10_000) == :output_limit_exceeded
+      assert Px.alive?(p) == false
+    end
+
+    test "sends kill_signal first when a limit is hit" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      assert Px.communicate(p, "", timeout: 50, kill_signal: :sigterm) == :timeout
+      assert Px.termination_signal(p) == {:signaled, 15, true}
+    end
+
+    test "escalates to SIGKILL after kill_timeout" do
+      p = Px.spawn!("sh", ["-c", "trap '' TERM; exec sleep 10"], stdout: :pipe)
+      opts = [timeout: 50, kill_signal: :sigterm, kill_timeout: 100]
+      assert Px.communicate(p, "", opts) == :timeout
+      assert Px.termination_signal(p) == {:signaled, 9, true}
+    end
+
+    test "requires piped stdin for non-empty input" do
+      p = Px.spawn!("true", [])
+      assert Px.communicate(p, "data") == {:error, :not_piped}
+      Px.wait(p)
+    end
+
+    test "reports the exit of a signaled child" do
+      p = Px.spawn!("sh", ["-c", "kill -9 $$"], stdout: :pipe)
+      assert {:ok, p, "", ""} = Px.communicate(p)
+      assert p.status == {:exited, 137}
+    end
+  end
+
+  describe "wait_full" do
+    test "drains output larger than the pipe buffer before reaping" do
+      p =
+        Px.spawn!("sh", ["-c", "head -c 200000 /dev/zero; head -c 200000 /dev/zero >&2; exit 2"],
+          stdout: :pipe,
+          stderr: :pipe
+        )
+
+      assert {p, stdout, stderr} = Px.wait_full(p)
+      assert p.status == {:exited, 2}
+      assert byte_size(stdout) == 200_000
+      assert byte_size(stderr) == 200_000
+    end
+
+    test "keeps only the last max_output bytes of each stream" do
+      p = Px.spawn!("sh", ["-c", "seq 1 100000; echo done >&2"], stdout: :pipe, stderr: :pipe)
+      assert {p, "99999\n100000\n", "done\n"} = Px.wait_full(p, max_output: 13)
+      assert p.status == {:exited, 0}
+    end
+
+    test "returns empty output for streams that are not piped" do
+      p = Px.spawn!("sh", ["-c", "echo hidden"])
+      assert {p, "", ""} = Px.wait_full(p)
+      assert p.status == {:exited, 0}
+    end
+  end
+
+  describe "exit cleanup options" do
+    test "spawns without pdeathsig" do
+      p = Px.spawn!("sh", ["-c", "exit 5"], pdeathsig: false)
+      p = Px.wait(p)
+      assert p.status == {:exited, 5}
+    end
+
+    test "accepts a cleanup signal" do
+      p = Px.spawn!("sleep", ["10"], pdeathsig: false, cleanup_signal: :sigterm)
+      assert Px.alive?(p)
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "rejects an invalid cleanup signal" do
+      assert Px.spawn("true", [], cleanup_signal: 999) == {:error, "Invalid signal"}
+    end
+
+    test "sets the OOM score adjustment" do
+      p = Px.spawn!("sleep", ["10"], oom_score_adj: 500)
+      assert File.read!("/proc/#{p.pid}/oom_score_adj") == "500\n"
+      Px.destroy(p)
+    end
+
+    test "rejects an out-of-range OOM score adjustment" do
+      assert {:error, "invalid oom_score_adj" <> _} = Px.spawn("true", [], oom_score_adj: 1001)
+
+      assert {:error, "invalid oom_score_adj" <> _} =
+               Px.validate("true", [], oom_score_adj: -1001)
+    end
+  end
+
+  describe "runtime" do
+    test "grows while the process runs" do
+      p = Px.spawn!("sleep", ["10"])
+      first = Px.runtime(p)
+      Process.sleep(50)
+      assert Px.runtime(p) >= first + 50
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "is frozen after the process is reaped" do
+      p = Px.spawn!("sleep", ["0.1"])
+      p = Px.wait(p)
+      total = Px.runtime(p)
+      assert total >= 100
+      Process.sleep(50)
+      assert Px.runtime(p) == total
+    end
+  end
+
+  describe "environment whitelist" do
+    setup do
+      System.put_env("PX_KEEP", "kept")
+      System.put_env("PX_DROP", "dropped")
+
+      on_exit(fn ->
+        System.delete_env("PX_KEEP")
+        System.delete_env("PX_DROP")
+      end)
+    end
+
+    test "clear_env starts from an empty environment plus :env" do
+      p = Px.spawn!("/usr/bin/env", [], clear_env: true, env: %{"ONLY" => "1"}, stdout: :pipe)
+      p = Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "ONLY=1\n"}
+    end
+
+    test "inherit_env carries over only the named variables" do
+      p =
+        Px.spawn!("/usr/bin/env", [],
+          clear_env: true,
+          inherit_env: ["PX_KEEP", "PX_MISSING"],
+          env: %{"EXTRA" => "x"},
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      {:ok, output} = Px.read(p, :stdout)
+      vars = output |> String.split("\n", trim: true) |> Enum.sort()
+      assert vars == ["EXTRA=x", "PX_KEEP=kept"]
+    end
+
+    test ":env overrides an inherited variable" do
+      p =
+        Px.spawn!("/usr/bin/env", [],
+          clear_env: true,
+          inherit_env: ["PX_KEEP"],
+          env: %{"PX_KEEP" => "override"},
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "PX_KEEP=override\n"}
+    end
+
+    test "inherit_env is ignored without clear_env" do
+      p = Px.spawn!("/usr/bin/env", [], inherit_env: ["PX_KEEP"], stdout: :pipe)
+      p = Px.wait(p)
+      assert collect_stdout(p) =~ "PX_DROP=dropped"
+    end
+  end
+
+  describe "env precedence" do
+    setup do
+      System.put_env("PX_SET", "inherited")
+      on_exit(fn -> System.delete_env("PX_SET") end)
+    end
+
+    test ":default entries only fill in variables the child would not have" do
+      p =
+        Px.spawn!("sh", ["-c", "echo $PX_SET-$PX_UNSET-$PX_FORCED"],
+          env: [
+            {"PX_SET", "fallback", :default},
+            {"PX_UNSET", "fallback", :default},
+            {"PX_FORCED", "first", :force},
+            {"PX_FORCED", "fallback", :default}
+          ],
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      assert collect_stdout(p) == "inherited-fallback-first\n"
+    end
+
+    test ":force entries override inherited variables" do
+      p =
+        Px.spawn!("sh", ["-c", "echo $PX_SET"],
+          env: [{"PX_SET", "forced", :force}],
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      assert collect_stdout(p) == "forced\n"
+    end
+
+    test "with clear_env, :default entries see only the whitelisted variables" do
+      p =
+        Px.spawn!("/usr/bin/env", [],
+          clear_env: true,
+          inherit_env: ["PX_SET"],
+          env: [{"PX_SET", "fallback", :default}, {"HOME", "/fallback", :default}],
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      vars = p |> collect_stdout() |> String.split("\n", trim: true) |> Enum.sort()
+      assert vars == ["HOME=/fallback", "PX_SET=inherited"]
+    end
+  end
+
+  describe "env_file" do
+    @describetag :tmp_dir
+
+    test "applies parsed variables before :env", %{tmp_dir: dir} do
+      path = Path.join(dir, ".env")
+
+      File.write!(path, """
+      # deployment settings
+      export PLAIN=one two # trailing comment
+
+      SINGLE='$HOME \\n'
+      DOUBLE="a\\tb \\"q\\""
+      OVERRIDDEN=file
+      """)
+
+      p =
+        Px.spawn!("/usr/bin/env", [],
+          clear_env: true,
+          env_file: path,
+          env: %{"OVERRIDDEN" => "env"},
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      vars = p |> collect_stdout() |> String.split("\n", trim: true) |> Enum.sort()
+
+      assert vars == [
+               "DOUBLE=a\tb \"q\"",
+               "OVERRIDDEN=env",
+               "PLAIN=one two",
+               "SINGLE=$HOME \\n"
+             ]
+    end
+
+    test "reports the line of a malformed entry", %{tmp_dir: dir} do
+      path = Path.join(dir, ".env")
+      File.write!(path, "GOOD=1\n\n# comment\nnot an assignment\n")
+
+      assert Px.spawn("/usr/bin/env", [], env_file: path) ==
+               {:error, {:invalid_env_file, 4}}
+    end
+
+    test "fails when the file cannot be read", %{tmp_dir: dir} do
+      assert {:error, message} = Px.spawn("/usr/bin/env", [], env_file: Path.join(dir, "nope"))
+      assert message =~ "Failed to read env file"
+    end
+  end
+
+  describe "max children" do
+    setup do
+      on_exit(fn -> Px.set_max_children(:infinity) end)
+    end
+
+    test "refuses spawns over the limit until a child is reaped" do
+      Px.set_max_children(Px.live_children() + 1)
+
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.spawn("true", []) == {:error, :too_many_children}
+
+      {:ok, p} = Px.signal(p, :sigkill)
+      Px.wait(p)
+
+      assert {:ok, q} = Px.spawn("true", [])
+      Px.wait(q)
+    end
+
+    test "counts children from spawn until reap" do
+      before = Px.live_children()
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.live_children() == before + 1
+
+      {:ok, p} = Px.signal(p, :sigkill)
+      Px.wait(p)
+      assert Px.live_children() == before
+    end
+
+    test "lists live PIDs until reap" do
+      p = Px.spawn!("sleep", ["10"])
+      assert p.pid in Px.live_child_pids()
+
+      Px.destroy(p)
+      refute p.pid in Px.live_child_pids()
+    end
+
+    test "drops PIDs whose process struct was garbage collected" do
+      pid =
+        Task.async(fn -> Px.spawn!("true", []).pid end)
+        |> Task.await()
+
+      :erlang.garbage_collect()
+      refute pid in Px.live_child_pids()
+    end
+  end
+
+  describe "decode_exit_status" do
+    test "decodes normal exits" do
+      assert Px.decode_exit_status(0) == {:exited, 0}
+      assert Px.decode_exit_status(42 * 256) == {:exited, 42}
+    end
+
+    test "decodes signal deaths with and without a core dump" do
+      assert Px.decode_exit_status(15) == {:signaled, 15, false}
+      assert Px.decode_exit_status(0x80 + 11) == {:signaled, 11, true}
+    end
+
+    test "rejects stopped and continued statuses" do
+      assert Px.decode_exit_status(0x137F) == {:error, :not_terminated}
+      assert Px.decode_exit_status(0xFFFF) == {:error, :not_terminated}
+    end
+  end
+
+  describe "read_balanced" do
+    test "takes data from both streams within the byte limit" do
+      script = "head -c 100000 /dev/zero; head -c 100000 /dev/zero >&2"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Process.sleep(100)
+
+      {stdout, stderr} = Px.read_balanced(p, 8192)
+      assert byte_size(stdout) + byte_size(stderr) <= 8192
+      assert byte_size(stdout) > 0
+
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "alternates between streams when both have data" do
+      script = "head -c 50000 /dev/zero >&2 & head -c 50000 /dev/zero; wait"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Process.sleep(100)
+
+      {stdout, stderr} = Px.read_balanced(p, 16_384)
+      assert byte_size(stdout) == 8192
+      assert byte_size(stderr) == 8192
+
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "splits the work across NIF calls at :max_reads" do
+      script = "for i in $(seq 200); do printf x; printf y >&2; done"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Px.wait(p)
+
+      assert {:more, stdout, ""} = Px.read_balanced_nif(p.resource, 65_536, 1)
+      assert stdout == String.duplicate("x", 200)
+      assert Px.read_balanced(p, 65_536, max_reads: 1) == {"", String.duplicate("y", 200)}
+    end
+
+    test "returns empty binaries when nothing is ready" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      assert Px.read_balanced(p) == {"", ""}
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+  end
+
+  describe "read_prioritized" do
+    test "drains the primary stream before the other" do
+      script = "head -c 50000 /dev/zero >&2 & head -c 50000 /dev/zero; wait"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Process.sleep(100)
+
+      {stdout, stderr} = Px.read_prioritized(p, :stderr, max_bytes: 16_384)
+      assert stdout == ""
+      assert byte_size(stderr) == 16_384
+
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "caps the primary stream with :primary_max_bytes" do
+      script = "head -c 50000 /dev/zero >&2 & head -c 50000 /dev/zero; wait"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Process.sleep(100)
+
+      opts = [max_bytes: 16_384, primary_max_bytes: 4096]
+      {stdout, stderr} = Px.read_prioritized(p, :stdout, opts)
+      assert byte_size(stdout) == 4096
+      assert byte_size(stderr) == 12_288
+
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "splits the work across NIF calls at :max_reads" do
+      script = "for i in $(seq 200); do printf x; printf y >&2; done"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe)
+      Px.wait(p)
+
+      assert {:more, "", stderr} = Px.read_prioritized_nif(p.resource, :stderr, 65_536, 65_536, 1)
+      assert stderr == String.duplicate("y", 200)
+      assert Px.read_prioritized(p, :stderr, max_reads: 1) == {String.duplicate("x", 200), ""}
+    end
+
+    test "moves on to the other stream once the primary has nothing ready" do
+      p = Px.spawn!("sh", ["-c", "echo out; echo err >&2"], stdout: :pipe, stderr: :pipe)
+      Px.wait(p)
+
+      assert Px.read_prioritized(p, :stderr) == {"out\n", "err\n"}
+      assert Px.read_prioritized(p, :stdout) == {"", ""}
+    end
+  end
+
+  describe "exit notification" do
+    test "messages the owner when the child exits" do
+      p = Px.spawn!("sh", ["-c", "exit 3"], notify_exit: self())
+      resource = p.resource
+
+      assert_receive {:px_exit, ^resource, {:exited, 3}}, 1000
+      assert Px.wait(p).status == {:exited, 3}
+    end
+
+    test "reports signal deaths and agrees with a concurrent wait" do
+      p = Px.spawn!("sleep", ["10"], notify_exit: self())
+      resource = p.resource
+      waiter = Task.async(fn -> Px.wait(p) end)
+
+      {:ok, _} = Px.signal(p, :sigkill)
+      assert_receive {:px_exit, ^resource, {:signaled, 9, false}}, 1000
+      assert Task.await(waiter).status == {:exited, 137}
+    end
+  end
+
+  describe "templates" do
+    test "spawn children with the template's options and their own args" do
+      {:ok, template} = Px.template("sh", env: %{"GREETING" => "hi"}, stdout: :pipe)
+
+      outputs =
+        for name <- ["a", "b", "c"] do
+          p = Px.spawn_from!(template, ["-c", "echo $GREETING #{name}"]) |> Px.wait()
+          assert p.stdout == :pipe
+          {:ok, output} = Px.read(p, :stdout)
+          output
+        end
+
+      assert outputs == ["hi a\n", "hi b\n", "hi c\n"]
+    end
+
+    test "rejects invalid options up front" do
+      assert Px.template("true", cleanup_signal: 999) == {:error, "Invalid signal"}
+    end
+
+    test "reports spawn errors per child" do
+      {:ok, template} = Px.template("nonexistent_command_12345")
+      assert {:error, :not_found, _} = Px.spawn_from(template, [])
+    end
+  end
+
+  describe "spawn failures" do
+    @tag :tmp_dir
+    test "are classified by errno", %{tmp_dir: dir} do
+      assert {:error, :not_found, message} = Px.spawn("/nonexistent/px-command", [])
+      assert is_binary(message)
+
+      not_executable = Path.join(dir, "not-executable")
+      File.write!(not_executable, "#!/bin/sh\n")
+      File.chmod!(not_executable, 0o640)
+      assert Px.spawn(not_executable, []) == {:error, :not_executable, 0o640}
+    end
+
+    @tag :tmp_dir
+    test "report a directory passed as the command", %{tmp_dir: dir} do
+      assert Px.spawn(dir, []) == {:error, :is_a_directory}
+
+      File.mkdir!(Path.join(dir, "tool"))
+      assert Px.spawn("tool", [], env: %{"PATH" => dir}) == {:error, :is_a_directory}
+    end
+
+    @tag :tmp_dir
+    test "report a script's missing interpreter", %{tmp_dir: dir} do
+      script = Path.join(dir, "script")
+      File.write!(script, "#!/nonexistent/python3 -u\nprint(1)\n")
+      File.chmod!(script, 0o755)
+
+      assert Px.spawn(script, []) == {:error, :bad_interpreter, "/nonexistent/python3"}
+
+      assert Px.spawn("script", [], env: %{"PATH" => dir}) ==
+               {:error, :bad_interpreter, "/nonexistent/python3"}
+    end
+  end
+
+  describe "exec string checks" do
+    test "reports a NUL byte in an argument, variable or the command" do
+      assert Px.spawn("echo", ["ok", "a\0b"]) == {:error, :invalid_arg, 1}
+      assert Px.spawn("echo", [], env: [{"GOOD", "1"}, {"BAD", "x\0y"}]) ==
+               {:error, :invalid_env, "BAD"}
+
+      assert Px.spawn("ec\0ho", []) == {:error, :invalid_command}
+      assert Px.validate("echo", ["a\0b"]) == {:error, :invalid_arg, 0}
+    end
+
+    @tag :tmp_dir
+    test "reports a NUL byte in a variable from env_file", %{tmp_dir: dir} do
+      path = Path.join(dir, ".env")
+      File.write!(path, "GOOD=1\nBAD=x\0y\n")
+
+      assert Px.spawn("echo", [], env_file: path) == {:error, :invalid_env, "BAD"}
+      assert Px.validate("echo", [], env_file: path) == {:error, :invalid_env, "BAD"}
+    end
+
+    test "reports an argument list over the system limit" do
+      huge = List.duplicate(:binary.copy("x", 100_000), 1_000)
+      assert Px.spawn("true", huge) == {:error, :arg_list_too_long}
+      assert Px.spawn("true", [:binary.copy("x", 200_000)]) == {:error, :arg_list_too_long}
+    end
+
+    test "applies to templates" do
+      {:ok, template} = Px.template("echo")
+      assert Px.spawn_from(template, ["a\0b"]) == {:error, :invalid_arg, 0}
+    end
+  end
+
+  describe "validate" do
+    @describetag :tmp_dir
+
+    test "accepts a valid configuration without creating files", %{tmp_dir: dir} do
+      out = Path.join(dir, "out.log")
+      assert Px.validate("echo", ["hi"], stdout: {:file, out}, cd: dir) == :ok
+      refute File.exists?(out)
+    end
+
+    test "reports a missing or non-executable command", %{tmp_dir: dir} do
+      assert {:error, :not_found, _} = Px.validate("nonexistent_command_12345", [])
+
+      script = Path.join(dir, "script")
+      File.write!(script, "#!/bin/sh\n")
+      File.chmod!(script, 0o600)
+      assert Px.validate(script, []) == {:error, :not_executable, 0o600}
+      assert Px.validate(dir, []) == {:error, :is_a_directory}
+
+      File.write!(script, "#!/nonexistent/python3\n")
+      File.chmod!(script, 0o755)
+      assert Px.validate(script, []) == {:error, :bad_interpreter, "/nonexistent/python3"}
+    end
+
+    test "resolves bare names on an overridden PATH", %{tmp_dir: dir} do
+      File.write!(Path.join(dir, "tool"), "#!/bin/sh\n")
+      File.chmod!(Path.join(dir, "tool"), 0o755)
+
+      assert Px.validate("tool", [], env: %{"PATH" => dir}) == :ok
+      assert {:error, :not_found, _} = Px.validate("tool", [])
+    end
+
+    test "reports file problems", %{tmp_dir: dir} do
+      missing = Path.join(dir, "missing")
+      assert {:error, message} = Px.validate("true", [], stdin: {:file, missing})
+      assert message =~ "Failed to open stdin file"
+
+      assert {:error, message} =
+               Px.validate("true", [], stdout: {:file, Path.join(missing, "out.log")})
+
+      assert message =~ "Failed to create stdout file"
+
+      taken = Path.join(dir, "taken")
+      File.write!(taken, "")
+
+      assert Px.validate("true", [], stderr: {:file, taken, flags: [:exclusive]}) ==
+               {:error, {:file_exists, taken}}
+    end
+
+    test "reports a missing working directory and bad env files", %{tmp_dir: dir} do
+      missing = Path.join(dir, "nope")
+      assert Px.validate("true", [], cd: missing) == {:error, :bad_cwd, missing}
+
+      env_file = Path.join(dir, ".env")
+      File.write!(env_file, "oops\n")
+      assert Px.validate("true", [], env_file: env_file) == {:error, {:invalid_env_file, 1}}
+    end
+  end
+
+  describe "search_path" do
+    @describetag :tmp_dir
+
+    test "resolves the command in the given directories only", %{tmp_dir: dir} do
+      tool = Path.join(dir, "echo")
+      File.write!(tool, "#!/bin/sh\necho \"own $PATH\"\n")
+      File.chmod!(tool, 0o755)
+
+      p = Px.spawn!("echo", ["x"], search_path: ["/nonexistent", dir], stdout: :pipe)
+      p = Px.wait(p)
+      assert {:ok, "own " <> path} = Px.read(p, :stdout)
+      assert String.trim(path) == System.get_env("PATH")
+    end
+
+    test "returns not_found when no directory has the command", %{tmp_dir: dir} do
+      assert Px.spawn("sh", [], search_path: [dir]) == {:error, :not_found}
+      assert Px.validate("sh", [], search_path: [dir]) == {:error, :not_found}
+    end
+
+    test "leaves names with a slash alone", %{tmp_dir: dir} do
+      p = Px.spawn!("/bin/sh", ["-c", "exit 4"], search_path: [dir])
+      assert Px.wait(p).status == {:exited, 4}
+    end
+  end
+
+  describe "file actions" do
+    @describetag :tmp_dir
+
+    test "applies actions in order after the stdio options", %{tmp_dir: dir} do
+      log = Path.join(dir, "out.log")
+
+      actions = [
+        {:open, 1, log, [:write, :create, :truncate], 0o600},
+        {:dup2, 1, 2}
+      ]
+
+      p = Px.spawn!("sh", ["-c", "echo out; echo err >&2"], file_actions: actions)
+      assert Px.wait(p).status == {:exited, 0}
+      assert File.read!(log) == "out\nerr\n"
+    end
+
+    test "closes descriptors" do
+      script = "echo gone 2>/dev/null || exit 3"
+      p = Px.spawn!("sh", ["-c", script], file_actions: [{:close, 1}])
+      assert Px.wait(p).status == {:exited, 3}
+    end
+
+    test "reports the failing action", %{tmp_dir: dir} do
+      missing = Path.join(dir, "missing/file")
+      actions = [{:open, 0, missing, [:read], 0}]
+      assert {:error, :file_action_failed, errno} = Px.spawn("true", [], file_actions: actions)
+      assert errno > 0
+    end
+  end
+
+  describe "namespaces" do
+    @describetag :tmp_dir
+
+    test "runs the child as root of a new user namespace" do
+      p = Px.spawn!("sh", ["-c", "id -u; id -g"], namespaces: [:user], stdout: :pipe)
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      assert collect_until_eof(p) == "0\n0\n"
+    end
+
+    test "keeps mounts in a new mount namespace", %{tmp_dir: dir} do
+      script = "mount -t tmpfs none \"$1\" && touch \"$1/inside\""
+      p = Px.spawn!("sh", ["-c", script, "sh", dir], namespaces: [:user, :mount])
+      assert Px.wait(p).status == {:exited, 0}
+      refute File.exists?(Path.join(dir, "inside"))
+    end
+
+    test "gives a new network namespace only a loopback device" do
+      p = Px.spawn!("cat", ["/proc/net/dev"], namespaces: [:user, :net], stdout: :pipe)
+      p = Px.wait(p)
+      devices = collect_until_eof(p) |> String.split("\n") |> Enum.filter(&(&1 =~ ":"))
+      assert [loopback] = devices
+      assert String.trim(loopback) =~ ~r/^lo:/
+    end
+
+    test "rejects pid and unknown namespaces" do
+      assert {:error, "pid namespaces are not supported" <> _} =
+               Px.spawn("true", [], namespaces: [:user, :pid])
+
+      assert {:error, "invalid namespace" <> _} = Px.validate("true", [], namespaces: [:cgroup])
+    end
+  end
+
+  describe "spawn_at" do
+    # -100 is AT_FDCWD: a relative name resolves against the working directory.
+    @at_fdcwd -100
+
+    test "execs a name relative to the directory fd" do
+      {:ok, p} = Px.spawn_at(@at_fdcwd, "echo", ["hi"], cd: "/bin", stdout: :pipe)
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+    end
+
+    test "passes the environment through" do
+      {:ok, p} =
+        Px.spawn_at(@at_fdcwd, "/bin/sh", ["-c", "echo $PX_AT"],
+          env: %{"PX_AT" => "set"},
+          stdout: :pipe
+        )
+
+      p = Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "set\n"}
+    end
+
+    test "reports execveat failures with errno" do
+      assert {:error, :execveat_failed, errno} = Px.spawn_at(999_999, "echo", [])
+      assert errno == 9
+    end
+  end
+
+  describe "spawn_fd" do
+    test "execs the open executable, whatever its path now holds" do
+      dir = Path.join(System.tmp_dir!(), "px_spawn_fd_#{System.unique_integer([:positive])}")
+      File.mkdir_p!(dir)
+      path = Path.join(dir, "prog")
+      File.cp!(System.find_executable("echo"), path)
+
+      {:ok, fd} = Px.open_exec(path)
+      assert File.read!("/proc/self/fd/#{fd}") == File.read!(path)
+      File.rm!(path)
+      File.write!(path, "#!/bin/sh\necho swapped\n")
+
+      {:ok, p} = Px.spawn_fd(fd, "echo", ["verified"], stdout: :pipe)
+      assert Px.wait(p) |> Px.read(:stdout) == {:ok, "verified\n"}
+      assert Px.close_fd(fd) == :ok
+      File.rm_rf!(dir)
+    end
+
+    test "passes argv[0] and the environment through" do
+      {:ok, fd} = Px.open_exec("/bin/sh")
+
+      {:ok, p} =
+        Px.spawn_fd(fd, "px-shell", ["-c", "echo $0 $PX_FD"],
+          env: %{"PX_FD" => "set"},
+          stdout: :pipe
+        )
+
+      assert Px.wait(p) |> Px.read(:stdout) == {:ok, "px-shell set\n"}
+      Px.close_fd(fd)
+    end
+
+    test "reports fexecve failures with errno" do
+      assert {:error, :fexecve_failed, 9} = Px.spawn_fd(999_999, "echo", [])
+      assert Px.validate("echo", [], exec_fd: 999_999) == {:error, :fexecve_failed, 9}
+    end
+
+    test "cannot be combined with exec_dirfd" do
+      {:ok, fd} = Px.open_exec("/bin/sh")
+      assert {:error, _} = Px.spawn_fd(fd, "sh", [], exec_dirfd: -100)
+      Px.close_fd(fd)
+    end
+
+    test "open_exec reports a missing file" do
+      assert {:error, message} = Px.open_exec("/nonexistent/px")
+      assert is_binary(message)
+    end
+  end
+
+  describe "spawn_method" do
+    test "uses posix_spawn when no child setup is needed" do
+      p = Px.spawn!("true", [], pdeathsig: false, cd: System.tmp_dir!())
+      assert Px.spawn_method(p) == :posix_spawn
+      Px.wait(p)
+    end
+
+    test "falls back to fork + exec for pdeathsig and pty" do
+      p = Px.spawn!("true", [])
+      assert Px.spawn_method(p) == :fork_exec
+      Px.wait(p)
+
+      p = Px.spawn!("true", [], pdeathsig: false, pty: true)
+      assert Px.spawn_method(p) == :fork_exec
+      Px.wait(p)
+    end
+
+    test "falls back to fork + exec for any other setup step" do
+      p = Px.spawn!("true", [], pdeathsig: false, pgid: 0)
+      assert Px.spawn_method(p) == :fork_exec
+      Px.wait(p)
+    end
+
+    test "falls back to fork + exec when PATH is overridden for a bare name" do
+      path = System.get_env("PATH")
+      p = Px.spawn!("true", [], pdeathsig: false, env: %{"PATH" => path})
+      assert Px.spawn_method(p) == :fork_exec
+      Px.wait(p)
+    end
+  end
+
+  describe "idle_time" do
+    test "counts from spawn before any I/O" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      Process.sleep(50)
+      assert Px.idle_time(p) >= 50
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "is reset by writes and reads" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      Process.sleep(100)
+      assert Px.write(p, "ping") == :ok
+      assert Px.idle_time(p) < 100
+
+      Process.sleep(100)
+      assert {:ok, "ping"} = Px.read(p, :stdout)
+      assert Px.idle_time(p) < 100
+
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "is not reset by reads that return no data" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      Process.sleep(50)
+      assert Px.read(p, :stdout) == :would_block
+      assert Px.idle_time(p) >= 50
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+  end
+
+  describe "read_buffered" do
+    test "returns everything buffered in one read" do
+      p = Px.spawn!("sh", ["-c", "head -c 20000 /dev/zero; sleep 10"], stdout: :pipe)
+      Process.sleep(100)
+
+      assert {:ok, data} = Px.read_buffered(p)
+      assert byte_size(data) == 20_000
+      assert Px.read_buffered(p) == :would_block
+
+      Px.destroy(p)
+    end
+
+    test "reports eof once the pipe is drained" do
+      p = Px.wait(Px.spawn!("echo", ["hi"], stdout: :pipe))
+      assert Px.read_buffered(p) == {:ok, "hi\n"}
+      assert Px.read_buffered(p) == :eof
+    end
+
+    test "returns what a line read left buffered first" do
+      p = Px.wait(Px.spawn!("printf", ["a\\nb"], stdout: :pipe, lines: true))
+      assert Px.read(p, :stdout) == {:ok, "a\n"}
+      assert Px.read_buffered(p) == {:ok, "b"}
+    end
+
+    test "requires a piped stdout" do
+      p = Px.spawn!("true", [])
+      assert Px.read_buffered(p) == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "read_vectored" do
+    test "splits one read across the binaries" do
+      p = Px.spawn!("printf", ["0005hello"], stdout: :pipe) |> Px.wait()
+      assert Px.read_vectored(p, [4, 5]) == {:ok, ["0005", "hello"]}
+      assert Px.read_vectored(p, [4, 5]) == :eof
+    end
+
+    test "fills in order on a short read" do
+      p = Px.spawn!("printf", ["abcdef"], stdout: :pipe) |> Px.wait()
+      assert Px.read_vectored(p, [4, 4, 4]) == {:ok, ["abcd", "ef", ""]}
+    end
+
+    test "leaves what does not fit in the pipe" do
+      p = Px.spawn!("printf", ["abcdef"], stdout: :pipe) |> Px.wait()
+      assert Px.read_vectored(p, [2, 2]) == {:ok, ["ab", "cd"]}
+      assert Px.read(p, :stdout) == {:ok, "ef"}
+    end
+
+    test "takes what a line read left buffered first" do
+      p = Px.spawn!("printf", ["a\\nbc"], stdout: :pipe, lines: true)
+      p = Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "a\n"}
+      assert Px.read_vectored(p, [1, 4]) == {:ok, ["b", "c"]}
+    end
+
+    test "reports would_block while nothing is ready" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      assert Px.read_vectored(p, [4]) == :would_block
+      Px.destroy(p)
+    end
+
+    test "pairs with a scatter write" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      :ok = Px.write(p, ["HDR", "body"])
+      :ok = Px.close(p, :stdin)
+      p = Px.wait(p)
+      assert Px.read_vectored(p, [3, 16]) == {:ok, ["HDR", "body"]}
+    end
+  end
+
+  describe "read_text" do
+    test "holds back a character split across writes" do
+      script = "printf 'caf\\303'; read _; printf '\\251!'"
+      p = Px.spawn!("sh", ["-c", script], stdin: :pipe, stdout: :pipe)
+      Process.sleep(50)
+
+      assert Px.read_text(p, :stdout) == {:ok, "caf"}
+      assert Px.read_text(p, :stdout) == :would_block
+
+      :ok = Px.write(p, "\n")
+      Process.sleep(50)
+      assert Px.read_text(p, :stdout) == {:ok, "é!"}
+      assert Px.read_text(p, :stdout) == :eof
+      Px.wait(p)
+    end
+
+    test "replaces invalid bytes and a partial character at EOF" do
+      p = Px.spawn!("printf", ["a\\377b\\342\\202"], stdout: :pipe)
+      Px.wait(p)
+
+      assert Px.read_text(p, :stdout) == {:ok, "a\uFFFDb"}
+      assert Px.read_text(p, :stdout) == {:ok, "\uFFFD"}
+      assert Px.read_text(p, :stdout) == :eof
+    end
+
+    test "requires a piped stream" do
+      p = Px.spawn!("true", [])
+      assert Px.read_text(p, :stdout) == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "exec confirmation" do
+    test "spawn returns once the target program is running" do
+      for opts <- [[pdeathsig: true], [pdeathsig: false]] do
+        p = Px.spawn!("sleep", ["10"], opts)
+        assert File.read!("/proc/#{p.pid}/comm") == "sleep\n"
+        {:ok, _} = Px.signal(p, :sigkill)
+        Px.wait(p)
+      end
+    end
+  end
+
+  describe "process groups" do
+    test "children join a group and are signaled together" do
+      leader = Px.spawn!("sleep", ["10"], pgid: 0)
+      script = "ps -o pgid= -p $$; sleep 10"
+      member = Px.spawn!("sh", ["-c", script], pgid: leader.pid, stdout: :pipe)
+
+      assert {:ok, pgid} = Px.read_chunk(member)
+      assert String.trim(pgid) == Integer.to_string(leader.pid)
+
+      assert Px.signal_group(leader.pid, :sigkill) == :ok
+      assert Px.wait(leader).status == {:exited, 137}
+      assert Px.wait(member).status == {:exited, 137}
+      assert Px.signal_group(leader.pid, :sigkill) == {:error, :not_found}
+    end
+
+    test "reports a group that cannot be joined" do
+      gone = Px.wait(Px.spawn!("true", [], pgid: 0))
+      assert Px.spawn("true", [], pgid: gone.pid) == {:error, :setpgid_failed, 1}
+    end
+
+    test "cannot be combined with a pty" do
+      assert Px.spawn("true", [], pgid: 0, pty: true) ==
+               {:error, "pgid cannot be combined with pty"}
+    end
+
+    test "kill_group_on_drop kills the group once the process is collected" do
+      parent = self()
+
+      spawn(fn ->
+        opts = [pgid: 0, kill_group_on_drop: true, stdout: :pipe]
+        p = Px.spawn!("sh", ["-c", "sleep 10 & echo $!; wait"], opts)
+        {:ok, grandchild} = Px.read_chunk(p)
+        send(parent, {:pids, p.pid, grandchild |> String.trim() |> String.to_integer()})
+      end)
+
+      assert_receive {:pids, leader, grandchild}, 1000
+      assert os_pid_gone?(leader)
+      assert os_pid_gone?(grandchild)
+    end
+
+    test "kill_group_on_drop requires a group of the child's own" do
+      assert Px.spawn("true", [], kill_group_on_drop: true) ==
+               {:error, "kill_group_on_drop requires pgid: 0 or pty"}
+    end
+  end
+
+  describe "kill_tree" do
+    test "requires the child to be in a cgroup" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.kill_tree(p) == {:error, :not_in_cgroup}
+      Px.destroy(p)
+    end
+
+    test "rejects a directory that is not a cgroup" do
+      dir = System.tmp_dir!()
+      assert {:error, "not a non-root cgroup v2 directory: " <> _} =
+               Px.spawn("true", [], cgroup: dir)
+
+      assert {:error, "not a non-root cgroup v2 directory: " <> _} =
+               Px.validate("true", [], cgroup: dir)
+    end
+  end
+
+  describe "kill_tree in a cgroup" do
+    # The BEAM's own cgroup v2 directory, if it may create cgroups below it.
+    cgroup_parent =
+      with {:ok, mounts} <- File.read("/proc/self/mounts"),
+           [_, mount] <- Regex.run(~r/^\S+ (\S+) cgroup2 /m, mounts),
+           {:ok, cgroups} <- File.read("/proc/self/cgroup"),
+           [_, path] <- Regex.run(~r/^0::(\S+)$/m, cgroups),
+           dir = Path.join(mount, path),
+           {:ok, %File.Stat{access: :read_write}} <- File.stat(dir) do
+        dir
+      else
+        _ -> nil
+      end
+
+    @cgroup_parent cgroup_parent
+
+    if is_nil(cgroup_parent) do
+      @describetag skip: "needs a writable cgroup v2 hierarchy"
+    end
+
+    setup do
+      dir = Path.join(@cgroup_parent, "px_test_#{System.unique_integer([:positive])}")
+      File.mkdir!(dir)
+      on_exit(fn -> File.rmdir(dir) end)
+      %{cgroup: dir}
+    end
+
+    test "kills descendants that left the process group", %{cgroup: dir} do
+      script = "setsid sleep 10 & sh -c 'sleep 10 & sleep 10' & echo ready; wait"
+      p = Px.spawn!("sh", ["-c", script], cgroup: dir, pgid: 0, stdout: :pipe)
+      assert {:ok, "ready\n"} = Px.read_chunk(p)
+
+      pids = cgroup_pids(dir)
+      assert p.pid in pids
+      assert length(pids) >= 4
+
+      assert {:ok, p} = Px.kill_tree(p)
+      assert p.status == {:exited, 137}
+      assert Enum.all?(pids, &os_pid_gone?/1)
+      assert File.read!(Path.join(dir, "cgroup.freeze")) == "0\n"
+    end
+
+    test "outruns a child that keeps forking", %{cgroup: dir} do
+      script = "echo ready; while true; do sleep 10 & done"
+      p = Px.spawn!("sh", ["-c", script], cgroup: dir, stdout: :pipe)
+      assert {:ok, "ready\n"} = Px.read_chunk(p)
+      Process.sleep(50)
+
+      assert {:ok, %Px{status: {:exited, 137}}} = Px.kill_tree(p)
+      assert Enum.all?(cgroup_pids(dir), &os_pid_gone?/1)
+    end
+  end
+
+  describe "timeout" do
+    test "kills a child still running at the deadline" do
+      p = Px.spawn!("sleep", ["10"], timeout: 50)
+      assert Px.wait(p).status == {:exited, 137}
+      assert Px.termination_signal(p) == {:signaled, 9, true}
+      assert Px.cancel_timeout(p) == {:error, :no_timeout}
+    end
+
+    test "leaves a child that exits in time alone" do
+      p = Px.spawn!("true", [], timeout: 5_000)
+      assert Px.wait(p).status == {:exited, 0}
+      assert Px.cancel_timeout(p) == {:error, :no_timeout}
+    end
+
+    test "extends the deadline" do
+      p = Px.spawn!("sleep", ["10"], timeout: 50)
+      assert Px.extend_timeout(p, 300) == :ok
+      Process.sleep(150)
+      assert Px.alive?(p)
+
+      assert Px.wait(p).status == {:exited, 137}
+      assert Px.runtime(p) >= 300
+    end
+
+    test "brings the deadline forward" do
+      p = Px.spawn!("sleep", ["10"], timeout: 60_000)
+      assert Px.extend_timeout(p, 10) == :ok
+      assert Px.wait(p).status == {:exited, 137}
+    end
+
+    test "is not re-armed once cancelled" do
+      p = Px.spawn!("sleep", ["10"], timeout: 50)
+      assert Px.cancel_timeout(p) == :ok
+      assert Px.extend_timeout(p, 10) == {:error, :no_timeout}
+      Process.sleep(100)
+      assert Px.alive?(p)
+      Px.destroy(p)
+    end
+
+    test "requires a timeout at spawn" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.cancel_timeout(p) == {:error, :no_timeout}
+      assert Px.extend_timeout(p, 10) == {:error, :no_timeout}
+      Px.destroy(p)
+    end
+  end
+
+  describe "max lifetime" do
+    test "terminates a running child once its lifetime is up" do
+      p = Px.spawn!("sleep", ["10"])
+      Process.sleep(100)
+      assert Px.set_max_lifetime(p, 200) == :ok
+
+      p = Px.wait(p)
+      assert p.status == {:exited, 143}
+      assert Px.runtime(p) >= 200
+    end
+
+    test "counts from spawn" do
+      p = Px.spawn!("sleep", ["10"])
+      Process.sleep(100)
+      assert Px.set_max_lifetime(p, 50) == :ok
+      assert Px.wait(p).status == {:exited, 143}
+    end
+
+    test "kills a child ignoring SIGTERM after the grace period" do
+      p = Px.spawn!("sh", ["-c", "trap '' TERM; while :; do sleep 0.05; done"])
+      assert Px.set_max_lifetime(p, 50, grace: 100) == :ok
+
+      p = Px.wait(p)
+      assert p.status == {:exited, 137}
+      assert Px.runtime(p) >= 150
+    end
+
+    test "can be moved and removed" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.set_max_lifetime(p, 50) == :ok
+      assert Px.set_max_lifetime(p, 300) == :ok
+      Process.sleep(150)
+      assert Px.alive?(p)
+
+      assert Px.set_max_lifetime(p, :infinity) == :ok
+      Process.sleep(300)
+      assert Px.alive?(p)
+      Px.destroy(p)
+    end
+
+    test "reports already_exited after reaping" do
+      p = Px.wait(Px.spawn!("true", []))
+      assert Px.set_max_lifetime(p, 10) == {:error, :already_exited}
+    end
+  end
+
+  describe "tmpfile output" do
+    test "captures output to read back in ranges" do
+      p = Px.spawn!("sh", ["-c", "seq 100000; echo err >&2"], stdout: :tmpfile, stderr: :tmpfile)
+      p = Px.wait(p)
+      assert Px.stdio_modes(p) == %{stdin: :null, stdout: :tmpfile, stderr: :tmpfile}
+
+      expected = Enum.map_join(1..100_000, &"#{&1}\n")
+      assert read_output(p, :stdout) == expected
+      assert Px.read_output_file(p, :stdout, 4, 6) == {:ok, "\n3\n4\n5"}
+      assert Px.read_output_file(p, :stderr, 0, 100) == {:ok, "err\n"}
+    end
+
+    test "can be read while the child is still writing" do
+      script = "echo one; read _; echo two"
+      p = Px.spawn!("sh", ["-c", script], stdin: :pipe, stdout: :tmpfile)
+      Process.sleep(50)
+      assert Px.read_output_file(p, :stdout, 0, 100) == {:ok, "one\n"}
+      assert Px.read_output_file(p, :stdout, 4, 100) == :eof
+
+      :ok = Px.write(p, "\n")
+      Px.wait(p)
+      assert Px.read_output_file(p, :stdout, 4, 100) == {:ok, "two\n"}
+    end
+
+    test "leaves no file behind in the temp directory" do
+      p = Px.wait(Px.spawn!("echo", ["hi"], stdout: :tmpfile))
+      assert Px.read_output_file(p, :stdout, 0, 10) == {:ok, "hi\n"}
+
+      prefix = "px-#{System.pid()}-"
+      leftover = Enum.filter(File.ls!(System.tmp_dir!()), &String.starts_with?(&1, prefix))
+      assert leftover == []
+    end
+
+    test "is only supported for output streams spawned with it" do
+      p = Px.wait(Px.spawn!("true", [], stdout: :pipe))
+      assert Px.read_output_file(p, :stdout, 0, 10) == {:error, :not_supported}
+
+      assert Px.spawn("true", [], stdin: :tmpfile) ==
+               {:error, "tmpfile mode is not supported for stdin"}
+    end
+  end
+
+  describe "log output" do
+    @describetag :tmp_dir
+
+    test "appends output to a file the BEAM can rotate", %{tmp_dir: dir} do
+      path = Path.join(dir, "app.log")
+      script = "echo one; read _; echo two"
+      p = Px.spawn!("sh", ["-c", script], stdin: :pipe, stdout: {:log, path})
+      assert Px.stdio_modes(p).stdout == :log
+
+      wait_for_file(path, "one\n")
+      File.rename!(path, path <> ".1")
+      assert Px.reopen_output(p, :stdout, path) == :ok
+
+      :ok = Px.write(p, "go\n")
+      Px.wait(p)
+      wait_for_file(path, "two\n")
+      assert File.read!(path <> ".1") == "one\n"
+    end
+
+    test "keeps the old file when the new path cannot be opened", %{tmp_dir: dir} do
+      path = Path.join(dir, "app.log")
+      p = Px.spawn!("sh", ["-c", "read _; echo late >&2"], stdin: :pipe, stderr: {:log, path})
+      assert {:error, _} = Px.reopen_output(p, :stderr, Path.join(dir, "no/such/file"))
+
+      Px.close!(p, :stdin)
+      Px.wait(p)
+      wait_for_file(path, "late\n")
+      assert Px.reopen_output(p, :stdout, path) == {:error, :not_supported}
+    end
+
+    test "is synced to disk by flush", %{tmp_dir: dir} do
+      path = Path.join(dir, "app.log")
+      p = Px.spawn!("echo", ["hi"], stdout: {:log, path})
+
+      Px.wait(p)
+      wait_for_file(path, "hi\n")
+      assert Px.flush(p) == :ok
+    end
+
+    test "fails the spawn when the log cannot be opened", %{tmp_dir: dir} do
+      assert {:error, message} =
+               Px.spawn("true", [], stdout: {:log, Path.join(dir, "no/such/file")})
+
+      assert message =~ "Failed to open stdout log"
+    end
+  end
+
+  describe "synthetic stdin" do
+    test ":zero reads as endless zero bytes" do
+      p = Px.spawn!("head", ["-c", "5"], stdin: :zero, stdout: :pipe)
+      assert {:ok, _, <<0, 0, 0, 0, 0>>, ""} = Px.communicate(p)
+    end
+
+    test "repeat with a limit closes stdin after that many bytes" do
+      p = Px.spawn!("cat", [], stdin: {:repeat, "abc", 100_000}, stdout: :pipe)
+      assert {:ok, _, out, ""} = Px.communicate(p)
+      assert byte_size(out) == 100_000
+      assert out == binary_part(String.duplicate("abc", 33_334), 0, 100_000)
+    end
+
+    test "repeat without a limit runs until the child stops reading" do
+      p = Px.spawn!("head", ["-c", "200000"], stdin: {:repeat, "xy"}, stdout: :pipe)
+      assert {:ok, _, out, ""} = Px.communicate(p)
+      assert out == String.duplicate("xy", 100_000)
+    end
+
+    test "leaves nothing to write to" do
+      p = Px.spawn!("head", ["-c", "1"], stdin: {:repeat, "x"})
+      assert Px.write(p, "more") == {:error, :not_piped}
+      Px.wait(p)
+    end
+
+    test "rejects an empty pattern" do
+      assert_raise FunctionClauseError, fn -> Px.spawn("cat", [], stdin: {:repeat, ""}) end
+    end
+  end
+
+  describe "stdin_from_file" do
+    @describetag :tmp_dir
+
+    test "feeds the file after what was written and closes stdin", %{tmp_dir: dir} do
+      path = Path.join(dir, "input")
+      File.write!(path, :binary.copy("body\n", 100_000))
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+
+      assert Px.write(p, "head\n") == :ok
+      assert Px.stdin_from_file(p, path) == :ok
+      assert Px.write(p, "more") == {:error, :not_piped}
+
+      assert {:ok, p, out, ""} = Px.communicate(p)
+      assert p.status == {:exited, 0}
+      assert out == "head\n" <> :binary.copy("body\n", 100_000)
+    end
+
+    test "leaves stdin alone when the file cannot be opened", %{tmp_dir: dir} do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      assert {:error, _reason} = Px.stdin_from_file(p, Path.join(dir, "missing"))
+      assert {:ok, _p, "still here", ""} = Px.communicate(p, "still here")
+    end
+
+    test "requires piped stdin", %{tmp_dir: dir} do
+      path = Path.join(dir, "input")
+      File.write!(path, "x")
+      p = Px.spawn!("true", [])
+      assert Px.stdin_from_file(p, path) == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "stdio_modes" do
+    test "reports the configured modes" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :socket, stderr: :inherit)
+      assert Px.stdio_modes(p) == %{stdin: :pipe, stdout: :socket, stderr: :inherit}
+      Px.close!(p, :stdin)
+      assert Px.stdio_modes(p).stdin == :pipe
+      Px.wait(p)
+    end
+
+    @tag :tmp_dir
+    test "reports files, background readers and ptys", %{tmp_dir: dir} do
+      path = Path.join(dir, "out")
+      p = Px.spawn!("true", [], stdin: :zero, stdout: {:file, path}, stderr: {:tail, 64})
+      assert Px.stdio_modes(p) == %{stdin: :file, stdout: :file, stderr: :tail}
+      Px.wait(p)
+
+      p = Px.spawn!("true", [], stdin: {:repeat, "x"}, stdout: :fanout)
+      assert Px.stdio_modes(p) == %{stdin: :repeat, stdout: :fanout, stderr: :null}
+      Px.wait(p)
+
+      p = Px.spawn!("true", [], pty: true)
+      assert Px.stdio_modes(p) == %{stdin: :pty, stdout: :pty, stderr: :pty}
+      Px.wait(p)
+    end
+
+    test "the spawn result fills in the stream fields" do
+      p = Px.spawn!("true", [], stdin: :socket, stdout: {:tail, 64})
+      assert {p.stdin, p.stdout, p.stderr} == {:socket, {:tail, 64}, nil}
+      Px.wait(p)
+
+      p = Px.spawn!("true", [], pty: true)
+      assert {p.stdin, p.stdout, p.stderr} == {:pty, :pty, :pty}
+      Px.wait(p)
+
+      {:ok, template} = Px.template("cat", stdin: :pipe, stdout: :pipe)
+      {:ok, p} = Px.spawn_from(template, [])
+      assert {p.stdin, p.stdout} == {:pipe, :pipe}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+
+      {:ok, p} = Px.shell("true", stdout: :pipe)
+      assert p.stdout == :pipe
+      Px.wait(p)
+    end
+  end
+
+  describe "io_counters" do
+    test "totals bytes written and read per stream" do
+      p =
+        Px.spawn!("sh", ["-c", "cat; echo oops >&2"], stdin: :pipe, stdout: :pipe, stderr: :pipe)
+
+      assert Px.io_counters(p) == %{stdin_written: 0, stdout_read: 0, stderr_read: 0}
+
+      assert Px.write(p, "hello") == :ok
+      Px.close!(p, :stdin)
+      assert {:ok, _, "hello", "oops\n"} = Px.communicate(p, "")
+
+      assert Px.io_counters(p) == %{stdin_written: 5, stdout_read: 5, stderr_read: 5}
+    end
+
+    test "survive the reap" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe)
+      assert {:ok, "hi\n"} = Px.read_chunk(p)
+      Px.wait(p)
+      assert Px.io_counters(p).stdout_read == 3
+    end
+  end
+
+  describe "file options" do
+    @describetag :tmp_dir
+
+    test "append keeps existing contents", %{tmp_dir: dir} do
+      path = Path.join(dir, "out.log")
+      File.write!(path, "first\n")
+
+      p = Px.spawn!("echo", ["second"], stdout: {:file, path, flags: [:append]})
+      Px.wait(p)
+
+      assert File.read!(path) == "first\nsecond\n"
+    end
+
+    test "mode sets the permissions of a new file", %{tmp_dir: dir} do
+      path = Path.join(dir, "secret.log")
+
+      p = Px.spawn!("echo", ["s3cret"], stdout: {:file, path, mode: 0o600})
+      Px.wait(p)
+
+      assert Bitwise.band(File.stat!(path).mode, 0o777) == 0o600
+      assert File.read!(path) == "s3cret\n"
+    end
+
+    test "exclusive refuses an existing file", %{tmp_dir: dir} do
+      path = Path.join(dir, "taken.log")
+      File.write!(path, "keep")
+
+      assert Px.spawn("echo", ["x"], stderr: {:file, path, flags: [:exclusive]}) ==
+               {:error, {:file_exists, path}}
+
+      assert File.read!(path) == "keep"
+    end
+
+    test "rejects unknown flags", %{tmp_dir: dir} do
+      path = Path.join(dir, "out.log")
+      assert {:error, message} = Px.spawn("echo", [], stdout: {:file, path, flags: [:bogus]})
+      assert message =~ "invalid file flag"
+    end
+  end
+
+  describe "wait_stdout_eof" do
+    test "returns once stdout closes, before the child exits" do
+      p = Px.spawn!("sh", ["-c", "echo one; echo two; exec >&-; sleep 10"], stdout: :pipe)
+
+      assert Px.wait_stdout_eof(p, 5_000) == {:eof, "one\ntwo\n"}
+      assert Px.alive?(p)
+
+      Px.destroy(p)
+    end
+
+    test "times out with what was read so far" do
+      p = Px.spawn!("sh", ["-c", "echo partial; sleep 10"], stdout: :pipe)
+
+      assert Px.wait_stdout_eof(p, 200) == {:timeout, "partial\n"}
+
+      Px.destroy(p)
+    end
+
+    test "requires piped stdout" do
+      p = Px.spawn!("true", [])
+      assert Px.wait_stdout_eof(p) == {:error, :not_piped}
+    end
+  end
+
+  describe "absolute deadlines" do
+    test "a sequence of requests shares one deadline" do
+      {:ok, p} = Px.coprocess("sh", ["-c", "read a; echo $a; sleep 10"])
+      deadline = {:deadline, Px.monotonic_ns() + 300_000_000}
+
+      assert Px.request(p, "one\n", timeout: deadline) == {:ok, "one\n"}
+
+      started = System.monotonic_time(:millisecond)
+      assert Px.request(p, "two\n", timeout: deadline) == :timeout
+      assert System.monotonic_time(:millisecond) - started < 300
+
+      Px.destroy(p)
+    end
+
+    test "a deadline in the past times out right away" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      past = {:deadline, Px.monotonic_ns() - 1}
+      assert Px.wait_stdout_eof(p, past) == {:timeout, ""}
+      Px.destroy(p)
+    end
+  end
+
+  describe "splice" do
+    test "connects one child's stdout to another's stdin" do
+      src = Px.spawn!("printf", ["one\ntwo\n"], stdout: :pipe)
+      dst = Px.spawn!("tr", ["a-z", "A-Z"], stdin: :pipe, stdout: :pipe)
+      Px.wait(src)
+
+      assert Px.splice(src, dst) == {:ok, 8}
+      assert Px.splice(src, dst) == :eof
+      Px.close(dst, :stdin)
+
+      dst = Px.wait(dst)
+      assert collect_stdout(dst) == "ONE\nTWO\n"
+    end
+
+    test "respects max_bytes" do
+      src = Px.spawn!("printf", ["abcdef"], stdout: :pipe)
+      dst = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      Px.wait(src)
+
+      assert Px.splice(src, dst, 4) == {:ok, 4}
+      assert Px.splice(src, dst, 4) == {:ok, 2}
+      Px.close(dst, :stdin)
+
+      assert collect_stdout(Px.wait(dst)) == "abcdef"
+    end
+
+    test "copies between socket streams" do
+      src = Px.spawn!("printf", ["sock"], stdout: :socket)
+      dst = Px.spawn!("cat", [], stdin: :socket, stdout: :pipe)
+      Px.wait(src)
+
+      assert Px.splice(src, dst) == {:ok, 4}
+      assert Px.splice(src, dst) == :eof
+      Px.close(dst, :stdin)
+
+      assert collect_stdout(Px.wait(dst)) == "sock"
+    end
+
+    test "reports would_block when the source has nothing ready" do
+      src = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      dst = Px.spawn!("cat", [], stdin: :pipe)
+
+      assert Px.splice(src, dst) == :would_block
+
+      Px.destroy(src)
+      Px.destroy(dst)
+    end
+
+    test "requires piped ends" do
+      src = Px.spawn!("true", [])
+      dst = Px.spawn!("cat", [], stdin: :pipe)
+      assert Px.splice(src, dst) == {:error, :not_piped}
+      Px.destroy(dst)
+    end
+  end
+
+  describe "controlling_tty" do
+    test "is the pty for a pty child" do
+      p = Px.spawn!("sh", ["-c", "tty; read x"], pty: true)
+      assert {:ok, line} = Px.read_chunk(p)
+      assert Px.controlling_tty(p) == {:ok, String.trim(line)}
+      Px.destroy(p)
+    end
+
+    test "is none for a child in a session of its own" do
+      p = Px.spawn!("setsid", ["sleep", "10"])
+      Process.sleep(50)
+      assert Px.controlling_tty(p) == {:ok, :none}
+      Px.destroy(p)
+    end
+
+    test "reports already_exited after reaping" do
+      p = Px.wait(Px.spawn!("true", []))
+      assert Px.controlling_tty(p) == {:error, :already_exited}
+    end
+  end
+
+  describe "sched_info" do
+    test "reads back the nice value and policy" do
+      p = Px.spawn!("nice", ["-n", "7", "sleep", "10"])
+      q = Px.spawn!("sleep", ["10"])
+      Process.sleep(50)
+
+      {:ok, %{nice: base}} = Px.sched_info(q)
+      assert {:ok, %{nice: nice, policy: :other, cpus: [_ | _]}} = Px.sched_info(p)
+      assert nice == min(base + 7, 19)
+
+      Px.destroy(p)
+      Px.destroy(q)
+    end
+
+    test "reads back the CPU affinity" do
+      p = Px.spawn!("taskset", ["-c", "0", "sleep", "10"])
+      Process.sleep(50)
+      assert {:ok, %{cpus: [0]}} = Px.sched_info(p)
+      Px.destroy(p)
+    end
+
+    test "reports already_exited after reaping" do
+      p = Px.wait(Px.spawn!("true", []))
+      assert Px.sched_info(p) == {:error, :already_exited}
+    end
+  end
+
+  describe "fd_count" do
+    test "counts descriptors the child opens" do
+      p = Px.spawn!("sh", ["-c", "exec 5</dev/null 6</dev/null; read x"], stdin: :pipe)
+      Process.sleep(50)
+      {:ok, with_extra} = Px.fd_count(p)
+
+      q = Px.spawn!("sh", ["-c", "read x"], stdin: :pipe)
+      Process.sleep(50)
+      {:ok, baseline} = Px.fd_count(q)
+
+      assert with_extra == baseline + 2
+
+      Px.destroy(p)
+      Px.destroy(q)
+    end
+
+    test "reports already_exited after reaping" do
+      p = Px.spawn!("true", [])
+      p = Px.wait(p)
+      assert Px.fd_count(p) == {:error, :already_exited}
+    end
+  end
+
+  describe "read_frame" do
+    test "reassembles frames split across writes" do
+      script = "printf '\\000\\000'; sleep 0.1; printf '\\000\\005hel'; sleep 0.1; printf 'lo'"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe)
+
+      assert Px.read_frame(p, timeout: 2000) == {:ok, "hello"}
+      assert Px.read_frame(p, timeout: 2000) == :eof
+    end
+
+    test "returns :would_block for an incomplete frame without a timeout" do
+      p = Px.spawn!("sh", ["-c", "printf '\\003ab'; sleep 10"], stdout: :pipe)
+      Process.sleep(50)
+
+      assert Px.read_frame(p, length_bytes: 1) == :would_block
+      assert Px.read_frame(p, length_bytes: 1, timeout: 50) == {:timeout, 3}
+      Px.destroy(p)
+    end
+
+    test "resumes a frame split across timed-out reads" do
+      script = "printf '\\000\\006ab'; read x; printf 'cd'; read x; printf 'ef'; sleep 10"
+      p = Px.spawn!("sh", ["-c", script], stdin: :pipe, stdout: :pipe)
+
+      assert Px.read_frame(p, length_bytes: 2, timeout: 100) == {:timeout, 4}
+      Px.write(p, "\n")
+      assert Px.read_frame(p, length_bytes: 2, timeout: 100) == {:timeout, 6}
+      assert Px.read_frame(p, length_bytes: 2, timeout: 50) == {:timeout, 6}
+      Px.write(p, "\n")
+      assert Px.read_frame(p, length_bytes: 2, timeout: 1000) == {:ok, "abcdef"}
+      assert Px.read_frame(p, length_bytes: 2, timeout: 50) == {:timeout, 0}
+      Px.destroy(p)
+    end
+
+    test "supports little-endian prefixes" do
+      p = Px.spawn!("printf", ["\\002\\000\\000\\000ok"], stdout: :pipe)
+      assert Px.read_frame(p, endianness: :little, timeout: 1000) == {:ok, "ok"}
+    end
+
+    test "reports a frame cut off by EOF" do
+      p = Px.spawn!("printf", ["\\000\\000\\000\\011short"], stdout: :pipe)
+      Px.wait(p)
+      assert Px.read_frame(p, timeout: 1000) == {:error, :truncated_frame}
+    end
+  end
+
+  describe "eof reason" do
+    test "reports a stream closed by a running child" do
+      p = Px.spawn!("sh", ["-c", "exec 1>&-; sleep 10"], stdout: :pipe)
+      Process.sleep(50)
+
+      assert Px.read(p, :stdout, eof_reason: true) == {:eof, :closed}
+      assert Px.read(p, :stdout) == :eof
+      Px.destroy(p)
+    end
+
+    test "reports an exited child without reaping it" do
+      p = Px.spawn!("printf", ["hi"], stdout: :pipe)
+      Process.sleep(50)
+
+      assert Px.read(p, :stdout, eof_reason: true) == {:ok, "hi"}
+      assert Px.read(p, :stdout, eof_reason: true) == {:eof, :exited}
+      assert Px.try_wait_usage(p) |> elem(1) |> is_map()
+    end
+  end
+
+  describe "read_chunks" do
+    test "returns separate chunks until the pipe is empty" do
+      p = Px.spawn!("sh", ["-c", "echo a; sleep 10"], stdout: :pipe)
+      Process.sleep(50)
+
+      assert Px.read_chunks(p) == {["a\n"], :would_block}
+      Px.destroy(p)
+    end
+
+    test "stops at the limits with :more" do
+      p = Px.spawn!("head", ["-c", "20000", "/dev/zero"], stdout: :pipe) |> Px.wait()
+
+      assert {[chunk], :more} = Px.read_chunks(p, max_chunks: 1)
+      assert byte_size(chunk) > 0
+      assert {chunks, :more} = Px.read_chunks(p, max_bytes: 100)
+      assert IO.iodata_length(chunks) == 100
+      assert {_chunks, :eof} = Px.read_chunks(p, max_chunks: 100)
+    end
+
+    test "a zero byte limit returns no chunks even with data buffered" do
+      p = Px.wait(Px.spawn!("printf", ["a\\nb"], stdout: :pipe, lines: true))
+      assert Px.read(p, :stdout) == {:ok, "a\n"}
+
+      assert Px.read_chunks(p, max_bytes: 0) == {[], :more}
+      assert Px.read_chunks(p) == {["b"], :eof}
+    end
+
+    test "requires a piped stdout" do
+      p = Px.spawn!("true", [])
+      assert Px.read_chunks(p) == {:error, :not_piped}
+    end
+  end
+
+  describe "reading after reap" do
+    test "output written before exit stays readable after wait" do
+      # Stays under the 64 KiB pipe capacity so the child can exit unread.
+      p = Px.spawn!("head", ["-c", "61440", "/dev/zero"], stdout: :pipe) |> Px.wait()
+      assert p.status == {:exited, 0}
+
+      assert byte_size(collect_until_eof(p)) == 61_440
+      assert Px.read(p, :stdout) == :eof
+    end
+
+    test "reaping does not cut off output still being written" do
+      script = "head -c 102400 /dev/zero &"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe) |> Px.wait()
+      assert p.status == {:exited, 0}
+
+      assert byte_size(collect_until_eof(p)) == 102_400
+    end
+  end
+
+  describe "read_chunk" do
+    test "waits for the first byte, then takes everything ready" do
+      script = "sleep 0.1; printf 'a'; printf 'b'; sleep 0.3; printf 'c'"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe)
+
+      assert Px.read_chunk(p, timeout: 2000) == {:ok, "ab"}
+      assert Px.read_chunk(p, timeout: 2000) == {:ok, "c"}
+      assert Px.read_chunk(p, timeout: 2000) == :eof
+    end
+
+    test "returns :timeout when nothing arrives" do
+      p = Px.spawn!("sleep", ["10"], stdout: :pipe)
+      assert Px.read_chunk(p, timeout: 50) == :timeout
+      Px.destroy(p)
+    end
+
+    test "stops at max_bytes" do
+      p = Px.spawn!("printf", ["abcdef"], stdout: :pipe)
+      Px.wait(p)
+
+      assert Px.read_chunk(p, max_bytes: 4) == {:ok, "abcd"}
+      assert Px.read_chunk(p, max_bytes: 4) == {:ok, "ef"}
+    end
+  end
+
+  describe "active output" do
+    test "streams stdout and stderr, then the exit" do
+      cmd = "seq 100000; echo err >&2; exit 3"
+      p = Px.spawn!("sh", ["-c", cmd], stdout: :active, stderr: :active)
+      resource = p.resource
+
+      expected = Enum.map_join(1..100_000, &"#{&1}\n")
+
+      assert collect_active(resource) == {expected, "err\n"}
+      assert_receive {:px_exit, ^resource, {:exited, 3}}, 2000
+      refute_received {:px_output, ^resource, _, _}
+
+      assert Px.stdio_modes(p) == %{stdin: :null, stdout: :active, stderr: :active}
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+      assert Px.io_counters(p).stdout_read == byte_size(expected)
+    end
+
+    test "sends to the owner and leaves other streams alone" do
+      parent = self()
+
+      owner =
+        spawn_link(fn ->
+          receive do
+            resource -> send(parent, {:collected, collect_active(resource)})
+          end
+        end)
+
+      opts = [stdout: :active, stderr: :pipe, owner: owner]
+      p = Px.spawn!("sh", ["-c", "echo out; echo err >&2"], opts)
+      send(owner, p.resource)
+
+      assert_receive {:collected, {"out\n", ""}}, 2000
+      assert %Px{status: {:exited, 0}} = Px.wait(p)
+      assert Px.read(p, :stderr) == {:ok, "err\n"}
+      refute_received {:px_output, _, _, _}
+    end
+
+    test "reports the same exit as wait" do
+      p = Px.spawn!("sh", ["-c", "read x; echo $x"], stdin: :pipe, stdout: :active)
+      resource = p.resource
+      Px.write(p, "hi\n")
+
+      assert %Px{status: {:exited, 0}} = Px.wait(p)
+      assert_receive {:px_output, ^resource, :stdout, "hi\n"}, 1000
+      assert_receive {:px_exit, ^resource, {:exited, 0}}, 1000
+    end
+
+    test "honours drain_buf_bytes" do
+      opts = [stdout: :active, drain_buf_bytes: 8]
+      p = Px.spawn!("sh", ["-c", "seq 1000"], opts)
+      resource = p.resource
+      assert_receive {:px_exit, ^resource, {:exited, 0}}, 2000
+
+      {:messages, messages} = Process.info(self(), :messages)
+      chunks = for {:px_output, ^resource, :stdout, data} <- messages, do: data
+      assert Enum.all?(chunks, &(byte_size(&1) <= 8))
+      assert IO.iodata_to_binary(chunks) == Enum.map_join(1..1000, &"#{&1}\n")
+    end
+  end
+
+  describe "readiness" do
+    @describetag :tmp_dir
+
+    test "reports a pattern on stdout, leaving the output readable" do
+      script = "echo starting; sleep 0.1; echo 'Listening on 4000'; sleep 0.1; echo more"
+      ready = {:output, :stdout, "Listening on"}
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, ready: ready)
+      resource = p.resource
+
+      assert_receive {:px_ready, ^resource}, 2000
+      assert collect_stdout(p) == "starting\nListening on 4000\n"
+
+      p = Px.wait(p)
+      assert collect_until_eof(p) == "more\n"
+      refute_received {:px_ready_timeout, ^resource}
+    end
+
+    test "finds a pattern split across writes" do
+      script = "printf Listen; sleep 0.1; printf 'ing\\n' >&2; printf 'ing\\n'"
+      ready = {:output, :stdout, "Listening"}
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe, stderr: :pipe, ready: ready)
+      resource = p.resource
+
+      assert_receive {:px_ready, ^resource}, 2000
+      Px.wait(p)
+    end
+
+    test "gives up after ready_timeout" do
+      opts = [stdout: :pipe, ready: {:output, :stdout, "never"}, ready_timeout: 100]
+      p = Px.spawn!("sh", ["-c", "echo hi; sleep 10"], opts)
+      resource = p.resource
+
+      assert_receive {:px_ready_timeout, ^resource}, 2000
+      assert collect_stdout(p) == "hi\n"
+      {:ok, p} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "gives up when the child exits first" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe, ready: {:output, :stdout, "never"})
+      resource = p.resource
+
+      assert_receive {:px_ready_timeout, ^resource}, 2000
+      refute_received {:px_ready, ^resource}
+      assert collect_until_eof(p) == "hi\n"
+    end
+
+    test "waits for a Unix socket to accept connections", %{tmp_dir: dir} do
+      path = Path.join(dir, "ready.sock")
+      p = Px.spawn!("sleep", ["10"], ready: {:unix, path}, ready_timeout: 2000)
+      resource = p.resource
+
+      Process.sleep(100)
+      refute_received {:px_ready, ^resource}
+      {:ok, socket} = :gen_tcp.listen(0, ifaddr: {:local, path})
+
+      assert_receive {:px_ready, ^resource}, 2000
+      :gen_tcp.close(socket)
+      {:ok, p} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "waits for a TCP port to accept connections" do
+      {:ok, socket} = :gen_tcp.listen(0, ip: {127, 0, 0, 1})
+      {:ok, port} = :inet.port(socket)
+      p = Px.spawn!("sleep", ["10"], ready: {:tcp, "127.0.0.1", port})
+      resource = p.resource
+
+      assert_receive {:px_ready, ^resource}, 2000
+      :gen_tcp.close(socket)
+      {:ok, p} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "an output probe requires a piped stream" do
+      assert {:error, message} = Px.spawn("true", [], ready: {:output, :stdout, "x"})
+      assert message =~ "requires its stream to be :pipe"
+    end
+  end
+
+  describe "fan-out" do
+    test "every subscriber receives all of stdout" do
+      p = Px.spawn!("sh", ["-c", "read x; seq 1000"], stdin: :pipe, stdout: :fanout)
+      parent = self()
+
+      spawn_link(fn ->
+        Px.subscribe(p, window: 1_000)
+        send(parent, :subscribed)
+        send(parent, {:other, collect_fanout(p.pid, <<>>)})
+      end)
+
+      assert_receive :subscribed
+      assert Px.subscribe(p, window: 1_000) == :ok
+      assert Px.subscribe(p) == {:error, :already_subscribed}
+      Px.write(p, "go\n")
+
+      expected = Enum.map_join(1..1000, &"#{&1}\n")
+      assert collect_fanout(p.pid, <<>>) == expected
+      assert_receive {:other, ^expected}, 2000
+      Px.wait(p)
+    end
+
+    test "a subscriber that does not ack holds back the output" do
+      p = Px.spawn!("sh", ["-c", "seq 100000; echo done >&2"], stdout: :fanout, stderr: :pipe)
+      :ok = Px.subscribe(p, window: 1)
+      os_pid = p.pid
+
+      assert_receive {:px_stdout, ^os_pid, _chunk}, 1000
+      refute_receive {:px_stdout, ^os_pid, _chunk}, 100
+      assert Px.alive?(p)
+
+      assert Px.unsubscribe(p) == :ok
+      assert Px.ack(p) == {:error, :not_subscribed}
+      Px.destroy(p)
+    end
+
+    test "dead subscribers are removed" do
+      p = Px.spawn!("sh", ["-c", "read x; echo hi"], stdin: :pipe, stdout: :fanout)
+      dead = spawn(fn -> :ok end)
+      ref = Process.monitor(dead)
+      assert_receive {:DOWN, ^ref, _, _, _}
+
+      :ok = Px.subscribe(p, pid: spawn(fn -> Process.sleep(10) end), window: 0)
+      :ok = Px.subscribe(p, pid: dead)
+      :ok = Px.subscribe(p)
+      Process.sleep(50)
+      Px.write(p, "go\n")
+
+      assert collect_fanout(p.pid, <<>>) == "hi\n"
+      Px.wait(p)
+    end
+
+    test "read and non-fan-out streams are rejected" do
+      p = Px.spawn!("echo", ["hi"], stdout: :fanout)
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+      q = Px.spawn!("echo", ["hi"], stdout: :pipe)
+      assert Px.subscribe(q) == {:error, :not_fanout}
+      Px.wait(p)
+      Px.wait(q)
+    end
+  end
+
+  describe "drain_buf_bytes" do
+    test "bounds the chunks fan-out subscribers receive" do
+      cmd = "read x; seq 10000"
+      opts = [stdin: :pipe, stdout: :fanout, drain_buf_bytes: 16]
+      p = Px.spawn!("sh", ["-c", cmd], opts)
+      :ok = Px.subscribe(p, window: 1_000_000)
+      Px.write(p, "go\n")
+
+      chunks = collect_fanout_chunks(p, [])
+      assert Enum.all?(chunks, &(byte_size(&1) <= 16))
+      assert IO.iodata_to_binary(chunks) == Enum.map_join(1..10_000, &"#{&1}\n")
+      Px.wait(p)
+    end
+
+    test "feeds tails and communicate" do
+      p = Px.spawn!("sh", ["-c", "seq 100000"], stdout: {:tail, 13}, drain_buf_bytes: 1)
+      assert %Px{status: {:exited, 0}} = Px.wait(p, 5000)
+      Process.sleep(50)
+      assert Px.tail_output(p, :stdout) == {:ok, "99999\n100000\n"}
+
+      p = Px.spawn!("sh", ["-c", "seq 100000"], stdout: :pipe, drain_buf_bytes: 1_048_576)
+      assert {:ok, _, output, ""} = Px.communicate(p)
+      assert output == Enum.map_join(1..100_000, &"#{&1}\n")
+    end
+
+    test "must be a positive integer" do
+      assert_raise FunctionClauseError, fn -> Px.spawn("true", [], drain_buf_bytes: 0) end
+    end
+  end
+
+  describe "tail output" do
+    test "keeps only the last bytes of stdout" do
+      p = Px.spawn!("sh", ["-c", "seq 100000"], stdout: {:tail, 13})
+      Px.wait(p)
+      Process.sleep(50)
+
+      assert Px.tail_output(p, :stdout) == {:ok, "99999\n100000\n"}
+    end
+
+    test "drains output larger than the pipe buffer without blocking the child" do
+      p = Px.spawn!("sh", ["-c", "head -c 1000000 /dev/zero >&2"], stderr: {:tail, 4})
+
+      assert %Px{status: {:exited, 0}} = Px.wait(p, 5000)
+      Process.sleep(50)
+      assert Px.tail_output(p, :stderr) == {:ok, <<0, 0, 0, 0>>}
+    end
+
+    test "tailed streams cannot be read and other streams are not tailed" do
+      p = Px.spawn!("echo", ["hi"], stdout: {:tail, 16}, stderr: :pipe)
+      Px.wait(p)
+
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+      assert Px.tail_output(p, :stderr) == {:error, :not_tailed}
+    end
+
+    test "interleaves both streams in the order they were read" do
+      script = "echo 1; sleep 0.05; echo 2 >&2; sleep 0.05; echo 3; sleep 0.05; echo 4 >&2"
+
+      p =
+        Px.spawn!("sh", ["-c", script],
+          stdout: {:tail, 64},
+          stderr: {:tail, 64},
+          interleaved_log: 1024
+        )
+
+      Px.wait(p)
+      Process.sleep(50)
+
+      assert {:ok, chunks} = Px.interleaved_log(p)
+      assert Enum.map(chunks, fn {stream, _, data} -> {stream, data} end) ==
+               [stdout: "1\n", stderr: "2\n", stdout: "3\n", stderr: "4\n"]
+
+      timestamps = Enum.map(chunks, fn {_, ns, _} -> ns end)
+      assert timestamps == Enum.sort(timestamps)
+      assert List.last(timestamps) <= Px.monotonic_ns()
+      assert Px.tail_output(p, :stdout) == {:ok, "1\n3\n"}
+    end
+
+    test "the interleaved log drops its oldest chunks past its size" do
+      script = "for i in 1 2 3 4 5; do echo $i$i$i; sleep 0.02; done"
+      p = Px.spawn!("sh", ["-c", script], stdout: {:tail, 64}, interleaved_log: 8)
+      Px.wait(p)
+      Process.sleep(50)
+
+      assert {:ok, chunks} = Px.interleaved_log(p)
+      assert Enum.map(chunks, fn {:stdout, _, data} -> data end) == ["444\n", "555\n"]
+    end
+
+    test "the interleaved log needs a tailed stream" do
+      assert Px.spawn("true", [], stdout: :pipe, interleaved_log: 64) ==
+               {:error, "interleaved_log requires a {:tail, bytes} stream"}
+
+      p = Px.spawn!("true", [], stdout: {:tail, 8})
+      assert Px.interleaved_log(p) == {:error, :not_interleaved}
+    end
+  end
+
+  describe "pipe_stats" do
+    test "reports unread output" do
+      p = Px.spawn!("printf", ["abc"], stdout: :pipe)
+      Px.wait(p)
+
+      assert {:ok, %{capacity: capacity, buffered: 3}} = Px.pipe_stats(p, :stdout)
+      assert capacity >= 4096
+
+      {:ok, "abc"} = Px.read(p, :stdout)
+      assert {:ok, %{buffered: 0}} = Px.pipe_stats(p, :stdout)
+    end
+
+    test "reports bytes waiting in stdin" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      :ok = Px.write(p, "hello")
+      assert {:ok, %{buffered: 5}} = Px.pipe_stats(p, :stdin)
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "is not available for streams that are not pipes" do
+      p = Px.spawn!("cat", [], stdin: :socket, stdout: :pipe)
+      assert Px.pipe_stats(p, :stdin) == {:error, :not_piped}
+      assert Px.pipe_stats(p, :stderr) == {:error, :not_piped}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+  end
+
+  describe "reassert_nonblocking" do
+    test "is a no-op when streams are already non-blocking" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe, stderr: :pipe)
+      assert Px.reassert_nonblocking(p) == {:ok, []}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "is safe when nothing is piped" do
+      p = Px.spawn!("true", [])
+      assert Px.reassert_nonblocking(p) == {:ok, []}
+      Px.wait(p)
+    end
+  end
+
+  describe "nonblocking: false" do
+    test "leaves piped streams blocking" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe, nonblocking: false)
+      assert Px.reassert_nonblocking(p) == {:ok, [:stdin, :stdout]}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "read waits for output instead of returning :would_block" do
+      p = Px.spawn!("sh", ["-c", "sleep 0.1; echo hi"], stdout: :pipe, nonblocking: false)
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+      Px.wait(p)
+    end
+
+    test "nonblocking_streams follows reassert_nonblocking" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe, nonblocking: false)
+      assert Px.nonblocking_streams(p) == []
+      assert Px.reassert_nonblocking(p) == {:ok, [:stdin, :stdout]}
+      assert Px.nonblocking_streams(p) == [:stdin, :stdout]
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+  end
+
+  describe "tolerate_nonblock_errors" do
+    test "spawns as usual when every stream can be made non-blocking" do
+      p =
+        Px.spawn!("cat", [],
+          stdin: :pipe,
+          stdout: :pipe,
+          stderr: :pipe,
+          tolerate_nonblock_errors: true
+        )
+
+      assert Px.nonblocking_streams(p) == [:stdin, :stdout, :stderr]
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "only piped streams are listed" do
+      p = Px.spawn!("true", [], stdout: {:tail, 64}, tolerate_nonblock_errors: true)
+      assert Px.nonblocking_streams(p) == []
+      Px.wait(p)
+    end
+  end
+
+  describe "pty" do
+    test "the child sees a terminal on all stdio streams" do
+      p = Px.spawn!("sh", ["-c", "test -t 0 && test -t 1 && test -t 2"], pty: true)
+      assert {p.stdin, p.stdout, p.stderr} == {:pty, :pty, :pty}
+      assert Px.wait(p).status == {:exited, 0}
+    end
+
+    test "reads output, including stderr, until the child exits" do
+      p = Px.spawn!("sh", ["-c", "echo out; echo err >&2"], pty: true)
+      p = Px.wait(p)
+      output = collect_until_eof(p)
+
+      assert output =~ "out\r\n"
+      assert output =~ "err\r\n"
+      assert Px.read(p, :stderr) == {:error, :not_piped}
+    end
+
+    test "runs the child in the terminal's foreground process group" do
+      p = Px.spawn!("sh", ["-c", "ps -o pgid= -o tpgid= -p $$"], pty: true)
+      p = Px.wait(p)
+      [pgid, tpgid] = collect_until_eof(p) |> String.split()
+
+      assert pgid == tpgid
+      assert String.to_integer(pgid) == p.pid
+    end
+
+    test "inherit_or_pty uses a pty unless the BEAM's stdio are terminals" do
+      on_terminal =
+        Enum.all?(0..2, fn fd ->
+          case File.read_link("/proc/self/fd/#{fd}") do
+            {:ok, path} -> String.starts_with?(path, ["/dev/pts/", "/dev/tty"])
+            {:error, _} -> false
+          end
+        end)
+
+      script = "test -t 0 && test -t 1 && test -t 2 && echo tty"
+      p = Px.spawn!("sh", ["-c", script], inherit_or_pty: true, stdout: :pipe)
+
+      if on_terminal do
+        assert {p.stdin, p.stdout, p.stderr} == {:inherit, :inherit, :inherit}
+        assert Px.wait(p).status == {:exited, 0}
+      else
+        assert {p.stdin, p.stdout, p.stderr} == {:pty, :pty, :pty}
+        assert Px.stdio_modes(p) == %{stdin: :pty, stdout: :pty, stderr: :pty}
+        p = Px.wait(p)
+        assert p.status == {:exited, 0}
+        assert collect_until_eof(p) == "tty\r\n"
+      end
+    end
+
+    test ":intr interrupts the foreground process group" do
+      p = Px.spawn!("sleep", ["10"], pty: true)
+      Process.sleep(100)
+      assert Px.send_control(p, :intr) == :ok
+      assert Px.wait(p).status == {:exited, 130}
+    end
+
+    test ":eof ends input" do
+      p = Px.spawn!("cat", [], pty: true)
+      :ok = Px.write(p, "hi\n")
+      assert Px.send_control(p, :eof) == :ok
+      assert Px.wait(p).status == {:exited, 0}
+    end
+
+    test ":no_echo stops input from being echoed" do
+      p = Px.spawn!("sh", ["-c", "read line; echo \"got $line\""], pty: true)
+      assert Px.set_term_mode(p, :no_echo) == :ok
+      :ok = Px.write(p, "secret\n")
+      p = Px.wait(p)
+
+      output = collect_until_eof(p)
+      assert output =~ "got secret"
+      refute output =~ "secret\r\n"
+    end
+
+    test ":raw passes bytes through without line buffering or translation" do
+      p = Px.spawn!("head", ["-c", "3"], pty: true)
+      assert Px.set_term_mode(p, :raw) == :ok
+      :ok = Px.write(p, "a\x03b")
+      p = Px.wait(p)
+
+      assert p.status == {:exited, 0}
+      assert collect_until_eof(p) == "a\x03b"
+    end
+
+    test "writes escape sequences through verbatim" do
+      keys = "\e[A\e[B\eOP\e[15~"
+      p = Px.spawn!("head", ["-c", "#{byte_size(keys)}"], pty: true)
+      assert Px.set_term_mode(p, :raw) == :ok
+      :ok = Px.write(p, keys)
+      p = Px.wait(p)
+
+      assert p.status == {:exited, 0}
+      assert collect_until_eof(p) == keys
+    end
+
+    test ":cooked restores echo after :raw" do
+      p = Px.spawn!("cat", [], pty: true)
+      :ok = Px.set_term_mode(p, :raw)
+      :ok = Px.set_term_mode(p, :cooked)
+      :ok = Px.write(p, "hi\n")
+      :ok = Px.send_control(p, :eof)
+      p = Px.wait(p)
+
+      assert collect_until_eof(p) == "hi\r\nhi\r\n"
+    end
+
+    test "resize sets the size before signaling SIGWINCH" do
+      script = "trap 'stty size; exit 0' WINCH; while :; do sleep 0.01; done"
+      p = Px.spawn!("sh", ["-c", script], pty: true)
+      Process.sleep(100)
+
+      assert Px.resize(p, 40, 100) == :ok
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      assert collect_until_eof(p) =~ "40 100"
+    end
+
+    test "resize signals even when the size is unchanged" do
+      script = "sleep 0.3; trap 'exit 7' WINCH; while :; do sleep 0.01; done"
+      p = Px.spawn!("sh", ["-c", script], pty: true)
+      Process.sleep(100)
+      # Ignored: the trap is not installed yet.
+      :ok = Px.resize(p, 24, 80)
+      Process.sleep(400)
+
+      :ok = Px.resize(p, 24, 80)
+      assert Px.wait(p).status == {:exited, 7}
+    end
+
+    test "resize requires a pty" do
+      p = Px.spawn!("true", [])
+      assert Px.resize(p, 24, 80) == {:error, :not_pty}
+      Px.wait(p)
+    end
+
+    test "set_term_mode requires a pty" do
+      p = Px.spawn!("true", [])
+      assert Px.set_term_mode(p, :raw) == {:error, :not_pty}
+      Px.wait(p)
+    end
+
+    test "send_control requires a pty" do
+      p = Px.spawn!("cat", [], stdin: :pipe)
+      assert Px.send_control(p, :intr) == {:error, :not_pty}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+  end
+
+  describe "start_stopped" do
+    test "holds the child after exec until resumed" do
+      p = Px.spawn!("echo", ["hi"], start_stopped: true, stdout: :pipe)
+      Process.sleep(50)
+
+      assert Px.alive?(p)
+      assert proc_state(p.pid) == "T"
+      assert Px.read(p, :stdout) == :would_block
+
+      {:ok, p} = Px.resume(p)
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+    end
+
+    test "reports exec failures as usual" do
+      assert {:error, :not_found, _} = Px.spawn("/nonexistent/px", [], start_stopped: true)
+    end
+  end
+
+  describe "adopt" do
+    test "waits for and signals a process it did not spawn" do
+      os_pid = background("sleep 10")
+      assert {:ok, p} = Px.adopt(os_pid)
+      assert %Px{pid: ^os_pid, status: :running, cmd: nil} = p
+      assert Px.alive?(p)
+      assert Px.state(p) == :running
+      assert Px.spawn_method(p) == :adopted
+      assert Px.stdio_modes(p) == %{stdin: :null, stdout: :null, stderr: :null}
+
+      assert {:ok, p} = Px.signal(p, :sigterm)
+      assert Px.wait(p).status == {:exited, :reaped_externally}
+      refute Px.alive?(p)
+      assert Px.state(p) == :reaped
+      assert Px.signal(%{p | status: :running}, :sigterm) == {:error, :already_exited}
+    end
+
+    test "times out and terminates like a spawned child" do
+      p = "sleep 10" |> background() |> Px.adopt() |> elem(1)
+      assert Px.wait(p, 50) == :timeout
+      assert {:ok, p} = Px.signal(p, :sigstop)
+      Process.sleep(50)
+      assert Px.state(p) == :stopped
+      assert {:ok, _} = Px.signal(p, :sigkill)
+      assert Px.wait(p, 1000).status == {:exited, :reaped_externally}
+    end
+
+    test "returns not_found for a pid with no process" do
+      p = Px.spawn!("true", [])
+      Px.wait(p)
+      assert Px.adopt(p.pid) == {:error, :not_found}
+    end
+  end
+
+  describe "state" do
+    test "follows the child from running through stopped to reaped" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.state(p) == :running
+
+      {:ok, p} = Px.signal(p, :sigstop)
+      Process.sleep(50)
+      assert Px.state(p) == :stopped
+
+      {:ok, p} = Px.signal(p, :sigkill)
+      Process.sleep(50)
+      assert Px.state(p) == :zombie
+      assert Px.pid_alive?(p)
+
+      Px.wait(p)
+      assert Px.state(p) == :reaped
+    end
+  end
+
+  describe "pid_alive?" do
+    test "does not reap an exited child" do
+      p = Px.spawn!("sh", ["-c", "exit 7"])
+      Process.sleep(50)
+
+      assert Px.pid_alive?(p)
+      assert proc_state(p.pid) == "Z"
+      assert Px.wait(p).status == {:exited, 7}
+      assert Px.pid_alive?(p) == false
+    end
+
+    test "is true for a running child" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.pid_alive?(p)
+      Px.destroy(p)
+    end
+  end
+
+  describe "try_wait_usage" do
+    test "returns :still_running without blocking" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.try_wait_usage(p) == :still_running
+      Px.destroy(p)
+    end
+
+    test "reaps the child and reports its usage" do
+      p = Px.spawn!("sh", ["-c", "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done; exit 3"])
+      Process.sleep(500)
+
+      assert {{:exited, 3}, usage} = Px.try_wait_usage(p)
+      assert usage.user_time_us + usage.system_time_us > 0
+      assert usage.max_rss_kb > 0
+      assert Px.wait(p).status == {:exited, 3}
+    end
+
+    test "reports signal deaths" do
+      p = Px.spawn!("sleep", ["10"])
+      {:ok, _} = Px.signal(p, :sigkill)
+      Process.sleep(50)
+
+      assert {{:signaled, 9, false}, %{}} = Px.try_wait_usage(p)
+    end
+
+    test "usage is nil once another call has reaped the child" do
+      p = Px.spawn!("true", [])
+      Px.wait(p)
+      assert Px.try_wait_usage(p) == {{:exited, 0}, nil}
+    end
+  end
+
+  describe "exit_info" do
+    test "distinguishes exit 137 from a SIGKILL death" do
+      exited = Px.spawn!("sh", ["-c", "exit 137"]) |> Px.wait()
+      killed = Px.spawn!("sleep", ["10"])
+      {:ok, _} = Px.signal(killed, :sigkill)
+      killed = Px.wait(killed)
+
+      assert exited.status == {:exited, 137}
+      assert killed.status == {:exited, 137}
+      assert Px.exit_info(exited) == {:ok, %{code: 137, signal: nil, core_dumped: false}}
+      assert Px.exit_info(killed) == {:ok, %{code: nil, signal: 9, core_dumped: false}}
+    end
+
+    test "returns {:error, :running} before the exit is recorded" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.exit_info(p) == {:error, :running}
+      Px.destroy(p)
+    end
+  end
+
+  describe "signal_info" do
+    test "describes a signal death from waitid" do
+      p = Px.spawn!("sleep", ["10"])
+      {:ok, _} = Px.signal(p, :sigterm)
+      p = Px.wait(p)
+
+      {uid, 0} = System.cmd("id", ["-u"])
+      uid = uid |> String.trim() |> String.to_integer()
+      assert {:ok, info} = Px.signal_info(p)
+      assert info == %{signal: 15, code: :killed, status: 15, pid: p.pid, uid: uid}
+    end
+
+    test "is kept when alive? polling reaps the child" do
+      p = Px.spawn!("sleep", ["10"])
+      {:ok, _} = Px.signal(p, :sigkill)
+      Process.sleep(50)
+      refute Px.alive?(p)
+
+      assert {:ok, %{signal: 9, code: :killed}} = Px.signal_info(p)
+      assert Px.exit_info(p) == {:ok, %{code: nil, signal: 9, core_dumped: false}}
+    end
+
+    test "reports not_signaled for a normal exit" do
+      p = Px.spawn!("sh", ["-c", "exit 137"]) |> Px.wait()
+      assert Px.signal_info(p) == {:error, :not_signaled}
+    end
+
+    test "returns {:error, :running} before the exit is recorded" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.signal_info(p) == {:error, :running}
+      Px.destroy(p)
+    end
+  end
+
+  describe "termination_signal" do
+    test "attributes a signal we sent" do
+      p = Px.spawn!("sleep", ["10"])
+      {:ok, p} = Px.destroy(p)
+      assert Px.termination_signal(p) == {:signaled, 9, true}
+    end
+
+    test "flags a signal sent from outside" do
+      p = Px.spawn!("sleep", ["10"])
+      {_, 0} = System.cmd("kill", ["-TERM", to_string(p.pid)])
+      Px.wait(p)
+      assert Px.termination_signal(p) == {:signaled, 15, false}
+    end
+
+    test "distinguishes a different signal than the one we sent" do
+      p = Px.spawn!("sh", ["-c", "trap '' TERM; sleep 10"])
+      Process.sleep(50)
+      {:ok, p} = Px.signal(p, :sigterm)
+      {_, 0} = System.cmd("kill", ["-KILL", to_string(p.pid)])
+      Px.wait(p)
+      assert Px.termination_signal(p) == {:signaled, 9, false}
+    end
+
+    test "reports normal exits and running children" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.termination_signal(p) == {:error, :running}
+      Px.destroy(p)
+
+      p = Px.spawn!("true", [])
+      Px.wait(p)
+      assert Px.termination_signal(p) == :not_signaled
+    end
+  end
+
+  describe "cancel_wait" do
+    test "releases a blocked wait while the child keeps running" do
+      p = Px.spawn!("sleep", ["10"])
+      waiter = Task.async(fn -> Px.wait(p) end)
+      Process.sleep(50)
+
+      assert Px.cancel_wait(p) == :ok
+      assert Task.await(waiter, 1_000) == :cancelled
+      assert Px.alive?(p)
+
+      {:ok, p} = Px.destroy(p)
+      assert p.status == {:exited, 137}
+    end
+
+    test "reports when nothing is waiting" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.cancel_wait(p) == {:error, :not_waiting}
+      Px.destroy(p)
+    end
+
+    test "does not affect later waits" do
+      p = Px.spawn!("sh", ["-c", "sleep 0.2"])
+      waiter = Task.async(fn -> Px.wait(p) end)
+      Process.sleep(50)
+      :ok = Px.cancel_wait(p)
+      :cancelled = Task.await(waiter)
+
+      assert Px.wait(p).status == {:exited, 0}
+    end
+  end
+
+  describe "destroy" do
+    test "kills and reaps a running process" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe, stdout: :pipe, stderr: :pipe)
+      assert {:ok, p} = Px.destroy(p)
+      assert p.status == {:exited, 137}
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+      assert Px.write(p, "x") == {:error, :not_piped}
+    end
+
+    test "is idempotent" do
+      p = Px.spawn!("sleep", ["10"])
+      {:ok, first} = Px.destroy(p)
+      {:ok, second} = Px.destroy(p)
+      assert first.status == second.status
+    end
+
+    test "keeps the status of a process that already exited" do
+      p = Px.spawn!("sh", ["-c", "exit 3"])
+      p = Px.wait(p)
+      assert {:ok, %Px{status: {:exited, 3}}} = Px.destroy(p)
+    end
+  end
+
+  describe "shutdown" do
+    test "closes stdin, drains the output and reaps the child" do
+      p =
+        Px.spawn!("sh", ["-c", "cat; echo done >&2"], stdin: :pipe, stdout: :pipe, stderr: :pipe)
+
+      assert Px.write(p, "input") == :ok
+
+      assert {:ok, p, "input", "done\n"} = Px.shutdown(p)
+      assert p.status == {:exited, 0}
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+    end
+
+    test "keeps output written after the signal" do
+      script = "trap 'echo cleaning up; exit 3' TERM; echo ready; while :; do sleep 0.01; done"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe)
+      assert {:ok, "ready\n"} = Px.read_chunk(p)
+
+      assert {:ok, p, "cleaning up\n", ""} = Px.shutdown(p, signal: :sigterm, grace: 1000)
+      assert p.status == {:exited, 3}
+    end
+
+    test "kills a child that outlives the grace period, keeping its output" do
+      script = "trap 'echo ignored' TERM; echo ready; while :; do sleep 0.01; done"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe)
+      assert {:ok, "ready\n"} = Px.read_chunk(p)
+
+      assert {:ok, p, "ignored\n", ""} = Px.shutdown(p, signal: :sigterm, grace: 200)
+      assert p.status == {:exited, 137}
+    end
+
+    test "without drain, a child blocked on a full pipe is not waited on forever" do
+      p = Px.spawn!("sh", ["-c", "head -c 1000000 /dev/zero"], stdout: :pipe)
+      Process.sleep(100)
+
+      assert {:ok, p, "", ""} = Px.shutdown(p, drain: false)
+      assert p.status != {:exited, 0}
+    end
+
+    test "leaves stdin open when asked" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+
+      assert {:ok, p, "", ""} =
+               Px.shutdown(p, close_stdin: false, signal: :sigterm, grace: 1000)
+
+      assert p.status == {:exited, 143}
+    end
+  end
+
+  describe "line mode" do
+    test "returns one complete line per read" do
+      p = Px.spawn!("printf", ["one\\ntwo\\nthr"], stdout: :pipe, lines: true)
+      Px.wait(p)
+
+      assert Px.read(p, :stdout) == {:ok, "one\n"}
+      assert Px.read(p, :stdout) == {:ok, "two\n"}
+      assert Px.read(p, :stdout) == {:ok, "thr"}
+      assert Px.read(p, :stdout) == :eof
+    end
+
+    test "holds back a partial line until its newline arrives" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe, lines: true)
+
+      :ok = Px.write(p, "hel")
+      Process.sleep(50)
+      assert Px.read(p, :stdout) == :would_block
+
+      :ok = Px.write(p, "lo\nwor")
+      Process.sleep(50)
+      assert Px.read(p, :stdout) == {:ok, "hello\n"}
+      assert Px.read(p, :stdout) == :would_block
+
+      Px.close!(p, :stdin)
+      Px.wait(p)
+      assert Px.read(p, :stdout) == {:ok, "wor"}
+      assert Px.read(p, :stdout) == :eof
+    end
+
+    test "applies to stderr" do
+      p = Px.spawn!("sh", ["-c", "printf 'a\\nb' >&2"], stderr: :pipe, lines: true)
+      Px.wait(p)
+
+      assert Px.read(p, :stderr) == {:ok, "a\n"}
+      assert Px.read(p, :stderr) == {:ok, "b"}
+      assert Px.read(p, :stderr) == :eof
+    end
+  end
+
+  describe "external reaping" do
+    # With SIGCHLD ignored the kernel reaps children itself, so waiting
+    # fails with ECHILD just as if another handler had collected them.
+    setup do
+      :os.set_signal(:sigchld, :ignore)
+      on_exit(fn -> :os.set_signal(:sigchld, :default) end)
+    end
+
+    test "wait reports :reaped_externally instead of raising" do
+      {:ok, p} = Px.spawn("true", [])
+      p = Px.wait(p)
+      assert p.status == {:exited, :reaped_externally}
+      assert Px.signal(p, :sigterm) == {:error, :already_exited}
+    end
+
+    test "alive? reports false" do
+      {:ok, p} = Px.spawn("true", [])
+      Process.sleep(50)
+      assert Px.alive?(p) == false
+      assert Px.wait(p).status == {:exited, :reaped_externally}
+    end
+
+    test "verify_identity refuses to signal a PID that was reaped elsewhere" do
+      {:ok, p} = Px.spawn("true", [])
+      Process.sleep(50)
+      assert Px.signal(p, :sigterm, verify_identity: true) == {:error, :identity_mismatch}
+    end
+  end
+
+  describe "signal_if" do
+    test "signals only in the expected state" do
+      p = Px.spawn!("sleep", ["10"])
+      assert Px.signal_if(p, :stopped, :sigcont) == {:error, :state_mismatch}
+      assert {:ok, p} = Px.signal_if(p, :running, :sigstop)
+      Process.sleep(50)
+
+      assert Px.signal_if(p, :running, :sigterm) == {:error, :state_mismatch}
+      assert {:ok, p} = Px.signal_if(p, :stopped, :sigcont)
+      Process.sleep(50)
+      assert {:ok, p} = Px.signal_if(p, :running, :sigterm)
+      assert Px.wait(p).status == {:exited, 143}
+    end
+
+    test "refuses an exited child, reaped or not" do
+      p = Px.spawn!("true", [])
+      Process.sleep(50)
+      assert Px.signal_if(p, :running, :sigterm) == {:error, :already_exited}
+
+      p = Px.wait(p)
+      assert Px.signal_if(p, :running, :sigterm) == {:error, :already_exited}
+    end
+  end
+
+  describe "signal with verify_identity" do
+    test "signals the child it was spawned as" do
+      {:ok, p} = Px.spawn("sleep", ["10"])
+      assert {:ok, p} = Px.signal(p, :sigterm, verify_identity: true)
+      assert Px.wait(p).status == {:exited, 143}
+    end
+  end
+
+  describe "sigqueue" do
+    test "delivers the signal to the child" do
+      script = "trap 'echo got; exit 0' USR1; echo ready; while :; do sleep 0.01; done"
+      p = Px.spawn!("sh", ["-c", script], stdout: :pipe)
+      assert {:ok, "ready\n"} = Px.read_chunk(p)
+
+      assert {:ok, p} = Px.sigqueue(p, :sigusr1, 42)
+      p = Px.wait(p)
+      assert p.status == {:exited, 0}
+      assert collect_stdout(p) == "got\n"
+    end
+
+    test "accepts realtime signals" do
+      p = Px.spawn!("sleep", ["10"])
+      assert {:ok, p} = Px.sigqueue(p, 35, -1)
+      assert Px.wait(p).status == {:exited, 128 + 35}
+    end
+
+    test "refuses an exited child" do
+      p = Px.spawn!("true", []) |> Px.wait()
+      assert Px.sigqueue(p, :sigterm, 1) == {:error, :already_exited}
+    end
+  end
+
+  describe "race" do
+    @describetag :tmp_dir
+
+    test "returns the first success and kills the rest", %{tmp_dir: dir} do
+      pidfile = Path.join(dir, "loser.pid")
+      loser = {"sh", ["-c", "echo $$ > #{pidfile}; sleep 10; echo late"]}
+      winner = {"sh", ["-c", "sleep 0.1; seq 100000"]}
+
+      assert {:ok, 1, output} = Px.race([loser, winner])
+      assert output == Enum.map_join(1..100_000, &"#{&1}\n")
+
+      pid = pidfile |> File.read!() |> String.trim()
+      assert {_, 1} = System.cmd("kill", ["-0", pid], stderr_to_stdout: true)
+    end
+
+    test "skips failures while another command is still running" do
+      specs = [{"sh", ["-c", "echo partial; exit 1"]}, {"sh", ["-c", "sleep 0.1; echo ok"]}]
+      assert Px.race(specs) == {:ok, 1, "ok\n"}
+    end
+
+    test "reports every exit when all fail" do
+      specs = [{"false", []}, {"sh", ["-c", "kill -TERM $$"]}]
+      assert Px.race(specs) == {:error, :all_failed, [{:exited, 1}, {:signaled, 15, false}]}
+    end
+
+    test "kills everything on timeout" do
+      assert Px.race([{"sleep", ["10"]}, {"sleep", ["10"]}], timeout: 50) == {:error, :timeout}
+    end
+
+    test "destroys spawned commands when a later spawn fails" do
+      specs = [{"sleep", ["10"]}, {"/nonexistent/px-command", []}]
+      assert {:error, :not_found, _} = Px.race(specs)
+    end
+  end
+
+  describe "write_close" do
+    test "writes a payload larger than the pipe and closes stdin" do
+      p = Px.spawn!("wc", ["-c"], stdin: :pipe, stdout: :pipe)
+      assert Px.write_close(p, :binary.copy("x", 1_000_000)) == :ok
+      assert Px.wait_stdout_eof(p, 5_000) == {:eof, "1000000\n"}
+      assert Px.write(p, "more") == {:error, :not_piped}
+    end
+
+    test "writes a pending tail first" do
+      p = Px.spawn!("sh", ["-c", "sleep 0.1; cat"], stdin: :pipe, stdout: :pipe)
+      chunk = :binary.copy("a", 100_000)
+      assert {:partial, _} = Px.write(p, chunk)
+
+      assert Px.write_close(p, "b") == :ok
+      assert {:eof, output} = Px.wait_stdout_eof(p, 5_000)
+      assert output == chunk <> "b"
+    end
+
+    test "leaves stdin open with the rest pending on timeout" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      data = :binary.copy("x", 200_000)
+
+      assert {:partial, n} = Px.write_close(p, data, timeout: 50)
+      assert n < byte_size(data)
+      assert Px.pending_write(p) == {:ok, byte_size(data) - n}
+      Px.destroy(p)
+    end
+
+    test "reports a child that closed stdin" do
+      p = Px.spawn!("sh", ["-c", "exec 0<&-; sleep 10"], stdin: :pipe)
+      Process.sleep(50)
+      assert Px.write_close(p, "data") == {:error, :broken_pipe}
+      Px.destroy(p)
+    end
+  end
+
+  describe "write iodata" do
+    test "writes nested binaries and bytes in order" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      data = ["he", [?l, "l", [[], ?o]], ?\s, ["wor" | "ld"]]
+
+      assert Px.write(p, data) == :ok
+      Px.close!(p, :stdin)
+      assert Px.wait_stdout_eof(p, 1_000) == {:eof, "hello world"}
+      assert Px.io_counters(p).stdin_written == 11
+    end
+
+    test "keeps the unwritten tail pending" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      chunk = :binary.copy("x", 65_536)
+
+      assert {:partial, n} = Px.write(p, [chunk, chunk, "tail"])
+      assert Px.pending_write(p) == {:ok, 2 * 65_536 + 4 - n}
+      Px.destroy(p)
+    end
+
+    test "rejects a term that is not iodata" do
+      p = Px.spawn!("cat", [], stdin: :pipe)
+      assert_raise ArgumentError, fn -> Px.write(p, ["ok", 256]) end
+      assert_raise ArgumentError, fn -> Px.write(p, [:atom]) end
+      Px.destroy(p)
+    end
+  end
+
+  describe "write_line" do
+    test "appends a newline only when missing" do
+      {:ok, p} = Px.coprocess("cat", [])
+      assert Px.write_line(p, "one") == :ok
+      assert Px.write_line(p, "two\n") == :ok
+      Px.close!(p, :stdin)
+
+      assert collect_until_eof(p) == "one\ntwo\n"
+      Px.wait(p)
+    end
+
+    test "keeps the rest of a partial line, newline included" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      data = :binary.copy("x", 1_000_000)
+
+      {:partial, written} = Px.write_line(p, data)
+      assert Px.pending_write(p) == {:ok, byte_size(data) + 1 - written}
+      Px.destroy(p)
+    end
+
+    test "requires piped stdin" do
+      p = Px.spawn!("true", [])
+      assert Px.write_line(p, "x") == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "write_continue" do
+    test "resumes a write that filled the pipe" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      data = :binary.copy("x", 1_000_000)
+
+      {:partial, written} = Px.write(p, data)
+      assert Px.pending_write(p) == {:ok, byte_size(data) - written}
+      assert Px.write_continue(p) == :would_block
+      assert Px.pending_write(p) == {:ok, byte_size(data) - written}
+
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "delivers the remaining bytes once the child reads" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      data = :binary.copy("y", 200_000)
+
+      assert {:partial, _} = Px.write(p, data)
+      output = drain_writes(p, <<>>)
+      Px.close!(p, :stdin)
+      output = output <> collect_until_eof(p)
+
+      assert output == data
+      Px.wait(p)
+    end
+
+    test "is :ok when nothing is pending" do
+      p = Px.spawn!("cat", [], stdin: :pipe)
+      assert Px.pending_write(p) == {:ok, 0}
+      assert Px.write_continue(p) == :ok
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "requires piped stdin" do
+      p = Px.spawn!("true", [])
+      assert Px.write_continue(p) == {:error, :not_piped}
+      assert Px.pending_write(p) == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "take_fd" do
+    test "hands the stdin write end to another child" do
+      {:ok, cat} = Px.coprocess("cat", [])
+      {:ok, fd} = Px.take_fd(cat, :stdin)
+      assert Px.write(cat, "x") == {:error, :not_piped}
+
+      p = Px.spawn!("echo", ["hi"], file_actions: [{:dup2, fd, 1}])
+      assert Px.wait(p).status == {:exited, 0}
+      assert Px.close_fd(fd) == :ok
+
+      cat = Px.wait(cat)
+      assert collect_until_eof(cat) == "hi\n"
+    end
+
+    test "hands over the stdout read end" do
+      p = Px.spawn!("echo", ["out"], stdout: :pipe)
+      {:ok, fd} = Px.take_fd(p, :stdout)
+      assert Px.read(p, :stdout) == {:error, :not_piped}
+      Px.wait(p)
+
+      reader = Px.spawn!("cat", [], stdout: :pipe, file_actions: [{:dup2, fd, 0}])
+      :ok = Px.close_fd(fd)
+      assert collect_until_eof(reader) == "out\n"
+      Px.wait(reader)
+    end
+
+    test "refuses while stdin has a pending write" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      {:partial, _} = Px.write(p, :binary.copy("x", 1_000_000))
+      assert Px.take_fd(p, :stdin) == {:error, :pending_data}
+      Px.destroy(p)
+    end
+
+    test "requires a piped stream" do
+      p = Px.spawn!("true", [])
+      assert Px.take_fd(p, :stdin) == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "dup_fd" do
+    test "gives a second handle while the stream stays readable" do
+      p = Px.spawn!("sh", ["-c", "echo one; read x; echo two"], stdin: :pipe, stdout: :pipe)
+      {:ok, dup} = Px.dup_fd(p, :stdout)
+      assert Px.read_chunk(p) == {:ok, "one\n"}
+      {:ok, fd} = Px.into_fd(dup)
+
+      :ok = Px.write(p, "go\n")
+      p = Px.wait(p)
+
+      reader = Px.spawn!("cat", [], stdout: :pipe, file_actions: [{:dup2, fd, 0}])
+      :ok = Px.close_fd(fd)
+      assert collect_until_eof(reader) == "two\n"
+      Px.wait(reader)
+      assert Px.read(p, :stdout) == :eof
+    end
+
+    test "keeps the pipe open after close" do
+      p = Px.spawn!("sh", ["-c", "read x; echo late"], stdin: :pipe, stdout: :pipe)
+      {:ok, dup} = Px.dup_fd(p, :stdout)
+      assert Px.close(p, :stdout) == :ok
+
+      :ok = Px.write(p, "go\n")
+      assert Px.wait(p).status == {:exited, 0}
+
+      assert Px.read_fd(dup) == {:ok, "late\n"}
+      assert Px.read_fd(dup) == :eof
+    end
+
+    test "is given up once taken" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe)
+      {:ok, dup} = Px.dup_fd(p, :stdout)
+      {:ok, fd} = Px.into_fd(dup)
+
+      assert Px.into_fd(dup) == {:error, :closed}
+      assert Px.read_fd(dup) == {:error, :closed}
+      assert Px.close_fd(fd) == :ok
+      Px.wait(p)
+    end
+
+    test "requires a piped stream" do
+      p = Px.spawn!("true", [])
+      assert Px.dup_fd(p, :stdout) == {:error, :not_piped}
+      Px.wait(p)
+    end
+  end
+
+  describe "flush" do
+    test "blocks until the pending stdin bytes are written" do
+      p = Px.spawn!("sh", ["-c", "sleep 0.2; wc -c"], stdin: :pipe, stdout: :pipe)
+      data = :binary.copy("z", 200_000)
+
+      assert {:partial, _} = Px.write(p, data)
+      assert Px.flush(p) == :ok
+      assert Px.pending_write(p) == {:ok, 0}
+      Px.close!(p, :stdin)
+
+      assert String.trim(collect_until_eof(p)) == "200000"
+      Px.wait(p)
+    end
+
+    test "times out with the rest still pending" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      {:partial, written} = Px.write(p, :binary.copy("x", 1_000_000))
+
+      assert Px.flush(p, timeout: 50) == :timeout
+      assert Px.pending_write(p) == {:ok, 1_000_000 - written}
+      Px.destroy(p)
+    end
+
+    test "reports which buffer failed" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      {:partial, _} = Px.write(p, :binary.copy("x", 1_000_000))
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+
+      assert Px.flush(p) == {:error, {:stdin, :broken_pipe}}
+      assert Px.pending_write(p) == {:ok, 0}
+    end
+
+    test "is :ok with nothing to flush" do
+      p = Px.spawn!("true", [])
+      assert Px.flush(p) == :ok
+      Px.wait(p)
+    end
+  end
+
+  describe "coprocess" do
+    test "answers one line per request" do
+      {:ok, p} = Px.coprocess("sh", ["-c", "while read x; do echo \"got $x\"; done"])
+
+      assert Px.request(p, "one\n") == {:ok, "got one\n"}
+      assert Px.request(p, "two\n") == {:ok, "got two\n"}
+
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "keeps output past the first line for the next call" do
+      {:ok, p} = Px.coprocess("cat", [])
+
+      assert Px.request(p, "one\ntwo\n") == {:ok, "one\n"}
+      assert Px.read(p, :stdout) == {:ok, "two\n"}
+
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "returns a chunk in :chunk mode" do
+      {:ok, p} = Px.coprocess("cat", [])
+      assert Px.request(p, "partial", read: :chunk) == {:ok, "partial"}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "caps a chunk at :max_bytes and keeps the rest" do
+      {:ok, p} = Px.coprocess("cat", [])
+      assert Px.request(p, "abcdef", read: :chunk, max_bytes: 4) == {:ok, "abcd"}
+      assert Px.request(p, "", read: :chunk) == {:ok, "ef"}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+
+    test "times out when the child does not answer" do
+      {:ok, p} = Px.coprocess("sleep", ["10"])
+      assert Px.request(p, "hello\n", timeout: 50) == :timeout
+      {:ok, _} = Px.signal(p, :sigkill)
+      Px.wait(p)
+    end
+
+    test "returns :eof once the child is gone" do
+      {:ok, p} = Px.coprocess("true", [])
+      Px.wait(p)
+      assert Px.request(p, "", read: :chunk) == :eof
+    end
+
+    test "requires stdin and stdout to be piped" do
+      p = Px.spawn!("cat", [], stdin: :pipe)
+      assert Px.request(p, "x\n") == {:error, :not_piped}
+      Px.close!(p, :stdin)
+      Px.wait(p)
+    end
+  end
+
+  describe "fault injection" do
+    setup do
+      on_exit(fn -> Px.clear_faults_nif() end)
+    end
+
+    test "a failed read is reported and the next one succeeds" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe) |> Px.wait()
+      Px.inject_fault_nif(:read, 1, 5)
+
+      assert {:error, message} = Px.read(p, :stdout)
+      assert message =~ "os error 5"
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+    end
+
+    test "a read error in read_balanced is returned, not taken as EOF" do
+      cmd = "echo out; echo err >&2"
+      p = Px.wait(Px.spawn!("sh", ["-c", cmd], stdout: :pipe, stderr: :pipe))
+      Px.inject_fault_nif(:read, 1, 5)
+
+      assert {:error, message} = Px.read_balanced(p)
+      assert message =~ "os error 5"
+      assert Px.read_balanced(p) == {"out\n", "err\n"}
+    end
+
+    test "a read error in read_prioritized is returned, not taken as EOF" do
+      cmd = "echo out; echo err >&2"
+      p = Px.wait(Px.spawn!("sh", ["-c", cmd], stdout: :pipe, stderr: :pipe))
+      Px.inject_fault_nif(:read, 1, 5)
+
+      assert {:error, message} = Px.read_prioritized(p, :stderr)
+      assert message =~ "os error 5"
+      assert Px.read_prioritized(p, :stderr) == {"out\n", "err\n"}
+    end
+
+    test "EAGAIN on read is :would_block even with data ready" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe) |> Px.wait()
+      Px.inject_fault_nif(:read, 1, 11)
+
+      assert Px.read(p, :stdout) == :would_block
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+    end
+
+    test "draining retries a read interrupted by EINTR" do
+      p = Px.spawn!("sh", ["-c", "seq 1 1000"], stdout: :pipe)
+      Px.inject_fault_nif(:read, 2, 4)
+
+      assert {:ok, _p, out, ""} = Px.communicate(p)
+      assert out == Enum.map_join(1..1000, &"#{&1}\n")
+    end
+
+    test "EPIPE on write is :broken_pipe" do
+      p = Px.spawn!("cat", [], stdin: :pipe, stdout: :pipe)
+      Px.inject_fault_nif(:write, 2, 32)
+
+      assert Px.write(p, "one") == :ok
+      assert Px.write(p, "two") == {:error, :broken_pipe}
+      assert Px.write(p, "three") == :ok
+      assert {:ok, _p, "onethree", ""} = Px.communicate(p)
+    end
+
+    test "ECHILD while reaping is an external reap" do
+      p = Px.spawn!("true", [])
+      Px.inject_fault_nif(:wait, 1, 10)
+      assert Px.wait(p).status == {:exited, :reaped_externally}
+    end
+
+    test "EPIPE while flushing drops the pending bytes" do
+      p = Px.spawn!("sleep", ["10"], stdin: :pipe)
+      {:partial, _} = Px.write(p, :binary.copy("x", 1_000_000))
+      Px.inject_fault_nif(:write, 1, 32)
+
+      assert Px.flush(p) == {:error, {:stdin, :broken_pipe}}
+      assert Px.pending_write(p) == {:ok, 0}
+      Px.destroy(p)
+    end
+
+    test "ECHILD while polling is an external reap" do
+      p = Px.spawn!("true", [])
+      Px.inject_fault_nif(:wait, 1, 10)
+
+      refute Px.alive?(p)
+      assert Px.wait(p).status == {:exited, :reaped_externally}
+    end
+
+    test "a failed allocation is an error, not a crash" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe) |> Px.wait()
+      Px.inject_fault_nif(:alloc, 1, 0)
+
+      assert Px.read(p, :stdout) == {:error, "Failed to allocate binary"}
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+    end
+
+    test "a failed allocation keeps a buffered line for the next read" do
+      p = Px.wait(Px.spawn!("printf", ["a\\nb"], stdout: :pipe, lines: true))
+
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read(p, :stdout) == {:error, "Failed to allocate binary"}
+      assert Px.read(p, :stdout) == {:ok, "a\n"}
+
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read(p, :stdout) == {:error, "Failed to allocate binary"}
+      assert Px.read(p, :stdout) == {:ok, "b"}
+    end
+
+    test "a failed allocation keeps a complete frame for the next read" do
+      p = Px.wait(Px.spawn!("printf", ["\\002hi"], stdout: :pipe))
+
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read_frame(p, length_bytes: 1) == {:error, "Failed to allocate binary"}
+      assert Px.read_frame(p, length_bytes: 1) == {:ok, "hi"}
+    end
+
+    test "a failed allocation keeps a read chunk for the next read" do
+      p = Px.wait(Px.spawn!("echo", ["hi"], stdout: :pipe))
+
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read_chunk(p) == {:error, "Failed to allocate binary"}
+      assert Px.read_chunk(p) == {:ok, "hi\n"}
+    end
+
+    test "a failed allocation keeps balanced and prioritized reads buffered" do
+      cmd = "echo out; echo err >&2"
+      p = Px.wait(Px.spawn!("sh", ["-c", cmd], stdout: :pipe, stderr: :pipe))
+
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read_balanced(p) == {:error, "Failed to allocate binary"}
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read_prioritized(p, :stderr) == {:error, "Failed to allocate binary"}
+      assert Px.read_balanced(p) == {"out\n", "err\n"}
+    end
+
+    test "a failed allocation keeps read chunks and text buffered" do
+      p = Px.wait(Px.spawn!("echo", ["hi"], stdout: :pipe))
+
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read_chunks(p) == {:error, "Failed to allocate binary"}
+      Px.inject_fault_nif(:alloc, 1, 0)
+      assert Px.read_text(p, :stdout) == {:error, "Failed to allocate binary"}
+      assert Px.read_chunks(p) == {["hi\n"], :eof}
+    end
+
+    test "a poisoned lock is an error" do
+      p = Px.spawn!("cat", [], stdin: :pipe)
+      Px.inject_fault_nif(:lock, 1, 0)
+
+      assert {:error, "Lock failed: " <> _} = Px.communicate(p)
+      assert {:ok, _p, "", ""} = Px.communicate(p)
+    end
+
+    test "faults can be disarmed before they fire" do
+      p = Px.spawn!("echo", ["hi"], stdout: :pipe) |> Px.wait()
+      Px.inject_fault_nif(:read, 1, 5)
+      Px.clear_faults_nif()
+      assert Px.read(p, :stdout) == {:ok, "hi\n"}
+    end
+  end
+
+  defp read_output(p, stream, offset \\ 0, acc \\ []) do
+    case Px.read_output_file(p, stream, offset, 65_536) do
+      {:ok, data} -> read_output(p, stream, offset + byte_size(data), [acc, data])
+      :eof -> IO.iodata_to_binary(acc)
+    end
+  end
+
+  defp wait_for_file(path, expected, attempts \\ 100) do
+    cond do
+      File.read(path) == {:ok, expected} -> :ok
+      attempts == 0 -> flunk("#{path} never held #{inspect(expected)}")
+      true ->
+        Process.sleep(10)
+        wait_for_file(path, expected, attempts - 1)
+    end
+  end
+
+  defp drain_writes(p, acc) do
+    acc = acc <> collect_stdout(p)
+
+    case Px.pending_write(p) do
+      {:ok, 0} ->
+        acc
+
+      {:ok, _} ->
+        Px.write_continue(p)
+        Process.sleep(1)
+        drain_writes(p, acc)
+    end
+  end
+
+  defp collect_until_eof(p, acc \\ <<>>) do
+    case Px.read(p, :stdout) do
+      {:ok, data} -> collect_until_eof(p, acc <> data)
+      :would_block ->
+        Process.sleep(1)
+        collect_until_eof(p, acc)
+
+      :eof -> acc
+    end
+  end
+
+  defp collect_fanout(os_pid, acc) do
+    receive do
+      {:px_stdout, ^os_pid, :eof} -> acc
+      {:px_stdout, ^os_pid, data} -> collect_fanout(os_pid, acc <> data)
+    after
+      2000 -> flunk("no fan-out output after #{byte_size(acc)} bytes")
+    end
+  end
+
+  defp collect_fanout_chunks(%Px{pid: os_pid} = p, acc) do
+    receive do
+      {:px_stdout, ^os_pid, :eof} ->
+        Enum.reverse(acc)
+
+      {:px_stdout, ^os_pid, data} ->
+        :ok = Px.ack(p)
+        collect_fanout_chunks(p, [data | acc])
+    after
+      2000 -> flunk("no fan-out output after #{length(acc)} chunks")
+    end
+  end
+
+  # Starts `command` in the background of a shell that exits right away, so
+  # the BEAM is not its parent, and returns its OS pid.
+  defp background(command) do
+    {pid, 0} = System.cmd("sh", ["-c", "#{command} > /dev/null 2>&1 & echo $!"])
+    pid |> String.trim() |> String.to_integer()
+  end
+
+  # Collects the output of `:active` streams up to the exit message, which
+  # is left in the mailbox.
+  defp collect_active(resource, out \\ "", err \\ "") do
+    receive do
+      {:px_output, ^resource, :stdout, data} -> collect_active(resource, out <> data, err)
+      {:px_output, ^resource, :stderr, data} -> collect_active(resource, out, err <> data)
+      {:px_exit, ^resource, _exit} = exit ->
+        send(self(), exit)
+        {out, err}
+    after
+      2000 -> flunk("no exit after #{byte_size(out)} bytes of stdout")
+    end
+  end
+
+  # Polls for up to a second until no process has `os_pid`, reaped or not.
+  defp cgroup_pids(dir) do
+    dir
+    |> Path.join("cgroup.procs")
+    |> File.read!()
+    |> String.split()
+    |> Enum.map(&String.to_integer/1)
+  end
+
+  defp os_pid_gone?(os_pid, tries \\ 50) do
+    cond do
+      not File.exists?("/proc/#{os_pid}") -> true
+      tries == 0 -> false
+      true ->
+        Process.sleep(20)
+        os_pid_gone?(os_pid, tries - 1)
+    end
+  end
+
+  defp proc_state(pid) do
+    stat = File.read!("/proc/#{pid}/stat")
+    [_, after_name] = String.split(stat, ") ", parts: 2)
+    after_name |> String.split(" ") |> hd()
+  end
+
   # Helper to collect all stdout until :eof or :would_block
   defp collect_stdout(p, acc \\ <<>>) do
     case Px.read(p, :stdout) do